@@ -0,0 +1,627 @@
+//! センサーが水位の推定に影響する河川ノード(`AFFECTS`)の計算
+//!
+//! センサーの親河川ノードから`RIVER_LINK`を上流方向と下流方向に別々にたどり、それぞれの影響範囲
+//! (`scope_upstream`、`scope_downstream`。省略した場合は`scope`)にある河川ノードへ`AFFECTS`を作る。
+//! 一方向にのみたどるため経路が折り返すことはなく、対象の河川ノードまでの経路が複数ある場合は最短の距離を使う。
+//!
+//! たどる段数と到達する河川ノードの数には上限(`AFFECTS_MAX_LEVEL`、`AFFECTS_MAX_PATHS`)があり、上限に達した場合は
+//! 影響範囲が打ち切られた可能性があるものとして報告する。
+//!
+//! 最短の距離はどちらの場合もサーバー側の[`ShortestPaths`]で求める。APOCを使えるNeo4jでは最大の段数までの
+//! `RIVER_LINK`を`apoc.path.subgraphAll`で一度に取得し、使えない場合は隣接する`RIVER_LINK`を一段ずつ取得する。
+//! APOCで一度に取得する河川ノードの数にも上限([`subgraph_limit`])があり、上限に達した場合は一段ずつ取得し直す。
+//! 取得の仕方が違うだけなので、作られる`AFFECTS`と打ち切りの判定は同じになる。
+
+use std::collections::HashMap;
+
+use neo4rs::{query, BoltType, Txn};
+use serde::Deserialize;
+
+use crate::db::Capabilities;
+use crate::env::EnvVars;
+
+/// センサーの影響範囲(河道に沿った距離、メートル)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scope {
+    /// 親河川ノードより上流側
+    pub upstream: f64,
+    /// 親河川ノードより下流側
+    pub downstream: f64,
+}
+
+impl Scope {
+    /// 方向ごとの影響範囲が省略された場合は、`scope`を使う
+    pub fn new(scope: f64, upstream: Option<f64>, downstream: Option<f64>) -> Self {
+        Scope {
+            upstream: upstream.unwrap_or(scope),
+            downstream: downstream.unwrap_or(scope),
+        }
+    }
+
+    /// たどる向きと、その向きの影響範囲
+    pub fn directions(self) -> [(Direction, f64); 2] {
+        [(Direction::Upstream, self.upstream), (Direction::Downstream, self.downstream)]
+    }
+}
+
+/// 影響範囲をたどる上限
+#[derive(Debug, Clone, Copy)]
+pub struct AffectsLimits {
+    /// `RIVER_LINK`をたどる最大の段数
+    pub max_level: i64,
+    /// 一方向あたりに到達する河川ノードの数の上限
+    pub max_paths: i64,
+}
+
+impl From<&EnvVars> for AffectsLimits {
+    fn from(env: &EnvVars) -> Self {
+        AffectsLimits {
+            max_level: env.affects_max_level,
+            max_paths: env.affects_max_paths,
+        }
+    }
+}
+
+/// `RIVER_LINK`を取得する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    /// `apoc.path.subgraphAll`で、最大の段数までの`RIVER_LINK`を一度に取得する。取得する河川ノードが多すぎる場合は一段ずつ取得する
+    Apoc,
+    /// 素のCypherで、隣接する`RIVER_LINK`を一段ずつ取得する
+    Native,
+}
+
+impl From<Capabilities> for Traversal {
+    fn from(capabilities: Capabilities) -> Self {
+        if capabilities.apoc {
+            Traversal::Apoc
+        } else {
+            Traversal::Native
+        }
+    }
+}
+
+/// `RIVER_LINK`をたどる向き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upstream,
+    Downstream,
+}
+
+impl Direction {
+    /// APOCの`relationshipFilter`(`RIVER_LINK`は上流から下流に向かう)
+    fn relationship_filter(self) -> &'static str {
+        match self {
+            Direction::Upstream => "<RIVER_LINK",
+            Direction::Downstream => "RIVER_LINK>",
+        }
+    }
+
+    /// 対象の河川ノードから見たセンサーの位置
+    pub fn position(self) -> &'static str {
+        match self {
+            Direction::Upstream => "downstream",
+            Direction::Downstream => "upstream",
+        }
+    }
+
+    /// 河床の落差の符号(下流に向かって下がる場合に正)
+    pub fn drop_sign(self) -> f64 {
+        match self {
+            Direction::Upstream => -1.0,
+            Direction::Downstream => 1.0,
+        }
+    }
+}
+
+/// センサーが影響する河川ノードの範囲
+#[derive(Debug, Clone, PartialEq)]
+pub struct Affected {
+    /// 影響する河川ノードの数
+    pub nodes: i64,
+    pub min_distance: Option<f64>,
+    pub max_distance: Option<f64>,
+    /// たどる段数または到達する河川ノードの数の上限に達し、影響範囲が打ち切られた可能性がある
+    pub truncated: bool,
+}
+
+impl From<Affected> for openapi::models::AffectedRange {
+    fn from(affected: Affected) -> Self {
+        openapi::models::AffectedRange {
+            nodes: affected.nodes,
+            min_distance: affected.min_distance,
+            max_distance: affected.max_distance,
+            truncated: affected.truncated,
+        }
+    }
+}
+
+/// 1本の`RIVER_LINK`(たどる向きに合わせた始点と終点)
+#[derive(Debug, Clone, Deserialize)]
+pub struct Link {
+    pub from: String,
+    pub to: String,
+    pub length: f64,
+    pub slope: Option<f64>,
+}
+
+/// 河川ノードまでの最短の経路
+#[derive(Debug, Clone, Copy)]
+pub struct Reached {
+    /// 河道に沿った距離
+    pub distance: f64,
+    /// 河床の落差(`slope`を持たない`RIVER_LINK`を含む場合は`None`)。符号はたどる向きに合わせていない
+    pub drop: Option<f64>,
+    /// たどった段数
+    pub level: i64,
+}
+
+/// 親河川ノードから一方向に、範囲内の河川ノードまでの最短の経路を一段ずつ求める
+///
+/// `frontier`から伸びる`RIVER_LINK`を`extend`に渡すことを、`frontier`が空になるまで繰り返す
+/// (取得済みの`RIVER_LINK`からたどる場合は`extend_all`)。より短い距離で到達した河川ノードのみ次の段でたどる。
+/// 到達する河川ノードは段数の少ない順(同じ段の中では近い順)に数え、数が上限に達した後は、既に到達した河川ノードの距離のみ短くする。
+pub struct ShortestPaths {
+    parent: String,
+    scope: f64,
+    limits: AffectsLimits,
+    reached: HashMap<String, Reached>,
+    frontier: Vec<String>,
+    truncated: bool,
+}
+
+impl ShortestPaths {
+    pub fn new(parent: &str, scope: f64, limits: AffectsLimits) -> Self {
+        ShortestPaths {
+            parent: parent.to_string(),
+            scope,
+            limits,
+            reached: HashMap::from([(parent.to_string(), Reached { distance: 0.0, drop: Some(0.0), level: 0 })]),
+            frontier: vec![parent.to_string()],
+            truncated: false,
+        }
+    }
+
+    /// 次の段でたどる河川ノード
+    pub fn frontier(&self) -> &[String] {
+        &self.frontier
+    }
+
+    /// `frontier`から伸びる`RIVER_LINK`で経路を延ばす
+    ///
+    /// `RIVER_LINK`を受け取る順に依らず同じ結果になるよう、この段の中では近い河川ノードから順に到達させる。
+    pub fn extend(&mut self, links: Vec<Link>) {
+        let mut candidates = links.into_iter()
+            .filter_map(|link| {
+                let from = self.reached.get(&link.from).copied()?;
+                Some((link.to, Reached {
+                    distance: from.distance + link.length,
+                    drop: from.drop.zip(link.slope).map(|(drop, slope)| drop + link.length * slope),
+                    level: from.level + 1,
+                }))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(a, a_reached), (b, b_reached)| {
+            a_reached.distance.total_cmp(&b_reached.distance)
+                .then(a_reached.level.cmp(&b_reached.level))
+                .then_with(|| a.cmp(b))
+        });
+
+        let mut next = Vec::new();
+        for (to, candidate) in candidates {
+            if candidate.distance > self.scope || self.reached.get(&to).is_some_and(|known| known.distance <= candidate.distance) {
+                continue;
+            }
+            if candidate.level > self.limits.max_level || (!self.reached.contains_key(&to) && self.reached.len() as i64 > self.limits.max_paths) {
+                self.truncated = true;
+                continue;
+            }
+
+            self.reached.insert(to.clone(), candidate);
+            next.push(to);
+        }
+
+        next.sort();
+        next.dedup();
+        self.frontier = next;
+    }
+
+    /// 取得済みの`RIVER_LINK`だけで、`frontier`が空になるまで経路を延ばす
+    ///
+    /// `links`には、最大の段数より一段先までの`RIVER_LINK`が含まれている必要がある(打ち切りの判定に使う)。
+    pub fn extend_all(&mut self, links: &[Link]) {
+        let mut adjacent = HashMap::<&str, Vec<&Link>>::new();
+        for link in links {
+            adjacent.entry(link.from.as_str()).or_default().push(link);
+        }
+
+        while !self.frontier.is_empty() {
+            let next = self.frontier.iter()
+                .flat_map(|node| adjacent.get(node.as_str()).into_iter().flatten())
+                .map(|link| (*link).clone())
+                .collect();
+            self.extend(next);
+        }
+    }
+
+    /// 到達した河川ノード(親河川ノードを除く)と、上限に達したかどうか
+    pub fn finish(mut self) -> (HashMap<String, Reached>, bool) {
+        self.reached.remove(&self.parent);
+        (self.reached, self.truncated)
+    }
+}
+
+/// 河川ノード群から、一方向に隣接する`RIVER_LINK`を取得する
+async fn links_from(tnx: &mut Txn, nodes: &[String], direction: Direction) -> anyhow::Result<Vec<Link>> {
+    let pattern = match direction {
+        Direction::Upstream => "(from:RiverNode)<-[link:RIVER_LINK]-(to:RiverNode)",
+        Direction::Downstream => "(from:RiverNode)-[link:RIVER_LINK]->(to:RiverNode)",
+    };
+
+    let mut result = tnx.execute(query(&format!(
+        r#"
+UNWIND $nodes AS node
+MATCH {pattern}
+WHERE elementId(from) = node
+RETURN node AS from,
+       elementId(to) AS to,
+       toFloat(link.length) AS length,
+       toFloat(link.slope) AS slope
+        "#,
+    ))
+        .param("nodes", nodes.to_vec())
+    ).await?;
+
+    let mut links = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        links.push(row.to()?);
+    }
+
+    Ok(links)
+}
+
+/// 素のCypherで、`frontier`が空になるまで隣接する`RIVER_LINK`を一段ずつ取得して経路を延ばす
+async fn extend_from_frontier(tnx: &mut Txn, paths: &mut ShortestPaths, direction: Direction) -> anyhow::Result<()> {
+    while !paths.frontier().is_empty() {
+        let links = links_from(tnx, paths.frontier(), direction).await?;
+        paths.extend(links);
+    }
+
+    Ok(())
+}
+
+/// APOCで一度に取得する河川ノード(親河川ノードを含む)の数の上限
+///
+/// 到達できる河川ノード(親河川ノードを含めて`max_paths + 1`)に加えて、それぞれに隣接する範囲外の河川ノードを取得できる数とする。
+fn subgraph_limit(limits: AffectsLimits) -> i64 {
+    (limits.max_paths + 1).saturating_mul(2)
+}
+
+/// APOCで、親河川ノードから一方向に最大の段数より一段先までの`RIVER_LINK`を取得する
+///
+/// 一段先までを取得するのは、最大の段数に達した後も範囲内の河川ノードが続くか([`ShortestPaths`]の打ち切りの判定)を調べるため。
+/// 取得する河川ノードの数が[`subgraph_limit`]に達した場合は、取得できなかった`RIVER_LINK`があるかもしれないので`None`を返す。
+async fn subgraph_links(tnx: &mut Txn, parent: &str, direction: Direction, limits: AffectsLimits) -> anyhow::Result<Option<Vec<Link>>> {
+    let limit = subgraph_limit(limits);
+    let mut result = tnx.execute(query(
+        r#"
+MATCH (parent:RiverNode)
+WHERE elementId(parent) = $parent
+CALL apoc.path.subgraphAll(parent, {
+    relationshipFilter: $relationship_filter,
+    maxLevel: $max_level,
+    limit: $limit
+}) YIELD nodes, relationships
+RETURN size(nodes) AS nodes,
+       [link IN relationships | {
+           from: elementId(CASE WHEN $downstream THEN startNode(link) ELSE endNode(link) END),
+           to: elementId(CASE WHEN $downstream THEN endNode(link) ELSE startNode(link) END),
+           length: toFloat(link.length),
+           slope: toFloat(link.slope)
+       }] AS links
+        "#,
+    )
+        .param("parent", parent)
+        .param("relationship_filter", direction.relationship_filter())
+        .param("downstream", direction == Direction::Downstream)
+        .param("max_level", limits.max_level + 1)
+        .param("limit", limit)
+    ).await?;
+
+    let mut links = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        if row.get::<i64>("nodes")? >= limit {
+            return Ok(None);
+        }
+        links.extend(row.get::<Vec<Link>>("links")?);
+    }
+
+    Ok(Some(links))
+}
+
+/// 一方向に`RIVER_LINK`をたどり、範囲内の河川ノードへの`AFFECTS`を作る
+///
+/// `traversal`に応じて`RIVER_LINK`を一度に、または一段ずつ取得し、[`ShortestPaths`]でたどる。上限に達した場合は`true`を返す。
+async fn link_direction(tnx: &mut Txn, id: &str, parent: &str, direction: Direction, scope: f64, limits: AffectsLimits, traversal: Traversal) -> anyhow::Result<bool> {
+    // 1. 親河川ノードから、範囲内の河川ノードまでの最短の経路を求める
+    let mut paths = ShortestPaths::new(parent, scope, limits);
+    match traversal {
+        Traversal::Apoc => match subgraph_links(tnx, parent, direction, limits).await? {
+            Some(links) => paths.extend_all(&links),
+            None => extend_from_frontier(tnx, &mut paths, direction).await?,
+        },
+        Traversal::Native => extend_from_frontier(tnx, &mut paths, direction).await?,
+    }
+    let (reached, truncated) = paths.finish();
+
+    // 2. AFFECTS を作る
+    let targets = reached.into_iter()
+        .map(|(node, reached)| HashMap::from([
+            ("node".to_string(), BoltType::from(node)),
+            ("distance".to_string(), BoltType::from(reached.distance)),
+            ("drop".to_string(), BoltType::from(reached.drop)),
+        ]))
+        .collect::<Vec<_>>();
+
+    tnx.run(query(
+        r#"
+MATCH (sensor:Sensor {id: $id})
+UNWIND $targets AS target
+MATCH (node:RiverNode)
+WHERE elementId(node) = target.node
+CREATE (sensor)-[:AFFECTS {
+    distance: target.distance,
+    bed_drop: $drop_sign * target.drop,
+    position: $position
+}]->(node)
+        "#,
+    )
+        .param("id", id)
+        .param("targets", targets)
+        .param("position", direction.position())
+        .param("drop_sign", direction.drop_sign())
+    ).await?;
+
+    Ok(truncated)
+}
+
+/// センサーを親河川ノードに所属させ、影響範囲にある河川ノードへの`AFFECTS`を作る
+///
+/// 親河川ノードが存在しない場合は何もせずに`None`を返す。
+pub async fn link_to_river(tnx: &mut Txn, id: &str, parent_node: &str, scope: Scope, limits: AffectsLimits, traversal: Traversal) -> anyhow::Result<Option<Affected>> {
+    // 1. 親河川ノードに所属させる
+    let mut result = tnx.execute(query(
+        r#"
+MATCH (parent:RiverNode {hilbert18: $parent_node})
+MATCH (sensor:Sensor {id: $id})
+CREATE (sensor)-[:BELONGS_TO]->(parent)
+RETURN elementId(parent) AS parent
+        "#,
+    )
+        .param("id", id)
+        .param("parent_node", parent_node)
+    ).await?;
+
+    let mut parent = None;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        parent = Some(row.get::<String>("parent")?);
+    }
+    let Some(parent) = parent else {
+        return Ok(None);
+    };
+
+    // 2. 上流側と下流側をそれぞれたどる
+    let mut truncated = false;
+    for (direction, scope) in scope.directions() {
+        if link_direction(tnx, id, &parent, direction, scope, limits, traversal).await? {
+            println!("link_to_river: affected nodes of {id} ({direction:?}) may be truncated by the limits {limits:?}");
+            truncated = true;
+        }
+    }
+
+    // 3. 影響範囲の河川ノードの数と距離の範囲を集計する
+    let mut result = tnx.execute(query(
+        r#"
+MATCH (sensor:Sensor {id: $id})
+OPTIONAL MATCH (sensor)-[affects:AFFECTS]->(:RiverNode)
+RETURN count(affects) AS nodes,
+       toFloat(min(affects.distance)) AS min_distance,
+       toFloat(max(affects.distance)) AS max_distance
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut affected = Affected { nodes: 0, min_distance: None, max_distance: None, truncated };
+    while let Some(row) = result.next(tnx.handle()).await? {
+        affected = Affected {
+            nodes: row.get("nodes")?,
+            min_distance: row.get("min_distance")?,
+            max_distance: row.get("max_distance")?,
+            truncated,
+        };
+    }
+
+    Ok(Some(affected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNLIMITED: AffectsLimits = AffectsLimits { max_level: 1000, max_paths: 10000 };
+
+    fn link(from: &str, to: &str, length: f64, slope: Option<f64>) -> Link {
+        Link { from: from.to_string(), to: to.to_string(), length, slope }
+    }
+
+    /// `links`を河川ノードとして、親河川ノード`P`から一段ずつたどる
+    fn walk(links: &[Link], scope: f64, limits: AffectsLimits) -> (HashMap<String, Reached>, bool) {
+        let mut paths = ShortestPaths::new("P", scope, limits);
+        while !paths.frontier().is_empty() {
+            let next = links.iter().filter(|link| paths.frontier().contains(&link.from)).cloned().collect();
+            paths.extend(next);
+        }
+        paths.finish()
+    }
+
+    /// `apoc.path.subgraphAll`と同じく、親河川ノード`P`から`max_level`段以内の河川ノードの間の`links`を返す
+    fn subgraph(links: &[Link], max_level: i64) -> Vec<Link> {
+        let mut hops = HashMap::from([("P".to_string(), 0)]);
+        for level in 1..=max_level {
+            for link in links {
+                if hops.get(&link.from) == Some(&(level - 1)) && !hops.contains_key(&link.to) {
+                    hops.insert(link.to.clone(), level);
+                }
+            }
+        }
+        links.iter().filter(|link| hops.contains_key(&link.from) && hops.contains_key(&link.to)).cloned().collect()
+    }
+
+    fn distance(reached: &HashMap<String, Reached>, node: &str) -> Option<f64> {
+        reached.get(node).map(|reached| reached.distance)
+    }
+
+    #[test]
+    fn deeper_path_wins_when_shorter() {
+        // P -> T を直接たどるより、P -> A -> B -> T の方が短い
+        let links = [
+            link("P", "T", 500.0, Some(0.01)),
+            link("P", "A", 50.0, Some(0.02)),
+            link("A", "B", 50.0, Some(0.02)),
+            link("B", "T", 50.0, None),
+            link("T", "U", 10.0, Some(0.01)),
+        ];
+        let (reached, truncated) = walk(&links, 1000.0, UNLIMITED);
+
+        assert!(!truncated);
+        assert_eq!(reached.len(), 4);
+        assert!(!reached.contains_key("P"));
+        let target = reached["T"];
+        assert_eq!((target.distance, target.level, target.drop), (150.0, 3, None));
+        // 短くなった河川ノードから先もたどり直す
+        let below = reached["U"];
+        assert_eq!((below.distance, below.level), (160.0, 4));
+        assert_eq!(reached["B"].drop.map(|drop| (drop * 1000.0).round() / 1000.0), Some(2.0));
+    }
+
+    #[test]
+    fn stops_at_scope() {
+        let links = [
+            link("P", "A", 100.0, None),
+            link("A", "B", 100.0, None),
+            link("B", "C", 100.0, None),
+        ];
+        let (reached, truncated) = walk(&links, 250.0, UNLIMITED);
+
+        assert!(!truncated);
+        assert_eq!(distance(&reached, "A"), Some(100.0));
+        assert_eq!(distance(&reached, "B"), Some(200.0));
+        assert_eq!(distance(&reached, "C"), None);
+    }
+
+    #[test]
+    fn reports_truncation_at_max_level() {
+        let links = [
+            link("P", "A", 100.0, None),
+            link("A", "B", 100.0, None),
+            link("B", "C", 100.0, None),
+        ];
+        let (reached, truncated) = walk(&links, 1000.0, AffectsLimits { max_level: 2, ..UNLIMITED });
+        assert!(truncated);
+        assert_eq!(reached.len(), 2);
+        assert_eq!(distance(&reached, "C"), None);
+
+        // 最大の段数より手前で範囲の外に出る場合は打ち切りではない
+        let (reached, truncated) = walk(&links, 250.0, AffectsLimits { max_level: 2, ..UNLIMITED });
+        assert!(!truncated);
+        assert_eq!(reached.len(), 2);
+    }
+
+    #[test]
+    fn reports_truncation_at_max_paths() {
+        let links = [
+            link("P", "A", 10.0, None),
+            link("P", "B", 20.0, None),
+            link("P", "C", 30.0, None),
+            link("A", "D", 10.0, None),
+        ];
+        let (reached, truncated) = walk(&links, 1000.0, AffectsLimits { max_paths: 2, ..UNLIMITED });
+
+        assert!(truncated);
+        assert_eq!(reached.len(), 2);
+        assert_eq!(distance(&reached, "A"), Some(10.0));
+        assert_eq!(distance(&reached, "B"), Some(20.0));
+
+        let (reached, truncated) = walk(&links, 1000.0, AffectsLimits { max_paths: 4, ..UNLIMITED });
+        assert!(!truncated);
+        assert_eq!(reached.len(), 4);
+    }
+
+    #[test]
+    fn confluence_reached_from_both_tributaries() {
+        // 2本の支川 A、B が C で合流する。遠い方の支川からの RIVER_LINK を先に渡す
+        let links = [
+            link("P", "A", 100.0, Some(0.01)),
+            link("P", "B", 50.0, Some(0.02)),
+            link("A", "C", 100.0, Some(0.01)),
+            link("B", "C", 100.0, Some(0.02)),
+            link("C", "D", 100.0, Some(0.01)),
+        ];
+        let (reached, truncated) = walk(&links, 1000.0, UNLIMITED);
+
+        assert!(!truncated);
+        assert_eq!(reached.len(), 4);
+        let confluence = reached["C"];
+        assert_eq!((confluence.distance, confluence.level), (150.0, 2));
+        assert_eq!(confluence.drop.map(|drop| (drop * 1000.0).round() / 1000.0), Some(3.0));
+        assert_eq!(distance(&reached, "D"), Some(250.0));
+    }
+
+    #[test]
+    fn apoc_and_native_traversals_agree() {
+        // 支川の合流、直接の RIVER_LINK より短い迂回路、範囲の外に続く河川ノードを含む河川
+        let links = [
+            link("P", "A", 100.0, Some(0.01)),
+            link("P", "B", 50.0, Some(0.02)),
+            link("P", "E", 400.0, None),
+            link("A", "C", 100.0, Some(0.01)),
+            link("B", "C", 100.0, Some(0.02)),
+            link("C", "D", 100.0, Some(0.01)),
+            link("C", "E", 50.0, Some(0.01)),
+            link("D", "F", 300.0, None),
+            link("E", "G", 20.0, None),
+            link("G", "H", 20.0, None),
+        ];
+        // 到達する順が変わっても結果は変わらない
+        let mut reversed = links.to_vec();
+        reversed.reverse();
+
+        let all_limits = [
+            UNLIMITED,
+            AffectsLimits { max_level: 2, ..UNLIMITED },
+            AffectsLimits { max_level: 4, ..UNLIMITED },
+            AffectsLimits { max_paths: 3, ..UNLIMITED },
+            AffectsLimits { max_level: 3, max_paths: 5 },
+        ];
+        for links in [links.to_vec(), reversed] {
+            for scope in [120.0, 250.0, 1000.0] {
+                for limits in all_limits {
+                    let (native, native_truncated) = walk(&links, scope, limits);
+
+                    let mut paths = ShortestPaths::new("P", scope, limits);
+                    paths.extend_all(&subgraph(&links, limits.max_level + 1));
+                    let (apoc, apoc_truncated) = paths.finish();
+
+                    let summary = |reached: &HashMap<String, Reached>| {
+                        let mut nodes = reached.iter().map(|(node, reached)| (node.clone(), reached.distance, reached.level)).collect::<Vec<_>>();
+                        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+                        nodes
+                    };
+                    assert_eq!(summary(&apoc), summary(&native), "scope {scope}, {limits:?}");
+                    assert_eq!(apoc_truncated, native_truncated, "scope {scope}, {limits:?}");
+                }
+            }
+        }
+    }
+}
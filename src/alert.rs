@@ -0,0 +1,488 @@
+//! 水位の警戒段階と警報の状態
+//!
+//! 警戒段階の閾値(水位)はセンサーまたは河川ノードごとに`(:Sensor|RiverNode)-[:ALERT_THRESHOLD]->(:AlertThreshold)`
+//! として設定する。センサーに閾値がない場合は、所属する河川ノードの閾値を使う。
+//! 警報はセンサーごとに評価するため、推定した水位の河川ノードは評価せず、所属するセンサーのない河川ノードには
+//! 閾値を設定できない。
+//!
+//!
+//! 警報の状態はセンサーごとに`(:Sensor)-[:ALERT]->(:Alert)`として保存し、段階が変わるたびに
+//! `(:Alert)-[:EVENT]->(:AlertEvent)`を記録する。段階はすぐに上げるが、下げるのは水位が閾値を
+//! ヒステリシス分下回ってからとする。段階が上がると確認済みの状態は解除される。
+
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Graph, Txn};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// 警戒段階
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum AlertStage {
+    /// 警戒なし
+    #[default]
+    Normal,
+    /// 待機
+    Standby,
+    /// 注意
+    Caution,
+    /// 避難
+    Evacuation,
+    /// 危険
+    Danger,
+}
+
+impl From<AlertStage> for openapi::models::AlertStage {
+    fn from(value: AlertStage) -> Self {
+        match value {
+            AlertStage::Normal => openapi::models::AlertStage::Normal,
+            AlertStage::Standby => openapi::models::AlertStage::Standby,
+            AlertStage::Caution => openapi::models::AlertStage::Caution,
+            AlertStage::Evacuation => openapi::models::AlertStage::Evacuation,
+            AlertStage::Danger => openapi::models::AlertStage::Danger,
+        }
+    }
+}
+
+/// 警戒段階の閾値
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Thresholds {
+    pub standby: Option<f64>,
+    pub caution: Option<f64>,
+    pub evacuation: Option<f64>,
+    pub danger: Option<f64>,
+    /// 段階を下げるときに閾値を下回る必要がある幅(メートル)
+    pub hysteresis: Option<f64>,
+}
+
+impl Thresholds {
+    fn levels(&self) -> [(AlertStage, Option<f64>); 4] {
+        [
+            (AlertStage::Standby, self.standby),
+            (AlertStage::Caution, self.caution),
+            (AlertStage::Evacuation, self.evacuation),
+            (AlertStage::Danger, self.danger),
+        ]
+    }
+
+    /// 水位が達している最も高い段階
+    pub fn stage_of(&self, level: f64) -> AlertStage {
+        self.levels()
+            .into_iter()
+            .filter(|(_, threshold)| threshold.is_some_and(|threshold| level >= threshold))
+            .map(|(stage, _)| stage)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// 現在の段階から、ヒステリシスを考慮して次の段階を決める
+    pub fn next_stage(&self, current: AlertStage, level: f64, default_hysteresis: f64) -> AlertStage {
+        let stage = self.stage_of(level);
+        if stage >= current {
+            return stage;
+        }
+
+        let hysteresis = self.hysteresis.unwrap_or(default_hysteresis);
+        self.stage_of(level + hysteresis).min(current)
+    }
+
+    /// 閾値が有限の値で、設定された段階の順に大きくなっていることを確かめる
+    ///
+    /// 同じ値の閾値は、高い段階に一度に上がるものとして許す。
+    pub fn validate(&self) -> Result<(), String> {
+        let levels = self.levels();
+        if let Some((stage, _)) = levels.iter().find(|(_, threshold)| threshold.is_some_and(|threshold| !threshold.is_finite())) {
+            return Err(format!("Threshold for {stage} must be finite"));
+        }
+        if self.hysteresis.is_some_and(|hysteresis| !hysteresis.is_finite() || hysteresis < 0.0) {
+            return Err("hysteresis must be a non-negative number".to_string());
+        }
+
+        let set = levels.into_iter()
+            .filter_map(|(stage, threshold)| threshold.map(|threshold| (stage, threshold)))
+            .collect::<Vec<_>>();
+        for pair in set.windows(2) {
+            let ((lower_stage, lower), (higher_stage, higher)) = (pair[0], pair[1]);
+            if lower > higher {
+                return Err(format!("Threshold for {lower_stage} ({lower}) must not exceed {higher_stage} ({higher})"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 水位から、まだ達していない最も低い閾値までの差
+    pub fn margin(&self, level: f64) -> Option<f64> {
+        self.levels()
+            .into_iter()
+            .filter_map(|(_, threshold)| threshold)
+            .filter(|threshold| *threshold > level)
+            .map(|threshold| threshold - level)
+            .min_by(f64::total_cmp)
+    }
+}
+
+impl From<openapi::models::AlertThresholds> for Thresholds {
+    fn from(value: openapi::models::AlertThresholds) -> Self {
+        let openapi::models::AlertThresholds { standby, caution, evacuation, danger, hysteresis } = value;
+        Thresholds { standby, caution, evacuation, danger, hysteresis }
+    }
+}
+
+impl From<Thresholds> for openapi::models::AlertThresholds {
+    fn from(value: Thresholds) -> Self {
+        let Thresholds { standby, caution, evacuation, danger, hysteresis } = value;
+        openapi::models::AlertThresholds { standby, caution, evacuation, danger, hysteresis }
+    }
+}
+
+/// 警報の段階の変化
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertChange {
+    pub sensor_id: String,
+    pub from: AlertStage,
+    pub to: AlertStage,
+    pub level: f64,
+    pub time: DateTime<Utc>,
+}
+
+/// Neo4jから取得した、警報の評価に必要な値
+#[derive(Debug, Deserialize)]
+struct EvaluationRow {
+    id: String,
+    level: f64,
+    stage: Option<String>,
+    thresholds: Option<Thresholds>,
+}
+
+/// センサーの閾値(なければ所属する河川ノードの閾値)を取得するCypher
+///
+/// `sensor`を受け取り、`threshold`を追加する。
+pub const EFFECTIVE_THRESHOLD: &str = r#"
+OPTIONAL MATCH (sensor)-[:ALERT_THRESHOLD]->(own:AlertThreshold)
+OPTIONAL MATCH (sensor)-[:BELONGS_TO]->(:RiverNode)-[:ALERT_THRESHOLD]->(inherited:AlertThreshold)
+WITH *, coalesce(own, inherited) AS threshold
+"#;
+
+/// センサーの最新の水位で警報の状態を更新し、段階が変わったものを返す
+pub async fn evaluate_alerts(tnx: &mut Txn, ids: &[String], default_hysteresis: f64) -> anyhow::Result<Vec<AlertChange>> {
+    let mut result = tnx.execute(query(&format!(
+        r#"
+// 1. センサーと最新の水位、閾値、現在の警報の状態の取得
+UNWIND $ids AS id
+MATCH (sensor:Sensor {{id: id}})
+WHERE sensor.water_level IS NOT NULL
+{EFFECTIVE_THRESHOLD}
+OPTIONAL MATCH (sensor)-[:ALERT]->(alert:Alert)
+
+RETURN sensor.id AS id,
+       sensor.water_level AS level,
+       alert.stage AS stage,
+       CASE WHEN threshold IS NULL THEN null ELSE {{
+           standby: threshold.standby,
+           caution: threshold.caution,
+           evacuation: threshold.evacuation,
+           danger: threshold.danger,
+           hysteresis: threshold.hysteresis
+       }} END AS thresholds
+        "#,
+    ))
+        .param("ids", ids.to_vec())
+    ).await?;
+
+    let now = Utc::now();
+    let mut changes = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        let EvaluationRow { id, level, stage, thresholds } = row.to()?;
+        let current = stage.map(|stage| stage.parse()).transpose()?.unwrap_or_default();
+
+        // 閾値がなくなったセンサーは警戒なしに戻す
+        let next = thresholds
+            .map(|thresholds| thresholds.next_stage(current, level, default_hysteresis))
+            .unwrap_or_default();
+
+        if next != current {
+            changes.push(AlertChange { sensor_id: id, from: current, to: next, level, time: now });
+        }
+    }
+
+    let records = changes.iter()
+        .map(|change| HashMap::from([
+            ("id".to_string(), BoltType::from(change.sensor_id.clone())),
+            ("from".to_string(), BoltType::from(change.from.to_string())),
+            ("to".to_string(), BoltType::from(change.to.to_string())),
+            ("escalated".to_string(), BoltType::from(change.to > change.from)),
+            ("level".to_string(), BoltType::from(change.level)),
+        ]))
+        .collect::<Vec<_>>();
+
+    tnx.run(query(
+        r#"
+// 2. 警報の状態を更新し、変化を記録する
+// 段階が上がった場合は確認済みを解除する
+UNWIND $changes AS change
+MATCH (sensor:Sensor {id: change.id})
+MERGE (sensor)-[:ALERT]->(alert:Alert)
+SET alert.stage = change.to,
+    alert.level = change.level,
+    alert.since = $time,
+    alert.acknowledged = CASE WHEN change.escalated THEN false ELSE coalesce(alert.acknowledged, false) END,
+    alert.acknowledged_at = CASE WHEN change.escalated THEN null ELSE alert.acknowledged_at END
+CREATE (alert)-[:EVENT]->(:AlertEvent {
+    from: change.from,
+    to: change.to,
+    level: change.level,
+    time: $time
+})
+        "#,
+    )
+        .param("changes", records)
+        .param("time", now.to_rfc3339())
+    ).await?;
+
+    Ok(changes)
+}
+
+/// 警報の状態
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertState {
+    pub sensor_id: String,
+    pub stage: String,
+    pub level: f64,
+    pub since: String,
+    pub acknowledged: bool,
+    pub acknowledged_at: Option<String>,
+}
+
+/// 警報の状態の一覧を取得する
+///
+/// `active`の場合は警戒なしの警報を含めない。
+pub async fn list_alerts(graph: &Graph, active: bool) -> anyhow::Result<Vec<AlertState>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (sensor:Sensor)-[:ALERT]->(alert:Alert)
+WHERE (NOT $active OR alert.stage <> 'normal')
+  AND sensor.decommissioned_at IS NULL
+RETURN sensor.id AS sensor_id,
+       alert.stage AS stage,
+       alert.level AS level,
+       alert.since AS since,
+       coalesce(alert.acknowledged, false) AS acknowledged,
+       alert.acknowledged_at AS acknowledged_at
+ORDER BY since DESC
+        "#,
+    )
+        .param("active", active)
+    ).await?;
+
+    let mut alerts = Vec::new();
+    while let Some(row) = result.next().await? {
+        alerts.push(row.to()?);
+    }
+
+    Ok(alerts)
+}
+
+/// 警報を確認済みにする
+///
+/// 警報がない場合は`false`を返す。
+pub async fn acknowledge(graph: &Graph, sensor_id: &str) -> anyhow::Result<bool> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (:Sensor {id: $id})-[:ALERT]->(alert:Alert)
+SET alert.acknowledged = true,
+    alert.acknowledged_at = $time
+CREATE (alert)-[:EVENT]->(:AlertEvent {
+    from: alert.stage,
+    to: alert.stage,
+    level: alert.level,
+    acknowledged: true,
+    time: $time
+})
+RETURN count(alert) AS count
+        "#,
+    )
+        .param("id", sensor_id)
+        .param("time", Utc::now().to_rfc3339())
+    ).await?;
+
+    let mut count = 0;
+    while let Some(row) = result.next().await? {
+        count = row.get::<i64>("count")?;
+    }
+
+    Ok(count > 0)
+}
+
+/// 閾値を設定する対象
+#[derive(Debug, Clone)]
+pub enum ThresholdTarget {
+    Sensor(String),
+    /// 河川ノードの`hilbert18`
+    RiverNode(String),
+}
+
+impl ThresholdTarget {
+    /// 対象のノードを`target`として取得するCypher
+    fn pattern(&self) -> (&'static str, &str) {
+        match self {
+            ThresholdTarget::Sensor(id) => ("MATCH (target:Sensor {id: $target})", id),
+            ThresholdTarget::RiverNode(node) => ("MATCH (target:RiverNode {hilbert18: $target})", node),
+        }
+    }
+}
+
+/// 対象に直接設定された閾値を取得する
+///
+/// 対象が存在しない場合は`None`、閾値が設定されていない場合は`Some(None)`を返す。
+pub async fn load_thresholds(graph: &Graph, target: &ThresholdTarget) -> anyhow::Result<Option<Option<Thresholds>>> {
+    let (pattern, key) = target.pattern();
+    let mut result = graph.execute(query(&format!(
+        r#"
+{pattern}
+OPTIONAL MATCH (target)-[:ALERT_THRESHOLD]->(threshold:AlertThreshold)
+RETURN CASE WHEN threshold IS NULL THEN null ELSE {{
+    standby: threshold.standby,
+    caution: threshold.caution,
+    evacuation: threshold.evacuation,
+    danger: threshold.danger,
+    hysteresis: threshold.hysteresis
+}} END AS thresholds
+        "#,
+    ))
+        .param("target", key)
+    ).await?;
+
+    let mut thresholds = None;
+    while let Some(row) = result.next().await? {
+        thresholds = Some(row.get::<Option<Thresholds>>("thresholds")?);
+    }
+
+    Ok(thresholds)
+}
+
+/// 対象に閾値を設定する
+///
+/// 対象が存在しない場合は`Ok(false)`を返す。閾値が段階の順に大きくなっていない場合と、
+/// 所属するセンサーのない河川ノード(警報を評価しない)に設定しようとした場合は、理由を`Err`として返す。
+pub async fn save_thresholds(graph: &Graph, target: &ThresholdTarget, thresholds: &Thresholds) -> anyhow::Result<Result<bool, String>> {
+    if let Err(e) = thresholds.validate() {
+        return Ok(Err(e));
+    }
+
+    if let ThresholdTarget::RiverNode(node) = target {
+        let mut result = graph.execute(query(
+            r#"
+MATCH (target:RiverNode {hilbert18: $target})
+RETURN EXISTS { (:Sensor)-[:BELONGS_TO]->(target) } AS belongs
+            "#,
+        )
+            .param("target", node.as_str())
+        ).await?;
+
+        let Some(row) = result.next().await? else {
+            return Ok(Ok(false));
+        };
+        if !row.get::<bool>("belongs")? {
+            return Ok(Err(format!("River node {node} has no sensor to evaluate alerts with")));
+        }
+    }
+
+    let (pattern, key) = target.pattern();
+    let mut result = graph.execute(query(&format!(
+        r#"
+{pattern}
+MERGE (target)-[:ALERT_THRESHOLD]->(threshold:AlertThreshold)
+SET threshold.standby = $standby,
+    threshold.caution = $caution,
+    threshold.evacuation = $evacuation,
+    threshold.danger = $danger,
+    threshold.hysteresis = $hysteresis
+RETURN count(threshold) AS count
+        "#,
+    ))
+        .param("target", key)
+        .param("standby", thresholds.standby)
+        .param("caution", thresholds.caution)
+        .param("evacuation", thresholds.evacuation)
+        .param("danger", thresholds.danger)
+        .param("hysteresis", thresholds.hysteresis)
+    ).await?;
+
+    let mut count = 0;
+    while let Some(row) = result.next().await? {
+        count = row.get::<i64>("count")?;
+    }
+
+    Ok(Ok(count > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            standby: Some(1.0),
+            caution: Some(2.0),
+            evacuation: Some(3.0),
+            danger: Some(4.0),
+            hysteresis: None,
+        }
+    }
+
+    #[test]
+    fn stage_rises_immediately() {
+        let thresholds = thresholds();
+        assert_eq!(thresholds.next_stage(AlertStage::Normal, 0.5, 0.2), AlertStage::Normal);
+        assert_eq!(thresholds.next_stage(AlertStage::Normal, 1.0, 0.2), AlertStage::Standby);
+        // 複数の段階を一度に上がる
+        assert_eq!(thresholds.next_stage(AlertStage::Standby, 3.5, 0.2), AlertStage::Evacuation);
+        assert_eq!(thresholds.next_stage(AlertStage::Evacuation, 10.0, 0.2), AlertStage::Danger);
+    }
+
+    #[test]
+    fn stage_falls_after_hysteresis() {
+        let thresholds = thresholds();
+        // 閾値を下回ってもヒステリシスの幅の中では下げない
+        assert_eq!(thresholds.next_stage(AlertStage::Caution, 1.9, 0.2), AlertStage::Caution);
+        assert_eq!(thresholds.next_stage(AlertStage::Caution, 1.8, 0.2), AlertStage::Caution);
+        assert_eq!(thresholds.next_stage(AlertStage::Caution, 1.7, 0.2), AlertStage::Standby);
+        // 複数の段階を一度に下がる
+        assert_eq!(thresholds.next_stage(AlertStage::Danger, 0.5, 0.2), AlertStage::Normal);
+    }
+
+    #[test]
+    fn own_hysteresis_overrides_default() {
+        let thresholds = Thresholds { hysteresis: Some(0.5), ..thresholds() };
+        assert_eq!(thresholds.next_stage(AlertStage::Caution, 1.7, 0.2), AlertStage::Caution);
+        assert_eq!(thresholds.next_stage(AlertStage::Caution, 1.4, 0.2), AlertStage::Standby);
+    }
+
+    #[test]
+    fn unset_stages_are_skipped() {
+        let thresholds = Thresholds { standby: None, evacuation: None, ..thresholds() };
+        assert_eq!(thresholds.next_stage(AlertStage::Normal, 1.5, 0.2), AlertStage::Normal);
+        assert_eq!(thresholds.next_stage(AlertStage::Normal, 3.5, 0.2), AlertStage::Caution);
+        assert_eq!(thresholds.next_stage(AlertStage::Danger, 3.5, 0.2), AlertStage::Caution);
+    }
+
+    #[test]
+    fn stage_is_not_raised_by_hysteresis() {
+        // 現在の段階より高い段階には、ヒステリシスを加えた水位では上がらない
+        let thresholds = thresholds();
+        assert_eq!(thresholds.next_stage(AlertStage::Standby, 1.9, 0.2), AlertStage::Standby);
+    }
+
+    #[test]
+    fn thresholds_must_increase_with_stage() {
+        assert!(thresholds().validate().is_ok());
+        assert!(Thresholds { caution: Some(1.0), ..thresholds() }.validate().is_ok());
+        assert!(Thresholds { standby: None, danger: None, ..thresholds() }.validate().is_ok());
+
+        assert!(Thresholds { caution: Some(0.5), ..thresholds() }.validate().is_err());
+        assert!(Thresholds { caution: None, evacuation: Some(0.5), ..thresholds() }.validate().is_err());
+        assert!(Thresholds { danger: Some(f64::NAN), ..thresholds() }.validate().is_err());
+        assert!(Thresholds { hysteresis: Some(-0.1), ..thresholds() }.validate().is_err());
+    }
+}
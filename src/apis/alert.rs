@@ -0,0 +1,136 @@
+//! 警報と警戒段階の閾値の管理
+
+use axum::async_trait;
+use axum::extract::Host;
+use axum::http::Method;
+use axum_extra::extract::CookieJar;
+use chrono::{DateTime, Utc};
+use openapi::apis::alert::{Alert as AlertApi, GetApiAlertsResponse, GetApiAlertsThresholdsResponse, PostApiAlertsAcknowledgeResponse, PostApiAlertsThresholdsResponse};
+use openapi::models::{AlertThresholds, GetApiAlertsQueryParams, GetApiAlertsThresholdsQueryParams, PostApiAlertsAcknowledgeQueryParams, PostApiAlertsThresholdsQueryParams};
+use uuid::Uuid;
+
+use crate::alert::{acknowledge, list_alerts, load_thresholds, save_thresholds, AlertStage, AlertState, ThresholdTarget};
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
+use crate::apis::ServerImpl;
+
+problem_response!(PostApiAlertsThresholdsResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+});
+
+/// `sensor_id`と`river_node`のどちらか一方だけが指定されている場合に、閾値を設定する対象を返す
+fn threshold_target(sensor_id: Option<Uuid>, river_node: Option<String>) -> Option<ThresholdTarget> {
+    match (sensor_id, river_node) {
+        (Some(id), None) => Some(ThresholdTarget::Sensor(id.to_string())),
+        (None, Some(node)) => Some(ThresholdTarget::RiverNode(node)),
+        _ => None,
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| e.to_string())
+}
+
+impl TryFrom<AlertState> for openapi::models::Alert {
+    type Error = String;
+
+    fn try_from(state: AlertState) -> Result<Self, Self::Error> {
+        let AlertState { sensor_id, stage, level, since, acknowledged, acknowledged_at } = state;
+
+        Ok(openapi::models::Alert {
+            sensor_id: sensor_id.parse().map_err(|_| format!("Invalid sensor id: {sensor_id}"))?,
+            stage: stage.parse::<AlertStage>().map_err(|e| e.to_string())?.into(),
+            level,
+            since: parse_time(&since)?,
+            acknowledged,
+            acknowledged_at: acknowledged_at.as_deref().map(parse_time).transpose()?,
+        })
+    }
+}
+
+#[async_trait]
+impl AlertApi for ServerImpl {
+    async fn get_api_alerts(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiAlertsQueryParams,
+    ) -> Result<GetApiAlertsResponse, String> {
+        let alerts = list_alerts(self.graph().map_err(|e| e.to_string())?, query_params.active.unwrap_or(false))
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(openapi::models::Alert::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(GetApiAlertsResponse::Status200_OK(alerts))
+    }
+
+    async fn post_api_alerts_acknowledge(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: PostApiAlertsAcknowledgeQueryParams,
+    ) -> Result<PostApiAlertsAcknowledgeResponse, String> {
+        let acknowledged = acknowledge(self.graph().map_err(|e| e.to_string())?, &query_params.id.to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if acknowledged {
+            Ok(PostApiAlertsAcknowledgeResponse::Status200_OK)
+        } else {
+            Ok(PostApiAlertsAcknowledgeResponse::Status404_NotFound)
+        }
+    }
+
+    async fn get_api_alerts_thresholds(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiAlertsThresholdsQueryParams,
+    ) -> Result<GetApiAlertsThresholdsResponse, String> {
+        let GetApiAlertsThresholdsQueryParams { sensor_id, river_node } = query_params;
+        let Some(target) = threshold_target(sensor_id, river_node) else {
+            return Ok(GetApiAlertsThresholdsResponse::Status400_BadRequest);
+        };
+
+        let thresholds = load_thresholds(self.graph().map_err(|e| e.to_string())?, &target).await.map_err(|e| e.to_string())?;
+
+        match thresholds.flatten() {
+            Some(thresholds) => Ok(GetApiAlertsThresholdsResponse::Status200_OK(thresholds.into())),
+            None => Ok(GetApiAlertsThresholdsResponse::Status404_NotFound),
+        }
+    }
+
+    async fn post_api_alerts_thresholds(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: PostApiAlertsThresholdsQueryParams,
+        body: Option<AlertThresholds>,
+    ) -> Result<PostApiAlertsThresholdsResponse, String> {
+        let PostApiAlertsThresholdsQueryParams { sensor_id, river_node } = query_params;
+        let Some(target) = threshold_target(sensor_id, river_node) else {
+            return PostApiAlertsThresholdsResponse::from_error(ApiError::BadRequest("Specify either sensor_id or river_node".to_string()));
+        };
+        let Some(thresholds) = body else {
+            return PostApiAlertsThresholdsResponse::from_error(ApiError::missing_body());
+        };
+
+        let saved = save_thresholds(self.graph().map_err(|e| e.to_string())?, &target, &thresholds.into())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match saved {
+            Ok(true) => Ok(PostApiAlertsThresholdsResponse::Status200_OK),
+            Ok(false) => PostApiAlertsThresholdsResponse::from_error(ApiError::NotFound("Threshold target not found".to_string())),
+            Err(e) => PostApiAlertsThresholdsResponse::from_error(ApiError::BadRequest(e)),
+        }
+    }
+}
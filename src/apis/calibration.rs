@@ -0,0 +1,121 @@
+//! センサーの校正値の管理
+
+use axum::async_trait;
+use axum::extract::Host;
+use axum::http::Method;
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use neo4rs::query;
+use openapi::apis::calibration::{Calibration as CalibrationApi, GetApiSensorsCalibrationsResponse, PostApiSensorsCalibrationsResponse};
+use openapi::models::{GetApiSensorsCalibrationsQueryParams, PostApiSensorsCalibrationsQueryParams};
+
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
+use crate::apis::ServerImpl;
+use crate::calibration::{load_calibrations, save_calibration, Calibration};
+use crate::water_level::update_water_levels;
+
+problem_response!(GetApiSensorsCalibrationsResponse {
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiSensorsCalibrationsResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+
+impl ServerImpl {
+    /// センサーの校正値を適用開始日時の昇順で返す
+    async fn sensor_calibrations(&self, id: String) -> Result<GetApiSensorsCalibrationsResponse, ApiError> {
+        let mut tnx = self.graph()?.start_txn().await?;
+        let calibrations = load_calibrations(&mut tnx, &id).await?;
+        tnx.commit().await?;
+
+        Ok(GetApiSensorsCalibrationsResponse::Status200_OK(
+            calibrations.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// 校正値の版を登録し、`recompute`の場合は再計算したセンサーデータの数を返す
+    async fn register_calibration(&self, id: String, recompute: bool, body: openapi::models::Calibration) -> Result<PostApiSensorsCalibrationsResponse, ApiError> {
+        let openapi::models::Calibration { zero_offset, scale, tilt, unit, effective_from } = body;
+        let calibration = Calibration {
+            zero_offset,
+            scale,
+            tilt,
+            unit: unit.into(),
+            effective_from: effective_from.unwrap_or_else(Utc::now),
+        };
+        calibration.validate().map_err(ApiError::BadRequest)?;
+
+        let mut tnx = self.graph()?.start_txn().await?;
+
+        let result = async {
+            let exists = tnx.execute(
+                query("MATCH (sensor:Sensor {id: $id}) RETURN sensor.id AS id").param("id", id.as_str()),
+            ).await?
+                .next(tnx.handle()).await?
+                .is_some();
+            if !exists {
+                return anyhow::Ok(None);
+            }
+
+            let count = save_calibration(&mut tnx, &id, &calibration, recompute).await?;
+
+            // 最新のデータの距離が変わった可能性があるので水位を更新する
+            if count > 0 {
+                update_water_levels(&mut tnx, &[id.clone()], &self.env).await?;
+            }
+
+            anyhow::Ok(Some(count))
+        }.await;
+
+        match result {
+            Ok(Some(count)) => {
+                tnx.commit().await?;
+                Ok(PostApiSensorsCalibrationsResponse::Status200_OK(count))
+            }
+            Ok(None) => {
+                tnx.rollback().await?;
+                Err(ApiError::NotFound(format!("Sensor {id} is not registered")))
+            }
+            Err(e) => {
+                println!("post_api_sensors_calibrations: {:?}", e);
+                tnx.rollback().await?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CalibrationApi for ServerImpl {
+    async fn get_api_sensors_calibrations(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiSensorsCalibrationsQueryParams,
+    ) -> Result<GetApiSensorsCalibrationsResponse, String> {
+        self.sensor_calibrations(query_params.id.to_string())
+            .await
+            .or_else(GetApiSensorsCalibrationsResponse::from_error)
+    }
+
+    async fn post_api_sensors_calibrations(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: PostApiSensorsCalibrationsQueryParams,
+        body: Option<openapi::models::Calibration>,
+    ) -> Result<PostApiSensorsCalibrationsResponse, String> {
+        let PostApiSensorsCalibrationsQueryParams { id, recompute } = query_params;
+
+        let result = match body {
+            Some(body) => self.register_calibration(id.to_string(), recompute.unwrap_or(false), body).await,
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(PostApiSensorsCalibrationsResponse::from_error)
+    }
+}
@@ -0,0 +1,145 @@
+//! ハンドラーのエラーと、Problem Details(RFC 9457)のレスポンス
+
+use std::fmt::{Display, Formatter};
+
+use neo4rs::{Neo4jClientErrorKind, Neo4jErrorKind};
+use openapi::models::Problem;
+
+/// ハンドラーで起きたエラー
+///
+/// openapi.yamlで宣言したステータスコードに対応付け、Problem Detailsを本文として返す。
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// リクエストの本文やパラメーターが不正(400)
+    BadRequest(String),
+    /// 指定されたものが存在しない(404)
+    NotFound(String),
+    /// 同時に行われた更新と競合した(409)。再試行すれば成功する可能性がある
+    Conflict(String),
+    /// 外部のサービスから正しい応答を得られなかった(502)
+    BadGateway(String),
+    /// Neo4jに接続できない(503)
+    ServiceUnavailable(String),
+    /// 上記以外の予期しないエラー(500)
+    Internal(String),
+}
+
+impl ApiError {
+    /// リクエストの本文がない場合のエラー
+    pub fn missing_body() -> Self {
+        ApiError::BadRequest("Request body is required".to_string())
+    }
+
+    /// HTTPのステータスコード
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::NotFound(_) => 404,
+            ApiError::Conflict(_) => 409,
+            ApiError::BadGateway(_) => 502,
+            ApiError::ServiceUnavailable(_) => 503,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "Bad Request",
+            ApiError::NotFound(_) => "Not Found",
+            ApiError::Conflict(_) => "Conflict",
+            ApiError::BadGateway(_) => "Bad Gateway",
+            ApiError::ServiceUnavailable(_) => "Service Unavailable",
+            ApiError::Internal(_) => "Internal Server Error",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::BadGateway(detail)
+            | ApiError::ServiceUnavailable(detail)
+            | ApiError::Internal(detail) => detail,
+        }
+    }
+
+    /// レスポンスの本文
+    pub fn problem(&self) -> Problem {
+        Problem {
+            title: self.title().to_string(),
+            status: self.status() as i32,
+            detail: Some(self.detail().to_string()),
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.title(), self.detail())
+    }
+}
+
+impl From<neo4rs::Error> for ApiError {
+    /// Neo4jに接続できない場合は503、一時的なエラー(デッドロックなど)は409とする
+    fn from(e: neo4rs::Error) -> Self {
+        match &e {
+            neo4rs::Error::IOError { .. } | neo4rs::Error::ConnectionError => ApiError::ServiceUnavailable(e.to_string()),
+            neo4rs::Error::Neo4j(error) => match error.kind() {
+                Neo4jErrorKind::Transient => ApiError::Conflict(e.to_string()),
+                Neo4jErrorKind::Client(Neo4jClientErrorKind::SessionExpired | Neo4jClientErrorKind::FatalDiscovery) => {
+                    ApiError::ServiceUnavailable(e.to_string())
+                }
+                _ => ApiError::Internal(e.to_string()),
+            },
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<neo4rs::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => ApiError::Internal(format!("{e:#}")),
+        }
+    }
+}
+
+impl From<neo4rs::DeError> for ApiError {
+    fn from(e: neo4rs::DeError) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+/// Problem Detailsを返せる、生成されたレスポンスの型
+pub trait ProblemResponse: Sized {
+    /// エラーをレスポンスにする
+    ///
+    /// そのパスで宣言していないステータスコードのエラーは`Err`として返し、500になる。
+    fn from_error(error: ApiError) -> Result<Self, String>;
+}
+
+/// 生成されたレスポンスの型に、宣言したステータスコードのバリアントを対応付ける
+///
+/// ```ignore
+/// problem_response!(ApiSensorsDeleteResponse {
+///     NotFound => Status404_NotFound,
+///     ServiceUnavailable => Status503_ServiceUnavailable,
+/// });
+/// ```
+macro_rules! problem_response {
+    ($response:ident { $($error:ident => $variant:ident),* $(,)? }) => {
+        impl $crate::apis::error::ProblemResponse for $response {
+            fn from_error(error: $crate::apis::error::ApiError) -> Result<Self, String> {
+                println!("{}: {}", stringify!($response), error);
+                match error {
+                    $($crate::apis::error::ApiError::$error(_) => Ok($response::$variant(error.problem())),)*
+                    error => Err(error.to_string()),
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use problem_response;
@@ -1,62 +1,133 @@
 //! パスごとに呼び出される処理を定義
 
+use crate::apis::error::ApiError;
+use crate::auth::{Auth, FileTokenStore, Neo4jTokenStore, TokenStore};
 use crate::cache::multi_layer::MultiLayerCache;
+use crate::db;
 use crate::env::EnvVars;
-use neo4rs::{ConfigBuilder, Graph};
+use crate::forecast;
+use crate::health;
+use crate::migration;
+use crate::retention;
+use crate::store::{Neo4jStore, Store};
+use crate::water_level;
+use crate::webhook::dispatcher;
+use neo4rs::Graph;
 use rust_socketio::asynchronous::{Client, ClientBuilder};
+use std::sync::Arc;
 
 mod tile;
 mod sensor;
 mod sensor_data;
+mod token;
+mod calibration;
+mod river_system;
+mod alert;
+mod webhook;
+mod error;
+#[cfg(test)]
+mod tests;
+
+pub use sensor_data::capture_signed_body;
 
 /// パスごとの処理内容をimplするための構造体
 #[derive(Clone)]
 pub struct ServerImpl {
-    graph: Graph,
+    /// 格納先を通さない校正値・警報・Webhook・稼働状態などの処理に使うNeo4j。`None`の場合はそれらの処理を503とする
+    graph: Option<Graph>,
     cache: MultiLayerCache,
     http_client: reqwest::Client,
-    socketio_client: Client,
+    /// 水位の更新を伝えるSocket.IOクライアント。`None`の場合は伝えない
+    socketio_client: Option<Client>,
+    env: Arc<EnvVars>,
+    auth: Auth,
+    /// センサー・計測値・河川ノードの水位の格納先
+    store: Arc<dyn Store>,
 }
 impl ServerImpl {
     /// 新しいServerImplを作成する
     pub async fn new() -> Self {
-        let env = EnvVars::read_env().unwrap();
-
-        let graph = {
-            let mut auth = env.neo4j_auth.split('/');
-            let neo4j_user = auth.next().unwrap();
-            let neo4j_password = auth.next().unwrap();
-
-            let config = ConfigBuilder::default()
-                .uri(env.neo4j_uri)
-                .user(neo4j_user)
-                .password(neo4j_password)
-                .db(env.neo4j_db)
-                .build()
-                .unwrap();
-            Graph::connect(config).await.unwrap()
+        let env = Arc::new(EnvVars::read_env().unwrap());
+
+        let graph = db::connect(&env).await;
+        // 移行に失敗しても、既存のスキーマのまま起動する(重複している値は移行の中で表示する)
+        match migration::migrate(&graph, false).await {
+            Ok(migrated) => println!("Neo4j schema version: {} -> {}", migrated.from, migrated.to()),
+            Err(e) => println!("Failed to migrate the Neo4j schema: {:?}", e),
+        }
+        let capabilities = db::detect_capabilities(&graph).await;
+        let store = Arc::new(Neo4jStore::new(graph.clone(), env.clone(), capabilities));
+
+        let socketio_client = ClientBuilder::new(env.socketio_host.clone())
+            .namespace("/water_surface")
+            .connect()
+            .await
+            .expect("Failed to connect to socket.io server");
+
+        let token_store: Arc<dyn TokenStore> = match &env.token_store_path {
+            Some(path) => Arc::new(FileTokenStore::open(path.into()).await.unwrap()),
+            None => Arc::new(Neo4jTokenStore::new(graph.clone())),
         };
 
+        Self::with_store(env, Some(graph), store, token_store, Some(socketio_client)).await
+    }
+
+    /// 格納先を指定してServerImplを作成する
+    ///
+    /// `graph`は格納先を通さない校正値・警報・Webhook・稼働状態などの処理に使う。
+    /// ルーターのテストのように`None`を渡した場合、それらの処理は503を返し、定期的な処理も開始しない。
+    pub async fn with_store(env: Arc<EnvVars>, graph: Option<Graph>, store: Arc<dyn Store>, token_store: Arc<dyn TokenStore>, socketio_client: Option<Client>) -> Self {
         let cache = MultiLayerCache::new(
             env.memory_cache_max_size,
             env.disk_cache_max_size,
-            env.disk_cache_base_path.into(),
+            env.disk_cache_base_path.clone().into(),
         ).await;
 
-
         let http_client = reqwest::Client::new();
-        let socketio_client = ClientBuilder::new(env.socketio_host)
-            .namespace("/water_surface")
-            .connect()
-            .await
-            .expect("Failed to connect to socket.io server");
+        let auth = Auth::new(token_store, env.admin_key.clone(), env.anonymous_viewer);
 
         Self {
             graph,
             cache,
             http_client,
             socketio_client,
+            env,
+            auth,
+            store,
+        }
+    }
+
+    /// 定期的に実行する処理を開始する
+    pub fn spawn_background_tasks(&self) {
+        let Some(graph) = &self.graph else {
+            return;
+        };
+
+        if self.env.forecast_interval > 0 {
+            tokio::spawn(forecast::run(graph.clone(), self.env.clone()));
+        }
+        if self.env.health_interval > 0 {
+            tokio::spawn(health::run(graph.clone(), self.env.clone()));
         }
+        if self.env.water_level_recompute_interval > 0 {
+            tokio::spawn(water_level::run(graph.clone(), self.env.clone()));
+        }
+        if self.env.retention_interval > 0 {
+            tokio::spawn(retention::run(graph.clone(), self.env.clone()));
+        }
+        if self.env.webhook_dispatch_interval > 0 {
+            tokio::spawn(dispatcher::run(graph.clone(), self.env.clone()));
+        }
+    }
+
+    /// 格納先を通さない処理に使うNeo4j
+    fn graph(&self) -> Result<&Graph, ApiError> {
+        self.graph.as_ref().ok_or_else(|| ApiError::ServiceUnavailable("Neo4j is not connected".to_string()))
+    }
+
+    /// 認証・認可に必要な状態を返す
+    pub fn auth(&self) -> Auth {
+        self.auth.clone()
     }
 }
 
@@ -0,0 +1,220 @@
+//! 水系ごとの水位の推定方法の管理
+
+use axum::async_trait;
+use axum::extract::Host;
+use axum::http::Method;
+use axum_extra::extract::CookieJar;
+use neo4rs::query;
+use openapi::apis::river_system::{GetApiRiverSystemsResponse, PostApiRiverSystemsMembersResponse, PostApiRiverSystemsResponse, RiverSystem};
+use openapi::models::{PostApiRiverSystemsMembersQueryParams, PostApiRiverSystemsQueryParams, RiverSystemMembers, WaterLevelModelConfig};
+
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
+use crate::apis::ServerImpl;
+use crate::water_level::WaterLevelModelKind;
+
+/// 水位の推定方法の設定値を検証する
+fn validate_model_config(config: &WaterLevelModelConfig) -> Result<(), ApiError> {
+    for (name, value) in [("idw_power", config.idw_power), ("bed_slope", config.bed_slope)] {
+        if let Some(value) = value.filter(|value| !(value.is_finite() && *value > 0.0)) {
+            return Err(ApiError::BadRequest(format!("{name} must be positive: {value}")));
+        }
+    }
+
+    Ok(())
+}
+
+problem_response!(GetApiRiverSystemsResponse {
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiRiverSystemsResponse {
+    BadRequest => Status400_BadRequest,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiRiverSystemsMembersResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+
+impl ServerImpl {
+    /// 水系ごとの水位の推定方法を返す
+    async fn river_systems(&self) -> Result<GetApiRiverSystemsResponse, ApiError> {
+        let mut result = self.graph()?.execute(query(
+            r#"
+MATCH (riverSystem:RiverSystem)
+RETURN riverSystem.id AS id,
+       riverSystem.model AS model,
+       riverSystem.idw_power AS idw_power,
+       riverSystem.bed_slope AS bed_slope
+ORDER BY id
+            "#,
+        ))
+            .await?;
+
+        let mut systems = Vec::new();
+        while let Some(row) = result.next().await? {
+            // 推定方法が設定されていない水系はサーバーの設定値を使う
+            let model = row.get::<Option<String>>("model")?
+                .map(|model| model.parse::<WaterLevelModelKind>())
+                .transpose()
+                .map_err(|e| ApiError::Internal(e.to_string()))?
+                .unwrap_or(self.env.water_level_model);
+
+            systems.push(openapi::models::RiverSystem {
+                id: row.get("id")?,
+                model: model.into(),
+                idw_power: row.get("idw_power")?,
+                bed_slope: row.get("bed_slope")?,
+            });
+        }
+
+        Ok(GetApiRiverSystemsResponse::Status200_OK(systems))
+    }
+
+    /// 水系の推定方法を設定する
+    async fn configure_river_system(&self, id: String, config: WaterLevelModelConfig) -> Result<PostApiRiverSystemsResponse, ApiError> {
+        validate_model_config(&config)?;
+        let WaterLevelModelConfig { model, idw_power, bed_slope } = config;
+
+        self.graph()?.run(
+            query(r#"
+MERGE (riverSystem:RiverSystem {id: $id})
+SET riverSystem.model = $model,
+    riverSystem.idw_power = $idw_power,
+    riverSystem.bed_slope = $bed_slope
+            "#)
+                .param("id", id)
+                .param("model", WaterLevelModelKind::from(model).to_string())
+                .param("idw_power", idw_power)
+                .param("bed_slope", bed_slope),
+        ).await?;
+
+        Ok(PostApiRiverSystemsResponse::Status200_OK)
+    }
+
+    /// 河川ノードを水系に加える、または水系から外す
+    async fn update_river_system_members(&self, id: String, members: RiverSystemMembers) -> Result<PostApiRiverSystemsMembersResponse, ApiError> {
+        let RiverSystemMembers { add, remove } = members;
+        let add = add.unwrap_or_default();
+        let remove = remove.unwrap_or_default();
+        if let Some(node) = add.iter().find(|node| remove.contains(node)) {
+            return Err(ApiError::BadRequest(format!("River node {node} is both added and removed")));
+        }
+
+        let mut tnx = self.graph()?.start_txn().await?;
+
+        let result = async {
+            // 1. 水系と河川ノードの存在確認
+            let row = tnx.execute(
+                query(r#"
+OPTIONAL MATCH (riverSystem:RiverSystem {id: $id})
+RETURN riverSystem IS NOT NULL AS exists,
+       [hilbert18 IN $add + $remove WHERE NOT EXISTS { MATCH (:RiverNode {hilbert18: hilbert18}) }] AS unknown
+                "#)
+                    .param("id", id.as_str())
+                    .param("add", add.clone())
+                    .param("remove", remove.clone()),
+            ).await?
+                .next(tnx.handle()).await?
+                .ok_or_else(|| anyhow::anyhow!("OPTIONAL MATCH returned no row"))?;
+
+            if !row.get::<bool>("exists")? {
+                return anyhow::Ok(Err(ApiError::NotFound(format!("River system {id} not found"))));
+            }
+            let unknown = row.get::<Vec<String>>("unknown")?;
+            if !unknown.is_empty() {
+                return anyhow::Ok(Err(ApiError::BadRequest(format!("Unknown river nodes: {}", unknown.join(", ")))));
+            }
+
+            // 2. 水系から外す
+            tnx.run(
+                query(r#"
+MATCH (:RiverSystem {id: $id})-[member:IN_SYSTEM]->(node:RiverNode)
+WHERE node.hilbert18 IN $remove
+DELETE member
+                "#)
+                    .param("id", id.as_str())
+                    .param("remove", remove),
+            ).await?;
+
+            // 3. 別の水系に属していた河川ノードは移してから水系に加える
+            tnx.run(
+                query(r#"
+MATCH (riverSystem:RiverSystem {id: $id})
+UNWIND $add AS hilbert18
+MATCH (node:RiverNode {hilbert18: hilbert18})
+OPTIONAL MATCH (other:RiverSystem)-[previous:IN_SYSTEM]->(node)
+WHERE other <> riverSystem
+DELETE previous
+WITH DISTINCT riverSystem, node
+MERGE (riverSystem)-[:IN_SYSTEM]->(node)
+                "#)
+                    .param("id", id.as_str())
+                    .param("add", add),
+            ).await?;
+
+            anyhow::Ok(Ok(()))
+        }.await;
+
+        match result {
+            Ok(Ok(())) => {
+                tnx.commit().await?;
+                Ok(PostApiRiverSystemsMembersResponse::Status200_OK)
+            }
+            Ok(Err(e)) => {
+                tnx.rollback().await?;
+                Err(e)
+            }
+            Err(e) => {
+                tnx.rollback().await?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RiverSystem for ServerImpl {
+    async fn get_api_river_systems(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+    ) -> Result<GetApiRiverSystemsResponse, String> {
+        self.river_systems()
+            .await
+            .or_else(GetApiRiverSystemsResponse::from_error)
+    }
+
+    async fn post_api_river_systems(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: PostApiRiverSystemsQueryParams,
+        body: Option<WaterLevelModelConfig>,
+    ) -> Result<PostApiRiverSystemsResponse, String> {
+        let result = match body {
+            Some(config) => self.configure_river_system(query_params.id, config).await,
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(PostApiRiverSystemsResponse::from_error)
+    }
+
+    async fn post_api_river_systems_members(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: PostApiRiverSystemsMembersQueryParams,
+        body: Option<RiverSystemMembers>,
+    ) -> Result<PostApiRiverSystemsMembersResponse, String> {
+        let result = match body {
+            Some(members) => self.update_river_system_members(query_params.id, members).await,
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(PostApiRiverSystemsMembersResponse::from_error)
+    }
+}
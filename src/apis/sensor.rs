@@ -2,11 +2,131 @@ use axum::async_trait;
 use axum::extract::Host;
 use axum::http::Method;
 use axum_extra::extract::CookieJar;
-use neo4rs::query;
-use openapi::apis::sensor::{ApiSensorsDeleteResponse, ApiSensorsPostResponse, Sensor};
-use openapi::models::{ApiSensorsDeleteQueryParams, ApiSensorsPostQueryParams, ApiSensorsPostRequest};
+use openapi::apis::sensor::{ApiSensorsDeleteResponse, ApiSensorsPostResponse, DeleteApiSensorsPendingIdResponse, GetApiSensorsHealthResponse, GetApiSensorsIdLocationsResponse, GetApiSensorsPendingResponse, PatchApiSensorsIdResponse, PostApiSensorsPendingIdResponse, Sensor};
+use openapi::models::{ApiSensorsDeleteQueryParams, ApiSensorsPostQueryParams, ApiSensorsPostRequest, DeleteApiSensorsPendingIdPathParams, GetApiSensorsHealthQueryParams, GetApiSensorsIdLocationsPathParams, PatchApiSensorsIdPathParams, PostApiSensorsPendingIdPathParams, SensorAssignment, SensorHealthStatus, SensorHealthSummary, SensorPatch, SensorRegistration};
 
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
 use crate::apis::ServerImpl;
+use crate::health::load_health;
+use crate::store::{validate_settings, SensorSettings, Updated};
+
+problem_response!(ApiSensorsDeleteResponse {
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(ApiSensorsPostResponse {
+    BadRequest => Status400_BadRequest,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(GetApiSensorsHealthResponse {
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PatchApiSensorsIdResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(GetApiSensorsIdLocationsResponse {
+    NotFound => Status404_NotFound,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(GetApiSensorsPendingResponse {
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiSensorsPendingIdResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(DeleteApiSensorsPendingIdResponse {
+    NotFound => Status404_NotFound,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+
+impl From<SensorAssignment> for ApiSensorsPostRequest {
+    fn from(assignment: SensorAssignment) -> Self {
+        let SensorAssignment { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream, parent_node } = assignment;
+        ApiSensorsPostRequest { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream, parent_node }
+    }
+}
+
+impl ServerImpl {
+    /// センサーの運用を終了する。`purge`の場合は完全に削除する
+    async fn delete_sensor(&self, id: String, purge: bool) -> Result<ApiSensorsDeleteResponse, ApiError> {
+        if !self.store.decommission_sensor(&id, purge).await? {
+            return Err(ApiError::NotFound(format!("Sensor {id} is not registered")));
+        }
+
+        Ok(ApiSensorsDeleteResponse::Status200)
+    }
+
+    /// センサーを登録または上書きする
+    async fn register_sensor(&self, id: String, rotate_secret: bool, body: ApiSensorsPostRequest) -> Result<SensorRegistration, ApiError> {
+        validate_settings(&SensorSettings::from(&body)).map_err(ApiError::BadRequest)?;
+
+        self.store.register_sensor(&id, &body, rotate_secret)
+            .await?
+            .ok_or_else(|| ApiError::BadRequest(format!("River node {} does not exist", body.parent_node)))
+    }
+
+    /// センサーの稼働状態を、状態ごとの台数とともに返す
+    async fn sensors_health(&self, status: Option<SensorHealthStatus>) -> Result<GetApiSensorsHealthResponse, ApiError> {
+        let sensors = load_health(self.graph()?)
+            .await?
+            .into_iter()
+            .map(openapi::models::SensorHealth::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let count = |status: SensorHealthStatus| sensors.iter().filter(|sensor| sensor.status == status).count() as i64;
+        let (healthy, warning, offline) = (count(SensorHealthStatus::Healthy), count(SensorHealthStatus::Warning), count(SensorHealthStatus::Offline));
+
+        let sensors = match status {
+            Some(status) => sensors.into_iter().filter(|sensor| sensor.status == status).collect(),
+            None => sensors,
+        };
+
+        Ok(GetApiSensorsHealthResponse::Status200_OK(SensorHealthSummary { healthy, warning, offline, sensors }))
+    }
+
+    /// センサーの指定された項目のみ変更する
+    ///
+    /// 設定値は、保存された値に変更を重ねたものをストアが同じトランザクションの中で検証する。
+    async fn update_sensor(&self, id: String, patch: SensorPatch) -> Result<PatchApiSensorsIdResponse, ApiError> {
+        match self.store.update_sensor(&id, &patch).await? {
+            Updated::Updated(update) => Ok(PatchApiSensorsIdResponse::Status200_OK(update)),
+            Updated::NotFound => Err(ApiError::NotFound(format!("Sensor {id} is not registered"))),
+            Updated::UnknownRiverNode => Err(ApiError::BadRequest(format!(
+                "River node {} does not exist", patch.parent_node.unwrap_or_default(),
+            ))),
+            Updated::Invalid(message) => Err(ApiError::BadRequest(message)),
+        }
+    }
+
+    /// センサーの設置場所の履歴を古い順に返す
+    async fn sensor_locations(&self, id: String) -> Result<GetApiSensorsIdLocationsResponse, ApiError> {
+        let Some(locations) = self.store.sensor_locations(&id).await? else {
+            return Err(ApiError::NotFound(format!("Sensor {id} is not registered")));
+        };
+
+        Ok(GetApiSensorsIdLocationsResponse::Status200_OK(locations))
+    }
+
+    /// 承認待ちのセンサーを河川ノードに割り当てて登録する
+    async fn approve_pending_sensor(&self, id: String, assignment: SensorAssignment) -> Result<PostApiSensorsPendingIdResponse, ApiError> {
+        if !self.store.is_pending(&id).await? {
+            return Err(ApiError::NotFound(format!("Sensor {id} is not pending")));
+        }
+
+        let registration = self.register_sensor(id.clone(), false, assignment.into()).await?;
+        self.store.remove_pending(&id).await?;
+
+        Ok(PostApiSensorsPendingIdResponse::Status200_OK(registration))
+    }
+}
 
 #[async_trait]
 impl Sensor for ServerImpl {
@@ -17,18 +137,11 @@ impl Sensor for ServerImpl {
         _cookies: CookieJar,
         query_params: ApiSensorsDeleteQueryParams,
     ) -> Result<ApiSensorsDeleteResponse, String> {
-        let query = query(
-            r#"
-MATCH (sensor:Sensor{id:$id})
-DETACH DELETE sensor
-RETURN id
-            "#,
-        )
-            .param("id", query_params.id.to_string());
+        let ApiSensorsDeleteQueryParams { id, purge } = query_params;
 
-        let _ = self.graph.execute(query).await.unwrap();
-
-        Ok(ApiSensorsDeleteResponse::Status200)
+        self.delete_sensor(id.to_string(), purge.unwrap_or(false))
+            .await
+            .or_else(ApiSensorsDeleteResponse::from_error)
     }
 
     async fn api_sensors_post(
@@ -39,72 +152,101 @@ RETURN id
         query_params: ApiSensorsPostQueryParams,
         body: Option<ApiSensorsPostRequest>,
     ) -> Result<ApiSensorsPostResponse, String> {
-        let body = body.unwrap();
-        let ApiSensorsPostRequest { altitude, interval, scope, parent_node } = body;
-        let ApiSensorsPostQueryParams { id } = query_params;
-
-        let mut tnx = self.graph.start_txn().await.unwrap();
-
-        let create_sensor_result = tnx.run(
-            query(r#"
-MERGE (sensor:Sensor{id:$id})
-ON CREATE
- SET sensor.altitude=$altitude,
-     sensor.scope=$scope,
-     sensor.interval=$interval,
-     sensor.parent_node=$parent_node
-ON MATCH
- SET sensor.altitude=$altitude,
-     sensor.scope=$scope,
-     sensor.interval=$interval,
-     sensor.parent_node=$parent_node
-
-WITH sensor
-MATCH (sensor)-[r:BELONGS_TO|AFFECTS]-(parent:RiverNode)
-DELETE r
-                "#)
-                .param("id", id.to_string())
-                .param("altitude", altitude)
-                .param("interval", interval)
-                .param("scope", scope)
-                .param("parent_node", parent_node.clone()),
-        ).await;
-
-        let create_affects_result =
-            tnx
-                .run(
-                    query(r#"
-MATCH (parent:RiverNode{hilbert18:$parent_node})
-MATCH (sensor:Sensor{id:$id})
-CREATE (sensor)-[:BELONGS_TO]->(parent)
-
-WITH sensor, parent
-CALL apoc.path.expandConfig(parent, {
-    relationshipFilter: 'RIVER_LINK',
-    minLevel: 1,
-    maxLevel: 1000,
-    bfs: true,
-    limit: 1000
-}) YIELD path
-
-WITH sensor, parent, last(nodes(path)) AS target,
-     reduce(totalDistance = 0, rel in relationships(path) | totalDistance + rel.length) AS distance
-
-WHERE distance <= $scope
-CREATE (sensor)-[:AFFECTS {distance: distance}]->(target)
-                "#)
-                        .param("id", id.to_string())
-                        .param("parent_node", parent_node)
-                        .param("scope", scope),
-                ).await;
-
-        if create_sensor_result.is_err() || create_affects_result.is_err() {
-            tnx.rollback().await.unwrap();
-            return Err("Failed to create sensor".to_string());
-        }
+        let ApiSensorsPostQueryParams { id, rotate_secret } = query_params;
 
-        tnx.commit().await.unwrap();
+        let result = match body {
+            Some(body) => self.register_sensor(id.to_string(), rotate_secret.unwrap_or(false), body)
+                .await
+                .map(ApiSensorsPostResponse::Status200),
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(ApiSensorsPostResponse::from_error)
+    }
 
-        Ok(ApiSensorsPostResponse::Status200)
+    async fn get_api_sensors_health(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiSensorsHealthQueryParams,
+    ) -> Result<GetApiSensorsHealthResponse, String> {
+        self.sensors_health(query_params.status)
+            .await
+            .or_else(GetApiSensorsHealthResponse::from_error)
+    }
+
+    async fn patch_api_sensors_id(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        path_params: PatchApiSensorsIdPathParams,
+        body: Option<SensorPatch>,
+    ) -> Result<PatchApiSensorsIdResponse, String> {
+        let result = match body {
+            Some(patch) => self.update_sensor(path_params.id.to_string(), patch).await,
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(PatchApiSensorsIdResponse::from_error)
+    }
+
+    async fn get_api_sensors_pending(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+    ) -> Result<GetApiSensorsPendingResponse, String> {
+        let result = async {
+            let pending = self.store.list_pending()
+                .await?
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok::<_, ApiError>(GetApiSensorsPendingResponse::Status200_OK(pending))
+        }.await;
+        result.or_else(GetApiSensorsPendingResponse::from_error)
+    }
+
+    async fn post_api_sensors_pending_id(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        path_params: PostApiSensorsPendingIdPathParams,
+        body: Option<SensorAssignment>,
+    ) -> Result<PostApiSensorsPendingIdResponse, String> {
+        let result = match body {
+            Some(assignment) => self.approve_pending_sensor(path_params.id.to_string(), assignment).await,
+            None => Err(ApiError::missing_body()),
+        };
+        result.or_else(PostApiSensorsPendingIdResponse::from_error)
+    }
+
+    async fn delete_api_sensors_pending_id(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        path_params: DeleteApiSensorsPendingIdPathParams,
+    ) -> Result<DeleteApiSensorsPendingIdResponse, String> {
+        let id = path_params.id.to_string();
+
+        match self.store.remove_pending(&id).await {
+            Ok(true) => Ok(DeleteApiSensorsPendingIdResponse::Status200_OK),
+            Ok(false) => DeleteApiSensorsPendingIdResponse::from_error(ApiError::NotFound(format!("Sensor {id} is not pending"))),
+            Err(e) => DeleteApiSensorsPendingIdResponse::from_error(e.into()),
+        }
+    }
+
+    async fn get_api_sensors_id_locations(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        path_params: GetApiSensorsIdLocationsPathParams,
+    ) -> Result<GetApiSensorsIdLocationsResponse, String> {
+        self.sensor_locations(path_params.id.to_string())
+            .await
+            .or_else(GetApiSensorsIdLocationsResponse::from_error)
     }
 }
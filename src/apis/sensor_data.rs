@@ -1,12 +1,366 @@
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
 use crate::apis::ServerImpl;
+use crate::export::{api_range, export, parse_ids, ExportFormat};
+use crate::interval::IntervalPolicy;
+use crate::store::{Reading, SignedReadings};
 use axum::async_trait;
-use axum::extract::Host;
-use axum::http::Method;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Host, Request};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum_extra::extract::CookieJar;
-use chrono::Local;
-use neo4rs::query;
-use openapi::apis::sensor_data::{PostApiSensorsDataResponse, SensorData};
-use openapi::models::{PostApiSensorsDataQueryParams, PostApiSensorsDataRequest};
+use chrono::{DateTime, Local, Utc};
+use hmac::{Hmac, Mac};
+use openapi::apis::sensor_data::{GetApiSensorsDataExportResponse, PostApiSensorsDataBatchResponse, PostApiSensorsDataBulkResponse, PostApiSensorsDataResponse, SensorData};
+use openapi::models::{GetApiSensorsDataExportQueryParams, PostApiSensorsDataBatchHeaderParams, PostApiSensorsDataBatchQueryParams, PostApiSensorsDataBatchRequest, PostApiSensorsDataBulkRequest, PostApiSensorsDataHeaderParams, PostApiSensorsDataQueryParams, PostApiSensorsDataRequest, SensorInterval, SensorPostStatus, SensorReading, SensorReadings};
+use openapi::types::ByteArray;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl From<PostApiSensorsDataRequest> for Reading {
+    fn from(body: PostApiSensorsDataRequest) -> Self {
+        let PostApiSensorsDataRequest { distance, battery_voltage, previous_sleep_time, network_status, time } = body;
+        Reading::new(distance, battery_voltage, previous_sleep_time, network_status, time)
+    }
+}
+
+impl From<SensorReading> for Reading {
+    fn from(reading: SensorReading) -> Self {
+        let SensorReading { distance, battery_voltage, previous_sleep_time, network_status, time } = reading;
+        Reading::new(distance, battery_voltage, previous_sleep_time, network_status, time)
+    }
+}
+
+impl From<&Reading> for SensorReading {
+    fn from(reading: &Reading) -> Self {
+        SensorReading {
+            distance: reading.distance,
+            battery_voltage: reading.battery_voltage,
+            previous_sleep_time: reading.previous_sleep_time,
+            network_status: reading.network_status.clone(),
+            time: reading.device_time.and_then(|time| DateTime::from_timestamp(time, 0)),
+        }
+    }
+}
+
+/// センサーからの送信のうち、署名を検証するパス
+const SIGNED_PATHS: [&str; 3] = ["/api/sensors/data", "/api/sensors/data/batch", "/api/sensors/data/bulk"];
+
+/// 署名を検証するために保持するリクエストボディの上限(axumの既定の上限と同じ)
+const SIGNED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+tokio::task_local! {
+    /// センサーから送られたままのリクエストボディ
+    static SIGNED_BODY: Bytes;
+}
+
+/// センサーからの送信のリクエストボディを、署名の検証のためにそのまま保持するミドルウェア
+///
+/// 生成されたハンドラーは解釈した後のボディしか受け取れないため、ハンドラーの実行中はタスクローカルに置く。
+pub async fn capture_signed_body(request: Request, next: Next) -> Response {
+    if request.method() != Method::POST || !SIGNED_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = to_bytes(body, SIGNED_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let request = Request::from_parts(parts, Body::from(bytes.clone()));
+
+    SIGNED_BODY.scope(bytes, next.run(request)).await
+}
+
+/// ハンドラーの実行中のリクエストボディ
+fn signed_body() -> Bytes {
+    SIGNED_BODY.try_with(Bytes::clone).unwrap_or_default()
+}
+
+/// 一括送信のボディのうち、センサーごとの署名の対象になる計測値の列
+#[derive(Deserialize)]
+struct RawBulkRequest<'a> {
+    #[serde(borrow)]
+    sensors: Vec<RawSensorReadings<'a>>,
+}
+
+#[derive(Deserialize)]
+struct RawSensorReadings<'a> {
+    #[serde(borrow)]
+    readings: &'a RawValue,
+}
+
+/// 署名対象のメッセージを組み立てる
+///
+/// `{id}\n{timestamp}\n`に続けて、センサーが送ったままの`signed`(リクエストボディ、
+/// 一括送信ではそのセンサーの`readings`のJSON)を連結したもの。
+fn signing_message(id: &str, timestamp: i64, signed: &[u8]) -> Vec<u8> {
+    let mut message = format!("{id}\n{timestamp}\n").into_bytes();
+    message.extend_from_slice(signed);
+    message
+}
+
+/// 計測時刻がサーバー時刻より`max_skew`秒を超えて先の計測値があれば、その計測時刻を返す
+///
+/// 時計のずれたセンサーの計測値が最新のデータになると、以降の正しい計測値がすべて遡って送られたものとして
+/// 扱われるため、このような計測値は受け付けない。
+fn future_reading_time(readings: &[Reading], now: DateTime<Utc>, max_skew: i64) -> Option<DateTime<Local>> {
+    readings.iter()
+        .map(|reading| reading.time)
+        .filter(|time| (time.with_timezone(&Utc) - now).num_seconds() > max_skew)
+        .max()
+}
+
+/// センサーの署名を検証した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verification {
+    /// 署名を検証できた
+    Verified,
+    /// 署名が不正、署名時刻がずれている、またはリプレイ
+    Rejected,
+    /// 登録されていない(または運用を終了した)センサー
+    Unregistered,
+}
+
+/// 1台のセンサーの計測値を受け付けた結果
+enum Posted {
+    /// 登録した。センサーに返す送信間隔
+    Ingested(i32),
+    /// 署名を検証できなかった
+    Unauthorized,
+    /// 登録されていないセンサーを承認待ちとして記録した
+    Pending,
+}
+
+impl ServerImpl {
+    /// センサーの署名を検証する
+    ///
+    /// 署名時刻がサーバー時刻から大きくずれている場合も検証に失敗したものとする。
+    /// リプレイかどうかは、計測値を登録するトランザクションで署名時刻を記録するときに判定する。
+    async fn verify_signature(&self, id: &str, timestamp: i64, signature: &str, signed: &[u8]) -> Result<Verification, ApiError> {
+        let Some(secret) = self.store.sensor_secret(id).await? else {
+            return Ok(Verification::Unregistered);
+        };
+
+        if (Utc::now().timestamp() - timestamp).abs() > self.env.sensor_auth_max_skew {
+            return Ok(Verification::Rejected);
+        }
+
+        let Ok(signature) = hex::decode(signature) else {
+            return Ok(Verification::Rejected);
+        };
+
+        // シークレットが未発行のセンサーは検証できない
+        let Some(secret) = secret else {
+            return Ok(Verification::Rejected);
+        };
+
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).map_err(|e| ApiError::Internal(e.to_string()))?;
+        mac.update(&signing_message(id, timestamp, signed));
+        if mac.verify_slice(&signature).is_err() {
+            return Ok(Verification::Rejected);
+        }
+
+        Ok(Verification::Verified)
+    }
+
+    /// センサーに返す送信間隔を決め、最新のセンサーデータに記録する
+    ///
+    /// 水位の変化率は直近の受け付けた計測値から求める。
+    async fn sensor_intervals(&self, ids: &[String]) -> Result<Vec<(String, i32)>, ApiError> {
+        let policy = IntervalPolicy::from(&*self.env);
+        let since = Utc::now() - chrono::Duration::minutes(self.env.interval_window_minutes);
+
+        let decisions = self.store.interval_states(ids, since).await?
+            .into_iter()
+            .map(|(id, state)| (id, policy.decide(&state)))
+            .collect::<Vec<_>>();
+
+        // 監査のため、決定した送信間隔と理由を記録する
+        self.store.record_intervals(&decisions, Local::now()).await?;
+
+        Ok(decisions.into_iter().map(|(id, decision)| (id, decision.interval)).collect())
+    }
+
+    /// 1台のセンサーの計測値を登録し、センサーに返す送信間隔を返す
+    ///
+    /// 署名時刻が前回受け付けたもの以前の場合(リプレイ)は登録しない。
+    async fn ingest_sensor_readings(&self, id: String, signed_at: i64, readings: Vec<Reading>) -> Result<Posted, ApiError> {
+        let ingested = self.store.ingest_readings(vec![SignedReadings { id: id.clone(), signed_at, readings }]).await?;
+        if !ingested.unknown_ids.is_empty() {
+            return Err(ApiError::NotFound(format!("Sensor {id} is not registered")));
+        }
+        if !ingested.replayed_ids.is_empty() {
+            return Ok(Posted::Unauthorized);
+        }
+
+        let (_, interval) = self.sensor_intervals(&[id.clone()]).await?
+            .pop()
+            .ok_or_else(|| ApiError::NotFound(format!("Sensor {id} is not registered")))?;
+
+        // TODO より細かい粒度でデータの更新を伝える
+        if !ingested.updated_ids.is_empty() {
+            self.broadcast_update().await;
+        }
+
+        Ok(Posted::Ingested(interval))
+    }
+
+    /// 水位が更新されたことを伝える
+    ///
+    /// 計測値は登録済みのため、伝えられなかった場合もエラーにはしない。
+    /// Socket.IOサーバーに接続していない場合は何もしない。
+    async fn broadcast_update(&self) {
+        let Some(socketio_client) = &self.socketio_client else {
+            return;
+        };
+        if let Err(e) = socketio_client.emit("broadcast_request", Local::now().to_rfc3339()).await {
+            println!("broadcast_update: {:?}", e);
+        }
+    }
+
+    /// 1台のセンサーの計測値を、リクエストボディの署名を検証してから登録する
+    ///
+    /// 登録されていないセンサーは、自動プロビジョニングが有効な場合は最新の計測値とともに承認待ちとして記録し、
+    /// 無効な場合は404とする。
+    async fn post_sensor_readings(&self, id: String, timestamp: i64, signature: &str, readings: Vec<Reading>) -> Result<Posted, ApiError> {
+        if let Some(time) = future_reading_time(&readings, Utc::now(), self.env.sensor_auth_max_skew) {
+            return Err(ApiError::BadRequest(format!("Reading time {} is in the future", time.to_rfc3339())));
+        }
+
+        match self.verify_signature(&id, timestamp, signature, &signed_body()).await? {
+            Verification::Verified => self.ingest_sensor_readings(id, timestamp, readings).await,
+            Verification::Rejected => Ok(Posted::Unauthorized),
+            Verification::Unregistered if self.env.sensor_auto_provision => {
+                match readings.iter().max_by_key(|reading| reading.time) {
+                    Some(latest) if !self.store.record_pending(&id, &latest.into(), Utc::now()).await? => {
+                        Err(ApiError::NotFound(format!("Sensor {id} is not registered and the pending queue is full")))
+                    }
+                    _ => Ok(Posted::Pending),
+                }
+            }
+            Verification::Unregistered => Err(ApiError::NotFound(format!("Sensor {id} is not registered"))),
+        }
+    }
+
+    /// ゲートウェイが中継する複数センサーの計測値を登録し、センサーごとの登録結果と送信間隔を返す
+    ///
+    /// 結果はリクエストと同じ順に並べる。
+    async fn post_bulk_readings(&self, sensor_readings: Vec<SensorReadings>) -> Result<Vec<SensorInterval>, ApiError> {
+        // 署名の対象はセンサーごとの`readings`の送られたままのJSON
+        let body = signed_body();
+        let raw = serde_json::from_slice::<RawBulkRequest>(&body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        if raw.sensors.len() != sensor_readings.len() {
+            return Err(ApiError::BadRequest("Request body could not be read for signature verification".to_string()));
+        }
+
+        // 署名を検証できたセンサーの計測値のみを登録する
+        let mut statuses = Vec::with_capacity(sensor_readings.len());
+        let mut sensors = Vec::with_capacity(sensor_readings.len());
+        for (SensorReadings { id: uuid, readings, timestamp, signature }, raw) in sensor_readings.into_iter().zip(raw.sensors) {
+            let id = uuid.to_string();
+            let readings = readings.into_iter().map(Reading::from).collect::<Vec<_>>();
+            if let Some(time) = future_reading_time(&readings, Utc::now(), self.env.sensor_auth_max_skew) {
+                println!("post_api_sensors_data_bulk: reading time {} of {id} is in the future", time.to_rfc3339());
+                statuses.push((uuid, SensorPostStatus::Invalid));
+                continue;
+            }
+
+            let status = match self.verify_signature(&id, timestamp, &signature, raw.readings.get().as_bytes()).await? {
+                Verification::Verified => {
+                    sensors.push(SignedReadings { id, signed_at: timestamp, readings });
+                    SensorPostStatus::Ok
+                }
+                Verification::Rejected => SensorPostStatus::Unauthorized,
+                Verification::Unregistered if self.env.sensor_auto_provision => {
+                    match readings.iter().max_by_key(|reading| reading.time) {
+                        Some(latest) if !self.store.record_pending(&id, &latest.into(), Utc::now()).await? => {
+                            println!("post_api_sensors_data_bulk: pending queue is full, {id} is not recorded");
+                            SensorPostStatus::NotFound
+                        }
+                        _ => SensorPostStatus::Pending,
+                    }
+                }
+                Verification::Unregistered => SensorPostStatus::NotFound,
+            };
+            statuses.push((uuid, status));
+        }
+
+        let mut ids = sensors.iter().map(|sensor| sensor.id.clone()).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+
+        let ingested = self.store.ingest_readings(sensors).await?;
+
+        let intervals = self.sensor_intervals(&ids).await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        let results = statuses.into_iter()
+            .map(|(id, status)| {
+                let key = id.to_string();
+                let interval = intervals.get(&key).copied();
+                // リプレイと、署名の検証後に運用を終了したセンサーの計測値は登録していない
+                let status = match status {
+                    SensorPostStatus::Ok if ingested.replayed_ids.contains(&key) => SensorPostStatus::Unauthorized,
+                    SensorPostStatus::Ok if interval.is_none() || ingested.unknown_ids.contains(&key) => SensorPostStatus::NotFound,
+                    status => status,
+                };
+                let interval = interval.filter(|_| status == SensorPostStatus::Ok);
+                SensorInterval { id, status, interval }
+            })
+            .collect();
+
+        if !ingested.updated_ids.is_empty() {
+            self.broadcast_update().await;
+        }
+
+        Ok(results)
+    }
+
+    /// 指定したセンサー群・期間のセンサーデータを書き出す
+    ///
+    /// 書き出した全体をまとめて返すため、期間は`EXPORT_MAX_DAYS`日以内に限る。
+    async fn export_sensor_data(&self, query_params: GetApiSensorsDataExportQueryParams) -> Result<GetApiSensorsDataExportResponse, ApiError> {
+        let GetApiSensorsDataExportQueryParams { ids, from, to, format } = query_params;
+        let ids = parse_ids(&ids).map_err(ApiError::BadRequest)?;
+        api_range(from, to, Utc::now(), self.env.export_max_days).map_err(ApiError::BadRequest)?;
+        let format = format.map(ExportFormat::from).unwrap_or_default();
+
+        let buf = export(self.graph()?, &ids, from, to, format, Vec::new()).await?;
+
+        Ok(GetApiSensorsDataExportResponse::Status200 {
+            body: ByteArray(buf),
+            content_disposition: Some(format!("attachment; filename=\"sensor_data.{}\"", format.extension())),
+        })
+    }
+}
+
+problem_response!(PostApiSensorsDataResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiSensorsDataBatchResponse {
+    BadRequest => Status400_BadRequest,
+    NotFound => Status404_NotFound,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(PostApiSensorsDataBulkResponse {
+    BadRequest => Status400_BadRequest,
+    Conflict => Status409_Conflict,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+problem_response!(GetApiSensorsDataExportResponse {
+    BadRequest => Status400_BadRequest,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
 
 #[async_trait]
 impl SensorData for ServerImpl {
@@ -14,133 +368,73 @@ impl SensorData for ServerImpl {
         &self, _method: Method,
         _host: Host,
         _cookies: CookieJar,
+        header_params: PostApiSensorsDataHeaderParams,
         query_params: PostApiSensorsDataQueryParams,
         body: Option<PostApiSensorsDataRequest>,
     ) -> Result<PostApiSensorsDataResponse, String> {
-        let body = body.unwrap();
-        let PostApiSensorsDataRequest {
-            distance,
-            battery_voltage,
-            previous_sleep_time,
-            network_status,
-        } = body;
-
-        let mut tnx = self.graph.start_txn().await.unwrap();
-
-        let create_sensor_data_result = tnx.run(query(
-            r#"
-// 1. センサーの取得
-MATCH (sensor:Sensor {id: $id})
-
-// 2. オプショナルマッチで現在のデータを取得
-OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(currentData:SensorData)
-
-// 3. 新しいセンサーデータの作成
-CREATE (newData:SensorData {
-    distance: $distance,
-    battery_voltage: $battery_voltage,
-    previous_sleep_time: $previous_sleep_time,
-    network_status: $network_status,
-    time: $time
-})
-
-WITH sensor, currentData, newData
-
-// 4. 条件分岐: currentData が存在するかどうか
-CALL apoc.do.when(
-    currentData IS NOT NULL,
-    '
-    // CURRENT_DATA リレーションシップを削除
-    MATCH (sensor)-[r:CURRENT_DATA]->(currentData)
-    DELETE r
-
-    // 新しい CURRENT_DATA リレーションシップを作成
-    CREATE (sensor)-[:CURRENT_DATA]->(newData)
-
-    // PREVIOUS_DATA リレーションシップを作成
-    CREATE (newData)-[:PREVIOUS_DATA]->(currentData)
-    ',
-    '
-    // 新しい CURRENT_DATA リレーションシップを作成
-    CREATE (sensor)-[:CURRENT_DATA]->(newData)
-    ',
-    // パラメータ
-    {sensor: sensor, currentData: currentData, newData: newData}
-) YIELD value
-
-RETURN value
-"#)
-            .param("id", query_params.id.to_string())
-            .param("distance", distance)
-            .param("battery_voltage", battery_voltage)
-            .param("previous_sleep_time", previous_sleep_time)
-            .param("network_status", network_status)
-            .param("time", Local::now().to_rfc3339())
-        ).await;
-
-        let upsert_water_level = tnx.run(query(
-            r#"
-// 5. センサーが所属する親河川ノードの取得
-MATCH (sensor:Sensor{id:$id})-[:BELONGS_TO]->(parentRiverNode:RiverNode)
-
-// 6. センサーの水位データを計算し、WaterLevel ノードを作成または更新
-// 水位は sensor の altitude から distance を引いた値
-MERGE (parentRiverNode)-[:WATER_LEVEL]->(parentWaterLevel:WaterLevel)
-  ON CREATE SET parentWaterLevel.value = sensor.altitude - $distance
-  ON MATCH SET parentWaterLevel.value = sensor.altitude - $distance
-
-WITH sensor, parentRiverNode, parentWaterLevel
-
-// 7. AFFECTS 関係を持つすべての河川ノードとその WaterLevel を取得
-MATCH (sensor)-[:AFFECTS]->(affectedRiverNode:RiverNode)
-MATCH (affectedRiverNode)<-[affectsLink:AFFECTS]-(:Sensor)-[:BELONGS_TO]->(:RiverNode)-[:WATER_LEVEL]->(waterLevel:WaterLevel)
-
-// 8. 加重平均の計算に必要な値を準備
-WITH affectedRiverNode, waterLevel.value AS value, 1.0 / affectsLink.distance AS weight
-
-// 9. 水位の加重平均を計算
-WITH affectedRiverNode,
-     SUM(value * weight) AS weightedSum,
-     SUM(weight) AS totalWeight
-
-// 10. 加重平均を計算結果として保持
-WITH affectedRiverNode,
-     weightedSum / totalWeight AS avgWaterLevel
-
-// 11. 計算された加重平均水位を WaterLevel ノードに設定
-MERGE (affectedRiverNode)-[:WATER_LEVEL]->(wl:WaterLevel)
-  ON CREATE SET wl.value = avgWaterLevel
-  ON MATCH SET wl.value = avgWaterLevel
-            "#,
-        )
-            .param("id", query_params.id.to_string())
-            .param("distance", distance)
-        ).await;
-
-        if create_sensor_data_result.is_err() || upsert_water_level.is_err() {
-            println!("create_sensor_data_result: {:?}", create_sensor_data_result);
-            println!("upsert_water_level: {:?}", upsert_water_level);
-            tnx.rollback().await.unwrap();
-            return Err("Failed to create sensor data".to_string());
+        let Some(body) = body else {
+            return PostApiSensorsDataResponse::from_error(ApiError::missing_body());
+        };
+        let readings = vec![Reading::from(body)];
+
+        let PostApiSensorsDataHeaderParams { x_sensor_timestamp, x_sensor_signature } = header_params;
+        match self.post_sensor_readings(query_params.id.to_string(), x_sensor_timestamp, &x_sensor_signature, readings).await {
+            Ok(Posted::Ingested(interval)) => Ok(PostApiSensorsDataResponse::Status200_OK(interval)),
+            Ok(Posted::Unauthorized) => Ok(PostApiSensorsDataResponse::Status401_Unauthorized),
+            Ok(Posted::Pending) => Ok(PostApiSensorsDataResponse::Status202_Accepted),
+            Err(e) => PostApiSensorsDataResponse::from_error(e),
         }
+    }
 
-        tnx.commit().await.unwrap();
+    async fn post_api_sensors_data_batch(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        header_params: PostApiSensorsDataBatchHeaderParams,
+        query_params: PostApiSensorsDataBatchQueryParams,
+        body: Option<PostApiSensorsDataBatchRequest>,
+    ) -> Result<PostApiSensorsDataBatchResponse, String> {
+        let Some(PostApiSensorsDataBatchRequest { readings }) = body else {
+            return PostApiSensorsDataBatchResponse::from_error(ApiError::missing_body());
+        };
+        let readings = readings.into_iter().map(Reading::from).collect::<Vec<_>>();
 
-        let interval = self.graph.execute(query(
-            r#"
-MATCH (sensor:Sensor {id: $id})
-RETURN tointeger(sensor.interval) AS interval
-            "#,
-        )
-            .param("id", query_params.id.to_string()))
-            .await.unwrap().next()
-            .await.unwrap().unwrap()
-            .to::<i32>().unwrap();
+        let PostApiSensorsDataBatchHeaderParams { x_sensor_timestamp, x_sensor_signature } = header_params;
+        match self.post_sensor_readings(query_params.id.to_string(), x_sensor_timestamp, &x_sensor_signature, readings).await {
+            Ok(Posted::Ingested(interval)) => Ok(PostApiSensorsDataBatchResponse::Status200_OK(interval)),
+            Ok(Posted::Unauthorized) => Ok(PostApiSensorsDataBatchResponse::Status401_Unauthorized),
+            Ok(Posted::Pending) => Ok(PostApiSensorsDataBatchResponse::Status202_Accepted),
+            Err(e) => PostApiSensorsDataBatchResponse::from_error(e),
+        }
+    }
 
+    async fn post_api_sensors_data_bulk(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        body: Option<PostApiSensorsDataBulkRequest>,
+    ) -> Result<PostApiSensorsDataBulkResponse, String> {
+        let Some(PostApiSensorsDataBulkRequest { sensors }) = body else {
+            return PostApiSensorsDataBulkResponse::from_error(ApiError::missing_body());
+        };
 
-        // TODO より細かい粒度でデータの更新を伝える
-        self.socketio_client.emit("broadcast_request", Local::now().to_rfc3339()).await.unwrap();
+        self.post_bulk_readings(sensors)
+            .await
+            .map(PostApiSensorsDataBulkResponse::Status200_OK)
+            .or_else(PostApiSensorsDataBulkResponse::from_error)
+    }
 
-        Ok(PostApiSensorsDataResponse::Status200_OK(interval))
+    async fn get_api_sensors_data_export(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiSensorsDataExportQueryParams,
+    ) -> Result<GetApiSensorsDataExportResponse, String> {
+        self.export_sensor_data(query_params)
+            .await
+            .or_else(GetApiSensorsDataExportResponse::from_error)
     }
 }
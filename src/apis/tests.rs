@@ -0,0 +1,579 @@
+//! メモリの格納先を使い、ルーター全体をHTTPで呼び出すテスト
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::serve;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+use crate::apis::ServerImpl;
+use crate::auth::{FileTokenStore, TokenStore};
+use crate::env::EnvVars;
+use crate::router;
+use crate::store::MemoryStore;
+
+const ADMIN_KEY: &str = "test-admin-key";
+
+/// テスト用の河川: 上流から`1`→`2`→`3`→`4`と100メートルごとに並ぶ
+const RIVER_NODES: [(&str, (f64, f64)); 4] = [
+    ("1", (135.005, 35.0)),
+    ("2", (135.006, 35.0)),
+    ("3", (135.007, 35.0)),
+    ("4", (135.008, 35.0)),
+];
+
+/// テスト用の河川ノードをすべて含むタイル
+const WATER_TILE: (u8, u32, u32) = (14, 14336, 6489);
+
+/// センサーのシークレットで署名する
+fn sign(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+struct TestServer {
+    base: String,
+    client: reqwest::Client,
+    store: Arc<MemoryStore>,
+    dir: PathBuf,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// メモリの格納先でサーバーを起動する
+///
+/// Neo4jへの接続は最初のクエリまで行われないため、格納先を通さない処理を呼ばない限り接続先は不要。
+async fn spawn(auto_provision: bool) -> TestServer {
+    let dir = std::env::temp_dir().join(format!("nahlun-server-test-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let vars = [
+        ("NEO4J_URI", "127.0.0.1:7687".to_string()),
+        ("NEO4J_AUTH", "neo4j/password".to_string()),
+        ("NEO4J_DB", "neo4j".to_string()),
+        ("SOCKETIO_HOST", "http://127.0.0.1:3000".to_string()),
+        ("SERVER_HOST", "127.0.0.1:0".to_string()),
+        ("CLIENT_HOST", "http://localhost:3000".to_string()),
+        ("DISK_CACHE_BASE_PATH", dir.join("cache").to_string_lossy().into_owned()),
+        ("DISK_CACHE_MAX_SIZE", "1048576".to_string()),
+        ("MEMORY_CACHE_MAX_SIZE", "1048576".to_string()),
+        ("ADMIN_KEY", ADMIN_KEY.to_string()),
+        ("SENSOR_AUTO_PROVISION", auto_provision.to_string()),
+        ("SENSOR_PENDING_LIMIT", "2".to_string()),
+    ];
+    let env = Arc::new(envy::from_iter::<_, EnvVars>(vars.map(|(key, value)| (key.to_string(), value))).unwrap());
+
+    let store = Arc::new(MemoryStore::new(env.clone()));
+    for (hilbert18, location) in RIVER_NODES {
+        store.add_river_node(hilbert18, location).await;
+    }
+    for link in RIVER_NODES.windows(2) {
+        store.add_river_link(link[0].0, link[1].0, 100.0, None).await;
+    }
+    let token_store: Arc<dyn TokenStore> = Arc::new(FileTokenStore::open(dir.join("tokens.json")).await.unwrap());
+
+    let server_impl = ServerImpl::with_store(env.clone(), None, store.clone(), token_store, None).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve(listener, router(server_impl, &env)));
+
+    TestServer { base, client: reqwest::Client::new(), store, dir }
+}
+
+impl TestServer {
+    async fn request(&self, method: reqwest::Method, path: &str, body: Option<Value>) -> (StatusCode, String, Value) {
+        let mut request = self.client.request(method, format!("{}{path}", self.base))
+            .header(AUTHORIZATION, format!("Bearer {ADMIN_KEY}"));
+        if let Some(body) = body {
+            request = request.header(CONTENT_TYPE, "application/json").body(body.to_string());
+        }
+
+        let response = request.send().await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let text = response.text().await.unwrap();
+
+        (status, content_type, serde_json::from_str(&text).unwrap_or(Value::Null))
+    }
+
+    /// センサーを登録し、発行された署名用シークレットを返す
+    async fn register(&self, id: Uuid, parent_node: &str) -> String {
+        let (status, _, body) = self.request(reqwest::Method::POST, &format!("/api/sensors?id={id}"), Some(json!({
+            "altitude": 10.0,
+            "interval": 600,
+            "scope": 150.0,
+            "parent_node": parent_node,
+        }))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        body["secret"].as_str().unwrap().to_string()
+    }
+
+    /// 1回分の計測値を署名して送る
+    async fn post_reading(&self, id: Uuid, secret: &str, timestamp: i64, distance: f32) -> StatusCode {
+        self.post_reading_at(id, secret, timestamp, distance, None).await
+    }
+
+    /// 計測時刻を指定した1回分の計測値を署名して送る
+    async fn post_reading_at(&self, id: Uuid, secret: &str, timestamp: i64, distance: f32, time: Option<DateTime<Utc>>) -> StatusCode {
+        let mut body = json!({
+            "distance": distance,
+            "battery_voltage": 3300,
+            "previous_sleep_time": 600,
+            "network_status": "good",
+        });
+        if let Some(time) = time {
+            body["time"] = json!(time.to_rfc3339());
+        }
+        let body = body.to_string();
+        let signature = sign(secret, &format!("{id}\n{timestamp}\n{body}"));
+
+        self.client.post(format!("{}/api/sensors/data?id={id}", self.base))
+            .header("X-Sensor-Timestamp", timestamp.to_string())
+            .header("X-Sensor-Signature", signature)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+}
+
+#[tokio::test]
+async fn register_sensor_links_to_river() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+
+    let (status, _, body) = server.request(reqwest::Method::POST, &format!("/api/sensors?id={id}"), Some(json!({
+        "altitude": 10.0,
+        "interval": 600,
+        "scope": 150.0,
+        "parent_node": "2",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body["secret"].is_string());
+    // 上流の`1`と下流の`3`のみが影響範囲に入る
+    assert_eq!(body["affects"]["nodes"], 2);
+    assert_eq!(body["affects"]["max_distance"], 100.0);
+    assert_eq!(body["affects"]["truncated"], false);
+
+    // 再登録ではシークレットを再発行しない
+    let (status, _, body) = server.request(reqwest::Method::POST, &format!("/api/sensors?id={id}"), Some(json!({
+        "altitude": 10.0,
+        "interval": 600,
+        "scope": 250.0,
+        "parent_node": "2",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("secret").is_none());
+    assert_eq!(body["affects"]["nodes"], 3);
+}
+
+#[tokio::test]
+async fn reregistered_sensor_leaves_previous_nodes() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "1").await;
+
+    assert_eq!(server.post_reading(id, &secret, Utc::now().timestamp(), 1.5).await, StatusCode::OK);
+    assert_eq!(server.store.river_water_level("1").await, Some(8.5));
+    assert!(server.store.river_water_level("2").await.is_some());
+
+    // 下流の`4`に移設すると、移設前の計測値は使わず、それまでの影響範囲の水位も残さない
+    let (status, _, _) = server.request(reqwest::Method::POST, &format!("/api/sensors?id={id}"), Some(json!({
+        "altitude": 10.0,
+        "interval": 600,
+        "scope": 150.0,
+        "parent_node": "4",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(server.store.river_water_level("1").await, None);
+    assert_eq!(server.store.river_water_level("2").await, None);
+    assert_eq!(server.store.river_water_level("3").await, None);
+}
+
+#[tokio::test]
+async fn register_sensor_rejects_unknown_river_node() {
+    let server = spawn(false).await;
+
+    let (status, content_type, _) = server.request(reqwest::Method::POST, &format!("/api/sensors?id={}", Uuid::new_v4()), Some(json!({
+        "altitude": 10.0,
+        "interval": 600,
+        "scope": 150.0,
+        "parent_node": "999",
+    }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/problem+json");
+}
+
+#[tokio::test]
+async fn signed_reading_updates_water_levels() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+
+    let status = server.post_reading(id, &secret, Utc::now().timestamp(), 1.5).await;
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(server.store.river_water_level("2").await, Some(8.5));
+    assert!(server.store.river_water_level("1").await.is_some());
+    assert!(server.store.river_water_level("3").await.is_some());
+    // 影響範囲の外の河川ノードは推定しない
+    assert_eq!(server.store.river_water_level("4").await, None);
+}
+
+#[tokio::test]
+async fn estimate_does_not_overwrite_measured_water_level() {
+    let server = spawn(false).await;
+    let (upstream, downstream) = (Uuid::new_v4(), Uuid::new_v4());
+    let upstream_secret = server.register(upstream, "2").await;
+    let downstream_secret = server.register(downstream, "3").await;
+    let timestamp = Utc::now().timestamp();
+
+    assert_eq!(server.post_reading(downstream, &downstream_secret, timestamp, 5.0).await, StatusCode::OK);
+    assert_eq!(server.post_reading(upstream, &upstream_secret, timestamp, 1.5).await, StatusCode::OK);
+    assert_eq!(server.post_reading(upstream, &upstream_secret, timestamp + 1, 2.0).await, StatusCode::OK);
+
+    // 下流の`3`は上流のセンサーの影響範囲にあるが、所属するセンサーの計測値のまま
+    assert_eq!(server.store.river_water_level("2").await, Some(8.0));
+    assert_eq!(server.store.river_water_level("3").await, Some(5.0));
+}
+
+#[tokio::test]
+async fn reading_with_invalid_signature_is_unauthorized() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    let timestamp = Utc::now().timestamp();
+
+    assert_eq!(server.post_reading(id, "wrong-secret", timestamp, 1.5).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.post_reading(id, &secret, timestamp, 1.5).await, StatusCode::OK);
+    // 同じ署名時刻はリプレイとして拒否する
+    assert_eq!(server.post_reading(id, &secret, timestamp, 1.5).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.store.river_water_level("2").await, Some(8.5));
+}
+
+#[tokio::test]
+async fn signature_covers_the_request_body() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    let timestamp = Utc::now().timestamp();
+
+    // 署名したボディと異なるボディ(丸めると同じ値になる距離)は受け付けない
+    let signed = r#"{"distance":1.5,"battery_voltage":3300,"previous_sleep_time":600,"network_status":"good"}"#;
+    let sent = r#"{"distance":1.5004,"battery_voltage":3300,"previous_sleep_time":600,"network_status":"good"}"#;
+    let status = server.client.post(format!("{}/api/sensors/data?id={id}", server.base))
+        .header("X-Sensor-Timestamp", timestamp.to_string())
+        .header("X-Sensor-Signature", sign(&secret, &format!("{id}\n{timestamp}\n{signed}")))
+        .header(CONTENT_TYPE, "application/json")
+        .body(sent)
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.store.river_water_level("2").await, None);
+}
+
+#[tokio::test]
+async fn reading_from_the_future_is_rejected() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    let timestamp = Utc::now().timestamp();
+
+    let status = server.post_reading_at(id, &secret, timestamp, 1.5, Some(Utc::now() + chrono::Duration::hours(1))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(server.store.river_water_level("2").await, None);
+
+    // 遡って送られた計測値は受け付ける
+    let status = server.post_reading_at(id, &secret, timestamp, 1.5, Some(Utc::now() - chrono::Duration::hours(1))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn interval_decisions_are_kept_as_history() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    let timestamp = Utc::now().timestamp();
+
+    assert_eq!(server.post_reading(id, &secret, timestamp, 1.5).await, StatusCode::OK);
+    // 遡って送られた計測値では最新のセンサーデータは変わらないが、決定は記録し直す
+    let status = server.post_reading_at(id, &secret, timestamp + 1, 1.5, Some(Utc::now() - chrono::Duration::hours(1))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(server.store.interval_decisions(&id.to_string()).await.len(), 2);
+}
+
+#[tokio::test]
+async fn reading_from_unregistered_sensor_is_not_found() {
+    let server = spawn(false).await;
+
+    let status = server.post_reading(Uuid::new_v4(), "secret", Utc::now().timestamp(), 1.5).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn bulk_readings_report_status_per_sensor() {
+    let server = spawn(false).await;
+    let (registered, unregistered, forged) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let secret = server.register(registered, "2").await;
+    server.register(forged, "3").await;
+    let timestamp = Utc::now().timestamp();
+
+    let readings = json!([{
+        "distance": 1.5,
+        "battery_voltage": 3300,
+        "previous_sleep_time": 600,
+        "network_status": "good",
+    }]);
+    let entry = |id: Uuid, secret: &str| json!({
+        "id": id,
+        "readings": readings,
+        "timestamp": timestamp,
+        "signature": sign(secret, &format!("{id}\n{timestamp}\n{readings}")),
+    });
+    let (status, _, body) = server.request(reqwest::Method::POST, "/api/sensors/data/bulk", Some(json!({
+        "sensors": [entry(registered, &secret), entry(unregistered, "secret"), entry(forged, "wrong-secret")],
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // 結果はリクエストと同じ順に並び、送信間隔は登録できたセンサーにのみ含む
+    assert_eq!(body[0]["id"], registered.to_string());
+    assert_eq!(body[0]["status"], "ok");
+    assert!(body[0]["interval"].is_i64());
+    assert_eq!(body[1]["status"], "not_found");
+    assert_eq!(body[2]["status"], "unauthorized");
+    assert!(body[2].get("interval").is_none());
+    assert_eq!(server.store.river_water_level("2").await, Some(8.5));
+}
+
+#[tokio::test]
+async fn unregistered_sensor_waits_for_approval() {
+    let server = spawn(true).await;
+    let id = Uuid::new_v4();
+
+    let status = server.post_reading(id, "secret", Utc::now().timestamp(), 1.5).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    let (status, _, body) = server.request(reqwest::Method::GET, "/api/sensors/pending", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["id"], id.to_string());
+    assert_eq!(body[0]["reports"], 1);
+
+    let (status, _, body) = server.request(reqwest::Method::POST, &format!("/api/sensors/pending/{id}"), Some(json!({
+        "altitude": 10.0,
+        "interval": 600,
+        "scope": 150.0,
+        "parent_node": "2",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let secret = body["secret"].as_str().unwrap();
+
+    let (_, _, body) = server.request(reqwest::Method::GET, "/api/sensors/pending", None).await;
+    assert_eq!(body, json!([]));
+
+    let status = server.post_reading(id, secret, Utc::now().timestamp(), 1.5).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn pending_queue_is_capped() {
+    let server = spawn(true).await;
+    let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let timestamp = Utc::now().timestamp();
+
+    assert_eq!(server.post_reading(first, "secret", timestamp, 1.5).await, StatusCode::ACCEPTED);
+    assert_eq!(server.post_reading(second, "secret", timestamp, 1.5).await, StatusCode::ACCEPTED);
+    assert_eq!(server.post_reading(third, "secret", timestamp, 1.5).await, StatusCode::NOT_FOUND);
+    // 既に承認待ちのセンサーは上限に達していても記録し続ける
+    assert_eq!(server.post_reading(first, "secret", timestamp + 1, 1.5).await, StatusCode::ACCEPTED);
+
+    let (_, _, body) = server.request(reqwest::Method::GET, "/api/sensors/pending", None).await;
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert!(body.as_array().unwrap().iter().any(|pending| pending["id"] == first.to_string() && pending["reports"] == 2));
+}
+
+#[tokio::test]
+async fn patch_sensor_validates_and_records_relocation() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    server.register(id, "2").await;
+
+    let (status, content_type, _) = server.request(reqwest::Method::PATCH, &format!("/api/sensors/{id}"), Some(json!({
+        "altitude": 5000.0,
+    }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/problem+json");
+
+    // 送信間隔の下限は、保存された送信間隔と比べる
+    let (status, _, _) = server.request(reqwest::Method::PATCH, &format!("/api/sensors/{id}"), Some(json!({
+        "min_interval": 900,
+        "parent_node": "3",
+    }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, body) = server.request(reqwest::Method::GET, &format!("/api/sensors/{id}/locations"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+
+    let (status, _, body) = server.request(reqwest::Method::PATCH, &format!("/api/sensors/{id}"), Some(json!({
+        "parent_node": "3",
+    }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["relocated"], true);
+    assert_eq!(body["relinked"], true);
+
+    let (status, _, body) = server.request(reqwest::Method::GET, &format!("/api/sensors/{id}/locations"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let locations = body.as_array().unwrap();
+    assert_eq!(locations.len(), 2);
+    assert_eq!(locations[0]["parent_node"], "2");
+    assert_eq!(locations[1]["parent_node"], "3");
+    assert!(locations[1].get("until").is_none());
+
+    let (status, _, _) = server.request(reqwest::Method::PATCH, &format!("/api/sensors/{}", Uuid::new_v4()), Some(json!({
+        "interval": 300,
+    }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn water_tile_is_generated_from_river_nodes() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    server.post_reading(id, &secret, Utc::now().timestamp(), 1.5).await;
+
+    let (z, x, y) = WATER_TILE;
+    let response = server.client.get(format!("{}/tiles/water/{z}/{x}/{y}", server.base))
+        .header(AUTHORIZATION, format!("Bearer {ADMIN_KEY}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.bytes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn decommissioned_sensor_is_not_found() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+    let secret = server.register(id, "2").await;
+    server.post_reading(id, &secret, Utc::now().timestamp(), 1.5).await;
+
+    let (status, _, _) = server.request(reqwest::Method::DELETE, &format!("/api/sensors?id={id}"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    // 影響するセンサーがなくなった河川ノードの水位は消す
+    assert_eq!(server.store.river_water_level("1").await, None);
+
+    let (status, _, _) = server.request(reqwest::Method::DELETE, &format!("/api/sensors?id={id}"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let status = server.post_reading(id, &secret, Utc::now().timestamp() + 1, 1.5).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn river_system_model_is_validated() {
+    let server = spawn(false).await;
+
+    let (status, content_type, _) = server.request(reqwest::Method::POST, "/api/river_systems?id=main", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/problem+json");
+
+    for body in [
+        json!({"model": "idw", "idw_power": -2.0}),
+        json!({"model": "idw", "idw_power": 0.0}),
+        json!({"model": "idw", "bed_slope": -0.001}),
+    ] {
+        let (status, _, _) = server.request(reqwest::Method::POST, "/api/river_systems?id=main", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+    }
+}
+
+#[tokio::test]
+async fn webhook_subscription_is_validated() {
+    let server = spawn(false).await;
+
+    let (status, content_type, _) = server.request(reqwest::Method::POST, "/api/webhooks", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/problem+json");
+
+    for body in [
+        json!({"url": "ftp://example.com/hook", "events": ["alert_changed"]}),
+        json!({"url": "https://example.com/hook", "events": []}),
+    ] {
+        let (status, content_type, _) = server.request(reqwest::Method::POST, "/api/webhooks", Some(body.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(content_type, "application/problem+json");
+    }
+}
+
+#[tokio::test]
+async fn export_range_is_limited() {
+    let server = spawn(false).await;
+    let id = Uuid::new_v4();
+
+    for query in [
+        format!("ids={id}"),
+        format!("ids={id}&from=2024-01-01T00:00:00Z&to=2024-03-01T00:00:00Z"),
+        format!("ids={id}&from=2024-01-02T00:00:00Z&to=2024-01-01T00:00:00Z"),
+    ] {
+        let (status, content_type, _) = server.request(reqwest::Method::GET, &format!("/api/sensors/data/export?{query}"), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+        assert_eq!(content_type, "application/problem+json");
+    }
+}
+
+#[tokio::test]
+async fn handlers_outside_the_store_are_unavailable_without_neo4j() {
+    let server = spawn(false).await;
+
+    let id = Uuid::new_v4();
+    for (method, path, body) in [
+        (reqwest::Method::GET, "/api/sensors/health".to_string(), None),
+        (reqwest::Method::GET, format!("/api/sensors/calibrations?id={id}"), None),
+        (reqwest::Method::POST, format!("/api/sensors/calibrations?id={id}"), Some(json!({"zero_offset": 0.0, "scale": 1.0, "tilt": 0.0, "unit": "meter"}))),
+        (reqwest::Method::GET, "/api/river_systems".to_string(), None),
+        (reqwest::Method::POST, "/api/river_systems?id=main".to_string(), Some(json!({"model": "idw"}))),
+        (reqwest::Method::POST, "/api/river_systems/members?id=main".to_string(), Some(json!({"add": ["1"]}))),
+    ] {
+        let (status, content_type, _) = server.request(method, &path, body).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{path}");
+        assert_eq!(content_type, "application/problem+json");
+    }
+
+    let (status, content_type, _) = server.request(reqwest::Method::POST, &format!("/api/sensors/calibrations?id={id}"), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/problem+json");
+}
+
+#[tokio::test]
+async fn request_without_token_is_unauthorized() {
+    let server = spawn(false).await;
+
+    let response = server.client.get(format!("{}/api/sensors/pending", server.base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
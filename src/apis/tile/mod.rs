@@ -8,11 +8,11 @@ use axum::async_trait;
 use axum::extract::Host;
 use axum::http::Method;
 use axum_extra::extract::CookieJar;
+use chrono::{DateTime, Utc};
 use coordinate_transformer::{pixel2ll, pixel_resolution};
 use gltf::Glb;
-use neo4rs::{query, BoltFloat, BoltPoint2D};
 use openapi::apis::tile::{Tile, TilesLandZxyGetResponse, TilesWaterZxyGetResponse};
-use openapi::models::{TilesLandZxyGetPathParams, TilesWaterZxyGetPathParams};
+use openapi::models::{TilesLandZxyGetPathParams, TilesWaterZxyGetPathParams, TilesWaterZxyGetQueryParams};
 use openapi::types::ByteArray;
 use reqwest::Client;
 use spade::{DelaunayTriangulation, FloatTriangulation, HasPosition, Point2, Triangulation};
@@ -23,6 +23,7 @@ use voxel_tiler_core::image::{DynamicImage, ImageFormat, ImageReader};
 use voxel_tiler_core::mesh::{Mesher, ValidSide};
 
 use crate::apis::tile::glb::{Point3D, VMesh, WaterGlbGen};
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
 use crate::apis::ServerImpl;
 use crate::cache::items::{CacheDataType, CacheKey, CachedData, TileId};
 
@@ -34,18 +35,25 @@ async fn fetch_image(url: &str, http_client: Client) -> anyhow::Result<DynamicIm
     Ok(img)
 }
 
-async fn generate_land_tile(path_params: TilesLandZxyGetPathParams, http_client: Client) -> Result<Vec<u8>, String> {
+/// ズームレベルを検証する
+fn parse_zoom(z: impl TryInto<u32> + Copy + std::fmt::Display) -> Result<ZoomLv, ApiError> {
+    z.try_into()
+        .ok()
+        .and_then(|zoom| ZoomLv::parse(zoom).ok())
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid zoom level: {z}")))
+}
+
+async fn generate_land_tile(path_params: TilesLandZxyGetPathParams, http_client: Client) -> Result<Vec<u8>, ApiError> {
     let TilesLandZxyGetPathParams { x, y, z } = path_params;
-    let zoom_lv = ZoomLv::parse(z).map_err(|_| "Invalid zoom level")?;
+    let zoom_lv = parse_zoom(z)?;
 
+    // 標高タイルと写真は外部のタイルサーバーから取得する
     let dem = fetch_image(
         &format!("https://tiles.gsj.jp/tiles/elev/land/{z}/{y}/{x}.png"),
         http_client.clone(),
     )
         .await
-        .map_err(|e| {
-            e.to_string()
-        })?
+        .map_err(|e| ApiError::BadGateway(format!("Failed to fetch elevation tile: {e}")))?
         .flipv();
 
     let photo = {
@@ -54,24 +62,24 @@ async fn generate_land_tile(path_params: TilesLandZxyGetPathParams, http_client:
             http_client.clone(),
         )
             .await
-            .map_err(|e| {
-                e.to_string()
-            })?
+            .map_err(|e| ApiError::BadGateway(format!("Failed to fetch photo tile: {e}")))?
             .flipv();
 
         let mut buf = Vec::<u8>::new();
-        dimage.write_to(&mut Cursor::new(&mut buf), ImageFormat::Jpeg).unwrap();
+        dimage.write_to(&mut Cursor::new(&mut buf), ImageFormat::Jpeg)
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
 
         buf
     };
 
     let resolution = {
         let (pixel_x, pixel_y) = (x as u32 * 256 + 128, y as u32 * 256 + 128);
-        let (_long, lat) = pixel2ll((pixel_x, pixel_y), ZoomLv::parse(z).unwrap());
+        let (_long, lat) = pixel2ll((pixel_x, pixel_y), zoom_lv);
         AltitudeResolutionCriteria::Lat(lat, zoom_lv)
     };
 
-    let sampled = GIAJTerrainImageSampler::sampling(resolution, dem, None).unwrap();
+    let sampled = GIAJTerrainImageSampler::sampling(resolution, dem, None)
+        .map_err(|e| ApiError::BadGateway(format!("Invalid elevation tile: {e:?}")))?;
 
     let mesh = Mesher::meshing(
         sampled,
@@ -85,11 +93,21 @@ async fn generate_land_tile(path_params: TilesLandZxyGetPathParams, http_client:
         mime_type: Mime::ImageJpeg,
     };
 
-    let glb = Glb::from_voxel_mesh_with_texture_projected_z(mesh, texture).unwrap();
+    let glb = Glb::from_voxel_mesh_with_texture_projected_z(mesh, texture)
+        .map_err(|e| ApiError::Internal(format!("{e:?}")))?;
 
-    Ok(glb.to_vec().unwrap())
+    glb.to_vec().map_err(|e| ApiError::Internal(e.to_string()))
 }
 
+problem_response!(TilesLandZxyGetResponse {
+    BadRequest => Status400_BadRequest,
+    BadGateway => Status502_BadGateway,
+});
+problem_response!(TilesWaterZxyGetResponse {
+    BadRequest => Status400_BadRequest,
+    ServiceUnavailable => Status503_ServiceUnavailable,
+});
+
 #[async_trait]
 impl Tile for ServerImpl {
     async fn tiles_land_zxy_get(
@@ -107,15 +125,15 @@ impl Tile for ServerImpl {
         };
 
 
+        // 生成に失敗したタイルはキャッシュせず、次のリクエストで再び生成する
         let compute_fu = async {
-            let bates = generate_land_tile(path_params, self.http_client.clone()).await;
-            CachedData::new(bates.unwrap_or_default())
+            generate_land_tile(path_params, self.http_client.clone()).await.map(CachedData::new)
         };
 
-        let buf = self.cache.get_or_compute(cache_key, compute_fu)
-            .await
-            .bytes;
-
+        let buf = match self.cache.try_get_or_compute(cache_key, compute_fu).await {
+            Ok(cached) => cached.bytes,
+            Err(e) => return TilesLandZxyGetResponse::from_error((*e).clone()),
+        };
 
         let response = TilesLandZxyGetResponse::Status200 {
             body: ByteArray(buf),
@@ -131,40 +149,38 @@ impl Tile for ServerImpl {
         _host: Host,
         _cookies: CookieJar,
         path_params: TilesWaterZxyGetPathParams,
+        query_params: TilesWaterZxyGetQueryParams,
     ) -> Result<TilesWaterZxyGetResponse, String> {
         let TilesWaterZxyGetPathParams { z, x, y } = path_params;
 
-        let tile_path = calc_tile_path(z as u32, x as u32, y as u32);
-
-        let query = query(
-            &format!(r#"
-MATCH {tile_path}-[:MEMBER]->(n:RiverNode)-[:WATER_LEVEL]->(wl:WaterLevel)
-RETURN n.location AS location, wl.value AS water_level
-            "#)
-        );
-
-        let mut result = self.graph.execute(query)
+        self.water_tile(z, x, y, query_params.time)
             .await
-            .unwrap();
-
-        let mut nodes = Vec::<RiverNode>::new();
+            .or_else(TilesWaterZxyGetResponse::from_error)
+    }
+}
 
-        while let Ok(Some(row)) = result.next().await {
-            let location: BoltPoint2D = row.get("location").unwrap();
-            let water_level: BoltFloat = row.get("water_level").unwrap();
+impl ServerImpl {
+    /// 指定した日時の水位(未来の場合は予測)から水面タイルを生成する
+    async fn water_tile(&self, z: i64, x: i64, y: i64, time: Option<DateTime<Utc>>) -> Result<TilesWaterZxyGetResponse, ApiError> {
+        let zoom_lv = parse_zoom(z)?;
+        let tile = TileId::new(x as u32, y as u32, z as u8);
 
-            println!("{:?}, {:?}", location, water_level);
+        // 未来の日時が指定された場合は、その日時に最も近い予測を使う
+        let forecast = time.filter(|time| *time > Utc::now() + FORECAST_THRESHOLD);
 
-            nodes.push(RiverNode {
-                location: Point2::new(location.x.value, location.y.value),
-                water_level: water_level.value,
-            });
-        }
+        let nodes = self.store.tile_water_levels(tile, forecast)
+            .await?
+            .into_iter()
+            .map(|node| RiverNode {
+                location: Point2::new(node.location.0, node.location.1),
+                water_level: node.water_level,
+            })
+            .collect::<Vec<_>>();
 
         println!("{:?}", nodes);
 
         //let data = generate_warter_surfce_data(nodes, x as u32, y as u32, z as u32);
-        let data = gen_poly(nodes, x as u32, y as u32, z as u32);
+        let data = gen_poly(nodes, x as u32, y as u32, zoom_lv)?;
 
         Ok(TilesWaterZxyGetResponse::Status200 {
             body: ByteArray(data),
@@ -173,16 +189,8 @@ RETURN n.location AS location, wl.value AS water_level
     }
 }
 
-// あるタイル座標までのレベル0のタイルからのパスを計算
-fn calc_tile_path(z: u32, x: u32, y: u32) -> String {
-    (0..=z).map(|current_z| {
-        // 算術演算で求める
-        let x = x >> (z - current_z);
-        let y = y >> (z - current_z);
-        format!("(:Tile{current_z}{{x:{x},y:{y}}})")
-    }).collect::<Vec<_>>()
-        .join("-[:CHILD]->")
-}
+/// 指定された日時が現在からこれ以上先の場合に予測を使う
+const FORECAST_THRESHOLD: chrono::Duration = chrono::Duration::minutes(30);
 
 #[derive(Debug)]
 struct RiverNode {
@@ -198,14 +206,15 @@ impl HasPosition for RiverNode {
     }
 }
 
-fn gen_poly(nodes: Vec<RiverNode>, tile_x: u32, tile_y: u32, zoom: u32) -> Vec<u8> {
-    let mut t = DelaunayTriangulation::<RiverNode>::bulk_load(nodes).unwrap();
+fn gen_poly(nodes: Vec<RiverNode>, tile_x: u32, tile_y: u32, zoom: ZoomLv) -> Result<Vec<u8>, ApiError> {
+    let mut t = DelaunayTriangulation::<RiverNode>::bulk_load(nodes)
+        .map_err(|e| ApiError::Internal(format!("Failed to triangulate river nodes: {e:?}")))?;
     let b = t.barycentric();
 
     let res = {
-        let (_long, lat) = pixel2ll((tile_x * 256, tile_y * 256), ZoomLv::parse(zoom).unwrap());
+        let (_long, lat) = pixel2ll((tile_x * 256, tile_y * 256), zoom);
 
-        pixel_resolution(lat, ZoomLv::parse(zoom).unwrap()) as f32
+        pixel_resolution(lat, zoom) as f32
     };
 
 
@@ -219,7 +228,7 @@ fn gen_poly(nodes: Vec<RiverNode>, tile_x: u32, tile_y: u32, zoom: u32) -> Vec<u
     };
 
     let points = query_points.iter().map(|point| {
-        let ll = pixel2ll((point.0 as u32, point.1 as u32), ZoomLv::parse(zoom).unwrap());
+        let ll = pixel2ll((point.0 as u32, point.1 as u32), zoom);
         let ll_point = Point2::new(ll.0.to_degrees(), ll.1.to_degrees());
 
         let water_level = b.interpolate(|v| v.data().water_level, ll_point).unwrap_or_default() as f32;
@@ -238,6 +247,8 @@ fn gen_poly(nodes: Vec<RiverNode>, tile_x: u32, tile_y: u32, zoom: u32) -> Vec<u
     let vmesh = VMesh::create_water_surface(points);
 
 
-    Glb::from_vmesh(vmesh).unwrap().to_vec().unwrap()
+    let glb = Glb::from_vmesh(vmesh)?;
+
+    glb.to_vec().map_err(|e| ApiError::Internal(e.to_string()))
 }
 
@@ -0,0 +1,64 @@
+//! APIトークンの管理
+
+use axum::async_trait;
+use axum::extract::Host;
+use axum::http::Method;
+use axum_extra::extract::CookieJar;
+use openapi::apis::token::{DeleteApiTokensResponse, GetApiTokensResponse, PostApiTokensResponse, Token};
+use openapi::models::{DeleteApiTokensQueryParams, IssuedToken, PostApiTokensRequest};
+
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
+use crate::apis::ServerImpl;
+
+problem_response!(PostApiTokensResponse {
+    BadRequest => Status400_BadRequest,
+});
+
+#[async_trait]
+impl Token for ServerImpl {
+    async fn delete_api_tokens(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: DeleteApiTokensQueryParams,
+    ) -> Result<DeleteApiTokensResponse, String> {
+        let revoked = self.auth.revoke(query_params.id).await.map_err(|e| e.to_string())?;
+
+        if revoked {
+            Ok(DeleteApiTokensResponse::Status200_OK)
+        } else {
+            Ok(DeleteApiTokensResponse::Status404_NotFound)
+        }
+    }
+
+    async fn get_api_tokens(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+    ) -> Result<GetApiTokensResponse, String> {
+        let tokens = self.auth.list().await.map_err(|e| e.to_string())?;
+
+        Ok(GetApiTokensResponse::Status200_OK(tokens.into_iter().map(Into::into).collect()))
+    }
+
+    async fn post_api_tokens(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        body: Option<PostApiTokensRequest>,
+    ) -> Result<PostApiTokensResponse, String> {
+        let Some(PostApiTokensRequest { role, label }) = body else {
+            return PostApiTokensResponse::from_error(ApiError::missing_body());
+        };
+
+        let (token, stored) = self.auth.issue(role.into(), label).await.map_err(|e| e.to_string())?;
+
+        Ok(PostApiTokensResponse::Status200_OK(IssuedToken {
+            token,
+            info: stored.into(),
+        }))
+    }
+}
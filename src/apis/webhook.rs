@@ -0,0 +1,108 @@
+//! Webhookの購読の管理と配信履歴
+
+use axum::async_trait;
+use axum::extract::Host;
+use axum::http::Method;
+use axum_extra::extract::CookieJar;
+use openapi::apis::webhook::{DeleteApiWebhooksResponse, GetApiWebhooksDeliveriesResponse, GetApiWebhooksResponse, PostApiWebhooksResponse, Webhook};
+use openapi::models::{CreatedWebhookSubscription, DeleteApiWebhooksQueryParams, GetApiWebhooksDeliveriesQueryParams, PostApiWebhooksRequest};
+
+use crate::apis::error::{problem_response, ApiError, ProblemResponse};
+use crate::apis::ServerImpl;
+use crate::webhook::{list_deliveries, list_subscriptions, subscribe, unsubscribe, EventType};
+
+/// 配信履歴の既定の件数
+const DEFAULT_DELIVERY_LIMIT: i64 = 100;
+
+problem_response!(PostApiWebhooksResponse {
+    BadRequest => Status400_BadRequest,
+});
+
+#[async_trait]
+impl Webhook for ServerImpl {
+    async fn delete_api_webhooks(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: DeleteApiWebhooksQueryParams,
+    ) -> Result<DeleteApiWebhooksResponse, String> {
+        let removed = unsubscribe(self.graph().map_err(|e| e.to_string())?, &query_params.id.to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if removed {
+            Ok(DeleteApiWebhooksResponse::Status200_OK)
+        } else {
+            Ok(DeleteApiWebhooksResponse::Status404_NotFound)
+        }
+    }
+
+    async fn get_api_webhooks(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+    ) -> Result<GetApiWebhooksResponse, String> {
+        let subscriptions = list_subscriptions(self.graph().map_err(|e| e.to_string())?)
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(GetApiWebhooksResponse::Status200_OK(subscriptions))
+    }
+
+    async fn post_api_webhooks(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        body: Option<PostApiWebhooksRequest>,
+    ) -> Result<PostApiWebhooksResponse, String> {
+        let Some(PostApiWebhooksRequest { url, events, river_system }) = body else {
+            return PostApiWebhooksResponse::from_error(ApiError::missing_body());
+        };
+
+        // 通知先はHTTP(S)のURLのみ受け付ける
+        let valid_url = reqwest::Url::parse(&url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+        if !valid_url {
+            return PostApiWebhooksResponse::from_error(ApiError::BadRequest(format!("url must be an HTTP(S) URL: {url}")));
+        }
+        if events.is_empty() {
+            return PostApiWebhooksResponse::from_error(ApiError::BadRequest("events must not be empty".to_string()));
+        }
+
+        let events = events.into_iter().map(EventType::from).collect::<Vec<_>>();
+        let (subscription, secret) = subscribe(self.graph().map_err(|e| e.to_string())?, url, &events, river_system)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(PostApiWebhooksResponse::Status200_OK(CreatedWebhookSubscription {
+            secret,
+            info: subscription.try_into().map_err(|e: anyhow::Error| e.to_string())?,
+        }))
+    }
+
+    async fn get_api_webhooks_deliveries(
+        &self,
+        _method: Method,
+        _host: Host,
+        _cookies: CookieJar,
+        query_params: GetApiWebhooksDeliveriesQueryParams,
+    ) -> Result<GetApiWebhooksDeliveriesResponse, String> {
+        let GetApiWebhooksDeliveriesQueryParams { subscription_id, limit } = query_params;
+
+        let deliveries = list_deliveries(self.graph().map_err(|e| e.to_string())?, subscription_id.map(|id| id.to_string()), limit.unwrap_or(DEFAULT_DELIVERY_LIMIT))
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(GetApiWebhooksDeliveriesResponse::Status200_OK(deliveries))
+    }
+}
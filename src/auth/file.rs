@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use axum::async_trait;
+use chrono::Utc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::auth::{StoredToken, TokenStore};
+
+/// ローカルのJSONファイルにトークンを保存する
+///
+/// 変更のたびにファイル全体を書き直す。トークンの数は多くならない想定。
+pub struct FileTokenStore {
+    path: PathBuf,
+    tokens: RwLock<Vec<StoredToken>>,
+}
+
+impl FileTokenStore {
+    /// ファイルから読み込む。ファイルがなければ空の状態から始める
+    pub async fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let tokens = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            tokens: RwLock::new(tokens),
+        })
+    }
+
+    async fn save(&self, tokens: &[StoredToken]) -> anyhow::Result<()> {
+        // 書き込み途中で壊れないよう、一時ファイルに書いてから置き換える
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(tokens)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TokenStore for FileTokenStore {
+    async fn find_active(&self, hash: &str) -> anyhow::Result<Option<StoredToken>> {
+        let tokens = self.tokens.read().await;
+        Ok(tokens.iter()
+            .find(|token| token.hash == hash && token.revoked_at.is_none())
+            .cloned())
+    }
+
+    async fn insert(&self, token: StoredToken) -> anyhow::Result<()> {
+        let mut tokens = self.tokens.write().await;
+        tokens.push(token);
+        self.save(&tokens).await
+    }
+
+    async fn revoke(&self, id: Uuid) -> anyhow::Result<bool> {
+        let mut tokens = self.tokens.write().await;
+        let Some(token) = tokens.iter_mut().find(|token| token.id == id) else {
+            return Ok(false);
+        };
+
+        token.revoked_at.get_or_insert_with(Utc::now);
+        self.save(&tokens).await?;
+
+        Ok(true)
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<StoredToken>> {
+        Ok(self.tokens.read().await.clone())
+    }
+}
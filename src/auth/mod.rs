@@ -0,0 +1,286 @@
+//! APIトークンによる認証とロールによる認可
+//!
+//! 生成されたルーターの外側にミドルウェアとして挟み、パスとメソッドごとに必要なロールを検査する。
+//! センサーからのデータ送信はセンサーごとの署名で認証するため、ここでは検査しない。
+
+mod file;
+mod neo4j;
+
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub use file::FileTokenStore;
+pub use neo4j::Neo4jTokenStore;
+
+/// APIトークンのロール
+///
+/// 上位のロールは下位のロールの権限を含む。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, strum::Display, strum::EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl From<openapi::models::Role> for Role {
+    fn from(value: openapi::models::Role) -> Self {
+        match value {
+            openapi::models::Role::Viewer => Role::Viewer,
+            openapi::models::Role::Operator => Role::Operator,
+            openapi::models::Role::Admin => Role::Admin,
+        }
+    }
+}
+
+impl From<Role> for openapi::models::Role {
+    fn from(value: Role) -> Self {
+        match value {
+            Role::Viewer => openapi::models::Role::Viewer,
+            Role::Operator => openapi::models::Role::Operator,
+            Role::Admin => openapi::models::Role::Admin,
+        }
+    }
+}
+
+/// 保存されているAPIトークンの情報
+///
+/// トークン自体は保存せず、SHA-256のハッシュ値のみを保存する。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub id: Uuid,
+    pub hash: String,
+    pub role: Role,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<StoredToken> for openapi::models::ApiToken {
+    fn from(token: StoredToken) -> Self {
+        let StoredToken { id, role, label, created_at, revoked_at, .. } = token;
+        openapi::models::ApiToken {
+            id,
+            role: role.into(),
+            label,
+            created_at,
+            revoked_at,
+        }
+    }
+}
+
+/// APIトークンの保存先
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// ハッシュ値が一致する有効な(失効していない)トークンを探す
+    async fn find_active(&self, hash: &str) -> anyhow::Result<Option<StoredToken>>;
+
+    /// トークンを保存する
+    async fn insert(&self, token: StoredToken) -> anyhow::Result<()>;
+
+    /// トークンを失効させる。該当するトークンがなければ`false`を返す
+    async fn revoke(&self, id: Uuid) -> anyhow::Result<bool>;
+
+    /// すべてのトークンを返す
+    async fn list(&self) -> anyhow::Result<Vec<StoredToken>>;
+}
+
+/// トークンのハッシュ値を計算する
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+/// ランダムな32バイトを16進数で表したシークレットを生成する
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// 認証・認可に必要な状態
+#[derive(Clone)]
+pub struct Auth {
+    store: Arc<dyn TokenStore>,
+    /// 環境変数で与える管理者用トークン(最初のトークンを発行するために使う)
+    bootstrap_token: Option<String>,
+    /// トークンなしのリクエストにviewerの権限を与えるか
+    anonymous_viewer: bool,
+}
+
+impl Auth {
+    pub fn new(store: Arc<dyn TokenStore>, bootstrap_token: Option<String>, anonymous_viewer: bool) -> Self {
+        Self {
+            store,
+            bootstrap_token,
+            anonymous_viewer,
+        }
+    }
+
+    /// 新しいトークンを発行する
+    pub async fn issue(&self, role: Role, label: String) -> anyhow::Result<(String, StoredToken)> {
+        let token = format!("nhl_{}", generate_secret());
+
+        let stored = StoredToken {
+            id: Uuid::new_v4(),
+            hash: hash_token(&token),
+            role,
+            label,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        self.store.insert(stored.clone()).await?;
+
+        Ok((token, stored))
+    }
+
+    pub async fn revoke(&self, id: Uuid) -> anyhow::Result<bool> {
+        self.store.revoke(id).await
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<StoredToken>> {
+        self.store.list().await
+    }
+
+    /// トークンからロールを求める
+    async fn role_of(&self, token: &str) -> anyhow::Result<Option<Role>> {
+        let hash = hash_token(token);
+
+        // 長さや一致した位置で比較時間が変わらないよう、ハッシュ値どうしを比較する
+        if let Some(bootstrap_token) = &self.bootstrap_token {
+            if hash_token(bootstrap_token) == hash {
+                return Ok(Some(Role::Admin));
+            }
+        }
+
+        Ok(self.store.find_active(&hash).await?.map(|token| token.role))
+    }
+}
+
+/// パスとメソッドに対して必要なロールを返す。`None`の場合は認証を要求しない
+///
+/// ロールが宣言されていない未知のパスにはadminを要求する。
+fn required_role(method: &Method, path: &str) -> Option<Role> {
+    declared_role(method, path).unwrap_or(Some(Role::Admin))
+}
+
+/// パスとメソッドに対して宣言したロールを返す。宣言していない場合は`None`を返す
+///
+/// 新しいパスを追加した場合はここにも追加すること。OpenAPIのすべての操作が宣言されていることをテストで確認する。
+fn declared_role(method: &Method, path: &str) -> Option<Option<Role>> {
+    let role = match (method.as_str(), path) {
+        (_, "/") => None,
+        // センサーからの送信はセンサーごとの署名で認証する
+        ("POST", "/api/sensors/data" | "/api/sensors/data/batch" | "/api/sensors/data/bulk") => None,
+        ("GET", path) if path.starts_with("/tiles/") => Some(Role::Viewer),
+        ("GET", "/api/sensors/data/export") => Some(Role::Viewer),
+        ("POST", "/api/sensors") => Some(Role::Admin),
+        ("DELETE", "/api/sensors") => Some(Role::Admin),
+        ("GET", "/api/sensors/health") => Some(Role::Viewer),
+        ("GET", "/api/sensors/pending") => Some(Role::Operator),
+        ("POST" | "DELETE", path) if path.starts_with("/api/sensors/pending/") => Some(Role::Operator),
+        ("GET", path) if path.starts_with("/api/sensors/") && path.ends_with("/locations") => Some(Role::Viewer),
+        ("PATCH", path) if path.starts_with("/api/sensors/") => Some(Role::Operator),
+        ("GET", "/api/sensors/calibrations") => Some(Role::Viewer),
+        ("POST", "/api/sensors/calibrations") => Some(Role::Operator),
+        ("GET", "/api/river_systems") => Some(Role::Viewer),
+        ("POST", "/api/river_systems" | "/api/river_systems/members") => Some(Role::Operator),
+        ("GET", "/api/alerts" | "/api/alerts/thresholds") => Some(Role::Viewer),
+        ("POST", "/api/alerts/acknowledge" | "/api/alerts/thresholds") => Some(Role::Operator),
+        (_, "/api/tokens") => Some(Role::Admin),
+        (_, "/api/webhooks" | "/api/webhooks/deliveries") => Some(Role::Admin),
+        _ => return None,
+    };
+
+    Some(role)
+}
+
+/// ロールを検査するミドルウェア
+pub async fn authorize(State(auth): State<Auth>, request: Request, next: Next) -> Response {
+    let Some(required) = required_role(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let token = request.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let role = match token {
+        Some(token) => match auth.role_of(token).await {
+            Ok(Some(role)) => role,
+            Ok(None) => return StatusCode::UNAUTHORIZED.into_response(),
+            Err(e) => {
+                println!("authorize: {:?}", e);
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+        },
+        None if auth.anonymous_viewer => Role::Viewer,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+
+    if role < required {
+        return if token.is_some() {
+            StatusCode::FORBIDDEN.into_response()
+        } else {
+            StatusCode::UNAUTHORIZED.into_response()
+        };
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use yaml_rust::YamlLoader;
+
+    use super::{declared_role, Role};
+
+    /// OpenAPIのすべての操作にロールが宣言されていて、セキュリティの宣言と一致する
+    ///
+    /// `security: []`は認証なし、`{}`を含むものは匿名のviewerを許すもの、それ以外はoperator以上とする。
+    #[test]
+    fn every_operation_declares_its_role() {
+        let docs = YamlLoader::load_from_str(include_str!("../../openapi.yaml")).unwrap();
+
+        for (path, operations) in docs[0]["paths"].as_hash().unwrap() {
+            let path = path.as_str().unwrap();
+            // パスパラメーターは適当な値に置き換える
+            let concrete = path.split('/')
+                .map(|segment| if segment.starts_with('{') { "0" } else { segment })
+                .collect::<Vec<_>>()
+                .join("/");
+
+            for (method, operation) in operations.as_hash().unwrap() {
+                let method = method.as_str().unwrap();
+                if !["get", "post", "put", "patch", "delete"].contains(&method) {
+                    continue;
+                }
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+
+                let role = declared_role(&method, &concrete)
+                    .unwrap_or_else(|| panic!("{method} {path} has no declared role"));
+                let security = operation["security"].as_vec().unwrap();
+                let anonymous = security.iter().any(|requirement| requirement.as_hash().is_some_and(|schemes| schemes.is_empty()));
+                match role {
+                    None => assert!(security.is_empty(), "{method} {path} is public but requires authentication"),
+                    Some(Role::Viewer) => assert!(anonymous, "{method} {path} is for viewers but does not allow anonymous requests"),
+                    Some(_) => assert!(!security.is_empty() && !anonymous, "{method} {path} requires {role:?} but allows anonymous requests"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use neo4rs::{query, Graph, Row};
+use uuid::Uuid;
+
+use crate::auth::{Role, StoredToken, TokenStore};
+
+/// `ApiToken`ノードとしてNeo4jにトークンを保存する
+pub struct Neo4jTokenStore {
+    graph: Graph,
+}
+
+impl Neo4jTokenStore {
+    pub fn new(graph: Graph) -> Self {
+        Self { graph }
+    }
+}
+
+fn to_stored_token(row: Row) -> anyhow::Result<StoredToken> {
+    let id = row.get::<String>("id")?.parse::<Uuid>()?;
+    let role = row.get::<String>("role")?.parse::<Role>()?;
+    let created_at = DateTime::parse_from_rfc3339(&row.get::<String>("created_at")?)?.with_timezone(&Utc);
+    let revoked_at = row.get::<Option<String>>("revoked_at")?
+        .map(|time| DateTime::parse_from_rfc3339(&time).map(|time| time.with_timezone(&Utc)))
+        .transpose()?;
+
+    Ok(StoredToken {
+        id,
+        hash: row.get("hash")?,
+        role,
+        label: row.get("label")?,
+        created_at,
+        revoked_at,
+    })
+}
+
+#[async_trait]
+impl TokenStore for Neo4jTokenStore {
+    async fn find_active(&self, hash: &str) -> anyhow::Result<Option<StoredToken>> {
+        let mut result = self.graph.execute(
+            query(r#"
+MATCH (token:ApiToken {hash: $hash})
+WHERE token.revoked_at IS NULL
+RETURN token.id AS id, token.hash AS hash, token.role AS role, token.label AS label,
+       token.created_at AS created_at, token.revoked_at AS revoked_at
+            "#)
+                .param("hash", hash),
+        ).await?;
+
+        result.next().await?.map(to_stored_token).transpose()
+    }
+
+    async fn insert(&self, token: StoredToken) -> anyhow::Result<()> {
+        self.graph.run(
+            query(r#"
+CREATE (:ApiToken {
+    id: $id,
+    hash: $hash,
+    role: $role,
+    label: $label,
+    created_at: $created_at
+})
+            "#)
+                .param("id", token.id.to_string())
+                .param("hash", token.hash)
+                .param("role", token.role.to_string())
+                .param("label", token.label)
+                .param("created_at", token.created_at.to_rfc3339()),
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn revoke(&self, id: Uuid) -> anyhow::Result<bool> {
+        let mut result = self.graph.execute(
+            query(r#"
+MATCH (token:ApiToken {id: $id})
+SET token.revoked_at = coalesce(token.revoked_at, $now)
+RETURN count(token) AS count
+            "#)
+                .param("id", id.to_string())
+                .param("now", Utc::now().to_rfc3339()),
+        ).await?;
+
+        let count = match result.next().await? {
+            Some(row) => row.get::<i64>("count")?,
+            None => 0,
+        };
+
+        Ok(count > 0)
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<StoredToken>> {
+        let mut result = self.graph.execute(
+            query(r#"
+MATCH (token:ApiToken)
+RETURN token.id AS id, token.hash AS hash, token.role AS role, token.label AS label,
+       token.created_at AS created_at, token.revoked_at AS revoked_at
+ORDER BY token.created_at
+            "#),
+        ).await?;
+
+        let mut tokens = Vec::new();
+        while let Some(row) = result.next().await? {
+            tokens.push(to_stored_token(row)?);
+        }
+
+        Ok(tokens)
+    }
+}
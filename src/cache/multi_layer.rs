@@ -5,6 +5,7 @@ use moka::future::Cache;
 use rustc_hash::FxBuildHasher;
 use std::future::Future;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 // 2層キャッシュの構造体
 #[derive(Clone)]
@@ -28,14 +29,16 @@ impl MultiLayerCache {
     }
     // let compute_fn: fn() -> impl Future<Output=Vec<u8>>+Sized
 
-    // キャッシュにデータを登録（必要ならば計算）
-    pub async fn get_or_compute(&self, key: CacheKey, compute_fu: impl Future<Output=CachedData>) -> CachedData
+    // キャッシュにデータを登録（必要ならば計算）。計算に失敗した場合は登録しない
+    pub async fn try_get_or_compute<E>(&self, key: CacheKey, compute_fu: impl Future<Output=Result<CachedData, E>>) -> Result<CachedData, Arc<E>>
+    where
+        E: Send + Sync + 'static,
     {
         self.memory
             .entry_by_ref(&key)
-            .or_insert_with(compute_fu)
+            .or_try_insert_with(compute_fu)
             .await
-            .into_value()
+            .map(|entry| entry.into_value())
     }
 
     // 特定の種類のエントリをキャッシュから削除
@@ -0,0 +1,286 @@
+//! センサーの校正
+//!
+//! 校正値は適用開始日時ごとに版を分けて`(:Sensor)-[:CALIBRATION]->(:Calibration)`として保存する。
+//! センサーデータには校正前の値を`raw_distance`、校正後の値(メートル)を`distance`として保存する。
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Txn};
+
+/// センサーが送る距離の単位
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum DistanceUnit {
+    #[default]
+    Meter,
+    Centimeter,
+    Millimeter,
+}
+
+impl DistanceUnit {
+    /// メートルへの換算係数
+    pub fn to_meters(&self) -> f64 {
+        match self {
+            DistanceUnit::Meter => 1.0,
+            DistanceUnit::Centimeter => 0.01,
+            DistanceUnit::Millimeter => 0.001,
+        }
+    }
+}
+
+impl From<openapi::models::DistanceUnit> for DistanceUnit {
+    fn from(value: openapi::models::DistanceUnit) -> Self {
+        match value {
+            openapi::models::DistanceUnit::Meter => DistanceUnit::Meter,
+            openapi::models::DistanceUnit::Centimeter => DistanceUnit::Centimeter,
+            openapi::models::DistanceUnit::Millimeter => DistanceUnit::Millimeter,
+        }
+    }
+}
+
+impl From<DistanceUnit> for openapi::models::DistanceUnit {
+    fn from(value: DistanceUnit) -> Self {
+        match value {
+            DistanceUnit::Meter => openapi::models::DistanceUnit::Meter,
+            DistanceUnit::Centimeter => openapi::models::DistanceUnit::Centimeter,
+            DistanceUnit::Millimeter => openapi::models::DistanceUnit::Millimeter,
+        }
+    }
+}
+
+/// ある時点から適用される校正値
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// 零点補正(メートル)
+    pub zero_offset: f64,
+    /// 倍率
+    pub scale: f64,
+    /// 鉛直方向からの取り付けの傾き(度)
+    pub tilt: f64,
+    pub unit: DistanceUnit,
+    pub effective_from: DateTime<Utc>,
+}
+
+impl Default for Calibration {
+    /// 校正値が登録されていない場合に使う、補正を行わない校正値
+    fn default() -> Self {
+        Calibration {
+            zero_offset: 0.0,
+            scale: 1.0,
+            tilt: 0.0,
+            unit: DistanceUnit::Meter,
+            effective_from: DateTime::<Utc>::MIN_UTC,
+        }
+    }
+}
+
+impl Calibration {
+    /// センサーが送った距離を、水面までの鉛直距離(メートル)に変換する
+    ///
+    /// 単位を換算して倍率と零点補正を適用した斜距離に、傾きの余弦を掛ける。
+    pub fn apply(&self, raw_distance: f64) -> f64 {
+        (raw_distance * self.unit.to_meters() * self.scale + self.zero_offset) * self.tilt.to_radians().cos()
+    }
+
+    /// 校正値を検査する
+    ///
+    /// 値はすべて有限で、倍率は正、傾きは0度以上90度未満でなければならない。
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.zero_offset.is_finite() && self.scale.is_finite() && self.tilt.is_finite()) {
+            return Err("zero_offset, scale and tilt must be finite".to_string());
+        }
+        if self.scale <= 0.0 {
+            return Err(format!("scale must be positive, got {}", self.scale));
+        }
+        if !(0.0..90.0).contains(&self.tilt) {
+            return Err(format!("tilt must be at least 0 and less than 90 degrees, got {}", self.tilt));
+        }
+
+        Ok(())
+    }
+
+    /// 適用開始日時の昇順に並んだ校正値から、`time`の時点で有効なものを返す
+    pub fn effective_at(calibrations: &[Calibration], time: DateTime<Utc>) -> Calibration {
+        calibrations.iter()
+            .rev()
+            .find(|calibration| calibration.effective_from <= time)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl From<Calibration> for openapi::models::Calibration {
+    fn from(calibration: Calibration) -> Self {
+        let Calibration { zero_offset, scale, tilt, unit, effective_from } = calibration;
+        openapi::models::Calibration {
+            zero_offset,
+            scale,
+            tilt,
+            unit: unit.into(),
+            effective_from: Some(effective_from),
+        }
+    }
+}
+
+/// センサーの校正値を適用開始日時の昇順で取得する
+pub async fn load_calibrations(tnx: &mut Txn, id: &str) -> anyhow::Result<Vec<Calibration>> {
+    let mut result = tnx.execute(
+        query(r#"
+MATCH (:Sensor {id: $id})-[:CALIBRATION]->(calibration:Calibration)
+RETURN calibration.zero_offset AS zero_offset,
+       calibration.scale AS scale,
+       calibration.tilt AS tilt,
+       calibration.unit AS unit,
+       calibration.effective_from AS effective_from
+ORDER BY datetime(effective_from)
+        "#)
+            .param("id", id),
+    ).await?;
+
+    let mut calibrations = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        calibrations.push(Calibration {
+            zero_offset: row.get("zero_offset")?,
+            scale: row.get("scale")?,
+            tilt: row.get("tilt")?,
+            unit: row.get::<String>("unit")?.parse()?,
+            effective_from: DateTime::parse_from_rfc3339(&row.get::<String>("effective_from")?)?.with_timezone(&Utc),
+        });
+    }
+
+    Ok(calibrations)
+}
+
+/// 校正値の版を保存し、その版が有効な期間のセンサーデータの校正後の距離を再計算する
+///
+/// 再計算したセンサーデータの数を返す。
+pub async fn save_calibration(tnx: &mut Txn, id: &str, calibration: &Calibration, recompute: bool) -> anyhow::Result<i64> {
+    tnx.run(
+        query(r#"
+MATCH (sensor:Sensor {id: $id})
+MERGE (sensor)-[:CALIBRATION]->(calibration:Calibration {effective_from: $effective_from})
+SET calibration.zero_offset = $zero_offset,
+    calibration.scale = $scale,
+    calibration.tilt = $tilt,
+    calibration.unit = $unit
+        "#)
+            .param("id", id)
+            .param("effective_from", calibration.effective_from.to_rfc3339())
+            .param("zero_offset", calibration.zero_offset)
+            .param("scale", calibration.scale)
+            .param("tilt", calibration.tilt)
+            .param("unit", calibration.unit.to_string()),
+    ).await?;
+
+    if !recompute {
+        return Ok(0);
+    }
+
+    // 次の版の適用開始日時までのデータが対象
+    let next = load_calibrations(tnx, id).await?
+        .into_iter()
+        .map(|calibration| calibration.effective_from)
+        .find(|effective_from| *effective_from > calibration.effective_from);
+
+    // 1. 適用開始日時以降のデータだけを新しい順にたどり、校正前の距離を取得
+    let mut result = tnx.execute(
+        query(r#"
+MATCH (:Sensor {id: $id})-[:CURRENT_DATA]->(current:SensorData)
+MATCH path = (current)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+WHERE all(node IN nodes(path) WHERE datetime(node.time) >= datetime($from))
+  AND ($to IS NULL OR datetime(data.time) < datetime($to))
+RETURN elementId(data) AS data_id,
+       coalesce(data.raw_distance, data.distance) AS raw_distance
+        "#)
+            .param("id", id)
+            .param("from", calibration.effective_from.to_rfc3339())
+            .param("to", next.map(|time| time.to_rfc3339())),
+    ).await?;
+
+    // 2. 計測値の登録と同じ`apply`で校正後の距離を求める
+    let mut updates = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        let raw_distance = row.get::<f64>("raw_distance")?;
+        updates.push(HashMap::from([
+            ("id".to_string(), BoltType::from(row.get::<String>("data_id")?)),
+            ("raw_distance".to_string(), BoltType::from(raw_distance)),
+            ("distance".to_string(), BoltType::from(calibration.apply(raw_distance))),
+        ]));
+    }
+    let count = updates.len() as i64;
+
+    // 3. 書き戻す
+    tnx.run(
+        query(r#"
+UNWIND $updates AS update
+MATCH (data:SensorData)
+WHERE elementId(data) = update.id
+SET data.raw_distance = update.raw_distance,
+    data.distance = update.distance
+        "#)
+            .param("updates", updates),
+    ).await?;
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+
+    use super::{Calibration, DistanceUnit};
+
+    fn calibration(effective_from: DateTime<Utc>) -> Calibration {
+        Calibration { effective_from, ..Calibration::default() }
+    }
+
+    #[test]
+    fn default_calibration_does_not_change_the_distance() {
+        assert_eq!(Calibration::default().apply(1.25), 1.25);
+    }
+
+    #[test]
+    fn apply_converts_unit_then_scale_offset_and_tilt() {
+        let calibration = Calibration {
+            zero_offset: 0.1,
+            scale: 2.0,
+            tilt: 60.0,
+            unit: DistanceUnit::Centimeter,
+            ..Calibration::default()
+        };
+
+        // (150cm * 0.01 * 2 + 0.1) * cos(60°)
+        assert!((calibration.apply(150.0) - 1.55).abs() < 1e-9);
+    }
+
+    #[test]
+    fn effective_at_picks_the_latest_version_started_by_then() {
+        let start = Utc::now();
+        let calibrations = [
+            Calibration { scale: 2.0, ..calibration(start) },
+            Calibration { scale: 3.0, ..calibration(start + Duration::days(1)) },
+        ];
+
+        // 最初の版より前は補正しない
+        assert_eq!(Calibration::effective_at(&calibrations, start - Duration::seconds(1)), Calibration::default());
+        // 適用開始日時ちょうどからその版を使う
+        assert_eq!(Calibration::effective_at(&calibrations, start).scale, 2.0);
+        assert_eq!(Calibration::effective_at(&calibrations, start + Duration::hours(23)).scale, 2.0);
+        assert_eq!(Calibration::effective_at(&calibrations, start + Duration::days(1)).scale, 3.0);
+        assert_eq!(Calibration::effective_at(&[], start), Calibration::default());
+    }
+
+    #[test]
+    fn validate_rejects_unusable_calibrations() {
+        assert!(Calibration::default().validate().is_ok());
+        assert!(Calibration { tilt: 89.9, ..Calibration::default() }.validate().is_ok());
+
+        assert!(Calibration { scale: 0.0, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { scale: -1.0, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { tilt: 90.0, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { tilt: -1.0, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { zero_offset: f64::NAN, ..Calibration::default() }.validate().is_err());
+        assert!(Calibration { scale: f64::INFINITY, ..Calibration::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! コマンドライン引数の定義
+
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use uuid::Uuid;
+
+use crate::db;
+use crate::env::EnvVars;
+use crate::export::{export, ExportFormat};
+use crate::migration::{find_duplicates, migrate};
+use crate::retention::apply_retention_all;
+use crate::store::Neo4jStore;
+use crate::webhook::sink::SinkArgs;
+
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// HTTPサーバーを起動する(サブコマンド省略時の動作)
+    Serve,
+    /// センサーデータをCSV/Parquetで書き出す
+    Export(ExportArgs),
+    /// 保存期間を過ぎたセンサーデータを集計して削除する
+    Retention(RetentionArgs),
+    /// Neo4jの制約・インデックスを作成し、スキーマを最新のバージョンに移行する(サーバーの起動時にも行う)
+    Migrate(MigrateArgs),
+    /// 既存のセンサーの影響範囲(`AFFECTS`)を、現在の`RIVER_LINK`から作り直す
+    Relink(RelinkArgs),
+    /// Webhookを受信して表示する確認用のHTTPサーバーを起動する
+    WebhookSink(SinkArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// 書き出すセンサーのID(カンマ区切り)
+    #[arg(long, required = true, value_delimiter = ',')]
+    pub ids: Vec<Uuid>,
+    /// この時刻以降のデータを含む(RFC3339)
+    #[arg(long)]
+    pub from: Option<DateTime<Utc>>,
+    /// この時刻より前のデータを含む(RFC3339)
+    #[arg(long)]
+    pub to: Option<DateTime<Utc>>,
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ExportFormat,
+    /// 出力先のファイル(省略時は標準出力)
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// `export`サブコマンドを実行する
+pub async fn run_export(args: ExportArgs) -> anyhow::Result<()> {
+    let env = EnvVars::read_env()?;
+    let graph = db::connect(&env).await;
+
+    let ExportArgs { ids, from, to, format, output } = args;
+
+    let out: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(stdout())),
+    };
+
+    let mut out = export(&graph, &ids, from, to, format, out).await?;
+    out.flush()?;
+
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct RetentionArgs {
+    /// 何も変更せず、削除するセンサーデータの数を表示する
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// `retention`サブコマンドを実行する
+pub async fn run_retention(args: RetentionArgs) -> anyhow::Result<()> {
+    let env = EnvVars::read_env()?;
+    let graph = db::connect(&env).await;
+
+    let retentions = apply_retention_all(&graph, &env, args.dry_run).await?;
+
+    let (raw, hourly) = if args.dry_run { ("would remove", "would roll up") } else { ("removed", "rolled up") };
+    for retention in &retentions {
+        if retention.raw_removed == 0 && retention.hourly_rolled_up == 0 {
+            continue;
+        }
+        println!(
+            "{}: {raw} {} readings ({} - {}), {hourly} {} hourly summaries",
+            retention.id,
+            retention.raw_removed,
+            retention.oldest.as_deref().unwrap_or("-"),
+            retention.newest.as_deref().unwrap_or("-"),
+            retention.hourly_rolled_up,
+        );
+    }
+
+    let total_raw = retentions.iter().map(|retention| retention.raw_removed).sum::<i64>();
+    let total_hourly = retentions.iter().map(|retention| retention.hourly_rolled_up).sum::<i64>();
+    println!("total: {raw} {total_raw} readings, {hourly} {total_hourly} hourly summaries");
+
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct MigrateArgs {
+    /// 何も変更せず、適用する移行と、一意制約の作成を妨げる重複した値を表示する
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// `migrate`サブコマンドを実行する
+pub async fn run_migrate(args: MigrateArgs) -> anyhow::Result<()> {
+    let env = EnvVars::read_env()?;
+    let graph = db::connect(&env).await;
+
+    let migrated = migrate(&graph, args.dry_run).await?;
+
+    let action = if args.dry_run { "would apply" } else { "applied" };
+    for applied in &migrated.applied {
+        println!("{action} {}: {}", applied.version, applied.description);
+    }
+    println!("schema version: {} -> {}", migrated.from, migrated.to());
+
+    if args.dry_run {
+        for duplicate in find_duplicates(&graph).await? {
+            println!("duplicate {}.{} {:?}: {} nodes", duplicate.label, duplicate.property, duplicate.value, duplicate.nodes);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct RelinkArgs {
+    /// 作り直すセンサーのID(カンマ区切り。省略時は運用中のすべてのセンサー)
+    #[arg(long, value_delimiter = ',')]
+    pub ids: Vec<Uuid>,
+}
+
+/// `relink`サブコマンドを実行する
+pub async fn run_relink(args: RelinkArgs) -> anyhow::Result<()> {
+    let env = EnvVars::read_env()?;
+    let graph = db::connect(&env).await;
+    let capabilities = db::detect_capabilities(&graph).await;
+    let store = Neo4jStore::new(graph, Arc::new(env), capabilities);
+
+    let ids = args.ids.iter().map(Uuid::to_string).collect::<Vec<_>>();
+    let relinked = store.relink_sensors(&ids).await?;
+
+    for relinked in &relinked {
+        match &relinked.affects {
+            Some(affects) => println!(
+                "{}: {} nodes ({} - {} m){}",
+                relinked.id,
+                affects.nodes,
+                affects.min_distance.map_or("-".to_string(), |distance| distance.to_string()),
+                affects.max_distance.map_or("-".to_string(), |distance| distance.to_string()),
+                if affects.truncated { ", truncated" } else { "" },
+            ),
+            None => println!("{}: river node {} does not exist, skipped", relinked.id, relinked.parent_node),
+        }
+    }
+    let skipped = relinked.iter().filter(|relinked| relinked.affects.is_none()).count();
+    println!("total: relinked {} sensors, skipped {skipped}", relinked.len() - skipped);
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Neo4jへの接続
+
+use neo4rs::{query, ConfigBuilder, Graph};
+
+use crate::env::EnvVars;
+
+/// 環境変数の設定でNeo4jに接続する
+pub async fn connect(env: &EnvVars) -> Graph {
+    let mut auth = env.neo4j_auth.split('/');
+    let neo4j_user = auth.next().unwrap();
+    let neo4j_password = auth.next().unwrap();
+
+    let config = ConfigBuilder::default()
+        .uri(env.neo4j_uri.clone())
+        .user(neo4j_user)
+        .password(neo4j_password)
+        .db(env.neo4j_db.clone())
+        .build()
+        .unwrap();
+    Graph::connect(config).await.unwrap()
+}
+
+/// 接続したNeo4jで使える機能
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    /// APOCの部分グラフの取得(`apoc.path.subgraphAll`)を使えるか
+    pub apoc: bool,
+}
+
+/// 接続したNeo4jで使える機能を調べる
+///
+/// マネージドのNeo4jなどでプラグインを入れられない場合に備え、使えない機能は代わりの実装を使う。
+/// 調べられなかった場合は、使えないものとする。
+pub async fn detect_capabilities(graph: &Graph) -> Capabilities {
+    let apoc = async {
+        let mut result = graph.execute(query(
+            r#"
+SHOW PROCEDURES YIELD name
+WHERE name = 'apoc.path.subgraphAll'
+RETURN count(name) AS found
+            "#,
+        )).await?;
+
+        let mut found = 0;
+        while let Some(row) = result.next().await? {
+            found = row.get::<i64>("found")?;
+        }
+        anyhow::Ok(found > 0)
+    }.await;
+
+    let capabilities = Capabilities {
+        apoc: apoc.unwrap_or_else(|e| {
+            println!("detect_capabilities: {:?}", e);
+            false
+        }),
+    };
+    println!("Neo4j capabilities: {:?}", capabilities);
+
+    capabilities
+}
@@ -1,5 +1,8 @@
 use serde::Deserialize;
 
+use crate::forecast::TrendKind;
+use crate::water_level::WaterLevelModelKind;
+
 #[derive(Debug, Deserialize)]
 pub struct EnvVars {
     pub neo4j_uri: String,
@@ -11,6 +14,252 @@ pub struct EnvVars {
     pub disk_cache_base_path: String,
     pub disk_cache_max_size: u64,
     pub memory_cache_max_size: u64,
+    /// 管理者用のAPIトークン(最初のトークンを発行するために使う)
+    pub admin_key: Option<String>,
+    /// APIトークンを保存するJSONファイル(未設定の場合はNeo4jに保存する)
+    pub token_store_path: Option<String>,
+    /// APIトークンなしのリクエストにviewerの権限を与えるか(既定では与えない)
+    #[serde(default)]
+    pub anonymous_viewer: bool,
+    /// センサーの署名時刻として許容するサーバー時刻とのずれ(秒)。計測時刻もこれを超えて先のものは受け付けない
+    #[serde(default = "default_sensor_auth_max_skew")]
+    pub sensor_auth_max_skew: i64,
+    /// 登録されていないセンサーからの送信を、承認待ちのセンサーとして記録するか(無効の場合は404を返す)
+    #[serde(default)]
+    pub sensor_auto_provision: bool,
+    /// 承認待ちのセンサーの上限。上限に達すると、新しいセンサーからの送信は承認待ちにせず404を返す
+    #[serde(default = "default_sensor_pending_limit")]
+    pub sensor_pending_limit: i64,
+    /// QC: 距離の上限(メートル)
+    #[serde(default = "default_qc_max_distance")]
+    pub qc_max_distance: f64,
+    /// QC: 水位の変化率の上限(メートル毎分)
+    #[serde(default = "default_qc_max_rate")]
+    pub qc_max_rate: f64,
+    /// QC: 中央値を求める計測値の数
+    #[serde(default = "default_qc_window")]
+    pub qc_window: usize,
+    /// QC: 中央値からの乖離の上限(メートル)
+    #[serde(default = "default_qc_median_threshold")]
+    pub qc_median_threshold: f64,
+    /// 水位の加重平均で、センサーの寄与が0になるまでの経過時間(送信間隔の倍数)
+    #[serde(default = "default_stale_reading_intervals")]
+    pub stale_reading_intervals: f64,
+    /// 計測値が古くなったセンサーの影響範囲を推定し直す間隔(秒)。0の場合は推定し直さない
+    #[serde(default = "default_water_level_recompute_interval")]
+    pub water_level_recompute_interval: u64,
+    /// 水系に設定がない場合の水位の推定方法
+    #[serde(default)]
+    pub water_level_model: WaterLevelModelKind,
+    /// 逆距離加重のべき指数
+    #[serde(default = "default_idw_power")]
+    pub idw_power: f64,
+    /// 河床の落差が分からない場合に使う河床勾配
+    #[serde(default = "default_bed_slope")]
+    pub bed_slope: f64,
+    /// 水位の予測を作り直す間隔(秒)。0の場合は予測しない
+    #[serde(default = "default_forecast_interval")]
+    pub forecast_interval: u64,
+    /// 傾向の当てはめに使う計測値の期間(時間)
+    #[serde(default = "default_forecast_window_hours")]
+    pub forecast_window_hours: f64,
+    /// 予測する先行時間(時間、最大6)
+    #[serde(default = "default_forecast_horizon_hours")]
+    pub forecast_horizon_hours: u32,
+    /// 傾向の当てはめ方。`linear`(最小二乗法)または`holt`(Holtの線形トレンド法)
+    #[serde(default)]
+    pub forecast_trend: TrendKind,
+    /// 送信間隔の決定で水位の変化率を求める期間(分)
+    #[serde(default = "default_interval_window_minutes")]
+    pub interval_window_minutes: i64,
+    /// 水位の上昇中とみなして送信間隔を短くする変化率(メートル毎時)
+    #[serde(default = "default_interval_rising_rate")]
+    pub interval_rising_rate: f64,
+    /// 水位が安定しているとみなして送信間隔を長くする変化率の大きさ(メートル毎時)
+    #[serde(default = "default_interval_stable_rate")]
+    pub interval_stable_rate: f64,
+    /// 電池の電圧が低いとみなして送信間隔を長くする値(センサーが送る`battery_voltage`と同じ単位)
+    #[serde(default = "default_interval_low_battery")]
+    pub interval_low_battery: i32,
+    /// 警戒段階の閾値が近いとみなして送信間隔を短くする水位の差(メートル)
+    #[serde(default = "default_interval_near_threshold")]
+    pub interval_near_threshold: f64,
+    /// 警戒段階の閾値にヒステリシスが設定されていない場合に使う値(メートル)
+    #[serde(default = "default_alert_hysteresis")]
+    pub alert_hysteresis: f64,
+    /// Webhookを配信する間隔(秒)。0の場合は配信しない
+    #[serde(default = "default_webhook_dispatch_interval")]
+    pub webhook_dispatch_interval: u64,
+    /// Webhookの配信を諦めるまでの送信回数
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: i64,
+    /// Webhookの最初の再送までの間隔(秒)。以降は倍々に延ばす
+    #[serde(default = "default_webhook_retry_base")]
+    pub webhook_retry_base: i64,
+    /// センサーの稼働状態を判定する間隔(秒)。0の場合は判定しない
+    #[serde(default = "default_health_interval")]
+    pub health_interval: u64,
+    /// 稼働状態の判定に使うセンサーデータの期間(時間)
+    #[serde(default = "default_health_window_hours")]
+    pub health_window_hours: f64,
+    /// 最新の計測から送信間隔のこの倍数を過ぎたセンサーをofflineとする
+    #[serde(default = "default_health_offline_windows")]
+    pub health_offline_windows: f64,
+    /// 判定期間内に送信がなかった回数がこの値以上のセンサーをwarningとする
+    #[serde(default = "default_health_missed_windows")]
+    pub health_missed_windows: i64,
+    /// 電池の電圧の低下(1日あたり、`battery_voltage`と同じ単位)がこの値以上のセンサーをwarningとする
+    #[serde(default = "default_health_battery_decline")]
+    pub health_battery_decline: f64,
+    /// 通信状態の低下を表す`network_status`の値(カンマ区切り)
+    #[serde(default = "default_health_degraded_network")]
+    pub health_degraded_network: Vec<String>,
+    /// センサーデータを集計して削除する間隔(秒)。0の場合は定期的には行わない(`retention`サブコマンドで実行する)
+    #[serde(default)]
+    pub retention_interval: u64,
+    /// センサーデータをそのまま保存する日数。過ぎたものは1時間ごとの集計にまとめて削除する
+    #[serde(default = "default_retention_raw_days")]
+    pub retention_raw_days: u32,
+    /// 1時間ごとの集計を保存する日数。過ぎたものは1日ごとの集計にまとめる
+    #[serde(default = "default_retention_hourly_days")]
+    pub retention_hourly_days: u32,
+    /// APIでセンサーデータを書き出せる期間の長さ(日)。これより長い期間は`export`サブコマンドで書き出す
+    #[serde(default = "default_export_max_days")]
+    pub export_max_days: i64,
+    /// センサーの影響範囲: `RIVER_LINK`をたどる最大の段数
+    #[serde(default = "default_affects_max_level")]
+    pub affects_max_level: i64,
+    /// センサーの影響範囲: 上流側・下流側それぞれで到達する河川ノードの数の上限
+    ///
+    /// たどった段数の少ない河川ノードから順に数え、同じ段の中では近い河川ノードから数える。
+    /// このため、段数の多い経路でより近くにある河川ノードが数えられずに打ち切られることがある。
+    /// APOCを使えるかどうかに関わらず同じ扱いになる。
+    #[serde(default = "default_affects_max_paths")]
+    pub affects_max_paths: i64,
+}
+
+fn default_sensor_auth_max_skew() -> i64 {
+    300
+}
+
+fn default_sensor_pending_limit() -> i64 {
+    100
+}
+
+fn default_qc_max_distance() -> f64 {
+    30.0
+}
+
+fn default_qc_max_rate() -> f64 {
+    0.5
+}
+
+fn default_qc_window() -> usize {
+    5
+}
+
+fn default_qc_median_threshold() -> f64 {
+    2.0
+}
+
+fn default_stale_reading_intervals() -> f64 {
+    3.0
+}
+
+fn default_water_level_recompute_interval() -> u64 {
+    60
+}
+
+fn default_idw_power() -> f64 {
+    1.0
+}
+
+fn default_bed_slope() -> f64 {
+    0.001
+}
+
+fn default_forecast_interval() -> u64 {
+    600
+}
+
+fn default_forecast_window_hours() -> f64 {
+    3.0
+}
+
+fn default_forecast_horizon_hours() -> u32 {
+    6
+}
+
+fn default_interval_window_minutes() -> i64 {
+    60
+}
+
+fn default_interval_rising_rate() -> f64 {
+    0.1
+}
+
+fn default_interval_stable_rate() -> f64 {
+    0.01
+}
+
+fn default_interval_low_battery() -> i32 {
+    3300
+}
+
+fn default_interval_near_threshold() -> f64 {
+    0.3
+}
+
+fn default_alert_hysteresis() -> f64 {
+    0.1
+}
+
+fn default_webhook_dispatch_interval() -> u64 {
+    10
+}
+
+fn default_webhook_max_attempts() -> i64 {
+    8
+}
+
+fn default_webhook_retry_base() -> i64 {
+    30
+}
+
+fn default_health_interval() -> u64 {
+    300
+}
+
+fn default_health_window_hours() -> f64 {
+    24.0
+}
+
+fn default_health_offline_windows() -> f64 {
+    3.0
+}
+
+fn default_health_missed_windows() -> i64 {
+    2
+}
+
+fn default_health_battery_decline() -> f64 {
+    50.0
+}
+
+fn default_health_degraded_network() -> Vec<String> {
+    vec!["weak".to_string(), "poor".to_string()]
+}
+
+fn default_retention_raw_days() -> u32 {
+    90
+}
+
+fn default_retention_hourly_days() -> u32 {
+    365
+}
+
+fn default_export_max_days() -> i64 {
+    31
 }
 
 impl EnvVars {
@@ -18,3 +267,11 @@ impl EnvVars {
         envy::from_env::<Self>()
     }
 }
+
+fn default_affects_max_level() -> i64 {
+    1000
+}
+
+fn default_affects_max_paths() -> i64 {
+    10000
+}
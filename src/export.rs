@@ -0,0 +1,247 @@
+//! センサーデータをCSV/Parquetとして書き出す
+//!
+//! APIとCLIの両方から利用する。
+
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, Float64Array, Int64Array, StringArray, TimestampMicrosecondArray};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use chrono::{DateTime, TimeDelta, Utc};
+use neo4rs::{query, Graph};
+use parquet::arrow::ArrowWriter;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Parquetの1行グループあたりに溜める行数
+const PARQUET_BATCH_SIZE: usize = 8192;
+
+/// 書き出し形式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    /// ファイル名に付ける拡張子
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl From<openapi::models::ExportFormat> for ExportFormat {
+    fn from(value: openapi::models::ExportFormat) -> Self {
+        match value {
+            openapi::models::ExportFormat::Csv => ExportFormat::Csv,
+            openapi::models::ExportFormat::Parquet => ExportFormat::Parquet,
+        }
+    }
+}
+
+/// 書き出す1行分のデータ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportRow {
+    pub sensor_id: String,
+    pub time: String,
+    pub altitude: f64,
+    pub parent_node: String,
+    /// センサーが送った距離
+    pub raw_distance: f64,
+    /// 校正後の距離(メートル)
+    pub distance: f64,
+    /// `altitude - distance`
+    pub water_level: f64,
+    pub battery_voltage: i64,
+    pub previous_sleep_time: i64,
+    pub network_status: String,
+    /// QCの結果(`ok`または`rejected`)
+    pub qc_status: String,
+}
+
+/// 形式ごとの書き出し先
+enum ExportWriter<W: Write + Send> {
+    Csv(csv::Writer<W>),
+    Parquet {
+        writer: ArrowWriter<W>,
+        schema: SchemaRef,
+        buffer: Vec<ExportRow>,
+    },
+}
+
+impl<W: Write + Send> ExportWriter<W> {
+    fn new(format: ExportFormat, out: W) -> anyhow::Result<Self> {
+        let writer = match format {
+            ExportFormat::Csv => ExportWriter::Csv(csv::Writer::from_writer(out)),
+            ExportFormat::Parquet => {
+                let schema = parquet_schema();
+                ExportWriter::Parquet {
+                    writer: ArrowWriter::try_new(out, schema.clone(), None)?,
+                    schema,
+                    buffer: Vec::with_capacity(PARQUET_BATCH_SIZE),
+                }
+            }
+        };
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, row: ExportRow) -> anyhow::Result<()> {
+        match self {
+            ExportWriter::Csv(writer) => writer.serialize(row)?,
+            ExportWriter::Parquet { writer, schema, buffer } => {
+                buffer.push(row);
+                if buffer.len() >= PARQUET_BATCH_SIZE {
+                    writer.write(&to_record_batch(schema.clone(), buffer)?)?;
+                    buffer.clear();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<W> {
+        let out = match self {
+            ExportWriter::Csv(writer) => writer.into_inner().map_err(|e| e.into_error())?,
+            ExportWriter::Parquet { mut writer, schema, buffer } => {
+                if !buffer.is_empty() {
+                    writer.write(&to_record_batch(schema, &buffer)?)?;
+                }
+                writer.into_inner()?
+            }
+        };
+
+        Ok(out)
+    }
+}
+
+fn parquet_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("sensor_id", DataType::Utf8, false),
+        Field::new("time", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), true),
+        Field::new("altitude", DataType::Float64, false),
+        Field::new("parent_node", DataType::Utf8, false),
+        Field::new("raw_distance", DataType::Float64, false),
+        Field::new("distance", DataType::Float64, false),
+        Field::new("water_level", DataType::Float64, false),
+        Field::new("battery_voltage", DataType::Int64, false),
+        Field::new("previous_sleep_time", DataType::Int64, false),
+        Field::new("network_status", DataType::Utf8, false),
+        Field::new("qc_status", DataType::Utf8, false),
+    ]))
+}
+
+fn to_record_batch(schema: SchemaRef, rows: &[ExportRow]) -> anyhow::Result<RecordBatch> {
+    // 時刻はRFC3339文字列で保存されているので、UTCのタイムスタンプに変換する
+    let time = rows.iter()
+        .map(|row| {
+            DateTime::parse_from_rfc3339(&row.time)
+                .ok()
+                .map(|time| time.timestamp_micros())
+        })
+        .collect::<Vec<_>>();
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.sensor_id))),
+        Arc::new(TimestampMicrosecondArray::from(time).with_timezone("UTC")),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.altitude))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.parent_node))),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.raw_distance))),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.distance))),
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.water_level))),
+        Arc::new(Int64Array::from_iter_values(rows.iter().map(|row| row.battery_voltage))),
+        Arc::new(Int64Array::from_iter_values(rows.iter().map(|row| row.previous_sleep_time))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.network_status))),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|row| &row.qc_status))),
+    ];
+
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// 指定したセンサー・期間のセンサーデータを`out`に書き出す
+///
+/// Neo4jから受け取った行を順に`out`へ書き込む(Parquetは行グループ単位でバッファする)。
+/// APIは`Vec`に書き出してから応答するため、[`api_range`]で期間を制限する。
+pub async fn export<W: Write + Send>(
+    graph: &Graph,
+    ids: &[Uuid],
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    format: ExportFormat,
+    out: W,
+) -> anyhow::Result<W> {
+    let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+    let mut result = graph.execute(
+        query(r#"
+MATCH (sensor:Sensor)-[:CURRENT_DATA]->(:SensorData)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+WHERE sensor.id IN $ids
+  AND ($from IS NULL OR datetime(data.time) >= datetime($from))
+  AND ($to IS NULL OR datetime(data.time) < datetime($to))
+
+// 移設したセンサーは、計測時刻に設置されていた場所の標高と河川ノードを使う
+OPTIONAL MATCH (sensor)-[:LOCATED_AT]->(location:SensorLocation)
+WHERE (location.since IS NULL OR datetime(location.since) <= datetime(data.time))
+  AND (location.until IS NULL OR datetime(data.time) < datetime(location.until))
+WITH sensor, data, head(collect(location)) AS location
+
+RETURN sensor.id AS sensor_id,
+       data.time AS time,
+       coalesce(location.altitude, sensor.altitude) AS altitude,
+       coalesce(location.parent_node, sensor.parent_node) AS parent_node,
+       coalesce(data.raw_distance, data.distance) AS raw_distance,
+       data.distance AS distance,
+       coalesce(location.altitude, sensor.altitude) - data.distance AS water_level,
+       data.battery_voltage AS battery_voltage,
+       data.previous_sleep_time AS previous_sleep_time,
+       data.network_status AS network_status,
+       coalesce(data.qc_status, 'ok') AS qc_status
+ORDER BY sensor_id, datetime(time)
+        "#)
+            .param("ids", ids)
+            .param("from", from.map(|time| time.to_rfc3339()))
+            .param("to", to.map(|time| time.to_rfc3339())),
+    ).await?;
+
+    let mut writer = ExportWriter::new(format, out)?;
+
+    while let Some(row) = result.next().await? {
+        writer.write(row.to::<ExportRow>()?)?;
+    }
+
+    writer.finish()
+}
+
+/// APIで書き出す期間を検証する
+///
+/// APIは書き出した全体をメモリに載せてから応答するため、`from`を必須とし、`to`(省略時は`now`)までの長さを
+/// `max_days`日以内に制限する。
+pub fn api_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>, now: DateTime<Utc>, max_days: i64) -> Result<(), String> {
+    let Some(from) = from else {
+        return Err(format!("from is required; at most {max_days} days can be exported at once"));
+    };
+    let to = to.unwrap_or(now);
+    if to <= from {
+        return Err(format!("to must be later than from: {from} - {to}"));
+    }
+    if to - from > TimeDelta::days(max_days) {
+        return Err(format!("At most {max_days} days can be exported at once: {from} - {to}"));
+    }
+
+    Ok(())
+}
+
+/// カンマ区切りのセンサーIDを解釈する
+pub fn parse_ids(ids: &str) -> Result<Vec<Uuid>, String> {
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<Uuid>().map_err(|_| format!("Invalid sensor id: {id}")))
+        .collect()
+}
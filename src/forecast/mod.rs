@@ -0,0 +1,237 @@
+//! 河川ノードの水位の短期予測
+//!
+//! 各センサーの直近の計測値に傾向を当てはめて数時間先の水位を求める。センサーが所属する(`BELONGS_TO`)河川ノードは
+//! そのセンサーの予測をそのまま使い、それ以外は現在の水位と同じく`AFFECTS`を通して河川ノードの水位を推定する。予測は`(:RiverNode)-[:WATER_LEVEL_FORECAST]->(:WaterLevelForecast)`として
+//! 先行時間ごとに保存し、定期的に作り直す。
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Graph, Txn};
+use serde::Deserialize;
+
+use crate::env::EnvVars;
+use crate::water_level::load_affected_nodes;
+
+pub use trend::{Trend, TrendKind};
+
+mod trend;
+
+/// 予測する最大の先行時間(時間)
+pub const MAX_HORIZON_HOURS: u32 = 6;
+
+/// 傾向の当てはめに必要な計測値の数
+const MIN_SAMPLES: usize = 3;
+
+/// Neo4jから取得した、センサーの直近の計測値
+#[derive(Debug, Deserialize)]
+pub(crate) struct HistoryRow {
+    time: String,
+    water_level: f64,
+}
+
+/// 計測時刻の昇順に並んだ計測値を、最新の計測時刻と`(最新の計測時刻からの時間, 水位)`に変換する
+///
+/// 計測値がない場合は`None`を返す。
+pub(crate) fn to_samples(history: Vec<HistoryRow>) -> anyhow::Result<Option<(DateTime<Utc>, Vec<(f64, f64)>)>> {
+    let history = history.into_iter()
+        .map(|row| anyhow::Ok((DateTime::parse_from_rfc3339(&row.time)?.with_timezone(&Utc), row.water_level)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let Some(&(latest, _)) = history.last() else {
+        return Ok(None);
+    };
+
+    let samples = history.iter()
+        .map(|(time, water_level)| ((*time - latest).num_seconds() as f64 / 3600.0, *water_level))
+        .collect();
+
+    Ok(Some((latest, samples)))
+}
+
+/// センサーごとに、直近の計測値に傾向を当てはめる
+///
+/// 最新の計測時刻と傾向を返す。計測値が少ないセンサーは含まない。
+async fn fit_trends(graph: &Graph, env: &EnvVars, now: DateTime<Utc>) -> anyhow::Result<HashMap<String, (DateTime<Utc>, Trend)>> {
+    let since = now - chrono::Duration::minutes((env.forecast_window_hours * 60.0) as i64);
+
+    let mut result = graph.execute(query(
+        r#"
+// 1. 運用中のセンサーの受け付けた直近のデータを取得
+// 連結リストは計測時刻の降順なので、$since より古いデータに達したところでたどるのをやめる
+MATCH (sensor:Sensor)-[:CURRENT_DATA]->(current:SensorData)
+WHERE sensor.decommissioned_at IS NULL
+MATCH path = (current)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+WHERE all(node IN nodes(path) WHERE datetime(node.time) >= datetime($since))
+  AND coalesce(data.qc_status, 'ok') <> 'rejected'
+  AND (sensor.relocated_at IS NULL OR datetime(data.time) >= datetime(sensor.relocated_at))
+
+// 2. 計測時刻の昇順に水位を並べる
+WITH sensor, data
+ORDER BY datetime(data.time)
+RETURN sensor.id AS id,
+       collect({time: data.time, water_level: sensor.altitude - data.distance}) AS history
+        "#,
+    )
+        .param("since", since.to_rfc3339())
+    ).await?;
+
+    let mut trends = HashMap::new();
+    while let Some(row) = result.next().await? {
+        let Some((latest, samples)) = to_samples(row.get("history")?)? else {
+            continue;
+        };
+
+        if let Some(trend) = env.forecast_trend.fit(&samples, MIN_SAMPLES) {
+            trends.insert(row.get("id")?, (latest, trend));
+        }
+    }
+
+    Ok(trends)
+}
+
+/// センサーが所属する河川ノード(`elementId`)ごとに、所属するセンサーを返す
+async fn load_parent_nodes(tnx: &mut Txn, ids: &[String]) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut result = tnx.execute(query(
+        r#"
+UNWIND $ids AS id
+MATCH (:Sensor {id: id})-[:BELONGS_TO]->(parentRiverNode:RiverNode)
+RETURN elementId(parentRiverNode) AS node, collect(id) AS ids
+        "#,
+    )
+        .param("ids", ids.to_vec())
+    ).await?;
+
+    let mut parents = HashMap::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        parents.insert(row.get("node")?, row.get("ids")?);
+    }
+
+    Ok(parents)
+}
+
+/// 河川ノードの水位の予測を作り直す
+///
+/// センサーが所属する河川ノードは、そのセンサー(複数ある場合はその平均)の予測とし、`AFFECTS`からは推定しない。
+pub async fn update_forecasts(graph: &Graph, env: &EnvVars) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let trends = fit_trends(graph, env, now).await?;
+    let horizon_hours = env.forecast_horizon_hours.clamp(1, MAX_HORIZON_HOURS);
+
+    let mut tnx = graph.start_txn().await?;
+
+    let result = async {
+        let ids = trends.keys().cloned().collect::<Vec<_>>();
+        let parent_nodes = load_parent_nodes(&mut tnx, &ids).await?;
+        let affected_nodes = load_affected_nodes(&mut tnx, &ids, env).await?;
+
+        let mut forecasts = Vec::new();
+        for horizon in 1..=horizon_hours {
+            let time = now + chrono::Duration::hours(horizon as i64);
+            let project = |id: &str| {
+                trends.get(id).map(|(latest, trend)| trend.project((time - *latest).num_seconds() as f64 / 3600.0))
+            };
+
+            let parents = parent_nodes.iter().filter_map(|(node, ids)| {
+                let values = ids.iter().filter_map(|id| project(id)).collect::<Vec<_>>();
+                (!values.is_empty()).then(|| (node, values.iter().sum::<f64>() / values.len() as f64))
+            });
+            // 傾向を当てはめられなかったセンサーは現在の水位が続くものとする
+            let affected = affected_nodes.iter()
+                .filter(|affected| !parent_nodes.contains_key(&affected.node))
+                .filter_map(|affected| Some((&affected.node, affected.estimate_with(&project)?)));
+
+            for (node, value) in parents.chain(affected) {
+                forecasts.push(HashMap::from([
+                    ("node".to_string(), BoltType::from(node.clone())),
+                    ("horizon".to_string(), BoltType::from(horizon as i64)),
+                    ("time".to_string(), BoltType::from(time.to_rfc3339())),
+                    ("value".to_string(), BoltType::from(value)),
+                ]));
+            }
+        }
+
+        // 以前の予測を削除してから作り直す
+        tnx.run(query(
+            r#"
+MATCH (forecast:WaterLevelForecast)
+DETACH DELETE forecast
+            "#,
+        )).await?;
+
+        tnx.run(query(
+            r#"
+UNWIND $forecasts AS forecast
+MATCH (node:RiverNode)
+WHERE elementId(node) = forecast.node
+CREATE (node)-[:WATER_LEVEL_FORECAST]->(:WaterLevelForecast {
+    horizon: forecast.horizon,
+    time: forecast.time,
+    value: forecast.value,
+    issued_at: $issued_at
+})
+            "#,
+        )
+            .param("forecasts", forecasts)
+            .param("issued_at", now.to_rfc3339())
+        ).await?;
+
+        anyhow::Ok(())
+    }.await;
+
+    match result {
+        Ok(()) => tnx.commit().await?,
+        Err(e) => {
+            tnx.rollback().await?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// 一定間隔で予測を作り直す
+pub async fn run(graph: Graph, env: Arc<EnvVars>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(env.forecast_interval));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = update_forecasts(&graph, &env).await {
+            println!("update_forecasts: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(time: &str, water_level: f64) -> HistoryRow {
+        HistoryRow { time: time.to_string(), water_level }
+    }
+
+    #[test]
+    fn samples_are_hours_before_latest() {
+        let (latest, samples) = to_samples(vec![
+            row("2024-06-01T09:00:00+09:00", 1.0),
+            row("2024-06-01T00:30:00Z", 1.5),
+            row("2024-06-01T01:00:00Z", 2.0),
+        ]).unwrap().unwrap();
+
+        assert_eq!(latest, DateTime::parse_from_rfc3339("2024-06-01T01:00:00Z").unwrap().with_timezone(&Utc));
+        assert_eq!(samples, vec![(-1.0, 1.0), (-0.5, 1.5), (0.0, 2.0)]);
+    }
+
+    #[test]
+    fn empty_history_has_no_samples() {
+        assert!(to_samples(Vec::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_time_is_an_error() {
+        assert!(to_samples(vec![row("yesterday", 1.0)]).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! 水位の時系列から傾向を当てはめる
+
+use serde::Deserialize;
+
+/// 指数平滑化の水準の平滑化係数
+const SMOOTHING_LEVEL: f64 = 0.5;
+/// 指数平滑化の傾きの平滑化係数
+const SMOOTHING_TREND: f64 = 0.3;
+
+/// 傾向の当てはめ方
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, strum::Display, strum::EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TrendKind {
+    /// 最小二乗法による直線
+    #[default]
+    Linear,
+    /// 水準と傾きを指数平滑化する(Holtの線形トレンド法)。直近の変化ほど重く見る
+    Holt,
+}
+
+/// 水位の傾向
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trend {
+    /// 最新の計測時刻での水位
+    pub level: f64,
+    /// 水位の変化率(メートル毎時)
+    pub rate: f64,
+}
+
+impl Trend {
+    /// 最新の計測時刻から`hours`時間後の水位
+    pub fn project(&self, hours: f64) -> f64 {
+        self.level + self.rate * hours
+    }
+}
+
+impl TrendKind {
+    /// 計測時刻の昇順に並んだ`(時刻(時間), 水位)`に傾向を当てはめる
+    ///
+    /// 計測値が`min_samples`件未満、または計測時刻がすべて同じ場合は`None`を返す。
+    pub fn fit(&self, samples: &[(f64, f64)], min_samples: usize) -> Option<Trend> {
+        if samples.len() < min_samples.max(2) {
+            return None;
+        }
+
+        match self {
+            TrendKind::Linear => fit_linear(samples),
+            TrendKind::Holt => fit_holt(samples),
+        }
+    }
+}
+
+fn fit_linear(samples: &[(f64, f64)]) -> Option<Trend> {
+    let n = samples.len() as f64;
+    let mean_t = samples.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_y = samples.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let (covariance, variance) = samples.iter()
+        .map(|(t, y)| ((t - mean_t) * (y - mean_y), (t - mean_t).powi(2)))
+        .fold((0.0, 0.0), |(c, v), (dc, dv)| (c + dc, v + dv));
+    if variance <= 0.0 {
+        return None;
+    }
+
+    let rate = covariance / variance;
+    let (last_t, _) = samples[samples.len() - 1];
+
+    Some(Trend { level: mean_y + rate * (last_t - mean_t), rate })
+}
+
+fn fit_holt(samples: &[(f64, f64)]) -> Option<Trend> {
+    let (mut previous_t, mut level) = samples[0];
+    let mut rate = 0.0;
+    let mut fitted = false;
+
+    for &(t, y) in &samples[1..] {
+        let dt = t - previous_t;
+        if dt <= 0.0 {
+            continue;
+        }
+
+        let previous_level = level;
+        level = SMOOTHING_LEVEL * y + (1.0 - SMOOTHING_LEVEL) * (level + rate * dt);
+        rate = if fitted {
+            SMOOTHING_TREND * (level - previous_level) / dt + (1.0 - SMOOTHING_TREND) * rate
+        } else {
+            (y - previous_level) / dt
+        };
+        previous_t = t;
+        fitted = true;
+    }
+
+    fitted.then_some(Trend { level, rate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `y = level + rate * t`を`step`時間ごとに`count`件、最新の計測時刻を0として並べる
+    fn line(level: f64, rate: f64, step: f64, count: usize) -> Vec<(f64, f64)> {
+        (0..count)
+            .map(|i| -((count - 1 - i) as f64) * step)
+            .map(|t| (t, level + rate * t))
+            .collect()
+    }
+
+    #[test]
+    fn linear_recovers_slope() {
+        let trend = TrendKind::Linear.fit(&line(3.0, -0.4, 0.25, 8), 3).unwrap();
+        assert!((trend.level - 3.0).abs() < 1e-9);
+        assert!((trend.rate + 0.4).abs() < 1e-9);
+        assert!((trend.project(2.0) - 2.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn linear_fits_noisy_samples_by_least_squares() {
+        let samples = [(-2.0, 1.0), (-1.0, 2.0), (0.0, 2.5)];
+        let trend = TrendKind::Linear.fit(&samples, 3).unwrap();
+        assert!((trend.rate - 0.75).abs() < 1e-9);
+        // 最新の計測時刻での当てはめた値
+        assert!((trend.level - 2.5833333333333335).abs() < 1e-9);
+    }
+
+    #[test]
+    fn holt_converges_to_rate() {
+        let trend = TrendKind::Holt.fit(&line(20.0, 2.0, 0.5, 20), 3).unwrap();
+        assert!((trend.level - 20.0).abs() < 0.01);
+        assert!((trend.rate - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn holt_weighs_recent_change() {
+        // 横ばいの後、直近の6時間は毎時0.5メートル上昇している
+        let samples = (0..12)
+            .map(|i| (i as f64 - 11.0, if i < 6 { 1.0 } else { 1.0 + 0.5 * (i - 5) as f64 }))
+            .collect::<Vec<_>>();
+
+        let linear = TrendKind::Linear.fit(&samples, 3).unwrap();
+        let holt = TrendKind::Holt.fit(&samples, 3).unwrap();
+        assert!(holt.rate > linear.rate);
+        assert!((holt.rate - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn fewer_than_min_samples_is_not_fitted() {
+        let samples = line(1.0, 1.0, 1.0, 3);
+        assert!(TrendKind::Linear.fit(&samples, 4).is_none());
+        assert!(TrendKind::Holt.fit(&samples, 4).is_none());
+        assert!(TrendKind::Linear.fit(&samples, 3).is_some());
+        // 傾きを求めるには最低2件が必要
+        assert!(TrendKind::Linear.fit(&samples[..1], 0).is_none());
+        assert!(TrendKind::Holt.fit(&samples[..1], 1).is_none());
+    }
+
+    #[test]
+    fn samples_at_the_same_time_are_not_fitted() {
+        let samples = [(0.0, 1.0), (0.0, 2.0), (0.0, 3.0)];
+        assert!(TrendKind::Linear.fit(&samples, 3).is_none());
+        assert!(TrendKind::Holt.fit(&samples, 3).is_none());
+    }
+}
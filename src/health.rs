@@ -0,0 +1,541 @@
+//! センサーの稼働状態の監視
+//!
+//! センサーデータの計測時刻、電池の電圧、通信状態から、センサーごとに稼働状態を healthy(正常)、warning(要注意)、
+//! offline(停止)のいずれかに判定し、`Sensor`の`health`と`health_issues`に記録する。
+//! 送信間隔はセンサーに最後に返した値(`SensorData`の`interval`)を使い、返していない場合は登録された値を使う。
+//! offlineになったセンサーはWebhookで通知する。
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Graph};
+use serde::Deserialize;
+
+use crate::env::EnvVars;
+use crate::forecast::TrendKind;
+use crate::webhook::{enqueue, Event, EventType};
+
+/// 電池の電圧の傾向の当てはめに必要な計測値の数
+const MIN_BATTERY_SAMPLES: usize = 3;
+/// この割合以上の計測値の通信状態が低下していれば、通信状態の低下とみなす
+const DEGRADED_NETWORK_RATIO: f64 = 0.5;
+
+/// センサーの稼働状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    Warning,
+    Offline,
+}
+
+impl From<HealthStatus> for openapi::models::SensorHealthStatus {
+    fn from(value: HealthStatus) -> Self {
+        match value {
+            HealthStatus::Healthy => openapi::models::SensorHealthStatus::Healthy,
+            HealthStatus::Warning => openapi::models::SensorHealthStatus::Warning,
+            HealthStatus::Offline => openapi::models::SensorHealthStatus::Offline,
+        }
+    }
+}
+
+/// 稼働状態を判定した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum HealthIssue {
+    /// センサーデータが一つもない
+    NoData,
+    /// 送信間隔を過ぎても送信がなかった
+    MissedReports,
+    /// 電池の電圧が下がり続けている
+    BatteryDeclining,
+    /// 電池の電圧が低い
+    LowBattery,
+    /// 通信状態が低下している
+    DegradedNetwork,
+}
+
+/// 稼働状態の判定方法
+#[derive(Debug, Clone)]
+pub struct HealthPolicy {
+    /// 最新の計測から、送信間隔のこの倍数を過ぎるとofflineとする
+    pub offline_windows: f64,
+    /// 判定期間内に送信がなかった回数がこの値以上の場合warningとする
+    pub missed_windows: i64,
+    /// 電池の電圧の低下がこの値(1日あたり)以上の場合warningとする
+    pub battery_decline: f64,
+    /// 電池の電圧がこの値未満の場合warningとする
+    pub low_battery: i32,
+    /// 通信状態の低下を表す`network_status`の値
+    pub degraded_network: Vec<String>,
+}
+
+impl From<&EnvVars> for HealthPolicy {
+    fn from(env: &EnvVars) -> Self {
+        HealthPolicy {
+            offline_windows: env.health_offline_windows,
+            missed_windows: env.health_missed_windows,
+            battery_decline: env.health_battery_decline,
+            low_battery: env.interval_low_battery,
+            degraded_network: env.health_degraded_network.clone(),
+        }
+    }
+}
+
+/// 稼働状態の判定に使うセンサーデータ
+#[derive(Debug, Clone)]
+pub struct HealthReading {
+    pub time: DateTime<Utc>,
+    /// このデータを受け取ったときにセンサーに返した送信間隔(秒)
+    pub interval: Option<i32>,
+    pub battery_voltage: Option<i32>,
+    pub network_status: Option<String>,
+}
+
+/// 判定した稼働状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthAssessment {
+    pub status: HealthStatus,
+    pub issues: Vec<HealthIssue>,
+    /// 判定期間内に送信がなかった回数
+    pub missed_windows: i64,
+    /// 電池の電圧の変化(1日あたり)
+    pub battery_trend: Option<f64>,
+}
+
+impl HealthPolicy {
+    /// 稼働状態を判定する
+    ///
+    /// `readings`は判定期間内のセンサーデータ(計測時刻の昇順)、`last`は期間に関わらず最新のセンサーデータ。
+    /// 送信がなかった回数は、連続する計測の間隔と最新の計測からの経過時間を、送信間隔で割って数える。
+    pub fn assess(&self, interval: i32, readings: &[HealthReading], last: Option<&HealthReading>, now: DateTime<Utc>) -> HealthAssessment {
+        let Some(last) = last else {
+            return HealthAssessment {
+                status: HealthStatus::Offline,
+                issues: vec![HealthIssue::NoData],
+                missed_windows: 0,
+                battery_trend: None,
+            };
+        };
+
+        let expected = |reading: &HealthReading| reading.interval.filter(|interval| *interval > 0).unwrap_or(interval);
+        let missed = |from: &HealthReading, to: DateTime<Utc>| {
+            let expected = expected(from);
+            if expected <= 0 {
+                return 0;
+            }
+            ((to - from.time).num_seconds() / expected as i64 - 1).max(0)
+        };
+
+        let mut issues = Vec::new();
+
+        // 1. 送信の途絶
+        let last_expected = expected(last);
+        let offline = last_expected > 0
+            && (now - last.time).num_seconds() as f64 > last_expected as f64 * self.offline_windows;
+
+        let missed_windows = readings.windows(2)
+            .map(|pair| missed(&pair[0], pair[1].time))
+            .sum::<i64>() + missed(last, now);
+        if offline || missed_windows >= self.missed_windows {
+            issues.push(HealthIssue::MissedReports);
+        }
+
+        // 2. 電池の電圧
+        let battery_samples = readings.iter()
+            .filter_map(|reading| Some(((reading.time - last.time).num_seconds() as f64 / 3600.0, reading.battery_voltage? as f64)))
+            .collect::<Vec<_>>();
+        let battery_trend = TrendKind::Linear.fit(&battery_samples, MIN_BATTERY_SAMPLES)
+            .map(|trend| trend.rate * 24.0);
+        if battery_trend.is_some_and(|trend| -trend >= self.battery_decline) {
+            issues.push(HealthIssue::BatteryDeclining);
+        }
+        if last.battery_voltage.is_some_and(|voltage| voltage < self.low_battery) {
+            issues.push(HealthIssue::LowBattery);
+        }
+
+        // 3. 通信状態
+        let degraded = readings.iter()
+            .filter(|reading| reading.network_status.as_ref().is_some_and(|status| self.degraded_network.contains(status)))
+            .count();
+        if !readings.is_empty() && degraded as f64 >= readings.len() as f64 * DEGRADED_NETWORK_RATIO {
+            issues.push(HealthIssue::DegradedNetwork);
+        }
+
+        let status = if offline {
+            HealthStatus::Offline
+        } else if !issues.is_empty() {
+            HealthStatus::Warning
+        } else {
+            HealthStatus::Healthy
+        };
+
+        HealthAssessment { status, issues, missed_windows, battery_trend }
+    }
+}
+
+/// Neo4jから取得したセンサーデータ
+#[derive(Debug, Deserialize)]
+struct ReadingRow {
+    time: String,
+    interval: Option<i64>,
+    battery_voltage: Option<i64>,
+    network_status: Option<String>,
+}
+
+impl TryFrom<ReadingRow> for HealthReading {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ReadingRow) -> Result<Self, Self::Error> {
+        Ok(HealthReading {
+            time: DateTime::parse_from_rfc3339(&row.time)?.with_timezone(&Utc),
+            interval: row.interval.map(|interval| interval as i32),
+            battery_voltage: row.battery_voltage.map(|voltage| voltage as i32),
+            network_status: row.network_status,
+        })
+    }
+}
+
+/// Neo4jから取得した、稼働状態の判定に必要な値
+#[derive(Debug, Deserialize)]
+struct HealthRow {
+    id: String,
+    interval: Option<i64>,
+    previous: Option<String>,
+    last: Option<ReadingRow>,
+    readings: Vec<ReadingRow>,
+}
+
+/// すべてのセンサーの稼働状態を判定して記録する
+///
+/// healthyまたはwarningからofflineになったセンサーはWebhookで通知する。
+pub async fn update_health(graph: &Graph, env: &EnvVars) -> anyhow::Result<()> {
+    let policy = HealthPolicy::from(env);
+    let now = Utc::now();
+    let since = now - chrono::Duration::minutes((env.health_window_hours * 60.0) as i64);
+
+    let mut result = graph.execute(query(
+        r#"
+// 1. 運用中のセンサーと最新のデータを取得
+MATCH (sensor:Sensor)
+WHERE sensor.decommissioned_at IS NULL
+OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(last:SensorData)
+
+// 2. 判定期間内のデータを計測時刻の昇順に並べる
+// 連結リストは計測時刻の降順なので、$since より古いデータに達したところでたどるのをやめる
+OPTIONAL MATCH path = (last)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+WHERE all(node IN nodes(path) WHERE datetime(node.time) >= datetime($since))
+WITH sensor, last, data
+ORDER BY datetime(data.time)
+WITH sensor, last,
+     collect(CASE WHEN data IS NULL THEN null ELSE {
+         time: data.time,
+         interval: data.interval,
+         battery_voltage: data.battery_voltage,
+         network_status: data.network_status
+     } END) AS readings
+
+RETURN sensor.id AS id,
+       sensor.interval AS interval,
+       sensor.health AS previous,
+       CASE WHEN last IS NULL THEN null ELSE {
+           time: last.time,
+           interval: last.interval,
+           battery_voltage: last.battery_voltage,
+           network_status: last.network_status
+       } END AS last,
+       readings
+        "#,
+    )
+        .param("since", since.to_rfc3339())
+    ).await?;
+
+    let mut updates = Vec::new();
+    let mut events = Vec::new();
+    while let Some(row) = result.next().await? {
+        let HealthRow { id, interval, previous, last, readings } = row.to()?;
+        let last = last.map(HealthReading::try_from).transpose()?;
+        let readings = readings.into_iter()
+            .map(HealthReading::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let assessment = policy.assess(interval.unwrap_or(0) as i32, &readings, last.as_ref(), now);
+        let issues = assessment.issues.iter().map(|issue| issue.to_string()).collect::<Vec<_>>();
+
+        // 稼働していたセンサーが止まった場合のみ通知する
+        let was_running = previous.as_deref().is_some_and(|previous| previous != HealthStatus::Offline.to_string());
+        if assessment.status == HealthStatus::Offline && was_running {
+            events.push(Event::new(
+                EventType::SensorOffline,
+                id.clone(),
+                serde_json::json!({
+                    "last_seen": last.as_ref().map(|last| last.time),
+                    "issues": issues,
+                }),
+            ));
+        }
+
+        updates.push(HashMap::from([
+            ("id".to_string(), BoltType::from(id)),
+            ("status".to_string(), BoltType::from(assessment.status.to_string())),
+            ("issues".to_string(), BoltType::from(issues)),
+            ("missed_windows".to_string(), BoltType::from(assessment.missed_windows)),
+            ("battery_trend".to_string(), BoltType::from(assessment.battery_trend)),
+        ]));
+    }
+
+    let mut tnx = graph.start_txn().await?;
+
+    let result = async {
+        tnx.run(query(
+            r#"
+UNWIND $updates AS update
+MATCH (sensor:Sensor {id: update.id})
+SET sensor.health_since = CASE WHEN sensor.health = update.status THEN sensor.health_since ELSE $now END,
+    sensor.health = update.status,
+    sensor.health_issues = update.issues,
+    sensor.health_missed_windows = update.missed_windows,
+    sensor.health_battery_trend = update.battery_trend,
+    sensor.health_checked_at = $now
+            "#,
+        )
+            .param("updates", updates)
+            .param("now", now.to_rfc3339())
+        ).await?;
+
+        enqueue(&mut tnx, &events).await?;
+
+        anyhow::Ok(())
+    }.await;
+
+    match result {
+        Ok(()) => tnx.commit().await?,
+        Err(e) => {
+            tnx.rollback().await?;
+            return Err(e);
+        }
+    }
+
+    for event in &events {
+        println!("update_health: sensor {} went offline", event.sensor_id);
+    }
+
+    Ok(())
+}
+
+/// 一定間隔で稼働状態を判定する
+pub async fn run(graph: Graph, env: Arc<EnvVars>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(env.health_interval));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = update_health(&graph, &env).await {
+            println!("update_health: {:?}", e);
+        }
+    }
+}
+
+/// 記録されたセンサーの稼働状態
+#[derive(Debug, Deserialize)]
+pub struct SensorHealth {
+    pub id: String,
+    pub status: String,
+    pub issues: Vec<String>,
+    pub since: String,
+    pub checked_at: String,
+    pub missed_windows: i64,
+    pub battery_trend: Option<f64>,
+    pub battery_voltage: Option<i64>,
+    pub network_status: Option<String>,
+    pub last_seen: Option<String>,
+}
+
+impl TryFrom<SensorHealth> for openapi::models::SensorHealth {
+    type Error = anyhow::Error;
+
+    fn try_from(health: SensorHealth) -> Result<Self, Self::Error> {
+        let SensorHealth { id, status, issues, since, checked_at, missed_windows, battery_trend, battery_voltage, network_status, last_seen } = health;
+        let parse_time = |time: &str| anyhow::Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc));
+
+        Ok(openapi::models::SensorHealth {
+            id: id.parse()?,
+            status: status.parse::<HealthStatus>()?.into(),
+            issues,
+            since: parse_time(&since)?,
+            checked_at: parse_time(&checked_at)?,
+            missed_windows,
+            battery_trend,
+            battery_voltage: battery_voltage.map(|voltage| voltage as i32),
+            network_status,
+            last_seen: last_seen.as_deref().map(parse_time).transpose()?,
+        })
+    }
+}
+
+/// 記録されたセンサーの稼働状態を取得する
+///
+/// まだ判定していないセンサーと、運用を終了したセンサーは含まない。
+pub async fn load_health(graph: &Graph) -> anyhow::Result<Vec<SensorHealth>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (sensor:Sensor)
+WHERE sensor.health IS NOT NULL
+  AND sensor.decommissioned_at IS NULL
+OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(last:SensorData)
+RETURN sensor.id AS id,
+       sensor.health AS status,
+       sensor.health_issues AS issues,
+       sensor.health_since AS since,
+       sensor.health_checked_at AS checked_at,
+       sensor.health_missed_windows AS missed_windows,
+       sensor.health_battery_trend AS battery_trend,
+       last.battery_voltage AS battery_voltage,
+       last.network_status AS network_status,
+       last.time AS last_seen
+ORDER BY id
+        "#,
+    )).await?;
+
+    let mut health = Vec::new();
+    while let Some(row) = result.next().await? {
+        health.push(row.to()?);
+    }
+
+    Ok(health)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> HealthPolicy {
+        HealthPolicy {
+            offline_windows: 3.0,
+            missed_windows: 3,
+            battery_decline: 50.0,
+            low_battery: 3000,
+            degraded_network: vec!["poor".to_string()],
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z").unwrap().with_timezone(&Utc)
+    }
+
+    /// `now`の`ago`秒前のセンサーデータ
+    fn reading(ago: i64, battery_voltage: i32, network_status: &str) -> HealthReading {
+        HealthReading {
+            time: now() - chrono::Duration::seconds(ago),
+            interval: None,
+            battery_voltage: Some(battery_voltage),
+            network_status: Some(network_status.to_string()),
+        }
+    }
+
+    /// 最新の計測が1分前の、600秒ごとの`count`件のセンサーデータ
+    fn regular(count: i64) -> Vec<HealthReading> {
+        (0..count).rev().map(|i| reading(60 + i * 600, 3300, "good")).collect()
+    }
+
+    fn assess(readings: &[HealthReading]) -> HealthAssessment {
+        policy().assess(600, readings, readings.last(), now())
+    }
+
+    #[test]
+    fn regular_reports_are_healthy() {
+        let assessment = assess(&regular(12));
+        assert_eq!(assessment.status, HealthStatus::Healthy);
+        assert!(assessment.issues.is_empty());
+        assert_eq!(assessment.missed_windows, 0);
+        assert_eq!(assessment.battery_trend, Some(0.0));
+    }
+
+    #[test]
+    fn sensor_without_data_is_offline() {
+        let assessment = policy().assess(600, &[], None, now());
+        assert_eq!(assessment.status, HealthStatus::Offline);
+        assert_eq!(assessment.issues, vec![HealthIssue::NoData]);
+    }
+
+    #[test]
+    fn missed_windows_are_counted_between_reports() {
+        // 2400秒の間隔は3回分の送信がなかったことになる
+        let readings = vec![reading(3660, 3300, "good"), reading(3060, 3300, "good"), reading(660, 3300, "good"), reading(60, 3300, "good")];
+        let assessment = assess(&readings);
+        assert_eq!(assessment.missed_windows, 3);
+        assert_eq!(assessment.status, HealthStatus::Warning);
+        assert_eq!(assessment.issues, vec![HealthIssue::MissedReports]);
+
+        // 2回までは正常とする
+        let readings = vec![reading(3060, 3300, "good"), reading(1260, 3300, "good"), reading(660, 3300, "good"), reading(60, 3300, "good")];
+        let assessment = assess(&readings);
+        assert_eq!(assessment.missed_windows, 2);
+        assert_eq!(assessment.status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn returned_interval_is_expected() {
+        // センサーに1200秒を返していれば、1200秒ごとの送信は途絶えていない
+        let readings = (0..4).rev()
+            .map(|i| HealthReading { interval: Some(1200), ..reading(60 + i * 1200, 3300, "good") })
+            .collect::<Vec<_>>();
+        assert_eq!(assess(&readings).missed_windows, 0);
+
+        let readings = readings.into_iter().map(|reading| HealthReading { interval: None, ..reading }).collect::<Vec<_>>();
+        assert_eq!(assess(&readings).missed_windows, 3);
+    }
+
+    #[test]
+    fn silent_sensor_goes_offline() {
+        let readings = vec![reading(2600, 3300, "good"), reading(2000, 3300, "good")];
+        let assessment = assess(&readings);
+        assert_eq!(assessment.status, HealthStatus::Offline);
+        assert_eq!(assessment.issues, vec![HealthIssue::MissedReports]);
+        assert_eq!(assessment.missed_windows, 2);
+
+        // 判定期間内にデータがなくても、最新のデータから判定する
+        let last = reading(86400, 3300, "good");
+        let assessment = policy().assess(600, &[], Some(&last), now());
+        assert_eq!(assessment.status, HealthStatus::Offline);
+        assert_eq!(assessment.battery_trend, None);
+    }
+
+    #[test]
+    fn declining_battery_is_a_warning() {
+        // 600秒ごとに10mV、1日あたり1440mV下がっている
+        let readings = (0..6).rev().map(|i| reading(60 + i * 600, 3300 + i as i32 * 10, "good")).collect::<Vec<_>>();
+        let assessment = assess(&readings);
+        assert_eq!(assessment.status, HealthStatus::Warning);
+        assert_eq!(assessment.issues, vec![HealthIssue::BatteryDeclining]);
+        assert!((assessment.battery_trend.unwrap() + 1440.0).abs() < 1e-6);
+
+        // 計測値が少ない場合は傾向を求めない
+        assert_eq!(assess(&readings[4..]).battery_trend, None);
+    }
+
+    #[test]
+    fn low_battery_is_a_warning() {
+        let readings = (0..6).rev().map(|i| reading(60 + i * 600, 2900, "good")).collect::<Vec<_>>();
+        let assessment = assess(&readings);
+        assert_eq!(assessment.status, HealthStatus::Warning);
+        assert_eq!(assessment.issues, vec![HealthIssue::LowBattery]);
+    }
+
+    #[test]
+    fn degraded_network_is_a_warning() {
+        let mut readings = regular(6);
+        for reading in readings.iter_mut().take(3) {
+            reading.network_status = Some("poor".to_string());
+        }
+        let assessment = assess(&readings);
+        assert_eq!(assessment.status, HealthStatus::Warning);
+        assert_eq!(assessment.issues, vec![HealthIssue::DegradedNetwork]);
+
+        // 半分未満なら正常とする
+        readings[2].network_status = Some("good".to_string());
+        assert_eq!(assess(&readings).status, HealthStatus::Healthy);
+    }
+}
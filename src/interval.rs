@@ -0,0 +1,215 @@
+//! センサーに返す送信間隔の決定
+//!
+//! センサーに登録された送信間隔(`interval`)を基準に、水位の上昇中や警戒段階の閾値が近い場合は短く、
+//! 水位が安定している場合や電池の電圧が低い場合は長くする。送信間隔はセンサーごとの上下限(`min_interval`、`max_interval`)に収め、
+//! 決定した送信間隔と理由を最新のセンサーデータに記録する。
+
+use crate::env::EnvVars;
+
+/// 上下限が登録されていない場合の、基準の送信間隔に対する下限の割合
+const DEFAULT_MIN_RATIO: f64 = 0.25;
+/// 上下限が登録されていない場合の、基準の送信間隔に対する上限の倍率
+const DEFAULT_MAX_RATIO: f64 = 4.0;
+
+/// 送信間隔を変えた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum IntervalReason {
+    /// 水位が上昇している
+    Rising,
+    /// 警戒段階の閾値が近い
+    NearThreshold,
+    /// 水位が安定している
+    Stable,
+    /// 電池の電圧が低い
+    LowBattery,
+    /// 上下限に収めた
+    Clamped,
+}
+
+/// 送信間隔の決定方法
+#[derive(Debug, Clone)]
+pub struct IntervalPolicy {
+    /// 水位の上昇中とみなす変化率(メートル毎時)
+    pub rising_rate: f64,
+    /// 水位が安定しているとみなす変化率の大きさ(メートル毎時)
+    pub stable_rate: f64,
+    /// 電池の電圧が低いとみなす値
+    pub low_battery: i32,
+    /// 警戒段階の閾値が近いとみなす水位の差(メートル)
+    pub near_threshold: f64,
+}
+
+impl From<&EnvVars> for IntervalPolicy {
+    fn from(env: &EnvVars) -> Self {
+        IntervalPolicy {
+            rising_rate: env.interval_rising_rate,
+            stable_rate: env.interval_stable_rate,
+            low_battery: env.interval_low_battery,
+            near_threshold: env.interval_near_threshold,
+        }
+    }
+}
+
+/// 送信間隔の決定に使うセンサーの状態
+#[derive(Debug, Clone)]
+pub struct SensorState {
+    /// 登録された送信間隔(秒)
+    pub interval: i32,
+    pub min_interval: Option<i32>,
+    pub max_interval: Option<i32>,
+    /// 直近の水位の変化率(メートル毎時)。計測値が少なく求められない場合は`None`
+    pub rate: Option<f64>,
+    /// 最新の電池の電圧
+    pub battery_voltage: Option<i32>,
+    /// 水位から、まだ達していない最も低い警戒段階の閾値までの差(メートル)
+    pub threshold_margin: Option<f64>,
+}
+
+/// 決定した送信間隔
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntervalDecision {
+    pub interval: i32,
+    pub reasons: Vec<IntervalReason>,
+}
+
+impl IntervalPolicy {
+    /// 送信間隔を決める
+    ///
+    /// 水位の上昇中や閾値が近い場合は基準の1/4、安定している場合は2倍とし、電池の電圧が低い場合はさらに2倍にする。
+    /// 上下限が登録されていない場合は、基準の1/4から4倍までとする。
+    pub fn decide(&self, state: &SensorState) -> IntervalDecision {
+        // 送信間隔が登録されていないセンサーはそのまま返す
+        if state.interval <= 0 {
+            return IntervalDecision { interval: state.interval, reasons: Vec::new() };
+        }
+
+        let base = state.interval as f64;
+        let mut factor = 1.0;
+        let mut reasons = Vec::new();
+
+        let near_threshold = state.threshold_margin.is_some_and(|margin| margin <= self.near_threshold);
+
+        match state.rate {
+            Some(rate) if rate >= self.rising_rate => {
+                factor *= 0.25;
+                reasons.push(IntervalReason::Rising);
+            }
+            _ if near_threshold => {
+                factor *= 0.25;
+                reasons.push(IntervalReason::NearThreshold);
+            }
+            Some(rate) if rate.abs() <= self.stable_rate => {
+                factor *= 2.0;
+                reasons.push(IntervalReason::Stable);
+            }
+            _ => {}
+        }
+
+        if state.battery_voltage.is_some_and(|voltage| voltage < self.low_battery) {
+            factor *= 2.0;
+            reasons.push(IntervalReason::LowBattery);
+        }
+
+        let min = state.min_interval.unwrap_or((base * DEFAULT_MIN_RATIO).round() as i32).max(1);
+        let max = state.max_interval.unwrap_or((base * DEFAULT_MAX_RATIO).round() as i32).max(min);
+
+        let proposed = (base * factor).round() as i32;
+        let interval = proposed.clamp(min, max);
+        if interval != proposed {
+            reasons.push(IntervalReason::Clamped);
+        }
+
+        IntervalDecision { interval, reasons }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> IntervalPolicy {
+        IntervalPolicy {
+            rising_rate: 0.1,
+            stable_rate: 0.01,
+            low_battery: 3000,
+            near_threshold: 0.3,
+        }
+    }
+
+    fn state(rate: Option<f64>) -> SensorState {
+        SensorState {
+            interval: 600,
+            min_interval: None,
+            max_interval: None,
+            rate,
+            battery_voltage: Some(3300),
+            threshold_margin: None,
+        }
+    }
+
+    #[test]
+    fn rising_water_shortens_interval() {
+        let decision = policy().decide(&state(Some(0.2)));
+        assert_eq!(decision, IntervalDecision { interval: 150, reasons: vec![IntervalReason::Rising] });
+    }
+
+    #[test]
+    fn near_threshold_shortens_interval() {
+        let decision = policy().decide(&SensorState { threshold_margin: Some(0.2), ..state(Some(0.05)) });
+        assert_eq!(decision, IntervalDecision { interval: 150, reasons: vec![IntervalReason::NearThreshold] });
+
+        // 閾値が近ければ水位が安定していても短くする
+        let decision = policy().decide(&SensorState { threshold_margin: Some(0.3), ..state(Some(0.0)) });
+        assert_eq!(decision.reasons, vec![IntervalReason::NearThreshold]);
+
+        let decision = policy().decide(&SensorState { threshold_margin: Some(0.5), ..state(Some(0.05)) });
+        assert_eq!(decision, IntervalDecision { interval: 600, reasons: Vec::new() });
+    }
+
+    #[test]
+    fn stable_level_lengthens_interval() {
+        let decision = policy().decide(&state(Some(-0.005)));
+        assert_eq!(decision, IntervalDecision { interval: 1200, reasons: vec![IntervalReason::Stable] });
+
+        // 変化率が求められない場合は基準のまま
+        let decision = policy().decide(&state(None));
+        assert_eq!(decision, IntervalDecision { interval: 600, reasons: Vec::new() });
+    }
+
+    #[test]
+    fn low_battery_lengthens_interval() {
+        let decision = policy().decide(&SensorState { battery_voltage: Some(2900), ..state(Some(0.005)) });
+        assert_eq!(decision, IntervalDecision { interval: 2400, reasons: vec![IntervalReason::Stable, IntervalReason::LowBattery] });
+
+        let decision = policy().decide(&SensorState { battery_voltage: Some(2900), ..state(Some(0.2)) });
+        assert_eq!(decision, IntervalDecision { interval: 300, reasons: vec![IntervalReason::Rising, IntervalReason::LowBattery] });
+    }
+
+    #[test]
+    fn interval_is_clamped_to_registered_limits() {
+        let decision = policy().decide(&SensorState { min_interval: Some(300), ..state(Some(0.2)) });
+        assert_eq!(decision, IntervalDecision { interval: 300, reasons: vec![IntervalReason::Rising, IntervalReason::Clamped] });
+
+        let decision = policy().decide(&SensorState { max_interval: Some(900), ..state(Some(0.0)) });
+        assert_eq!(decision, IntervalDecision { interval: 900, reasons: vec![IntervalReason::Stable, IntervalReason::Clamped] });
+    }
+
+    #[test]
+    fn default_limits_are_relative_to_interval() {
+        // 安定していて電池の電圧が低い場合の4倍は、既定の上限と同じなので収めない
+        let decision = policy().decide(&SensorState { battery_voltage: Some(2900), ..state(Some(0.0)) });
+        assert_eq!(decision.interval, 2400);
+        assert!(!decision.reasons.contains(&IntervalReason::Clamped));
+
+        // 上限が下限より小さい場合は下限に揃える
+        let decision = policy().decide(&SensorState { min_interval: Some(700), max_interval: Some(500), ..state(None) });
+        assert_eq!(decision, IntervalDecision { interval: 700, reasons: vec![IntervalReason::Clamped] });
+    }
+
+    #[test]
+    fn unregistered_interval_is_kept() {
+        let decision = policy().decide(&SensorState { interval: 0, ..state(Some(0.2)) });
+        assert_eq!(decision, IntervalDecision { interval: 0, reasons: Vec::new() });
+    }
+}
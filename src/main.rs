@@ -1,36 +1,82 @@
+use axum::http::header::AUTHORIZATION;
 use axum::http::Method;
+use axum::middleware::{from_fn, from_fn_with_state};
 use axum::routing::get;
-use axum::serve;
+use axum::{serve, Router};
+use clap::Parser;
 use openapi::server::new;
 use tokio::net::TcpListener;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
-use crate::apis::ServerImpl;
+use crate::apis::{capture_signed_body, ServerImpl};
+use crate::auth::authorize;
+use crate::cli::{Cli, Command};
 use crate::env::EnvVars;
 
+mod affects;
+mod alert;
 mod apis;
+mod auth;
 mod cache;
+mod calibration;
+mod cli;
+mod db;
 mod env;
+mod export;
+mod forecast;
+mod health;
+mod interval;
+mod migration;
+mod provision;
+mod qc;
+mod retention;
+mod store;
+mod water_level;
+mod webhook;
 #[derive(Clone)]
 struct ServerState {}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve_api().await,
+        Command::Export(args) => cli::run_export(args).await.unwrap(),
+        Command::Retention(args) => cli::run_retention(args).await.unwrap(),
+        Command::Migrate(args) => cli::run_migrate(args).await.unwrap(),
+        Command::Relink(args) => cli::run_relink(args).await.unwrap(),
+        Command::WebhookSink(args) => webhook::sink::run_sink(args).await.unwrap(),
+    }
+}
+
+async fn serve_api() {
     let env = EnvVars::read_env().unwrap();
-    let EnvVars { server_host, client_host, .. } = env;
 
-    let listener = TcpListener::bind(server_host.clone()).await.unwrap();
+    let listener = TcpListener::bind(env.server_host.clone()).await.unwrap();
+
+    let server_impl = ServerImpl::new().await;
+    server_impl.spawn_background_tasks();
 
-    let router = new(ServerImpl::new().await)
+    serve(listener, router(server_impl, &env)).await.unwrap();
+}
+
+/// 認証・認可とCORSを含むルーターを作る
+fn router(server_impl: ServerImpl, env: &EnvVars) -> Router {
+    let server_host = env.server_host.clone();
+    let auth = server_impl.auth();
+
+    new(server_impl)
         .route("/", get(|| async move {
             const VERSION: &'static str = env!("CARGO_PKG_VERSION");
             format!("Hello, Nahlun! by {server_host}\nI'm Server Container v{VERSION}.\n")
         }))
+        .layer(from_fn(capture_signed_body))
+        .layer(from_fn_with_state(auth, authorize))
         .layer(
             CorsLayer::new()
-                .allow_origin(AllowOrigin::exact(client_host.parse().unwrap()))
-                .allow_methods(vec![Method::GET]),
-        );
-
-    serve(listener, router).await.unwrap();
+                .allow_origin(AllowOrigin::exact(env.client_host.parse().unwrap()))
+                .allow_methods(vec![Method::GET])
+                .allow_headers([AUTHORIZATION]),
+        )
 }
@@ -0,0 +1,224 @@
+//! Neo4jのスキーマ(制約・インデックス)の作成と移行
+//!
+//! 移行はバージョン順に並べ、適用したものを`(:SchemaMigration {version, description, applied_at})`として
+//! 記録する。起動時と`migrate`サブコマンドで、記録されている最新のバージョンより新しい移行のみ適用する。
+//!
+//! Neo4jではスキーマの変更とデータの書き込みを同じトランザクションで行えないため、各文は個別に実行してから
+//! 移行を記録する。途中で失敗した場合に再び実行できるよう、各文は`IF NOT EXISTS`などで冪等にする。
+//! 一意制約を作成できなかった場合は、既存のデータで重複している値を表示する。
+
+use chrono::Utc;
+use neo4rs::{query, Graph};
+
+/// タイルの階層`(:Tile0)-[:CHILD]->...`の最大のズームレベル(`hilbert18`と同じ)
+const MAX_TILE_ZOOM: u8 = 18;
+
+/// スキーマの移行
+struct Migration {
+    version: i64,
+    description: &'static str,
+    statements: fn() -> Vec<String>,
+}
+
+/// すべての移行(バージョンの昇順)
+///
+/// 適用済みの移行は変更せず、変更が必要な場合は新しいバージョンを追加する。
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "unique sensors, river nodes and tiles",
+        statements: || {
+            let mut statements = vec![
+                "CREATE CONSTRAINT sensor_id IF NOT EXISTS FOR (n:Sensor) REQUIRE n.id IS UNIQUE".to_string(),
+                "CREATE CONSTRAINT river_node_hilbert18 IF NOT EXISTS FOR (n:RiverNode) REQUIRE n.hilbert18 IS UNIQUE".to_string(),
+            ];
+            // タイルはズームレベルごとのラベルで、座標の組が一意
+            statements.extend((0..=MAX_TILE_ZOOM).map(|z| {
+                format!("CREATE CONSTRAINT tile{z}_xy IF NOT EXISTS FOR (n:Tile{z}) REQUIRE (n.x, n.y) IS UNIQUE")
+            }));
+            statements
+        },
+    },
+    Migration {
+        version: 2,
+        description: "point index on river node locations",
+        statements: || vec![
+            "CREATE POINT INDEX river_node_location IF NOT EXISTS FOR (n:RiverNode) ON (n.location)".to_string(),
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "unique api tokens, pending sensors and schema migrations",
+        statements: || vec![
+            "CREATE CONSTRAINT api_token_hash IF NOT EXISTS FOR (n:ApiToken) REQUIRE n.hash IS UNIQUE".to_string(),
+            "CREATE CONSTRAINT pending_sensor_id IF NOT EXISTS FOR (n:PendingSensor) REQUIRE n.id IS UNIQUE".to_string(),
+            "CREATE CONSTRAINT schema_migration_version IF NOT EXISTS FOR (n:SchemaMigration) REQUIRE n.version IS UNIQUE".to_string(),
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "river system membership as IN_SYSTEM",
+        // タイルの階層と同じ MEMBER を使っていた水系の所属を、IN_SYSTEM に付け替える
+        statements: || vec![
+            "MATCH (riverSystem:RiverSystem)-[member:MEMBER]->(node:RiverNode) MERGE (riverSystem)-[:IN_SYSTEM]->(node) DELETE member".to_string(),
+        ],
+    },
+];
+
+/// 一意制約を付けるラベルとプロパティ(タイルの座標の組を除く)
+const UNIQUE_KEYS: &[(&str, &str)] = &[
+    ("Sensor", "id"),
+    ("RiverNode", "hilbert18"),
+    ("ApiToken", "hash"),
+    ("PendingSensor", "id"),
+    ("SchemaMigration", "version"),
+];
+
+/// 適用した移行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Applied {
+    pub version: i64,
+    pub description: &'static str,
+}
+
+/// スキーマを移行した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrated {
+    /// 移行前のバージョン(一度も移行していない場合は0)
+    pub from: i64,
+    /// 適用した(`dry_run`の場合は適用する)移行
+    pub applied: Vec<Applied>,
+}
+
+impl Migrated {
+    /// 移行後のバージョン
+    pub fn to(&self) -> i64 {
+        self.applied.last().map_or(self.from, |applied| applied.version)
+    }
+}
+
+/// グラフに記録されている最新のスキーマのバージョン
+async fn schema_version(graph: &Graph) -> anyhow::Result<i64> {
+    let mut result = graph.execute(query(
+        r#"
+OPTIONAL MATCH (migration:SchemaMigration)
+RETURN coalesce(max(migration.version), 0) AS version
+        "#,
+    )).await?;
+
+    let mut version = 0;
+    while let Some(row) = result.next().await? {
+        version = row.get::<i64>("version")?;
+    }
+
+    Ok(version)
+}
+
+/// 一意制約を付けるプロパティで、複数のノードが同じ値を持つもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    pub label: &'static str,
+    pub property: &'static str,
+    pub value: String,
+    /// 同じ値を持つノードの数
+    pub nodes: i64,
+}
+
+/// 一意制約を付けるプロパティで重複している値を、ラベルごとに最大100件返す
+pub async fn find_duplicates(graph: &Graph) -> anyhow::Result<Vec<Duplicate>> {
+    let mut duplicates = Vec::new();
+    for &(label, property) in UNIQUE_KEYS {
+        let mut result = graph.execute(query(&format!(
+            r#"
+MATCH (n:{label})
+WHERE n.{property} IS NOT NULL
+WITH n.{property} AS value, count(n) AS nodes
+WHERE nodes > 1
+RETURN toString(value) AS value, nodes
+ORDER BY value
+LIMIT 100
+            "#,
+        ))).await?;
+
+        while let Some(row) = result.next().await? {
+            duplicates.push(Duplicate { label, property, value: row.get("value")?, nodes: row.get("nodes")? });
+        }
+    }
+
+    Ok(duplicates)
+}
+
+/// 一意制約を作成できなかった原因として、重複している値を表示する
+async fn report_duplicates(graph: &Graph) {
+    match find_duplicates(graph).await {
+        Ok(duplicates) => {
+            for Duplicate { label, property, value, nodes } in duplicates {
+                println!("migrate: {nodes} {label} nodes have the same {property} {value:?}");
+            }
+        }
+        Err(e) => println!("migrate: failed to find duplicate keys: {:?}", e),
+    }
+}
+
+/// 未適用の移行を順に適用する
+///
+/// `dry_run`の場合は何も変更せず、適用する移行を返す。
+/// グラフのバージョンがこのサーバーの知る最新のバージョンより新しい場合は、何もせずに警告を表示する。
+pub async fn migrate(graph: &Graph, dry_run: bool) -> anyhow::Result<Migrated> {
+    let from = schema_version(graph).await?;
+
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if from > latest {
+        println!("migrate: schema version {from} is newer than the latest known version {latest}");
+    }
+
+    let mut migrated = Migrated { from, applied: Vec::new() };
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > from) {
+        if !dry_run {
+            // 1. 制約・インデックスを作成
+            for statement in (migration.statements)() {
+                if let Err(e) = graph.run(query(&statement)).await {
+                    report_duplicates(graph).await;
+                    anyhow::bail!("Schema migration {} failed at `{statement}`: {e}", migration.version);
+                }
+            }
+
+            // 2. 適用した移行を記録
+            graph.run(query(
+                r#"
+MERGE (migration:SchemaMigration {version: $version})
+SET migration.description = $description,
+    migration.applied_at = $applied_at
+                "#,
+            )
+                .param("version", migration.version)
+                .param("description", migration.description)
+                .param("applied_at", Utc::now().to_rfc3339())
+            ).await?;
+        }
+
+        migrated.applied.push(Applied { version: migration.version, description: migration.description });
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_ascending_from_one() {
+        let versions = MIGRATIONS.iter().map(|migration| migration.version).collect::<Vec<_>>();
+        assert_eq!(versions, (1..=MIGRATIONS.len() as i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unique_keys_have_constraints() {
+        let statements = MIGRATIONS.iter().flat_map(|migration| (migration.statements)()).collect::<Vec<_>>();
+        for (label, property) in UNIQUE_KEYS {
+            let constraint = format!("FOR (n:{label}) REQUIRE n.{property} IS UNIQUE");
+            assert!(statements.iter().any(|statement| statement.ends_with(&constraint)), "{label}.{property}");
+        }
+    }
+}
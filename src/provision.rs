@@ -0,0 +1,167 @@
+//! 登録されていないセンサーの自動プロビジョニング
+//!
+//! `SENSOR_AUTO_PROVISION`を有効にすると、登録されていないセンサーからの送信を`(:PendingSensor)`として
+//! 承認待ちの一覧に記録する。署名用シークレットがないため署名は検証できず、計測値も登録しない。
+//! 誰でも送信できるため、承認待ちのセンサーの数は`SENSOR_PENDING_LIMIT`までとする。
+//! オペレーターが河川ノードに割り当てるとセンサーとして登録され、署名用シークレットが発行される。
+
+use chrono::{DateTime, Utc};
+use neo4rs::{query, Graph};
+use openapi::models::SensorReading;
+use serde::Deserialize;
+
+/// 承認待ちのセンサー
+#[derive(Debug, Clone, Deserialize)]
+pub struct PendingSensor {
+    pub id: String,
+    /// 初めて送信があった日時
+    pub first_seen: String,
+    /// 最後に送信があった日時
+    pub last_seen: String,
+    /// 送信があった回数
+    pub reports: i64,
+    pub distance: f64,
+    pub battery_voltage: i64,
+    pub previous_sleep_time: i64,
+    pub network_status: String,
+    /// センサーが送った計測時刻
+    pub time: Option<String>,
+}
+
+impl TryFrom<PendingSensor> for openapi::models::PendingSensor {
+    type Error = anyhow::Error;
+
+    fn try_from(pending: PendingSensor) -> Result<Self, Self::Error> {
+        let PendingSensor { id, first_seen, last_seen, reports, distance, battery_voltage, previous_sleep_time, network_status, time } = pending;
+        let parse_time = |time: &str| anyhow::Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc));
+
+        Ok(openapi::models::PendingSensor {
+            id: id.parse()?,
+            first_seen: parse_time(&first_seen)?,
+            last_seen: parse_time(&last_seen)?,
+            reports,
+            last_reading: SensorReading {
+                distance: distance as f32,
+                battery_voltage: battery_voltage as i32,
+                previous_sleep_time: previous_sleep_time as i32,
+                network_status,
+                time: time.as_deref().map(parse_time).transpose()?,
+            },
+        })
+    }
+}
+
+/// 登録されていないセンサーからの送信を承認待ちとして記録する
+///
+/// 既に承認待ちのセンサーは、送信回数と最後の計測値を更新する。承認待ちのセンサーが`limit`台に
+/// 達している場合は新しいセンサーを記録せず、`false`を返す。
+pub async fn record_pending(graph: &Graph, id: &str, reading: &SensorReading, now: DateTime<Utc>, limit: i64) -> anyhow::Result<bool> {
+    let mut result = graph.execute(query(
+        r#"
+// 1. 既に承認待ちか、承認待ちの数が上限未満の場合のみ記録する
+OPTIONAL MATCH (existing:PendingSensor {id: $id})
+CALL {
+    MATCH (queued:PendingSensor)
+    RETURN count(queued) AS queued
+}
+WITH existing, queued
+WHERE existing IS NOT NULL OR queued < $limit
+
+// 2. 記録する
+MERGE (pending:PendingSensor {id: $id})
+ON CREATE SET pending.first_seen = $now,
+              pending.reports = 0
+SET pending.last_seen = $now,
+    pending.reports = pending.reports + 1,
+    pending.distance = $distance,
+    pending.battery_voltage = $battery_voltage,
+    pending.previous_sleep_time = $previous_sleep_time,
+    pending.network_status = $network_status,
+    pending.time = $time
+RETURN count(pending) AS recorded
+        "#,
+    )
+        .param("id", id)
+        .param("limit", limit)
+        .param("now", now.to_rfc3339())
+        .param("distance", reading.distance)
+        .param("battery_voltage", reading.battery_voltage)
+        .param("previous_sleep_time", reading.previous_sleep_time)
+        .param("network_status", reading.network_status.clone())
+        .param("time", reading.time.map(|time| time.to_rfc3339()))
+    ).await?;
+
+    let mut recorded = 0;
+    while let Some(row) = result.next().await? {
+        recorded = row.get::<i64>("recorded")?;
+    }
+
+    Ok(recorded > 0)
+}
+
+/// 承認待ちのセンサーを、初めて送信があった順に取得する
+pub async fn list_pending(graph: &Graph) -> anyhow::Result<Vec<PendingSensor>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (pending:PendingSensor)
+RETURN pending.id AS id,
+       pending.first_seen AS first_seen,
+       pending.last_seen AS last_seen,
+       pending.reports AS reports,
+       toFloat(pending.distance) AS distance,
+       pending.battery_voltage AS battery_voltage,
+       pending.previous_sleep_time AS previous_sleep_time,
+       pending.network_status AS network_status,
+       pending.time AS time
+ORDER BY datetime(pending.first_seen)
+        "#,
+    )).await?;
+
+    let mut pending = Vec::new();
+    while let Some(row) = result.next().await? {
+        pending.push(row.to()?);
+    }
+
+    Ok(pending)
+}
+
+/// センサーが承認待ちかどうか
+pub async fn is_pending(graph: &Graph, id: &str) -> anyhow::Result<bool> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (pending:PendingSensor {id: $id})
+RETURN count(pending) AS pending
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut pending = 0;
+    while let Some(row) = result.next().await? {
+        pending = row.get::<i64>("pending")?;
+    }
+
+    Ok(pending > 0)
+}
+
+/// 承認待ちの一覧から取り除く
+///
+/// 承認待ちでなかった場合は`false`を返す。
+pub async fn remove_pending(graph: &Graph, id: &str) -> anyhow::Result<bool> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (pending:PendingSensor {id: $id})
+DELETE pending
+RETURN count(*) AS removed
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut removed = 0;
+    while let Some(row) = result.next().await? {
+        removed = row.get::<i64>("removed")?;
+    }
+
+    Ok(removed > 0)
+}
@@ -0,0 +1,186 @@
+//! 受信した計測値の品質管理(QC)
+//!
+//! 計測値ごとに範囲・変化率・直近の中央値からの乖離を検査し、結果を`SensorData`の`qc_status`と
+//! `qc_reasons`に記録する。棄却された計測値は水位の計算に使わない。
+
+use chrono::{DateTime, Utc};
+
+use crate::env::EnvVars;
+
+/// QCの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum QcStatus {
+    Ok,
+    Rejected,
+}
+
+/// 棄却した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum QcReason {
+    /// 距離が測定範囲外
+    Range,
+    /// 直前の計測値からの変化が速すぎる
+    RateOfChange,
+    /// 直近の計測値の中央値から離れすぎている
+    MedianDeviation,
+}
+
+/// QCの設定
+#[derive(Debug, Clone)]
+pub struct QcConfig {
+    /// 距離の上限(メートル)
+    pub max_distance: f64,
+    /// 水位の変化率の上限(メートル毎分)
+    pub max_rate: f64,
+    /// 中央値を求める計測値の数
+    pub window: usize,
+    /// 中央値からの乖離の上限(メートル)
+    pub median_threshold: f64,
+}
+
+impl From<&EnvVars> for QcConfig {
+    fn from(env: &EnvVars) -> Self {
+        QcConfig {
+            max_distance: env.qc_max_distance,
+            max_rate: env.qc_max_rate,
+            window: env.qc_window,
+            median_threshold: env.qc_median_threshold,
+        }
+    }
+}
+
+/// 過去の計測値
+#[derive(Debug, Clone)]
+pub struct QcSample {
+    pub water_level: f64,
+    pub time: DateTime<Utc>,
+}
+
+/// QCの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct QcResult {
+    pub status: QcStatus,
+    pub reasons: Vec<QcReason>,
+}
+
+impl QcConfig {
+    /// 計測値を検査する
+    ///
+    /// `distance`は校正後の距離。`previous`は計測時刻より前に受け付けた最新の計測値、
+    /// `recent`は計測時刻より前の計測値の水位を棄却されたものも含めて新しい順に並べたもの。
+    ///
+    /// 中央値は検査する計測値も含めた窓で求めるので、一度だけの外れ値は棄却される一方、
+    /// 水位が実際に急変して同じ水準の計測値が続いた場合は中央値が追従して受け付けるようになる。
+    pub fn check(&self, altitude: f64, distance: f64, time: DateTime<Utc>, previous: Option<&QcSample>, recent: &[f64]) -> QcResult {
+        let water_level = altitude - distance;
+        let mut reasons = Vec::new();
+
+        // 水面がセンサーより上にあることはなく、測定範囲より遠い距離も信用できない
+        if !(0.0..=self.max_distance).contains(&distance) {
+            reasons.push(QcReason::Range);
+        }
+
+        if let Some(previous) = previous {
+            let minutes = (time - previous.time).num_milliseconds() as f64 / 60_000.0;
+            if minutes > 0.0 && (water_level - previous.water_level).abs() / minutes > self.max_rate {
+                reasons.push(QcReason::RateOfChange);
+            }
+        }
+
+        if !recent.is_empty() {
+            let mut window = recent.iter()
+                .take(self.window.saturating_sub(1))
+                .copied()
+                .chain([water_level])
+                .collect::<Vec<_>>();
+            window.sort_by(f64::total_cmp);
+            let median = if window.len() % 2 == 0 {
+                (window[window.len() / 2 - 1] + window[window.len() / 2]) / 2.0
+            } else {
+                window[window.len() / 2]
+            };
+
+            if (water_level - median).abs() > self.median_threshold {
+                reasons.push(QcReason::MedianDeviation);
+            }
+        }
+
+        let status = if reasons.is_empty() { QcStatus::Ok } else { QcStatus::Rejected };
+
+        QcResult { status, reasons }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    fn config() -> QcConfig {
+        QcConfig {
+            max_distance: 30.0,
+            max_rate: 0.5,
+            window: 5,
+            median_threshold: 2.0,
+        }
+    }
+
+    #[test]
+    fn distance_out_of_range_is_rejected() {
+        let now = Utc::now();
+
+        assert_eq!(config().check(10.0, 0.0, now, None, &[]).status, QcStatus::Ok);
+        assert_eq!(config().check(10.0, 30.0, now, None, &[]).status, QcStatus::Ok);
+        assert_eq!(config().check(10.0, -0.1, now, None, &[]).reasons, vec![QcReason::Range]);
+        assert_eq!(config().check(10.0, 30.1, now, None, &[]).reasons, vec![QcReason::Range]);
+    }
+
+    #[test]
+    fn fast_change_from_previous_reading_is_rejected() {
+        let now = Utc::now();
+        let previous = QcSample { water_level: 8.0, time: now - Duration::minutes(2) };
+
+        // 2分で0.4メートルは毎分0.2メートル
+        assert_eq!(config().check(10.0, 1.6, now, Some(&previous), &[]).status, QcStatus::Ok);
+        // 2分で2メートルは毎分1メートル
+        assert_eq!(config().check(10.0, 0.0, now, Some(&previous), &[]).reasons, vec![QcReason::RateOfChange]);
+
+        // 同じ時刻の計測値とは変化率を求めない
+        let simultaneous = QcSample { water_level: 8.0, time: now };
+        assert_eq!(config().check(10.0, 0.0, now, Some(&simultaneous), &[]).status, QcStatus::Ok);
+    }
+
+    #[test]
+    fn spike_away_from_recent_median_is_rejected() {
+        let now = Utc::now();
+
+        // 窓は[8.0, 8.1, 7.9, 8.0, 3.0]で中央値は8.0
+        let result = config().check(10.0, 7.0, now, None, &[8.0, 8.1, 7.9, 8.0, 8.2]);
+        assert_eq!(result.status, QcStatus::Rejected);
+        assert_eq!(result.reasons, vec![QcReason::MedianDeviation]);
+
+        // 比較する計測値がなければ検査しない
+        assert_eq!(config().check(10.0, 7.0, now, None, &[]).status, QcStatus::Ok);
+    }
+
+    #[test]
+    fn median_follows_a_sustained_change() {
+        let now = Utc::now();
+
+        // 窓は[3.0, 3.1, 8.0, 8.0, 3.0]で中央値は3.1
+        let result = config().check(10.0, 7.0, now, None, &[3.0, 3.1, 8.0, 8.0]);
+        assert_eq!(result.status, QcStatus::Ok);
+    }
+
+    #[test]
+    fn every_failed_check_is_reported() {
+        let now = Utc::now();
+        let previous = QcSample { water_level: 8.0, time: now - Duration::minutes(1) };
+
+        let result = config().check(10.0, 31.0, now, Some(&previous), &[8.0, 8.0, 8.0, 8.0]);
+        assert_eq!(result.reasons, vec![QcReason::Range, QcReason::RateOfChange, QcReason::MedianDeviation]);
+    }
+}
@@ -0,0 +1,636 @@
+//! センサーデータの保存期間の管理
+//!
+//! `PREVIOUS_DATA`の連結リストのうち、保存期間(`retention_raw_days`)を過ぎたセンサーデータを1時間ごとの集計
+//! `(:Sensor)-[:SUMMARY]->(:SensorDataSummary {resolution: 'hourly'})`にまとめてから削除する。
+//! さらに`retention_hourly_days`を過ぎた1時間ごとの集計は1日ごとの集計(`resolution: 'daily'`)にまとめる。
+//!
+//! 連結リストは計測時刻順に並んでいるため、ある時刻より古いデータは常にリストの末尾に連続している。
+//! 削除は末尾から新しい方へ1日分ずつ行い、次の日は削除したデータの一つ新しいデータからたどるので、
+//! 連結リストの全体をたどるのは末尾を探す最初の一度だけになる。最新のデータ(`CURRENT_DATA`)は保存期間を過ぎていても削除しない。
+//! 集計の区切りはサーバーのタイムゾーンで行う。
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, DurationRound, Local, NaiveTime, TimeDelta, TimeZone, Utc};
+use neo4rs::{query, BoltType, Graph, Txn};
+use serde::Deserialize;
+
+use crate::env::EnvVars;
+
+/// 集計の単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Resolution {
+    Hourly,
+    Daily,
+}
+
+impl Resolution {
+    /// `time`を含む集計期間の開始時刻
+    ///
+    /// 夏時間の切り替えで0時が存在しない日は、切り替え後の最初の時刻を1日の始まりとする。
+    fn start_of<Tz: TimeZone>(&self, time: DateTime<Tz>) -> anyhow::Result<DateTime<Tz>> {
+        match self {
+            Resolution::Hourly => Ok(time.duration_trunc(TimeDelta::hours(1))?),
+            Resolution::Daily => {
+                let date = time.date_naive();
+                (0..24)
+                    .find_map(|hour| date.and_time(NaiveTime::MIN + TimeDelta::hours(hour)).and_local_timezone(time.timezone()).earliest())
+                    .ok_or_else(|| anyhow::anyhow!("No local time on {date}"))
+            }
+        }
+    }
+
+    /// `start`から始まる集計期間の終了時刻
+    fn end_of<Tz: TimeZone>(&self, start: DateTime<Tz>) -> anyhow::Result<DateTime<Tz>> {
+        match self {
+            Resolution::Hourly => Ok(start + TimeDelta::hours(1)),
+            Resolution::Daily => Resolution::Daily.start_of(start + TimeDelta::hours(25)),
+        }
+    }
+}
+
+/// 集計期間内のセンサーデータの集計
+///
+/// 平均は`sum_distance / count`で求める。後から同じ期間のデータを追加できるよう、平均ではなく合計を保存する。
+#[derive(Debug, Clone, Default)]
+struct Summary {
+    /// QCで受け付けたデータの数
+    count: i64,
+    /// QCで棄却されたデータの数
+    rejected: i64,
+    min_distance: Option<f64>,
+    max_distance: Option<f64>,
+    sum_distance: f64,
+    min_battery_voltage: Option<i64>,
+    max_battery_voltage: Option<i64>,
+}
+
+fn min_of<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+fn max_of<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b > a { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Summary {
+    fn add(&mut self, row: &RawRow) {
+        if row.qc_status.as_deref() == Some("rejected") {
+            self.rejected += 1;
+        } else if let Some(distance) = row.distance {
+            self.count += 1;
+            self.min_distance = min_of(self.min_distance, Some(distance));
+            self.max_distance = max_of(self.max_distance, Some(distance));
+            self.sum_distance += distance;
+        }
+
+        self.min_battery_voltage = min_of(self.min_battery_voltage, row.battery_voltage);
+        self.max_battery_voltage = max_of(self.max_battery_voltage, row.battery_voltage);
+    }
+
+    fn merge(&mut self, other: &Summary) {
+        self.count += other.count;
+        self.rejected += other.rejected;
+        self.min_distance = min_of(self.min_distance, other.min_distance);
+        self.max_distance = max_of(self.max_distance, other.max_distance);
+        self.sum_distance += other.sum_distance;
+        self.min_battery_voltage = min_of(self.min_battery_voltage, other.min_battery_voltage);
+        self.max_battery_voltage = max_of(self.max_battery_voltage, other.max_battery_voltage);
+    }
+}
+
+/// Neo4jから取得した、削除するセンサーデータ
+#[derive(Debug, Deserialize)]
+struct RawRow {
+    id: String,
+    time: String,
+    distance: Option<f64>,
+    battery_voltage: Option<i64>,
+    qc_status: Option<String>,
+}
+
+/// Neo4jから取得した、1日ごとの集計にまとめる1時間ごとの集計
+#[derive(Debug, Deserialize)]
+struct SummaryRow {
+    id: String,
+    start: String,
+    count: i64,
+    rejected: i64,
+    min_distance: Option<f64>,
+    max_distance: Option<f64>,
+    sum_distance: f64,
+    min_battery_voltage: Option<i64>,
+    max_battery_voltage: Option<i64>,
+}
+
+impl From<&SummaryRow> for Summary {
+    fn from(row: &SummaryRow) -> Self {
+        Summary {
+            count: row.count,
+            rejected: row.rejected,
+            min_distance: row.min_distance,
+            max_distance: row.max_distance,
+            sum_distance: row.sum_distance,
+            min_battery_voltage: row.min_battery_voltage,
+            max_battery_voltage: row.max_battery_voltage,
+        }
+    }
+}
+
+/// センサーごとの処理結果(ドライランの場合は処理する予定の内容)
+#[derive(Debug, Clone, Default)]
+pub struct SensorRetention {
+    pub id: String,
+    /// 削除したセンサーデータの数
+    pub raw_removed: i64,
+    /// 削除したセンサーデータのうち最も古い計測時刻
+    pub oldest: Option<String>,
+    /// 削除したセンサーデータのうち最も新しい計測時刻
+    pub newest: Option<String>,
+    /// 1日ごとの集計にまとめた1時間ごとの集計の数
+    pub hourly_rolled_up: i64,
+}
+
+/// 保存期間
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// この時刻より前のセンサーデータを1時間ごとの集計にまとめる
+    pub raw_cutoff: DateTime<Local>,
+    /// この時刻より前に始まる1時間ごとの集計を1日ごとの集計にまとめる
+    pub hourly_cutoff: DateTime<Local>,
+}
+
+impl RetentionPolicy {
+    pub fn new(env: &EnvVars, now: DateTime<Utc>) -> Self {
+        let now = now.with_timezone(&Local);
+        RetentionPolicy {
+            raw_cutoff: now - TimeDelta::days(env.retention_raw_days as i64),
+            hourly_cutoff: now - TimeDelta::days(env.retention_hourly_days as i64),
+        }
+    }
+}
+
+fn parse_local(time: &str) -> anyhow::Result<DateTime<Local>> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Local))
+}
+
+/// 集計を保存する。同じ期間の集計が既にある場合は足し合わせる
+async fn save_summaries(tnx: &mut Txn, id: &str, resolution: Resolution, summaries: &BTreeMap<DateTime<Local>, Summary>) -> anyhow::Result<()> {
+    let summaries = summaries.iter()
+        .map(|(start, summary)| anyhow::Ok(HashMap::from([
+            ("start".to_string(), BoltType::from(start.to_rfc3339())),
+            ("end".to_string(), BoltType::from(resolution.end_of(*start)?.to_rfc3339())),
+            ("count".to_string(), BoltType::from(summary.count)),
+            ("rejected".to_string(), BoltType::from(summary.rejected)),
+            ("min_distance".to_string(), BoltType::from(summary.min_distance)),
+            ("max_distance".to_string(), BoltType::from(summary.max_distance)),
+            ("sum_distance".to_string(), BoltType::from(summary.sum_distance)),
+            ("min_battery_voltage".to_string(), BoltType::from(summary.min_battery_voltage)),
+            ("max_battery_voltage".to_string(), BoltType::from(summary.max_battery_voltage)),
+        ])))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    tnx.run(query(
+        r#"
+// 1. 集計期間ごとの集計を取得または作成
+MATCH (sensor:Sensor {id: $id})
+UNWIND $summaries AS rollup
+MERGE (sensor)-[:SUMMARY]->(summary:SensorDataSummary {resolution: $resolution, start: rollup.start})
+ON CREATE SET summary.end = rollup.end,
+              summary.count = 0,
+              summary.rejected = 0,
+              summary.sum_distance = 0.0
+
+// 2. 足し合わせる(最小値・最大値は片方がnullの場合もう片方を使う)
+SET summary.count = summary.count + rollup.count,
+    summary.rejected = summary.rejected + rollup.rejected,
+    summary.sum_distance = summary.sum_distance + rollup.sum_distance,
+    summary.min_distance = coalesce(CASE WHEN rollup.min_distance < summary.min_distance THEN rollup.min_distance ELSE summary.min_distance END, rollup.min_distance),
+    summary.max_distance = coalesce(CASE WHEN rollup.max_distance > summary.max_distance THEN rollup.max_distance ELSE summary.max_distance END, rollup.max_distance),
+    summary.min_battery_voltage = coalesce(CASE WHEN rollup.min_battery_voltage < summary.min_battery_voltage THEN rollup.min_battery_voltage ELSE summary.min_battery_voltage END, rollup.min_battery_voltage),
+    summary.max_battery_voltage = coalesce(CASE WHEN rollup.max_battery_voltage > summary.max_battery_voltage THEN rollup.max_battery_voltage ELSE summary.max_battery_voltage END, rollup.max_battery_voltage)
+        "#,
+    )
+        .param("id", id)
+        .param("resolution", resolution.to_string())
+        .param("summaries", summaries)
+    ).await?;
+
+    Ok(())
+}
+
+/// 連結リストの末尾にある、最新のデータ以外で最も古いセンサーデータの`elementId`と計測時刻
+async fn raw_tail(graph: &Graph, id: &str) -> anyhow::Result<Option<(String, DateTime<Local>)>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (:Sensor {id: $id})-[:CURRENT_DATA]->(:SensorData)-[:PREVIOUS_DATA*1..]->(tail:SensorData)
+WHERE NOT (tail)-[:PREVIOUS_DATA]->()
+RETURN elementId(tail) AS id, tail.time AS time
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut tail = None;
+    while let Some(row) = result.next().await? {
+        tail = Some((row.get::<String>("id")?, parse_local(&row.get::<String>("time")?)?));
+    }
+
+    Ok(tail)
+}
+
+/// 連結リストの末尾`tail`から、`before`より前のセンサーデータを1時間ごとの集計にまとめてから削除する
+///
+/// 最新のデータは削除しない。削除したデータを計測時刻の昇順で返し、削除したデータの一つ新しいデータ
+/// (次の末尾)があればその`elementId`と計測時刻も返す。
+async fn roll_up_raw(graph: &Graph, id: &str, tail: &str, before: DateTime<Local>) -> anyhow::Result<(Vec<RawRow>, Option<(String, DateTime<Local>)>)> {
+    let mut tnx = graph.start_txn().await?;
+
+    let result = async {
+        // 1. 末尾から新しい方へ、削除するデータをたどる
+        // $before 以降のデータか最新のデータに達したところでたどるのをやめる
+        let mut result = tnx.execute(query(
+            r#"
+MATCH (tail:SensorData)
+WHERE elementId(tail) = $tail
+MATCH path = (tail)<-[:PREVIOUS_DATA*0..]-(data:SensorData)
+WHERE all(node IN nodes(path) WHERE datetime(node.time) < datetime($before) AND NOT (node)<-[:CURRENT_DATA]-(:Sensor))
+RETURN elementId(data) AS id,
+       data.time AS time,
+       data.distance AS distance,
+       data.battery_voltage AS battery_voltage,
+       data.qc_status AS qc_status
+ORDER BY length(path)
+            "#,
+        )
+            .param("tail", tail)
+            .param("before", before.to_rfc3339())
+        ).await?;
+
+        let mut rows = Vec::<RawRow>::new();
+        while let Some(row) = result.next(tnx.handle()).await? {
+            rows.push(row.to()?);
+        }
+
+        // 削除したデータの一つ新しいデータが次の末尾になる
+        let mut next = None;
+        if let Some(newest) = rows.last() {
+            let mut result = tnx.execute(query(
+                r#"
+MATCH (next:SensorData)-[:PREVIOUS_DATA]->(newest:SensorData)
+WHERE elementId(newest) = $newest
+  AND NOT (next)<-[:CURRENT_DATA]-(:Sensor)
+RETURN elementId(next) AS id, next.time AS time
+                "#,
+            )
+                .param("newest", newest.id.as_str())
+            ).await?;
+
+            while let Some(row) = result.next(tnx.handle()).await? {
+                next = Some((row.get::<String>("id")?, parse_local(&row.get::<String>("time")?)?));
+            }
+        }
+
+        // 2. 1時間ごとに集計して保存
+        let mut summaries = BTreeMap::<DateTime<Local>, Summary>::new();
+        for row in &rows {
+            let start = Resolution::Hourly.start_of(parse_local(&row.time)?)?;
+            summaries.entry(start).or_default().add(row);
+        }
+        save_summaries(&mut tnx, id, Resolution::Hourly, &summaries).await?;
+
+        // 3. 送信間隔の決定の履歴ごと削除する。連結リストの末尾なので、残るデータとの関係も一緒に消える
+        tnx.run(query(
+            r#"
+MATCH (data:SensorData)
+WHERE elementId(data) IN $ids
+OPTIONAL MATCH (data)-[:INTERVAL_DECISION]->(decision:IntervalDecision)
+DETACH DELETE decision, data
+            "#,
+        )
+            .param("ids", rows.iter().map(|row| row.id.clone()).collect::<Vec<_>>())
+        ).await?;
+
+        anyhow::Ok((rows, next))
+    }.await;
+
+    match result {
+        Ok(rolled_up) => {
+            tnx.commit().await?;
+            Ok(rolled_up)
+        }
+        Err(e) => {
+            tnx.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// `before`より前に始まる1時間ごとの集計を1日ごとの集計にまとめる
+///
+/// まとめた1時間ごとの集計の数を返す。
+async fn roll_up_hourly(graph: &Graph, id: &str, before: DateTime<Local>, dry_run: bool) -> anyhow::Result<i64> {
+    let mut tnx = graph.start_txn().await?;
+
+    let result = async {
+        // 1. まとめる集計を取得
+        let mut result = tnx.execute(query(
+            r#"
+MATCH (:Sensor {id: $id})-[:SUMMARY]->(summary:SensorDataSummary {resolution: 'hourly'})
+WHERE datetime(summary.start) < datetime($before)
+RETURN elementId(summary) AS id,
+       summary.start AS start,
+       summary.count AS count,
+       summary.rejected AS rejected,
+       summary.min_distance AS min_distance,
+       summary.max_distance AS max_distance,
+       summary.sum_distance AS sum_distance,
+       summary.min_battery_voltage AS min_battery_voltage,
+       summary.max_battery_voltage AS max_battery_voltage
+            "#,
+        )
+            .param("id", id)
+            .param("before", before.to_rfc3339())
+        ).await?;
+
+        let mut rows = Vec::<SummaryRow>::new();
+        while let Some(row) = result.next(tnx.handle()).await? {
+            rows.push(row.to()?);
+        }
+
+        if dry_run || rows.is_empty() {
+            return anyhow::Ok(rows.len() as i64);
+        }
+
+        // 2. 1日ごとに足し合わせて保存
+        let mut summaries = BTreeMap::<DateTime<Local>, Summary>::new();
+        for row in &rows {
+            let start = Resolution::Daily.start_of(parse_local(&row.start)?)?;
+            summaries.entry(start).or_default().merge(&Summary::from(row));
+        }
+        save_summaries(&mut tnx, id, Resolution::Daily, &summaries).await?;
+
+        // 3. まとめた集計を削除
+        tnx.run(query(
+            r#"
+MATCH (summary:SensorDataSummary)
+WHERE elementId(summary) IN $ids
+DETACH DELETE summary
+            "#,
+        )
+            .param("ids", rows.iter().map(|row| row.id.clone()).collect::<Vec<_>>())
+        ).await?;
+
+        anyhow::Ok(rows.len() as i64)
+    }.await;
+
+    match result {
+        Ok(count) => {
+            tnx.commit().await?;
+            Ok(count)
+        }
+        Err(e) => {
+            tnx.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// 保存期間を過ぎたセンサーデータの数と計測時刻の範囲を数える
+async fn count_raw(graph: &Graph, id: &str, before: DateTime<Local>) -> anyhow::Result<(i64, Option<String>, Option<String>)> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (:Sensor {id: $id})-[:CURRENT_DATA]->(:SensorData)-[:PREVIOUS_DATA*1..]->(data:SensorData)
+WHERE datetime(data.time) < datetime($before)
+WITH data
+ORDER BY datetime(data.time)
+WITH collect(data.time) AS times
+RETURN size(times) AS count,
+       head(times) AS oldest,
+       last(times) AS newest
+        "#,
+    )
+        .param("id", id)
+        .param("before", before.to_rfc3339())
+    ).await?;
+
+    let mut counted = (0, None, None);
+    while let Some(row) = result.next().await? {
+        counted = (row.get("count")?, row.get("oldest")?, row.get("newest")?);
+    }
+
+    Ok(counted)
+}
+
+/// 1台のセンサーのセンサーデータを集計して削除する
+///
+/// `dry_run`の場合は何も変更せず、処理する予定の内容を返す。ドライランの1日ごとの集計にまとめる数には、
+/// この処理で新しく作られる1時間ごとの集計は含まない。
+pub async fn apply_retention(graph: &Graph, id: &str, policy: RetentionPolicy, dry_run: bool) -> anyhow::Result<SensorRetention> {
+    let mut retention = SensorRetention { id: id.to_string(), ..Default::default() };
+
+    if dry_run {
+        (retention.raw_removed, retention.oldest, retention.newest) = count_raw(graph, id, policy.raw_cutoff).await?;
+        retention.hourly_rolled_up = roll_up_hourly(graph, id, policy.hourly_cutoff, true).await?;
+        return Ok(retention);
+    }
+
+    // 1. 末尾から1日分ずつ集計して削除
+    let mut tail = raw_tail(graph, id).await?;
+    while let Some((tail_id, oldest)) = tail {
+        if oldest >= policy.raw_cutoff {
+            break;
+        }
+
+        let before = Resolution::Daily.end_of(Resolution::Daily.start_of(oldest)?)?.min(policy.raw_cutoff);
+        let (rows, next) = roll_up_raw(graph, id, &tail_id, before).await?;
+        if rows.is_empty() {
+            break;
+        }
+        tail = next;
+
+        // 古い順に処理するので、最初の日の最初のデータが最も古い
+        retention.raw_removed += rows.len() as i64;
+        retention.oldest = retention.oldest.or_else(|| rows.first().map(|row| row.time.clone()));
+        retention.newest = rows.last().map(|row| row.time.clone());
+    }
+
+    // 2. 古い1時間ごとの集計を1日ごとにまとめる
+    retention.hourly_rolled_up = roll_up_hourly(graph, id, policy.hourly_cutoff, false).await?;
+
+    Ok(retention)
+}
+
+/// すべてのセンサーのセンサーデータを集計して削除する
+pub async fn apply_retention_all(graph: &Graph, env: &EnvVars, dry_run: bool) -> anyhow::Result<Vec<SensorRetention>> {
+    let policy = RetentionPolicy::new(env, Utc::now());
+
+    let mut result = graph.execute(query(
+        r#"
+MATCH (sensor:Sensor)
+RETURN sensor.id AS id
+ORDER BY id
+        "#,
+    )).await?;
+
+    let mut ids = Vec::<String>::new();
+    while let Some(row) = result.next().await? {
+        ids.push(row.get("id")?);
+    }
+
+    let mut retentions = Vec::new();
+    for id in ids {
+        retentions.push(apply_retention(graph, &id, policy, dry_run).await?);
+    }
+
+    Ok(retentions)
+}
+
+/// 一定間隔でセンサーデータを集計して削除する
+pub async fn run(graph: Graph, env: Arc<EnvVars>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(env.retention_interval));
+
+    loop {
+        interval.tick().await;
+
+        match apply_retention_all(&graph, &env, false).await {
+            Ok(retentions) => {
+                let removed = retentions.iter().map(|retention| retention.raw_removed).sum::<i64>();
+                let rolled_up = retentions.iter().map(|retention| retention.hourly_rolled_up).sum::<i64>();
+                println!("apply_retention: removed {removed} readings, rolled up {rolled_up} hourly summaries");
+            }
+            Err(e) => println!("apply_retention: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono_tz::{America, Asia};
+
+    fn at<Tz: TimeZone>(tz: Tz, time: &str) -> DateTime<Tz> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&tz)
+    }
+
+    fn raw(time: &str, distance: Option<f64>, battery_voltage: Option<i64>, qc_status: Option<&str>) -> RawRow {
+        RawRow {
+            id: time.to_string(),
+            time: time.to_string(),
+            distance,
+            battery_voltage,
+            qc_status: qc_status.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn hourly_period_is_truncated_to_the_hour() {
+        let start = Resolution::Hourly.start_of(at(Asia::Tokyo, "2024-06-01T12:34:56+09:00")).unwrap();
+        assert_eq!(start, at(Asia::Tokyo, "2024-06-01T12:00:00+09:00"));
+        assert_eq!(Resolution::Hourly.end_of(start).unwrap(), at(Asia::Tokyo, "2024-06-01T13:00:00+09:00"));
+
+        // 夏時間の終わりに2回ある1時台は、それぞれの時差で区切る
+        let first = Resolution::Hourly.start_of(at(America::New_York, "2024-11-03T01:30:00-04:00")).unwrap();
+        let second = Resolution::Hourly.start_of(at(America::New_York, "2024-11-03T01:30:00-05:00")).unwrap();
+        assert_eq!(first, at(America::New_York, "2024-11-03T01:00:00-04:00"));
+        assert_eq!(second, at(America::New_York, "2024-11-03T01:00:00-05:00"));
+    }
+
+    #[test]
+    fn daily_period_starts_at_local_midnight() {
+        let tokyo = |time| at(Asia::Tokyo, time);
+        assert_eq!(Resolution::Daily.start_of(tokyo("2024-06-01T00:00:00+09:00")).unwrap(), tokyo("2024-06-01T00:00:00+09:00"));
+        assert_eq!(Resolution::Daily.start_of(tokyo("2024-06-01T23:59:59+09:00")).unwrap(), tokyo("2024-06-01T00:00:00+09:00"));
+        // UTCでは前日でも、サーバーのタイムゾーンの日付で区切る
+        assert_eq!(Resolution::Daily.start_of(tokyo("2024-05-31T15:00:00Z")).unwrap(), tokyo("2024-06-01T00:00:00+09:00"));
+        assert_eq!(Resolution::Daily.end_of(tokyo("2024-06-30T00:00:00+09:00")).unwrap(), tokyo("2024-07-01T00:00:00+09:00"));
+    }
+
+    #[test]
+    fn daily_period_follows_daylight_saving_time() {
+        let new_york = |time| at(America::New_York, time);
+
+        // 夏時間の始まりは23時間
+        let start = Resolution::Daily.start_of(new_york("2024-03-10T12:00:00-04:00")).unwrap();
+        assert_eq!(start, new_york("2024-03-10T00:00:00-05:00"));
+        let end = Resolution::Daily.end_of(start).unwrap();
+        assert_eq!(end, new_york("2024-03-11T00:00:00-04:00"));
+        assert_eq!(end - start, TimeDelta::hours(23));
+
+        // 夏時間の終わりは25時間
+        let start = Resolution::Daily.start_of(new_york("2024-11-03T12:00:00-05:00")).unwrap();
+        assert_eq!(start, new_york("2024-11-03T00:00:00-04:00"));
+        let end = Resolution::Daily.end_of(start).unwrap();
+        assert_eq!(end, new_york("2024-11-04T00:00:00-05:00"));
+        assert_eq!(end - start, TimeDelta::hours(25));
+    }
+
+    #[test]
+    fn daily_period_starts_after_skipped_midnight() {
+        // 2018年11月4日のサンパウロは0時が1時に切り替わった
+        let sao_paulo = |time| at(America::Sao_Paulo, time);
+        let start = Resolution::Daily.start_of(sao_paulo("2018-11-04T12:00:00-02:00")).unwrap();
+        assert_eq!(start, sao_paulo("2018-11-04T01:00:00-02:00"));
+
+        let previous = Resolution::Daily.start_of(sao_paulo("2018-11-03T12:00:00-03:00")).unwrap();
+        assert_eq!(Resolution::Daily.end_of(previous).unwrap(), start);
+    }
+
+    #[test]
+    fn summary_counts_accepted_and_rejected_readings() {
+        let mut summary = Summary::default();
+        summary.add(&raw("2024-06-01T00:00:00Z", Some(1.5), Some(3300), None));
+        summary.add(&raw("2024-06-01T00:10:00Z", Some(0.5), Some(3200), Some("ok")));
+        summary.add(&raw("2024-06-01T00:20:00Z", Some(9.0), Some(3100), Some("rejected")));
+        summary.add(&raw("2024-06-01T00:30:00Z", None, None, Some("ok")));
+
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.min_distance, Some(0.5));
+        assert_eq!(summary.max_distance, Some(1.5));
+        assert_eq!(summary.sum_distance, 2.0);
+        // 棄却されたデータの電池の電圧も含める
+        assert_eq!(summary.min_battery_voltage, Some(3100));
+        assert_eq!(summary.max_battery_voltage, Some(3300));
+    }
+
+    #[test]
+    fn summaries_merge_like_their_readings() {
+        let rows = [
+            raw("2024-06-01T00:00:00Z", Some(1.5), Some(3300), None),
+            raw("2024-06-01T01:00:00Z", Some(0.5), None, None),
+            raw("2024-06-01T02:00:00Z", Some(9.0), Some(3100), Some("rejected")),
+        ];
+
+        let mut all = Summary::default();
+        rows.iter().for_each(|row| all.add(row));
+
+        let mut merged = Summary::default();
+        for row in &rows {
+            let mut hourly = Summary::default();
+            hourly.add(row);
+            merged.merge(&hourly);
+        }
+        // 空の集計を足しても変わらない
+        merged.merge(&Summary::default());
+
+        assert_eq!(merged.count, all.count);
+        assert_eq!(merged.rejected, all.rejected);
+        assert_eq!(merged.min_distance, all.min_distance);
+        assert_eq!(merged.max_distance, all.max_distance);
+        assert_eq!(merged.sum_distance, all.sum_distance);
+        assert_eq!(merged.min_battery_voltage, Some(3100));
+        assert_eq!(merged.max_battery_voltage, Some(3300));
+    }
+}
@@ -0,0 +1,133 @@
+//! メモリに保存する格納先
+//!
+//! Neo4jなしでルーター全体を動かすテストに使う。河川ノードと`RIVER_LINK`は[`MemoryStore::add_river_node`]と
+//! [`MemoryStore::add_river_link`]で用意し、タイルの所属は河川ノードの経度・緯度から求める。
+//!
+//! 校正値、警報の閾値、水系ごとの推定方法、水位の予測は保持しないため、校正せず、警報を評価せず、
+//! サーバーの設定値の推定方法を使い、予測を指定された場合は河川ノードを返さない。
+//! Webhookのイベントも保持しない。
+
+mod reading;
+mod river;
+mod sensor;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, Utc};
+use openapi::models::SensorLocation;
+use tokio::sync::RwLock;
+
+use crate::affects::Link;
+use crate::env::EnvVars;
+use crate::interval::IntervalDecision;
+use crate::provision::PendingSensor;
+use crate::qc::QcStatus;
+use crate::water_level::Position;
+
+/// メモリに保存する格納先
+pub struct MemoryStore {
+    env: Arc<EnvVars>,
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    sensors: HashMap<String, Sensor>,
+    pending: HashMap<String, PendingSensor>,
+    /// `hilbert18`ごとの河川ノード
+    river_nodes: HashMap<String, RiverNode>,
+    /// 上流から下流に向かう`RIVER_LINK`
+    river_links: Vec<Link>,
+}
+
+#[derive(Default)]
+struct Sensor {
+    altitude: f64,
+    scope: f64,
+    scope_upstream: Option<f64>,
+    scope_downstream: Option<f64>,
+    interval: i32,
+    min_interval: Option<i32>,
+    max_interval: Option<i32>,
+    parent_node: String,
+    secret: Option<String>,
+    last_signed_at: Option<i64>,
+    decommissioned_at: Option<DateTime<Utc>>,
+    relocated_at: Option<DateTime<Utc>>,
+    water_level: Option<f64>,
+    water_level_time: Option<DateTime<Utc>>,
+    /// 所属する(`BELONGS_TO`)河川ノード
+    belongs_to: Option<String>,
+    affects: Vec<Affects>,
+    /// 計測時刻の昇順に並んだセンサーデータ
+    data: Vec<SensorData>,
+    locations: Vec<SensorLocation>,
+}
+
+struct SensorData {
+    /// 校正後の距離
+    distance: f64,
+    battery_voltage: i32,
+    time: DateTime<Utc>,
+    qc_status: QcStatus,
+    /// 送信間隔の決定の履歴
+    interval_decisions: Vec<(IntervalDecision, DateTime<Local>)>,
+}
+
+/// センサーから河川ノードへの`AFFECTS`
+struct Affects {
+    node: String,
+    distance: f64,
+    bed_drop: Option<f64>,
+    position: Position,
+}
+
+struct RiverNode {
+    /// 経度・緯度(度)
+    location: (f64, f64),
+    water_level: Option<WaterLevel>,
+}
+
+struct WaterLevel {
+    value: f64,
+    time: Option<DateTime<Utc>>,
+}
+
+impl MemoryStore {
+    pub fn new(env: Arc<EnvVars>) -> Self {
+        Self {
+            env,
+            state: RwLock::new(State::default()),
+        }
+    }
+
+    /// 河川ノードを追加する
+    pub async fn add_river_node(&self, hilbert18: &str, location: (f64, f64)) {
+        self.state.write().await.river_nodes.insert(hilbert18.to_string(), RiverNode { location, water_level: None });
+    }
+
+    /// 上流の河川ノードから下流の河川ノードへの`RIVER_LINK`を追加する
+    pub async fn add_river_link(&self, upstream: &str, downstream: &str, length: f64, slope: Option<f64>) {
+        self.state.write().await.river_links.push(Link {
+            from: upstream.to_string(),
+            to: downstream.to_string(),
+            length,
+            slope,
+        });
+    }
+
+    /// センサーについて記録された送信間隔の決定を、記録した順に返す
+    pub async fn interval_decisions(&self, id: &str) -> Vec<IntervalDecision> {
+        self.state.read().await.sensors.get(id)
+            .map(|sensor| sensor.data.iter()
+                .flat_map(|data| data.interval_decisions.iter().map(|(decision, _)| decision.clone()))
+                .collect())
+            .unwrap_or_default()
+    }
+
+    /// 河川ノードの水位
+    pub async fn river_water_level(&self, hilbert18: &str) -> Option<f64> {
+        self.state.read().await.river_nodes.get(hilbert18)?.water_level.as_ref().map(|water_level| water_level.value)
+    }
+}
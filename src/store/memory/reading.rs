@@ -0,0 +1,155 @@
+//! 計測値の登録と、送信間隔の決定に使うセンサーの状態
+
+use axum::async_trait;
+use chrono::{DateTime, Local, Utc};
+
+use crate::forecast::TrendKind;
+use crate::interval::{IntervalDecision, SensorState};
+use crate::qc::{QcConfig, QcSample, QcStatus};
+use crate::store::memory::{MemoryStore, SensorData, State};
+use crate::store::{Ingested, ReadingStore, SignedReadings};
+
+impl State {
+    /// 計測値のQCに必要な、計測時刻より前のデータを取得する
+    ///
+    /// Neo4jの格納先と同じく、センサーの標高、受け付けた最新の計測値、直近`window`件の水位(新しい順)を返す。
+    fn qc_context(&self, id: &str, time: DateTime<Utc>, window: usize) -> Option<(f64, Option<QcSample>, Vec<f64>)> {
+        let sensor = self.sensors.get(id).filter(|sensor| sensor.decommissioned_at.is_none())?;
+
+        let history = sensor.data.iter()
+            .rev()
+            .filter(|data| data.time < time && sensor.relocated_at.map_or(true, |relocated_at| data.time >= relocated_at))
+            .collect::<Vec<_>>();
+        let recent = history.iter().take(window).map(|data| sensor.altitude - data.distance).collect();
+        let previous = history.iter()
+            .find(|data| data.qc_status != QcStatus::Rejected)
+            .map(|data| QcSample { water_level: sensor.altitude - data.distance, time: data.time });
+
+        Some((sensor.altitude, previous, recent))
+    }
+
+    /// センサーデータを計測時刻順の位置に挿入する
+    ///
+    /// 挿入したデータが最新のデータになった場合は`Some(true)`、センサーが存在しない場合は`None`を返す。
+    fn insert_reading(&mut self, id: &str, data: SensorData) -> Option<bool> {
+        let sensor = self.sensors.get_mut(id)?;
+
+        let index = sensor.data.partition_point(|older| older.time <= data.time);
+        let is_current = index == sensor.data.len();
+        sensor.data.insert(index, data);
+
+        Some(is_current)
+    }
+}
+
+#[async_trait]
+impl ReadingStore for MemoryStore {
+    async fn sensor_secret(&self, id: &str) -> anyhow::Result<Option<Option<String>>> {
+        let state = self.state.read().await;
+
+        Ok(state.sensors.get(id)
+            .filter(|sensor| sensor.decommissioned_at.is_none())
+            .map(|sensor| sensor.secret.clone()))
+    }
+
+    async fn ingest_readings(&self, sensors: Vec<SignedReadings>) -> anyhow::Result<Ingested> {
+        let qc_config = QcConfig::from(&*self.env);
+        let mut ingested = Ingested { updated_ids: Vec::new(), unknown_ids: Vec::new(), replayed_ids: Vec::new(), alert_changes: Vec::new(), events: Vec::new() };
+
+        let mut state = self.state.write().await;
+        for SignedReadings { id, signed_at, mut readings } in sensors {
+            // リプレイ防止のため、署名時刻が前回より新しい場合のみ記録する
+            let Some(sensor) = state.sensors.get_mut(&id).filter(|sensor| sensor.decommissioned_at.is_none()) else {
+                ingested.unknown_ids.push(id);
+                continue;
+            };
+            if sensor.last_signed_at.is_some_and(|last_signed_at| last_signed_at >= signed_at) {
+                ingested.replayed_ids.push(id);
+                continue;
+            }
+            sensor.last_signed_at = Some(signed_at);
+
+            readings.sort_by_key(|reading| reading.time);
+
+            let mut newest_is_current = Some(false);
+            let mut accepted = false;
+            for reading in &readings {
+                let time = reading.time.with_timezone(&Utc);
+                let distance = reading.distance as f64;
+
+                let Some((altitude, previous, recent)) = state.qc_context(&id, time, qc_config.window) else {
+                    newest_is_current = None;
+                    break;
+                };
+                let qc = qc_config.check(altitude, distance, time, previous.as_ref(), &recent);
+                accepted |= qc.status == QcStatus::Ok;
+
+                newest_is_current = state.insert_reading(&id, SensorData {
+                    distance,
+                    battery_voltage: reading.battery_voltage,
+                    time,
+                    qc_status: qc.status,
+                    interval_decisions: Vec::new(),
+                });
+                if newest_is_current.is_none() {
+                    break;
+                }
+            }
+
+            match newest_is_current {
+                Some(true) if accepted => ingested.updated_ids.push(id),
+                Some(_) => {}
+                None => ingested.unknown_ids.push(id),
+            }
+        }
+
+        if !ingested.updated_ids.is_empty() {
+            state.update_water_levels(&ingested.updated_ids, &self.env);
+        }
+
+        Ok(ingested)
+    }
+
+    async fn interval_states(&self, ids: &[String], since: DateTime<Utc>) -> anyhow::Result<Vec<(String, SensorState)>> {
+        let state = self.state.read().await;
+
+        let states = ids.iter()
+            .filter_map(|id| Some((id, state.sensors.get(id)?)))
+            .map(|(id, sensor)| {
+                let history = sensor.data.iter()
+                    .filter(|data| data.time >= since && data.qc_status != QcStatus::Rejected)
+                    .filter(|data| sensor.relocated_at.map_or(true, |relocated_at| data.time >= relocated_at))
+                    .collect::<Vec<_>>();
+                // 最新の計測時刻からの時間(時間)と水位
+                let rate = history.last().and_then(|latest| {
+                    let samples = history.iter()
+                        .map(|data| ((data.time - latest.time).num_seconds() as f64 / 3600.0, sensor.altitude - data.distance))
+                        .collect::<Vec<_>>();
+                    TrendKind::Linear.fit(&samples, 2)
+                }).map(|trend| trend.rate);
+
+                (id.clone(), SensorState {
+                    interval: sensor.interval,
+                    min_interval: sensor.min_interval,
+                    max_interval: sensor.max_interval,
+                    rate,
+                    battery_voltage: sensor.data.last().map(|data| data.battery_voltage),
+                    threshold_margin: None,
+                })
+            })
+            .collect();
+
+        Ok(states)
+    }
+
+    async fn record_intervals(&self, decisions: &[(String, IntervalDecision)], decided_at: DateTime<Local>) -> anyhow::Result<()> {
+        let mut state = self.state.write().await;
+        for (id, decision) in decisions {
+            if let Some(current) = state.sensors.get_mut(id).and_then(|sensor| sensor.data.last_mut()) {
+                current.interval_decisions.push((decision.clone(), decided_at));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! 影響範囲、河川ノードの水位とタイルの所属
+
+use std::f64::consts::PI;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::affects::{Affected, AffectsLimits, Direction, Link, Scope, ShortestPaths};
+use crate::cache::items::TileId;
+use crate::env::EnvVars;
+use crate::qc::QcStatus;
+use crate::store::memory::{Affects, MemoryStore, State, WaterLevel};
+use crate::store::{NodeLevel, RiverStore};
+use crate::water_level::{freshness, AffectedNode, Contribution, ModelConfig};
+
+/// 経度・緯度(度)を含むタイルの座標
+fn tile_of((longitude, latitude): (f64, f64), z: u8) -> (u32, u32) {
+    let n = (1u64 << z) as f64;
+    let latitude = latitude.to_radians();
+    let x = (longitude + 180.0) / 360.0 * n;
+    let y = (1.0 - (latitude.tan() + 1.0 / latitude.cos()).ln() / PI) / 2.0 * n;
+
+    (x.floor() as u32, y.floor() as u32)
+}
+
+impl State {
+    /// 河川ノード群から、一方向に隣接する`RIVER_LINK`を返す
+    fn links_from(&self, nodes: &[String], direction: Direction) -> Vec<Link> {
+        self.river_links.iter()
+            .filter_map(|link| {
+                let (from, to) = match direction {
+                    Direction::Upstream => (&link.to, &link.from),
+                    Direction::Downstream => (&link.from, &link.to),
+                };
+                nodes.contains(from).then(|| Link { from: from.clone(), to: to.clone(), length: link.length, slope: link.slope })
+            })
+            .collect()
+    }
+
+    /// センサーを親河川ノードに所属させ、影響範囲にある河川ノードへの`AFFECTS`を作る
+    ///
+    /// センサーまたは親河川ノードが存在しない場合は何もせずに`None`を返す。
+    pub(super) fn link_to_river(&mut self, id: &str, parent_node: &str, scope: Scope, limits: AffectsLimits) -> Option<Affected> {
+        if !self.sensors.contains_key(id) || !self.river_nodes.contains_key(parent_node) {
+            return None;
+        }
+
+        let mut affects = Vec::new();
+        let mut truncated = false;
+        for (direction, scope) in scope.directions() {
+            let mut paths = ShortestPaths::new(parent_node, scope, limits);
+            while !paths.frontier().is_empty() {
+                let links = self.links_from(paths.frontier(), direction);
+                paths.extend(links);
+            }
+
+            let (reached, hit_limit) = paths.finish();
+            truncated |= hit_limit;
+            let position = direction.position().parse().unwrap_or_default();
+            affects.extend(reached.into_iter().map(|(node, reached)| Affects {
+                node,
+                distance: reached.distance,
+                bed_drop: reached.drop.map(|drop| direction.drop_sign() * drop),
+                position,
+            }));
+        }
+
+        let affected = Affected {
+            nodes: affects.len() as i64,
+            min_distance: affects.iter().map(|affects| affects.distance).min_by(f64::total_cmp),
+            max_distance: affects.iter().map(|affects| affects.distance).max_by(f64::total_cmp),
+            truncated,
+        };
+
+        let sensor = self.sensors.get_mut(id)?;
+        sensor.belongs_to = Some(parent_node.to_string());
+        sensor.affects = affects;
+
+        Some(affected)
+    }
+
+    /// センサーの`BELONGS_TO`と`AFFECTS`を削除し、それまで繋がっていた河川ノードを返す
+    pub(super) fn unlink_from_river(&mut self, id: &str) -> Vec<String> {
+        let Some(sensor) = self.sensors.get_mut(id) else {
+            return Vec::new();
+        };
+
+        let mut nodes = sensor.belongs_to.take()
+            .into_iter()
+            .chain(sensor.affects.drain(..).map(|affects| affects.node))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.dedup();
+
+        nodes
+    }
+
+    /// センサーが`AFFECTS`で影響するすべての河川ノード
+    pub(super) fn affected_node_ids(&self, ids: &[String]) -> Vec<String> {
+        let mut nodes = ids.iter()
+            .filter_map(|id| self.sensors.get(id))
+            .flat_map(|sensor| sensor.affects.iter().map(|affects| affects.node.clone()))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.dedup();
+
+        nodes
+    }
+
+    /// センサーの受け付けた最新データから水位を計算し、影響範囲にある河川ノードの水位を更新する
+    pub(super) fn update_water_levels(&mut self, ids: &[String], env: &EnvVars) {
+        for id in ids {
+            let Some(sensor) = self.sensors.get_mut(id) else {
+                continue;
+            };
+            let Some(parent) = sensor.belongs_to.clone() else {
+                continue;
+            };
+            let current = sensor.data.iter()
+                .rev()
+                .find(|data| data.qc_status != QcStatus::Rejected && sensor.relocated_at.map_or(true, |relocated_at| data.time >= relocated_at))
+                .map(|data| (sensor.altitude - data.distance, data.time));
+            let Some((water_level, time)) = current else {
+                continue;
+            };
+
+            sensor.water_level = Some(water_level);
+            sensor.water_level_time = Some(time);
+            if let Some(node) = self.river_nodes.get_mut(&parent) {
+                node.water_level = Some(WaterLevel { value: water_level, time: Some(time) });
+            }
+        }
+
+        let nodes = self.affected_node_ids(ids);
+        self.recompute_nodes(&nodes, env);
+    }
+
+    /// 河川ノードの水位を、影響するセンサーから推定し直す
+    ///
+    /// 推定できなかった河川ノードと、影響するセンサーがなくなった河川ノードは水位を消す。
+    /// ただし、水位を計測したセンサーが所属する河川ノードの水位はそのセンサーの水位なので、推定値で上書きも削除もしない。
+    pub(super) fn recompute_nodes(&mut self, nodes: &[String], env: &EnvVars) {
+        let config = ModelConfig::from(env);
+        let now = Utc::now();
+
+        for node in nodes {
+            if self.sensors.values().any(|sensor| sensor.belongs_to.as_ref() == Some(node) && sensor.water_level.is_some()) {
+                continue;
+            }
+
+            let mut contributions = Vec::new();
+            for (id, sensor) in &self.sensors {
+                let Some(affects) = sensor.affects.iter().find(|affects| affects.node == *node) else {
+                    continue;
+                };
+                // 水位を保持していないセンサーは親河川ノードの水位で代用する
+                let Some(parent_level) = sensor.belongs_to.as_ref()
+                    .and_then(|parent| self.river_nodes.get(parent))
+                    .and_then(|parent| parent.water_level.as_ref()) else {
+                    continue;
+                };
+
+                let time = sensor.water_level_time
+                    .or(parent_level.time)
+                    .or(sensor.data.last().map(|data| data.time));
+                let age = time.map(|time| (now - time).num_seconds() as f64);
+
+                contributions.push((id.clone(), Contribution {
+                    water_level: sensor.water_level.unwrap_or(parent_level.value),
+                    distance: affects.distance,
+                    position: affects.position,
+                    freshness: freshness(age, Some(sensor.interval as f64), env.stale_reading_intervals),
+                    bed_drop: affects.bed_drop,
+                }));
+            }
+
+            contributions.retain(|(_, contribution)| contribution.freshness > 0.0);
+            let value = AffectedNode { node: node.clone(), config: config.clone(), contributions }.estimate();
+
+            if let Some(river_node) = self.river_nodes.get_mut(node) {
+                river_node.water_level = value.map(|value| WaterLevel {
+                    value,
+                    time: river_node.water_level.as_ref().and_then(|water_level| water_level.time),
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RiverStore for MemoryStore {
+    async fn tile_water_levels(&self, tile: TileId, forecast: Option<DateTime<Utc>>) -> anyhow::Result<Vec<NodeLevel>> {
+        // 予測は保持しない
+        if forecast.is_some() {
+            return Ok(Vec::new());
+        }
+
+        let state = self.state.read().await;
+        let nodes = state.river_nodes.values()
+            .filter(|node| tile_of(node.location, tile.z) == (tile.x, tile.y))
+            .filter_map(|node| Some(NodeLevel {
+                location: node.location,
+                water_level: node.water_level.as_ref()?.value,
+            }))
+            .collect();
+
+        Ok(nodes)
+    }
+}
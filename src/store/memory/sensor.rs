@@ -0,0 +1,262 @@
+//! センサーの登録・変更・運用終了
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use openapi::models::{ApiSensorsPostRequest, SensorLocation, SensorPatch, SensorReading, SensorRegistration, SensorUpdate};
+
+use crate::affects::{AffectsLimits, Scope};
+use crate::auth::generate_secret;
+use crate::provision::PendingSensor;
+use crate::store::memory::{MemoryStore, Sensor, State};
+use crate::store::{validate_settings, SensorSettings, SensorStore, Updated};
+
+impl State {
+    /// センサーの場所が変わる場合(または再登録する場合)に、移設の履歴を記録してセンサーの水位を消す
+    ///
+    /// センサーが存在しない場合や場所が変わらない場合は`false`を返す。
+    fn record_relocation(&mut self, id: &str, parent_node: &str, altitude: f64, now: DateTime<Utc>) -> bool {
+        let Some(sensor) = self.sensors.get_mut(id) else {
+            return false;
+        };
+        if sensor.parent_node == parent_node && sensor.altitude == altitude && sensor.decommissioned_at.is_none() {
+            return false;
+        }
+
+        match sensor.locations.iter_mut().find(|location| location.until.is_none()) {
+            Some(current) => current.until = Some(now),
+            None if sensor.decommissioned_at.is_none() => sensor.locations.push(SensorLocation {
+                parent_node: sensor.parent_node.clone(),
+                altitude: sensor.altitude,
+                since: None,
+                until: Some(now),
+            }),
+            None => {}
+        }
+        sensor.locations.push(SensorLocation {
+            parent_node: parent_node.to_string(),
+            altitude,
+            since: Some(now),
+            until: None,
+        });
+
+        sensor.relocated_at = Some(now);
+        sensor.water_level = None;
+        sensor.water_level_time = None;
+
+        true
+    }
+
+    /// センサーの運用を終了する。センサーが存在しない場合や既に運用を終了している場合は`false`を返す
+    fn decommission(&mut self, id: &str, now: DateTime<Utc>) -> bool {
+        let Some(sensor) = self.sensors.get_mut(id).filter(|sensor| sensor.decommissioned_at.is_none()) else {
+            return false;
+        };
+
+        match sensor.locations.iter_mut().find(|location| location.until.is_none()) {
+            Some(current) => current.until = Some(now),
+            None => sensor.locations.push(SensorLocation {
+                parent_node: sensor.parent_node.clone(),
+                altitude: sensor.altitude,
+                since: None,
+                until: Some(now),
+            }),
+        }
+
+        sensor.decommissioned_at = Some(now);
+        sensor.secret = None;
+        sensor.water_level = None;
+        sensor.water_level_time = None;
+
+        true
+    }
+}
+
+#[async_trait]
+impl SensorStore for MemoryStore {
+    async fn register_sensor(&self, id: &str, settings: &ApiSensorsPostRequest, rotate_secret: bool) -> anyhow::Result<Option<SensorRegistration>> {
+        let ApiSensorsPostRequest { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream, ref parent_node } = *settings;
+        let affects_scope = Scope::new(scope as f64, scope_upstream.map(f64::from), scope_downstream.map(f64::from));
+
+        let mut state = self.state.write().await;
+        if !state.river_nodes.contains_key(parent_node) {
+            return Ok(None);
+        }
+
+        state.record_relocation(id, parent_node, altitude as f64, Utc::now());
+
+        let sensor = state.sensors.entry(id.to_string()).or_insert_with(Sensor::default);
+        sensor.altitude = altitude as f64;
+        sensor.scope = scope as f64;
+        sensor.scope_upstream = scope_upstream.map(f64::from);
+        sensor.scope_downstream = scope_downstream.map(f64::from);
+        sensor.interval = interval;
+        sensor.min_interval = min_interval;
+        sensor.max_interval = max_interval;
+        sensor.parent_node = parent_node.clone();
+        sensor.decommissioned_at = None;
+
+        let mut previous_nodes = state.unlink_from_river(id);
+        let Some(affects) = state.link_to_river(id, parent_node, affects_scope, AffectsLimits::from(&*self.env)) else {
+            return Ok(None);
+        };
+
+        let ids = [id.to_string()];
+        state.update_water_levels(&ids, &self.env);
+
+        let current_nodes = state.affected_node_ids(&ids);
+        previous_nodes.retain(|node| !current_nodes.contains(node));
+        state.recompute_nodes(&previous_nodes, &self.env);
+
+        let Some(sensor) = state.sensors.get_mut(id) else {
+            return Ok(None);
+        };
+        let issued = sensor.secret.is_none() || rotate_secret;
+        if issued {
+            sensor.secret = Some(generate_secret());
+        }
+
+        Ok(Some(SensorRegistration {
+            secret: sensor.secret.clone().filter(|_| issued),
+            affects: affects.into(),
+        }))
+    }
+
+    async fn update_sensor(&self, id: &str, patch: &SensorPatch) -> anyhow::Result<Updated> {
+        let mut state = self.state.write().await;
+        let Some(current) = state.sensors.get(id).filter(|sensor| sensor.decommissioned_at.is_none()) else {
+            return Ok(Updated::NotFound);
+        };
+
+        let settings = SensorSettings {
+            altitude: current.altitude,
+            interval: current.interval,
+            min_interval: current.min_interval,
+            max_interval: current.max_interval,
+            scope: current.scope,
+            scope_upstream: current.scope_upstream,
+            scope_downstream: current.scope_downstream,
+        }.patched(patch);
+        if let Err(message) = validate_settings(&settings) {
+            return Ok(Updated::Invalid(message));
+        }
+
+        let new_parent_node = patch.parent_node.clone().unwrap_or(current.parent_node.clone());
+        let new_altitude = settings.altitude;
+        let current_scope = Scope::new(current.scope, current.scope_upstream, current.scope_downstream);
+        let new_scope = Scope::new(settings.scope, settings.scope_upstream, settings.scope_downstream);
+        let relinked = new_parent_node != current.parent_node || new_scope != current_scope;
+        if relinked && !state.river_nodes.contains_key(&new_parent_node) {
+            return Ok(Updated::UnknownRiverNode);
+        }
+
+        let relocated = state.record_relocation(id, &new_parent_node, new_altitude, Utc::now());
+
+        if let Some(sensor) = state.sensors.get_mut(id) {
+            let SensorSettings { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream } = settings;
+            sensor.altitude = altitude;
+            sensor.interval = interval;
+            sensor.min_interval = min_interval;
+            sensor.max_interval = max_interval;
+            sensor.scope = scope;
+            sensor.scope_upstream = scope_upstream;
+            sensor.scope_downstream = scope_downstream;
+            sensor.parent_node = new_parent_node.clone();
+        }
+
+        let mut previous_nodes = Vec::new();
+        let mut affects = None;
+        if relinked {
+            previous_nodes = state.unlink_from_river(id);
+            affects = state.link_to_river(id, &new_parent_node, new_scope, AffectsLimits::from(&*self.env));
+        }
+
+        if relinked || relocated {
+            let ids = [id.to_string()];
+            state.update_water_levels(&ids, &self.env);
+
+            let current_nodes = state.affected_node_ids(&ids);
+            previous_nodes.retain(|node| !current_nodes.contains(node));
+            state.recompute_nodes(&previous_nodes, &self.env);
+        }
+
+        Ok(Updated::Updated(SensorUpdate { relocated, relinked, affects: affects.map(Into::into) }))
+    }
+
+    async fn decommission_sensor(&self, id: &str, purge: bool) -> anyhow::Result<bool> {
+        let mut state = self.state.write().await;
+        if !state.decommission(id, Utc::now()) && !purge {
+            return Ok(false);
+        }
+
+        let previous_nodes = state.unlink_from_river(id);
+        state.recompute_nodes(&previous_nodes, &self.env);
+
+        if purge {
+            return Ok(state.sensors.remove(id).is_some());
+        }
+
+        Ok(true)
+    }
+
+    async fn sensor_locations(&self, id: &str) -> anyhow::Result<Option<Vec<SensorLocation>>> {
+        let state = self.state.read().await;
+        let Some(sensor) = state.sensors.get(id) else {
+            return Ok(None);
+        };
+
+        // 移設したことがないセンサーは、現在の場所を登録時からの場所として返す
+        let locations = if sensor.locations.is_empty() {
+            vec![SensorLocation { parent_node: sensor.parent_node.clone(), altitude: sensor.altitude, since: None, until: None }]
+        } else {
+            let mut locations = sensor.locations.clone();
+            locations.sort_by_key(|location| (location.since.is_some(), location.since));
+            locations
+        };
+
+        Ok(Some(locations))
+    }
+
+    async fn record_pending(&self, id: &str, reading: &SensorReading, now: DateTime<Utc>) -> anyhow::Result<bool> {
+        let now = now.to_rfc3339();
+
+        let mut state = self.state.write().await;
+        if !state.pending.contains_key(id) && state.pending.len() as i64 >= self.env.sensor_pending_limit {
+            return Ok(false);
+        }
+        let pending = state.pending.entry(id.to_string()).or_insert_with(|| PendingSensor {
+            id: id.to_string(),
+            first_seen: now.clone(),
+            last_seen: now.clone(),
+            reports: 0,
+            distance: 0.0,
+            battery_voltage: 0,
+            previous_sleep_time: 0,
+            network_status: String::new(),
+            time: None,
+        });
+        pending.last_seen = now;
+        pending.reports += 1;
+        pending.distance = reading.distance as f64;
+        pending.battery_voltage = reading.battery_voltage as i64;
+        pending.previous_sleep_time = reading.previous_sleep_time as i64;
+        pending.network_status = reading.network_status.clone();
+        pending.time = reading.time.map(|time| time.to_rfc3339());
+
+        Ok(true)
+    }
+
+    async fn list_pending(&self) -> anyhow::Result<Vec<PendingSensor>> {
+        let mut pending = self.state.read().await.pending.values().cloned().collect::<Vec<_>>();
+        pending.sort_by(|a, b| a.first_seen.cmp(&b.first_seen));
+
+        Ok(pending)
+    }
+
+    async fn is_pending(&self, id: &str) -> anyhow::Result<bool> {
+        Ok(self.state.read().await.pending.contains_key(id))
+    }
+
+    async fn remove_pending(&self, id: &str) -> anyhow::Result<bool> {
+        Ok(self.state.write().await.pending.remove(id).is_some())
+    }
+}
@@ -0,0 +1,319 @@
+//! センサー・計測値・河川ノードの水位・タイルの所属の格納先
+//!
+//! ハンドラーはNeo4jを直接扱わず、ここで定義するトレイトを通して読み書きする。
+//! 本番では[`Neo4jStore`]を、ルーターのテストではNeo4jなしで動く`MemoryStore`を使う。
+//! 一つのメソッドが一つのトランザクションにあたり、失敗した場合は何も変更しない。
+//!
+//! 校正値、警報の閾値、Webhook、稼働状態、水位の予測の作成は、それぞれのモジュールでNeo4jを直接扱う。
+
+#[cfg(test)]
+mod memory;
+mod neo4j;
+
+use axum::async_trait;
+use chrono::{DateTime, Local, Utc};
+use openapi::models::{ApiSensorsPostRequest, SensorLocation, SensorPatch, SensorReading, SensorRegistration, SensorUpdate};
+use std::ops::RangeInclusive;
+
+use crate::alert::AlertChange;
+use crate::cache::items::TileId;
+use crate::interval::{IntervalDecision, SensorState};
+use crate::provision::PendingSensor;
+use crate::webhook::Event;
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+pub use neo4j::Neo4jStore;
+
+/// センサーの1回分の計測値
+pub struct Reading {
+    pub distance: f32,
+    pub battery_voltage: i32,
+    pub previous_sleep_time: i32,
+    pub network_status: String,
+    /// 計測時刻(センサーが送らなかった場合は受信時刻)
+    pub time: DateTime<Local>,
+    /// センサーが送った計測時刻(UNIX秒)
+    pub device_time: Option<i64>,
+}
+
+impl Reading {
+    pub fn new(distance: f32, battery_voltage: i32, previous_sleep_time: i32, network_status: String, time: Option<DateTime<Utc>>) -> Self {
+        Reading {
+            distance,
+            battery_voltage,
+            previous_sleep_time,
+            network_status,
+            time: time.map(|time| time.with_timezone(&Local)).unwrap_or_else(Local::now),
+            device_time: time.map(|time| time.timestamp()),
+        }
+    }
+}
+
+/// 署名を検証した、1台のセンサーの計測値
+pub struct SignedReadings {
+    pub id: String,
+    /// 署名時刻(UNIX秒)。リプレイ防止のため、計測値と同じトランザクションで記録する
+    pub signed_at: i64,
+    pub readings: Vec<Reading>,
+}
+
+/// 計測値を登録した結果
+pub struct Ingested {
+    /// 最新の計測値が`CURRENT_DATA`になり、QCで受け付けた計測値があったため水位を更新したセンサー
+    pub updated_ids: Vec<String>,
+    /// 登録されていないセンサー
+    pub unknown_ids: Vec<String>,
+    /// 署名時刻が前回受け付けたもの以前だったため、計測値を登録しなかったセンサー(リプレイ)
+    pub replayed_ids: Vec<String>,
+    /// 警報の段階が変わったセンサー
+    pub alert_changes: Vec<AlertChange>,
+    /// Webhookで通知するイベント
+    pub events: Vec<Event>,
+}
+
+/// センサーの設定を部分的に変更した結果
+pub enum Updated {
+    Updated(SensorUpdate),
+    /// 登録されていない(または運用を終了した)センサー
+    NotFound,
+    /// 変更後の親河川ノードが存在しない
+    UnknownRiverNode,
+    /// 変更後の設定値が不正
+    Invalid(String),
+}
+
+/// 標高として受け付ける範囲(メートル)
+const ALTITUDE_RANGE: RangeInclusive<f64> = -100.0..=4000.0;
+
+/// 検証の対象となる、センサーの設定値
+///
+/// 登録時は送信された値、部分更新では保存された値に変更を重ねた値にあたる。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSettings {
+    pub altitude: f64,
+    pub interval: i32,
+    pub min_interval: Option<i32>,
+    pub max_interval: Option<i32>,
+    pub scope: f64,
+    pub scope_upstream: Option<f64>,
+    pub scope_downstream: Option<f64>,
+}
+
+impl SensorSettings {
+    /// 指定された項目のみ変更した設定値
+    pub fn patched(&self, patch: &SensorPatch) -> Self {
+        SensorSettings {
+            altitude: patch.altitude.map(f64::from).unwrap_or(self.altitude),
+            interval: patch.interval.unwrap_or(self.interval),
+            min_interval: patch.min_interval.or(self.min_interval),
+            max_interval: patch.max_interval.or(self.max_interval),
+            scope: patch.scope.map(f64::from).unwrap_or(self.scope),
+            scope_upstream: patch.scope_upstream.map(f64::from).or(self.scope_upstream),
+            scope_downstream: patch.scope_downstream.map(f64::from).or(self.scope_downstream),
+        }
+    }
+}
+
+impl From<&ApiSensorsPostRequest> for SensorSettings {
+    fn from(settings: &ApiSensorsPostRequest) -> Self {
+        SensorSettings {
+            altitude: settings.altitude.into(),
+            interval: settings.interval,
+            min_interval: settings.min_interval,
+            max_interval: settings.max_interval,
+            scope: settings.scope.into(),
+            scope_upstream: settings.scope_upstream.map(f64::from),
+            scope_downstream: settings.scope_downstream.map(f64::from),
+        }
+    }
+}
+
+/// センサーの設定値を検証する
+///
+/// 送信間隔の下限・上限は、送信間隔とも互いとも比べる。
+pub fn validate_settings(settings: &SensorSettings) -> Result<(), String> {
+    let SensorSettings { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream } = *settings;
+
+    if !ALTITUDE_RANGE.contains(&altitude) {
+        return Err(format!("altitude must be between {} and {}: {altitude}", ALTITUDE_RANGE.start(), ALTITUDE_RANGE.end()));
+    }
+    for (name, scope) in [("scope", Some(scope)), ("scope_upstream", scope_upstream), ("scope_downstream", scope_downstream)] {
+        if let Some(scope) = scope.filter(|scope| !(scope.is_finite() && *scope > 0.0)) {
+            return Err(format!("{name} must be positive: {scope}"));
+        }
+    }
+    if interval <= 0 {
+        return Err(format!("interval must be positive: {interval}"));
+    }
+    if let Some(min_interval) = min_interval.filter(|min_interval| *min_interval <= 0 || *min_interval > interval) {
+        return Err(format!("min_interval must be positive and not exceed interval {interval}: {min_interval}"));
+    }
+    if let Some(max_interval) = max_interval.filter(|max_interval| *max_interval < interval) {
+        return Err(format!("max_interval must not be less than interval {interval}: {max_interval}"));
+    }
+    if let (Some(min_interval), Some(max_interval)) = (min_interval, max_interval) {
+        if min_interval > max_interval {
+            return Err(format!("min_interval must not exceed max_interval: {min_interval} > {max_interval}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// 水面タイルの生成に使う、河川ノードの水位
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeLevel {
+    /// 経度・緯度(度)
+    pub location: (f64, f64),
+    pub water_level: f64,
+}
+
+/// センサーの登録・変更・運用終了と、承認待ちのセンサー
+#[async_trait]
+pub trait SensorStore: Send + Sync {
+    /// センサーを登録または上書きし、影響範囲(`AFFECTS`)を作り直す
+    ///
+    /// 既存のセンサーの場所が変わる場合は移設の履歴を残す。署名用シークレットは、未発行の場合か
+    /// `rotate_secret`の場合のみ発行する。親河川ノードが存在しない場合は何も変更せずに`None`を返す。
+    async fn register_sensor(&self, id: &str, settings: &ApiSensorsPostRequest, rotate_secret: bool) -> anyhow::Result<Option<SensorRegistration>>;
+
+    /// センサーの指定された項目のみ変更する
+    ///
+    /// 場所が変わる場合は移設の履歴を残し、影響範囲が変わる場合のみ`AFFECTS`を作り直して水位を計算し直す。
+    async fn update_sensor(&self, id: &str, patch: &SensorPatch) -> anyhow::Result<Updated>;
+
+    /// センサーの運用を終了し、影響範囲から外れた河川ノードの水位を残ったセンサーから推定し直す
+    ///
+    /// `purge`の場合はセンサーデータとセンサーに付随するノードごと削除する。
+    /// センサーが存在しない場合や、`purge`でなく既に運用を終了している場合は`false`を返す。
+    async fn decommission_sensor(&self, id: &str, purge: bool) -> anyhow::Result<bool>;
+
+    /// センサーの設置場所の履歴を古い順に返す。センサーが存在しない場合は`None`を返す
+    async fn sensor_locations(&self, id: &str) -> anyhow::Result<Option<Vec<SensorLocation>>>;
+
+    /// 登録されていないセンサーからの送信を承認待ちとして記録する
+    ///
+    /// 承認待ちのセンサーが`sensor_pending_limit`台に達していて記録しなかった場合は`false`を返す。
+    async fn record_pending(&self, id: &str, reading: &SensorReading, now: DateTime<Utc>) -> anyhow::Result<bool>;
+
+    /// 承認待ちのセンサーを、初めて送信があった順に返す
+    async fn list_pending(&self) -> anyhow::Result<Vec<PendingSensor>>;
+
+    async fn is_pending(&self, id: &str) -> anyhow::Result<bool>;
+
+    /// 承認待ちの一覧から取り除く。承認待ちでなかった場合は`false`を返す
+    async fn remove_pending(&self, id: &str) -> anyhow::Result<bool>;
+}
+
+/// 計測値の登録と、センサーに返す送信間隔
+#[async_trait]
+pub trait ReadingStore: Send + Sync {
+    /// 運用中のセンサーの署名用シークレット
+    ///
+    /// センサーが登録されていない場合は`None`、シークレットが未発行の場合は`Some(None)`を返す。
+    async fn sensor_secret(&self, id: &str) -> anyhow::Result<Option<Option<String>>>;
+
+    /// 複数センサーの計測値を登録する
+    ///
+    /// 署名時刻が前回受け付けたものより新しいセンサーのみ、署名時刻を記録して計測値を登録する。
+    /// 各計測値は校正してからQCで検査する。各センサーの最新の計測値が`CURRENT_DATA`になり、
+    /// QCで受け付けた計測値があった場合のみ、それらをまとめて水位を再計算し、警報の状態を更新する。
+    async fn ingest_readings(&self, sensors: Vec<SignedReadings>) -> anyhow::Result<Ingested>;
+
+    /// 送信間隔の決定に使うセンサーの状態を返す
+    ///
+    /// 水位の変化率は`since`以降に受け付けた計測値から求める。登録されていないセンサーは含まない。
+    async fn interval_states(&self, ids: &[String], since: DateTime<Utc>) -> anyhow::Result<Vec<(String, SensorState)>>;
+
+    /// 監査のため、決定した送信間隔と理由を最新のセンサーデータに記録する
+    ///
+    /// 同じセンサーデータについて何度決定しても、以前の記録は履歴として残す。
+    async fn record_intervals(&self, decisions: &[(String, IntervalDecision)], decided_at: DateTime<Local>) -> anyhow::Result<()>;
+}
+
+/// 河川ノードの水位とタイルの所属
+#[async_trait]
+pub trait RiverStore: Send + Sync {
+    /// タイルに所属する河川ノードの水位を返す
+    ///
+    /// `forecast`を指定した場合は、現在の水位の代わりにその日時に最も近い予測を使う。
+    async fn tile_water_levels(&self, tile: TileId, forecast: Option<DateTime<Utc>>) -> anyhow::Result<Vec<NodeLevel>>;
+}
+
+/// ハンドラーが使うすべての格納先
+pub trait Store: SensorStore + ReadingStore + RiverStore {}
+
+impl<T: SensorStore + ReadingStore + RiverStore> Store for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SensorSettings {
+        SensorSettings {
+            altitude: 10.0,
+            interval: 600,
+            min_interval: Some(60),
+            max_interval: Some(3600),
+            scope: 1000.0,
+            scope_upstream: None,
+            scope_downstream: None,
+        }
+    }
+
+    fn patch() -> SensorPatch {
+        SensorPatch {
+            altitude: None,
+            interval: None,
+            min_interval: None,
+            max_interval: None,
+            scope: None,
+            scope_upstream: None,
+            scope_downstream: None,
+            parent_node: None,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_settings() {
+        assert_eq!(validate_settings(&settings()), Ok(()));
+        assert_eq!(validate_settings(&SensorSettings { min_interval: None, max_interval: None, ..settings() }), Ok(()));
+        assert_eq!(validate_settings(&SensorSettings { min_interval: Some(600), max_interval: Some(600), ..settings() }), Ok(()));
+        assert_eq!(validate_settings(&SensorSettings { altitude: -100.0, scope_upstream: Some(500.0), ..settings() }), Ok(()));
+    }
+
+    #[test]
+    fn rejects_out_of_range_altitude_and_scope() {
+        assert!(validate_settings(&SensorSettings { altitude: 4000.5, ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { altitude: f64::NAN, ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { scope: 0.0, ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { scope_upstream: Some(f64::INFINITY), ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { scope_downstream: Some(-1.0), ..settings() }).is_err());
+    }
+
+    #[test]
+    fn rejects_inconsistent_intervals() {
+        assert!(validate_settings(&SensorSettings { interval: 0, ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { min_interval: Some(0), ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { min_interval: Some(601), ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { max_interval: Some(599), ..settings() }).is_err());
+        assert!(validate_settings(&SensorSettings { interval: 60, min_interval: Some(120), max_interval: Some(30), ..settings() }).is_err());
+    }
+
+    #[test]
+    fn patched_settings_are_compared_with_stored_values() {
+        // 送信間隔の下限だけを、保存された送信間隔より大きくする
+        let patched = settings().patched(&SensorPatch { min_interval: Some(900), ..patch() });
+        assert_eq!(patched, SensorSettings { min_interval: Some(900), ..settings() });
+        assert!(validate_settings(&patched).is_err());
+
+        // 送信間隔も同時に変更すれば受け付ける
+        let patched = settings().patched(&SensorPatch { interval: Some(1200), min_interval: Some(900), ..patch() });
+        assert_eq!(validate_settings(&patched), Ok(()));
+
+        // 保存された上限より大きい下限は、送信間隔を変更しても受け付けない
+        let patched = settings().patched(&SensorPatch { interval: Some(4000), min_interval: Some(4000), ..patch() });
+        assert!(validate_settings(&patched).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! Neo4jに保存する格納先
+
+mod reading;
+mod river;
+mod sensor;
+#[cfg(test)]
+mod tests;
+
+use std::sync::Arc;
+
+use neo4rs::Graph;
+
+use crate::affects::{AffectsLimits, Traversal};
+use crate::db::Capabilities;
+use crate::env::EnvVars;
+
+/// Neo4jに保存する格納先
+#[derive(Clone)]
+pub struct Neo4jStore {
+    graph: Graph,
+    env: Arc<EnvVars>,
+    /// 接続したNeo4jで使える機能
+    capabilities: Capabilities,
+}
+
+impl Neo4jStore {
+    pub fn new(graph: Graph, env: Arc<EnvVars>, capabilities: Capabilities) -> Self {
+        Self {
+            graph,
+            env,
+            capabilities,
+        }
+    }
+
+    fn affects_limits(&self) -> AffectsLimits {
+        AffectsLimits::from(&*self.env)
+    }
+
+    fn traversal(&self) -> Traversal {
+        Traversal::from(self.capabilities)
+    }
+}
@@ -0,0 +1,406 @@
+//! 計測値の登録と、送信間隔の決定に使うセンサーの状態
+
+use std::collections::HashMap;
+
+use axum::async_trait;
+use chrono::{DateTime, Local, Utc};
+use neo4rs::{query, BoltType, Txn};
+
+use crate::alert::{evaluate_alerts, Thresholds, EFFECTIVE_THRESHOLD};
+use crate::calibration::{load_calibrations, Calibration};
+use crate::forecast::{to_samples, TrendKind};
+use crate::interval::{IntervalDecision, SensorState};
+use crate::qc::{QcConfig, QcResult, QcSample, QcStatus};
+use crate::store::neo4j::Neo4jStore;
+use crate::store::{Ingested, Reading, ReadingStore, SignedReadings};
+use crate::water_level::update_water_levels;
+use crate::webhook::{enqueue, Event, EventType};
+
+/// 計測値のQCに必要な、計測時刻より前のデータを取得する
+///
+/// センサーの標高、受け付けた最新の計測値、棄却されたものも含めた直近`window`件の水位(新しい順)を返す。
+/// 移設前のデータは標高が異なるため使わない。
+/// 連結リストは計測時刻より新しいデータ、直近`window`件、受け付けたデータに至るまでの棄却されたデータの間だけたどる。
+/// センサーが存在しない場合や運用を終了している場合は`None`を返す。
+async fn qc_context(tnx: &mut Txn, id: &str, time: &DateTime<Local>, window: usize) -> anyhow::Result<Option<(f64, Option<QcSample>, Vec<f64>)>> {
+    let mut result = tnx.execute(query(&format!(
+        r#"
+// 1. 運用中のセンサーの取得
+MATCH (sensor:Sensor {{id: $id}})
+WHERE sensor.decommissioned_at IS NULL
+OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(current:SensorData)
+
+// 2. 計測時刻以降のデータの間だけたどり、計測時刻より前の最新のデータを取得
+OPTIONAL MATCH newerPath = (current)-[:PREVIOUS_DATA*0..]->(newer:SensorData)
+WHERE all(data IN nodes(newerPath) WHERE datetime(data.time) >= datetime($time))
+WITH sensor, current, newer
+ORDER BY length(newerPath) DESC
+LIMIT 1
+WITH sensor,
+     CASE WHEN newer IS NULL THEN current ELSE [(newer)-[:PREVIOUS_DATA]->(next:SensorData) | next][0] END AS latest
+
+// 3. 直近`window`件のデータを新しい順に取得
+OPTIONAL MATCH recentPath = (latest)-[:PREVIOUS_DATA*0..{max_hops}]->(data:SensorData)
+WHERE sensor.relocated_at IS NULL OR datetime(data.time) >= datetime(sensor.relocated_at)
+WITH sensor, latest, data
+ORDER BY length(recentPath)
+WITH sensor, latest, collect(data) AS recent
+
+// 4. 棄却されたデータの間だけたどり、受け付けた最新のデータを取得
+OPTIONAL MATCH rejectedPath = (latest)-[:PREVIOUS_DATA*0..]->(rejected:SensorData)
+WHERE all(data IN nodes(rejectedPath) WHERE data.qc_status = 'rejected')
+WITH sensor, latest, recent, rejected
+ORDER BY length(rejectedPath) DESC
+LIMIT 1
+WITH sensor, recent,
+     CASE WHEN rejected IS NULL THEN latest ELSE [(rejected)-[:PREVIOUS_DATA]->(next:SensorData) | next][0] END AS previous
+WITH sensor, recent,
+     CASE WHEN sensor.relocated_at IS NULL OR datetime(previous.time) >= datetime(sensor.relocated_at) THEN previous END AS previous
+
+RETURN sensor.altitude AS altitude,
+       [data IN recent | sensor.altitude - data.distance] AS recent,
+       sensor.altitude - previous.distance AS previous_level,
+       previous.time AS previous_time
+"#,
+        max_hops = window.saturating_sub(1),
+    ))
+        .param("id", id)
+        .param("time", time.to_rfc3339())
+    ).await?;
+
+    let mut context = None;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        let previous_level = row.get::<Option<f64>>("previous_level")?;
+        let previous_time = row.get::<Option<String>>("previous_time")?;
+        let previous = match (previous_level, previous_time) {
+            (Some(water_level), Some(time)) => Some(QcSample {
+                water_level,
+                time: DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc),
+            }),
+            _ => None,
+        };
+
+        context = Some((row.get::<f64>("altitude")?, previous, row.get::<Vec<f64>>("recent")?));
+    }
+
+    Ok(context)
+}
+
+/// センサーデータを作成し、`PREVIOUS_DATA`の連結リストの計測時刻順の位置に挿入する
+///
+/// 距離は校正後の値`distance`と、センサーが送った値`raw_distance`の両方を保存する。
+/// QCの結果は`qc_status`と`qc_reasons`に保存する。
+///
+/// 最新のデータ以降に計測された場合は`CURRENT_DATA`の直後に繋ぐ。遡って送られた場合も、
+/// 連結リストをたどるのは計測時刻より新しいデータの間だけで、履歴全体は読まない。
+///
+/// 挿入したデータが最新のデータ(`CURRENT_DATA`)になった場合は`Some(true)`、
+/// センサーが存在しない場合は`None`を返す。
+async fn insert_reading(tnx: &mut Txn, id: &str, reading: &Reading, distance: f64, qc: &QcResult) -> anyhow::Result<Option<bool>> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. センサーの取得
+MATCH (sensor:Sensor {id: $id})
+
+// 2. 新しいセンサーデータの作成
+CREATE (newData:SensorData {
+    raw_distance: $raw_distance,
+    distance: $distance,
+    battery_voltage: $battery_voltage,
+    previous_sleep_time: $previous_sleep_time,
+    network_status: $network_status,
+    time: $time,
+    qc_status: $qc_status,
+    qc_reasons: $qc_reasons
+})
+
+// 3. 最新のデータ以降に計測された(またはデータが一つもない)場合は、新しいデータが最新のデータになる
+WITH sensor, newData
+OPTIONAL MATCH (sensor)-[currentRel:CURRENT_DATA]->(current:SensorData)
+WITH sensor, newData, current, currentRel,
+     current IS NULL OR datetime(current.time) <= datetime($time) AS isCurrent
+
+// 4. 遡って送られた場合は、計測時刻より新しいデータの間だけ末尾に向かってたどり、
+//    挿入位置の前のデータ(計測時刻より新しいもののうち最も古いもの)を取得
+OPTIONAL MATCH path = (current)-[:PREVIOUS_DATA*0..]->(newer:SensorData)
+WHERE NOT isCurrent
+  AND all(data IN nodes(path) WHERE datetime(data.time) > datetime($time))
+WITH sensor, newData, current, currentRel, isCurrent, newer
+ORDER BY length(path) DESC
+LIMIT 1
+
+// 5. 挿入位置の次のデータ(末尾に追加する場合は存在しない)を取得
+OPTIONAL MATCH (newer)-[outgoing:PREVIOUS_DATA]->(older:SensorData)
+
+// 6. 新しいデータを繋ぐ
+FOREACH (_ IN CASE WHEN isCurrent THEN [1] ELSE [] END |
+    CREATE (sensor)-[:CURRENT_DATA]->(newData))
+FOREACH (_ IN CASE WHEN isCurrent AND current IS NOT NULL THEN [1] ELSE [] END |
+    CREATE (newData)-[:PREVIOUS_DATA]->(current))
+FOREACH (_ IN CASE WHEN newer IS NOT NULL THEN [1] ELSE [] END |
+    CREATE (newer)-[:PREVIOUS_DATA]->(newData))
+FOREACH (_ IN CASE WHEN older IS NOT NULL THEN [1] ELSE [] END |
+    CREATE (newData)-[:PREVIOUS_DATA]->(older))
+
+// 7. 繋ぎ直した元の関係を削除
+FOREACH (rel IN CASE WHEN isCurrent AND currentRel IS NOT NULL THEN [currentRel] ELSE [] END |
+    DELETE rel)
+FOREACH (rel IN CASE WHEN outgoing IS NOT NULL THEN [outgoing] ELSE [] END |
+    DELETE rel)
+
+RETURN isCurrent AS is_current
+"#)
+        .param("id", id)
+        .param("raw_distance", reading.distance)
+        .param("distance", distance)
+        .param("battery_voltage", reading.battery_voltage)
+        .param("previous_sleep_time", reading.previous_sleep_time)
+        .param("network_status", reading.network_status.clone())
+        .param("time", reading.time.to_rfc3339())
+        .param("qc_status", qc.status.to_string())
+        .param("qc_reasons", qc.reasons.iter().map(|reason| reason.to_string()).collect::<Vec<_>>())
+    ).await?;
+
+    let mut is_current = None;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        is_current = Some(row.get::<bool>("is_current")?);
+    }
+
+    Ok(is_current)
+}
+
+/// リプレイ防止のため、署名時刻が前回より新しい場合のみ記録する
+///
+/// 記録した場合は`Some(true)`、前回以前の場合は`Some(false)`、運用中のセンサーが存在しない場合は`None`を返す。
+async fn accept_signature(tnx: &mut Txn, id: &str, signed_at: i64) -> anyhow::Result<Option<bool>> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. 同時に送られた同じ署名を両方受け付けないよう、書き込みロックを取ってから前回の署名時刻を読む
+MATCH (sensor:Sensor {id: $id})
+WHERE sensor.decommissioned_at IS NULL
+SET sensor._signing = true
+REMOVE sensor._signing
+
+// 2. 前回より新しい場合のみ記録
+WITH sensor, coalesce(sensor.last_signed_at, 0) < $signed_at AS accepted
+FOREACH (_ IN CASE WHEN accepted THEN [1] ELSE [] END |
+    SET sensor.last_signed_at = $signed_at)
+
+RETURN accepted
+"#)
+        .param("id", id)
+        .param("signed_at", signed_at)
+    ).await?;
+
+    let mut accepted = None;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        accepted = Some(row.get::<bool>("accepted")?);
+    }
+
+    Ok(accepted)
+}
+
+#[async_trait]
+impl ReadingStore for Neo4jStore {
+    async fn sensor_secret(&self, id: &str) -> anyhow::Result<Option<Option<String>>> {
+        let row = self.graph.execute(query(
+            r#"
+MATCH (sensor:Sensor {id: $id})
+WHERE sensor.decommissioned_at IS NULL
+RETURN sensor.secret AS secret
+            "#,
+        )
+            .param("id", id))
+            .await?
+            .next()
+            .await?;
+
+        Ok(row.map(|row| row.get::<Option<String>>("secret")).transpose()?)
+    }
+
+    /// 警報の段階の変化と棄却した計測値は、同じトランザクションでWebhookのアウトボックスに保存する。
+    async fn ingest_readings(&self, sensors: Vec<SignedReadings>) -> anyhow::Result<Ingested> {
+        let qc_config = QcConfig::from(&*self.env);
+        let mut tnx = self.graph.start_txn().await?;
+
+        let result = async {
+            let mut ingested = Ingested { updated_ids: Vec::new(), unknown_ids: Vec::new(), replayed_ids: Vec::new(), alert_changes: Vec::new(), events: Vec::new() };
+
+            for SignedReadings { id, signed_at, mut readings } in sensors {
+                match accept_signature(&mut tnx, &id, signed_at).await? {
+                    Some(true) => {}
+                    Some(false) => {
+                        ingested.replayed_ids.push(id);
+                        continue;
+                    }
+                    None => {
+                        ingested.unknown_ids.push(id);
+                        continue;
+                    }
+                }
+
+                readings.sort_by_key(|reading| reading.time);
+
+                let calibrations = load_calibrations(&mut tnx, &id).await?;
+
+                let mut newest_is_current = Some(false);
+                let mut accepted = false;
+                for reading in &readings {
+                    let time = reading.time.with_timezone(&Utc);
+                    let distance = Calibration::effective_at(&calibrations, time).apply(reading.distance as f64);
+
+                    let Some((altitude, previous, recent)) = qc_context(&mut tnx, &id, &reading.time, qc_config.window).await? else {
+                        newest_is_current = None;
+                        break;
+                    };
+                    let qc = qc_config.check(altitude, distance, time, previous.as_ref(), &recent);
+                    if qc.status == QcStatus::Rejected {
+                        println!("ingest_readings: rejected reading of {id} at {time}: {:?}", qc.reasons);
+                        ingested.events.push(Event::new(
+                            EventType::ReadingRejected,
+                            id.clone(),
+                            serde_json::json!({
+                                "time": time,
+                                "distance": distance,
+                                "reasons": qc.reasons.iter().map(|reason| reason.to_string()).collect::<Vec<_>>(),
+                            }),
+                        ));
+                    }
+                    accepted |= qc.status == QcStatus::Ok;
+
+                    newest_is_current = insert_reading(&mut tnx, &id, reading, distance, &qc).await?;
+                    if newest_is_current.is_none() {
+                        break;
+                    }
+                }
+
+                match newest_is_current {
+                    Some(true) if accepted => ingested.updated_ids.push(id),
+                    Some(_) => {}
+                    None => ingested.unknown_ids.push(id),
+                }
+            }
+
+            if !ingested.updated_ids.is_empty() {
+                update_water_levels(&mut tnx, &ingested.updated_ids, &self.env).await?;
+                ingested.alert_changes = evaluate_alerts(&mut tnx, &ingested.updated_ids, self.env.alert_hysteresis).await?;
+                ingested.events.extend(ingested.alert_changes.iter().map(Event::alert_changed));
+            }
+
+            enqueue(&mut tnx, &ingested.events).await?;
+
+            anyhow::Ok(ingested)
+        }.await;
+
+        match result {
+            Ok(ingested) => {
+                tnx.commit().await?;
+                for change in &ingested.alert_changes {
+                    println!("ingest_readings: alert of {} changed from {} to {} at level {}", change.sensor_id, change.from, change.to, change.level);
+                }
+                Ok(ingested)
+            }
+            Err(e) => {
+                println!("ingest_readings: {:?}", e);
+                tnx.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn interval_states(&self, ids: &[String], since: DateTime<Utc>) -> anyhow::Result<Vec<(String, SensorState)>> {
+        let mut result = self.graph.execute(query(&format!(
+            r#"
+// 1. センサーと最新のデータ、警戒段階の閾値の取得
+UNWIND $ids AS id
+MATCH (sensor:Sensor {{id: id}})
+OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(current:SensorData)
+{EFFECTIVE_THRESHOLD}
+
+// 2. 受け付けた直近のデータを計測時刻の昇順に取得
+// 連結リストは計測時刻の降順なので、$since より古いデータに達したところでたどるのをやめる
+OPTIONAL MATCH path = (current)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+WHERE all(node IN nodes(path) WHERE datetime(node.time) >= datetime($since))
+  AND coalesce(data.qc_status, 'ok') <> 'rejected'
+  AND (sensor.relocated_at IS NULL OR datetime(data.time) >= datetime(sensor.relocated_at))
+WITH sensor, current, threshold, data
+ORDER BY datetime(data.time)
+
+RETURN sensor.id AS id,
+       tointeger(sensor.interval) AS interval,
+       tointeger(sensor.min_interval) AS min_interval,
+       tointeger(sensor.max_interval) AS max_interval,
+       tointeger(current.battery_voltage) AS battery_voltage,
+       sensor.water_level AS water_level,
+       CASE WHEN threshold IS NULL THEN null ELSE {{
+           standby: threshold.standby,
+           caution: threshold.caution,
+           evacuation: threshold.evacuation,
+           danger: threshold.danger,
+           hysteresis: threshold.hysteresis
+       }} END AS thresholds,
+       collect(CASE WHEN data IS NULL THEN null ELSE {{time: data.time, water_level: sensor.altitude - data.distance}} END) AS history
+            "#,
+        ))
+            .param("ids", ids.to_vec())
+            .param("since", since.to_rfc3339()))
+            .await?;
+
+        let mut states = Vec::with_capacity(ids.len());
+        while let Some(row) = result.next().await? {
+            let id = row.get::<String>("id")?;
+
+            let rate = to_samples(row.get("history")?)
+                ?
+                .and_then(|(_, samples)| TrendKind::Linear.fit(&samples, 2))
+                .map(|trend| trend.rate);
+
+            let water_level = row.get::<Option<f64>>("water_level")?;
+            let threshold_margin = row.get::<Option<Thresholds>>("thresholds")?
+                .zip(water_level)
+                .and_then(|(thresholds, water_level)| thresholds.margin(water_level));
+
+            states.push((id, SensorState {
+                interval: row.get("interval")?,
+                min_interval: row.get("min_interval")?,
+                max_interval: row.get("max_interval")?,
+                rate,
+                battery_voltage: row.get("battery_voltage")?,
+                threshold_margin,
+            }));
+        }
+
+        Ok(states)
+    }
+
+    async fn record_intervals(&self, decisions: &[(String, IntervalDecision)], decided_at: DateTime<Local>) -> anyhow::Result<()> {
+        let decisions = decisions.iter()
+            .map(|(id, decision)| HashMap::from([
+                ("id".to_string(), BoltType::from(id.clone())),
+                ("interval".to_string(), BoltType::from(decision.interval)),
+                ("reasons".to_string(), BoltType::from(decision.reasons.iter().map(|reason| reason.to_string()).collect::<Vec<_>>())),
+            ]))
+            .collect::<Vec<_>>();
+
+        self.graph.run(query(
+            r#"
+// 最新の決定はセンサーデータに、これまでの決定は IntervalDecision ノードに残す
+UNWIND $decisions AS decision
+MATCH (:Sensor {id: decision.id})-[:CURRENT_DATA]->(current:SensorData)
+SET current.interval = decision.interval,
+    current.interval_reasons = decision.reasons,
+    current.interval_decided_at = $decided_at
+CREATE (current)-[:INTERVAL_DECISION]->(:IntervalDecision {
+    interval: decision.interval,
+    reasons: decision.reasons,
+    decided_at: $decided_at
+})
+            "#,
+        )
+            .param("decisions", decisions)
+            .param("decided_at", decided_at.to_rfc3339()))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! 河川ノードの水位とタイルの所属
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltFloat, BoltPoint2D};
+
+use crate::cache::items::TileId;
+use crate::store::neo4j::Neo4jStore;
+use crate::store::{NodeLevel, RiverStore};
+
+// あるタイル座標までのレベル0のタイルからのパスを計算
+fn calc_tile_path(z: u32, x: u32, y: u32) -> String {
+    (0..=z).map(|current_z| {
+        // 算術演算で求める
+        let x = x >> (z - current_z);
+        let y = y >> (z - current_z);
+        format!("(:Tile{current_z}{{x:{x},y:{y}}})")
+    }).collect::<Vec<_>>()
+        .join("-[:CHILD]->")
+}
+
+#[async_trait]
+impl RiverStore for Neo4jStore {
+    async fn tile_water_levels(&self, tile: TileId, forecast: Option<DateTime<Utc>>) -> anyhow::Result<Vec<NodeLevel>> {
+        let tile_path = calc_tile_path(tile.z as u32, tile.x, tile.y);
+
+        let query = match forecast {
+            Some(time) => query(
+                &format!(r#"
+MATCH {tile_path}-[:MEMBER]->(n:RiverNode)-[:WATER_LEVEL_FORECAST]->(forecast:WaterLevelForecast)
+WITH n, forecast
+ORDER BY abs(duration.inSeconds(datetime(forecast.time), datetime($time)).seconds)
+WITH n, head(collect(forecast)) AS forecast
+RETURN n.location AS location, forecast.value AS water_level
+                "#)
+            )
+                .param("time", time.to_rfc3339()),
+            None => query(
+                &format!(r#"
+MATCH {tile_path}-[:MEMBER]->(n:RiverNode)-[:WATER_LEVEL]->(wl:WaterLevel)
+RETURN n.location AS location, wl.value AS water_level
+                "#)
+            ),
+        };
+
+        let mut result = self.graph.execute(query).await?;
+
+        let mut nodes = Vec::new();
+        while let Some(row) = result.next().await? {
+            let location: BoltPoint2D = row.get("location")?;
+            let water_level: BoltFloat = row.get("water_level")?;
+
+            nodes.push(NodeLevel {
+                location: (location.x.value, location.y.value),
+                water_level: water_level.value,
+            });
+        }
+
+        Ok(nodes)
+    }
+}
@@ -0,0 +1,618 @@
+//! センサーの登録・変更・運用終了
+
+use std::collections::HashMap;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Txn};
+use openapi::models::{ApiSensorsPostRequest, SensorLocation, SensorPatch, SensorReading, SensorRegistration, SensorUpdate};
+use serde::Deserialize;
+
+use crate::affects::{link_to_river, Affected, Scope};
+use crate::auth::generate_secret;
+use crate::provision::{self, PendingSensor};
+use crate::store::neo4j::Neo4jStore;
+use crate::store::{validate_settings, SensorSettings, SensorStore, Updated};
+use crate::water_level::{affected_node_ids, recompute_nodes, update_water_levels};
+
+/// センサーの`BELONGS_TO`と`AFFECTS`を削除し、それまで繋がっていた河川ノードの`elementId`を返す
+async fn unlink_from_river(tnx: &mut Txn, id: &str) -> anyhow::Result<Vec<String>> {
+    let mut result = tnx.execute(query(
+        r#"
+MATCH (:Sensor {id: $id})-[link:BELONGS_TO|AFFECTS]->(node:RiverNode)
+DELETE link
+RETURN DISTINCT elementId(node) AS node
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut nodes = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        nodes.push(row.get("node")?);
+    }
+
+    Ok(nodes)
+}
+
+/// センサーの場所(親河川ノードまたは標高)が変わる場合に、移設の履歴を`(:Sensor)-[:LOCATED_AT]->(:SensorLocation)`として記録する
+///
+/// 履歴がないセンサーを初めて移設するときは、移設前の場所も登録時からの場所として記録する。
+/// 運用を終了したセンサーを再登録する場合は、場所が変わらなくても新しい場所の履歴を始める。
+/// 移設前のデータを現在の標高で水位にしないよう、`relocated_at`を記録してセンサーの水位を消す。
+/// センサーが存在しない場合や場所が変わらない場合は`false`を返す。
+async fn record_relocation(tnx: &mut Txn, id: &str, parent_node: &str, altitude: f64, now: &str) -> anyhow::Result<bool> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. 場所が変わるセンサー(または再登録するセンサー)と、現在の場所の履歴を取得
+MATCH (sensor:Sensor {id: $id})
+WHERE sensor.parent_node <> $parent_node OR sensor.altitude <> $altitude OR sensor.decommissioned_at IS NOT NULL
+OPTIONAL MATCH (sensor)-[:LOCATED_AT]->(current:SensorLocation)
+WHERE current.until IS NULL
+
+// 2. 現在の場所の履歴を閉じる(履歴がない場合は登録時からの場所として作る)
+FOREACH (_ IN CASE WHEN current IS NULL AND sensor.decommissioned_at IS NULL THEN [1] ELSE [] END |
+    CREATE (sensor)-[:LOCATED_AT]->(:SensorLocation {
+        parent_node: sensor.parent_node,
+        altitude: sensor.altitude,
+        since: null,
+        until: $now
+    }))
+SET current.until = $now
+
+// 3. 新しい場所の履歴を作る
+WITH sensor
+CREATE (sensor)-[:LOCATED_AT]->(:SensorLocation {
+    parent_node: $parent_node,
+    altitude: $altitude,
+    since: $now,
+    until: null
+})
+SET sensor.relocated_at = $now,
+    sensor.water_level = null,
+    sensor.water_level_time = null
+RETURN count(sensor) AS relocated
+        "#,
+    )
+        .param("id", id)
+        .param("parent_node", parent_node)
+        .param("altitude", altitude)
+        .param("now", now)
+    ).await?;
+
+    let mut relocated = 0;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        relocated = row.get::<i64>("relocated")?;
+    }
+
+    Ok(relocated > 0)
+}
+
+/// センサーの運用を終了する
+///
+/// センサーデータや警報の履歴は残したまま、署名用シークレットと水位を消して計測値を受け付けないようにする。
+/// 場所の履歴は運用終了日時で閉じる。センサーが存在しない場合や既に運用を終了している場合は`false`を返す。
+async fn decommission(tnx: &mut Txn, id: &str, now: &str) -> anyhow::Result<bool> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. 運用中のセンサーと、現在の場所の履歴を取得
+MATCH (sensor:Sensor {id: $id})
+WHERE sensor.decommissioned_at IS NULL
+OPTIONAL MATCH (sensor)-[:LOCATED_AT]->(current:SensorLocation)
+WHERE current.until IS NULL
+
+// 2. 現在の場所の履歴を閉じる(履歴がない場合は登録時からの場所として作る)
+FOREACH (_ IN CASE WHEN current IS NULL THEN [1] ELSE [] END |
+    CREATE (sensor)-[:LOCATED_AT]->(:SensorLocation {
+        parent_node: sensor.parent_node,
+        altitude: sensor.altitude,
+        since: null,
+        until: $now
+    }))
+SET current.until = $now
+
+// 3. 計測値を受け付けないようにする
+WITH sensor
+SET sensor.decommissioned_at = $now,
+    sensor.secret = null,
+    sensor.water_level = null,
+    sensor.water_level_time = null
+RETURN count(sensor) AS decommissioned
+        "#,
+    )
+        .param("id", id)
+        .param("now", now)
+    ).await?;
+
+    let mut decommissioned = 0;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        decommissioned = row.get::<i64>("decommissioned")?;
+    }
+
+    Ok(decommissioned > 0)
+}
+
+/// センサーを、センサーデータの連結リストと付随するノードごと削除する
+///
+/// センサーが存在しない場合は`false`を返す。
+async fn purge_sensor(tnx: &mut Txn, id: &str) -> anyhow::Result<bool> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. センサーデータの連結リストを削除
+MATCH (sensor:Sensor {id: $id})
+OPTIONAL MATCH (sensor)-[:CURRENT_DATA]->(:SensorData)-[:PREVIOUS_DATA*0..]->(data:SensorData)
+OPTIONAL MATCH (data)-[:INTERVAL_DECISION]->(decision:IntervalDecision)
+DETACH DELETE decision, data
+
+// 2. 集計、場所の履歴、校正値、閾値、警報とその履歴を削除
+WITH DISTINCT sensor
+OPTIONAL MATCH (sensor)-[:SUMMARY|LOCATED_AT|CALIBRATION|ALERT_THRESHOLD|ALERT]->(owned)
+OPTIONAL MATCH (owned)-[:EVENT]->(event:AlertEvent)
+DETACH DELETE event, owned
+
+// 3. センサーを削除
+WITH DISTINCT sensor
+DETACH DELETE sensor
+RETURN count(*) AS purged
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut purged = 0;
+    while let Some(row) = result.next(tnx.handle()).await? {
+        purged = row.get::<i64>("purged")?;
+    }
+
+    Ok(purged > 0)
+}
+
+/// Neo4jから取得した、センサーの現在の設定値と場所
+#[derive(Debug, Deserialize)]
+struct CurrentSettings {
+    altitude: f64,
+    interval: i32,
+    min_interval: Option<i32>,
+    max_interval: Option<i32>,
+    scope: f64,
+    scope_upstream: Option<f64>,
+    scope_downstream: Option<f64>,
+    parent_node: String,
+}
+
+impl From<&CurrentSettings> for SensorSettings {
+    fn from(current: &CurrentSettings) -> Self {
+        SensorSettings {
+            altitude: current.altitude,
+            interval: current.interval,
+            min_interval: current.min_interval,
+            max_interval: current.max_interval,
+            scope: current.scope,
+            scope_upstream: current.scope_upstream,
+            scope_downstream: current.scope_downstream,
+        }
+    }
+}
+
+/// Neo4jから取得した、センサーの場所の履歴
+#[derive(Debug, Deserialize)]
+struct LocationRow {
+    parent_node: String,
+    altitude: f64,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl TryFrom<LocationRow> for SensorLocation {
+    type Error = anyhow::Error;
+
+    fn try_from(row: LocationRow) -> Result<Self, Self::Error> {
+        let parse_time = |time: &str| anyhow::Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc));
+
+        Ok(SensorLocation {
+            parent_node: row.parent_node,
+            altitude: row.altitude,
+            since: row.since.as_deref().map(parse_time).transpose()?,
+            until: row.until.as_deref().map(parse_time).transpose()?,
+        })
+    }
+}
+
+/// Neo4jから取得した、影響範囲を作り直すセンサー
+#[derive(Debug, Deserialize)]
+struct LinkedSensor {
+    id: String,
+    parent_node: String,
+    scope: f64,
+    scope_upstream: Option<f64>,
+    scope_downstream: Option<f64>,
+}
+
+/// センサーの影響範囲を作り直した結果
+#[derive(Debug)]
+pub struct Relinked {
+    pub id: String,
+    pub parent_node: String,
+    /// 作り直した影響範囲。親河川ノードが存在しないため作り直さなかった場合は`None`
+    pub affects: Option<Affected>,
+}
+
+impl Neo4jStore {
+    /// 運用中のセンサーの`AFFECTS`を、保存された設定値と現在の`RIVER_LINK`から作り直す
+    ///
+    /// 影響範囲の求め方を変えた後や、河川ノードを追加・削除した後に使う。センサーごとに1つのトランザクションで作り直し、
+    /// 水位を計算し直す。`ids`が空の場合は運用中のすべてのセンサーを対象にする。
+    pub async fn relink_sensors(&self, ids: &[String]) -> anyhow::Result<Vec<Relinked>> {
+        // 1. 対象のセンサーを取得
+        let mut result = self.graph.execute(query(
+            r#"
+MATCH (sensor:Sensor)
+WHERE sensor.decommissioned_at IS NULL AND (size($ids) = 0 OR sensor.id IN $ids)
+RETURN sensor.id AS id,
+       sensor.parent_node AS parent_node,
+       toFloat(sensor.scope) AS scope,
+       toFloat(sensor.scope_upstream) AS scope_upstream,
+       toFloat(sensor.scope_downstream) AS scope_downstream
+ORDER BY id
+            "#,
+        )
+            .param("ids", ids.to_vec())
+        ).await?;
+
+        let mut sensors = Vec::new();
+        while let Some(row) = result.next().await? {
+            sensors.push(row.to::<LinkedSensor>()?);
+        }
+
+        // 2. センサーごとに AFFECTS を作り直し、水位を計算し直す
+        let mut relinked = Vec::new();
+        for LinkedSensor { id, parent_node, scope, scope_upstream, scope_downstream } in sensors {
+            let mut tnx = self.graph.start_txn().await?;
+
+            let result = async {
+                let mut previous_nodes = unlink_from_river(&mut tnx, &id).await?;
+                let scope = Scope::new(scope, scope_upstream, scope_downstream);
+                let Some(affects) = link_to_river(&mut tnx, &id, &parent_node, scope, self.affects_limits(), self.traversal()).await? else {
+                    return anyhow::Ok(None);
+                };
+
+                let ids = [id.clone()];
+                update_water_levels(&mut tnx, &ids, &self.env).await?;
+
+                let current_nodes = affected_node_ids(&mut tnx, &ids).await?;
+                previous_nodes.retain(|node| !current_nodes.contains(node));
+                recompute_nodes(&mut tnx, &previous_nodes, &self.env).await?;
+
+                anyhow::Ok(Some(affects))
+            }.await;
+
+            let affects = match result {
+                Ok(Some(affects)) => {
+                    tnx.commit().await?;
+                    Some(affects)
+                }
+                Ok(None) => {
+                    tnx.rollback().await?;
+                    None
+                }
+                Err(e) => {
+                    println!("relink_sensors: {:?}", e);
+                    tnx.rollback().await?;
+                    return Err(e);
+                }
+            };
+            relinked.push(Relinked { id, parent_node, affects });
+        }
+
+        Ok(relinked)
+    }
+}
+
+#[async_trait]
+impl SensorStore for Neo4jStore {
+    async fn register_sensor(&self, id: &str, settings: &ApiSensorsPostRequest, rotate_secret: bool) -> anyhow::Result<Option<SensorRegistration>> {
+        let ApiSensorsPostRequest { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream, ref parent_node } = *settings;
+        let affects_scope = Scope::new(scope as f64, scope_upstream.map(f64::from), scope_downstream.map(f64::from));
+
+        let mut tnx = self.graph.start_txn().await?;
+
+        let result = async {
+            // 1. 既存のセンサーの場所が変わる場合は移設の履歴を残す
+            record_relocation(&mut tnx, id, parent_node, altitude as f64, &Utc::now().to_rfc3339()).await?;
+
+            // 2. センサーを作成または上書きし、影響範囲を作り直す
+            tnx.run(
+                query(r#"
+MERGE (sensor:Sensor{id:$id})
+ON CREATE
+ SET sensor.altitude=$altitude,
+     sensor.scope=$scope,
+     sensor.scope_upstream=$scope_upstream,
+     sensor.scope_downstream=$scope_downstream,
+     sensor.interval=$interval,
+     sensor.min_interval=$min_interval,
+     sensor.max_interval=$max_interval,
+     sensor.parent_node=$parent_node
+ON MATCH
+ SET sensor.altitude=$altitude,
+     sensor.scope=$scope,
+     sensor.scope_upstream=$scope_upstream,
+     sensor.scope_downstream=$scope_downstream,
+     sensor.interval=$interval,
+     sensor.min_interval=$min_interval,
+     sensor.max_interval=$max_interval,
+     sensor.parent_node=$parent_node,
+     sensor.decommissioned_at=null
+                "#)
+                    .param("id", id)
+                    .param("altitude", altitude)
+                    .param("interval", interval)
+                    .param("min_interval", min_interval)
+                    .param("max_interval", max_interval)
+                    .param("scope", scope)
+                    .param("scope_upstream", scope_upstream)
+                    .param("scope_downstream", scope_downstream)
+                    .param("parent_node", parent_node.clone()),
+            ).await?;
+
+            let mut previous_nodes = unlink_from_river(&mut tnx, id).await?;
+            let Some(affects) = link_to_river(&mut tnx, id, parent_node, affects_scope, self.affects_limits(), self.traversal()).await? else {
+                return anyhow::Ok(None);
+            };
+
+            // 3. 水位を計算し直す。再登録で影響範囲から外れた河川ノードは残ったセンサーから推定し直す
+            update_water_levels(&mut tnx, &[id.to_string()], &self.env).await?;
+
+            let current_nodes = affected_node_ids(&mut tnx, &[id.to_string()]).await?;
+            previous_nodes.retain(|node| !current_nodes.contains(node));
+            recompute_nodes(&mut tnx, &previous_nodes, &self.env).await?;
+
+            // 4. 未発行または再発行を指定された場合のみ、署名用シークレットを発行する
+            let secret = generate_secret();
+            let mut result = tnx.execute(
+                query(r#"
+MATCH (sensor:Sensor{id:$id})
+WITH sensor, sensor.secret IS NULL OR $rotate_secret AS issue
+SET sensor.secret = CASE WHEN issue THEN $secret ELSE sensor.secret END
+RETURN issue
+                "#)
+                    .param("id", id)
+                    .param("rotate_secret", rotate_secret)
+                    .param("secret", secret.clone()),
+            ).await?;
+
+            let mut issued = false;
+            while let Some(row) = result.next(tnx.handle()).await? {
+                issued = row.get::<bool>("issue")?;
+            }
+            anyhow::Ok(Some(SensorRegistration { secret: issued.then_some(secret), affects: affects.into() }))
+        }.await;
+
+        match result {
+            Ok(Some(registration)) => {
+                tnx.commit().await?;
+                Ok(Some(registration))
+            }
+            Ok(None) => {
+                tnx.rollback().await?;
+                Ok(None)
+            }
+            Err(e) => {
+                println!("register_sensor: {:?}", e);
+                tnx.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn update_sensor(&self, id: &str, patch: &SensorPatch) -> anyhow::Result<Updated> {
+        let SensorPatch { altitude, interval, min_interval, max_interval, scope, scope_upstream, scope_downstream, ref parent_node } = *patch;
+
+        // 指定された項目のみ変更する
+        let mut changes = HashMap::<String, BoltType>::new();
+        if let Some(altitude) = altitude {
+            changes.insert("altitude".to_string(), altitude.into());
+        }
+        if let Some(interval) = interval {
+            changes.insert("interval".to_string(), interval.into());
+        }
+        if let Some(min_interval) = min_interval {
+            changes.insert("min_interval".to_string(), min_interval.into());
+        }
+        if let Some(max_interval) = max_interval {
+            changes.insert("max_interval".to_string(), max_interval.into());
+        }
+        if let Some(scope) = scope {
+            changes.insert("scope".to_string(), scope.into());
+        }
+        if let Some(scope_upstream) = scope_upstream {
+            changes.insert("scope_upstream".to_string(), scope_upstream.into());
+        }
+        if let Some(scope_downstream) = scope_downstream {
+            changes.insert("scope_downstream".to_string(), scope_downstream.into());
+        }
+        if let Some(parent_node) = parent_node {
+            changes.insert("parent_node".to_string(), parent_node.clone().into());
+        }
+
+        let mut tnx = self.graph.start_txn().await?;
+
+        let result = async {
+            // 1. 現在の設定値を取得し、変更を重ねた設定値を検証
+            let mut result = tnx.execute(query(
+                r#"
+MATCH (sensor:Sensor {id: $id})
+WHERE sensor.decommissioned_at IS NULL
+RETURN toFloat(sensor.altitude) AS altitude,
+       sensor.interval AS interval,
+       sensor.min_interval AS min_interval,
+       sensor.max_interval AS max_interval,
+       toFloat(sensor.scope) AS scope,
+       toFloat(sensor.scope_upstream) AS scope_upstream,
+       toFloat(sensor.scope_downstream) AS scope_downstream,
+       sensor.parent_node AS parent_node
+                "#,
+            )
+                .param("id", id)
+            ).await?;
+
+            let mut current = None;
+            while let Some(row) = result.next(tnx.handle()).await? {
+                current = Some(row.to::<CurrentSettings>()?);
+            }
+            let Some(current) = current else {
+                return anyhow::Ok(Updated::NotFound);
+            };
+
+            let settings = SensorSettings::from(&current).patched(patch);
+            if let Err(message) = validate_settings(&settings) {
+                return anyhow::Ok(Updated::Invalid(message));
+            }
+
+            let new_parent_node = parent_node.clone().unwrap_or(current.parent_node.clone());
+            let new_altitude = settings.altitude;
+            let current_scope = Scope::new(current.scope, current.scope_upstream, current.scope_downstream);
+            let new_scope = Scope::new(settings.scope, settings.scope_upstream, settings.scope_downstream);
+
+            // 2. 場所が変わる場合は移設の履歴を残す
+            let relocated = record_relocation(&mut tnx, id, &new_parent_node, new_altitude, &Utc::now().to_rfc3339()).await?;
+
+            // 3. 指定された項目を変更
+            tnx.run(query(
+                r#"
+MATCH (sensor:Sensor {id: $id})
+SET sensor += $changes
+                "#,
+            )
+                .param("id", id)
+                .param("changes", changes)
+            ).await?;
+
+            // 4. 影響範囲が変わる場合のみ AFFECTS を作り直す
+            let relinked = new_parent_node != current.parent_node || new_scope != current_scope;
+            let mut previous_nodes = Vec::new();
+            let mut affects = None;
+            if relinked {
+                previous_nodes = unlink_from_river(&mut tnx, id).await?;
+                affects = link_to_river(&mut tnx, id, &new_parent_node, new_scope, self.affects_limits(), self.traversal()).await?;
+                if affects.is_none() {
+                    return anyhow::Ok(Updated::UnknownRiverNode);
+                }
+            }
+
+            // 5. 水位を計算し直す。影響範囲から外れた河川ノードは残ったセンサーから推定し直す
+            if relinked || relocated {
+                update_water_levels(&mut tnx, &[id.to_string()], &self.env).await?;
+
+                let current_nodes = affected_node_ids(&mut tnx, &[id.to_string()]).await?;
+                previous_nodes.retain(|node| !current_nodes.contains(node));
+                recompute_nodes(&mut tnx, &previous_nodes, &self.env).await?;
+            }
+
+            anyhow::Ok(Updated::Updated(SensorUpdate { relocated, relinked, affects: affects.map(Into::into) }))
+        }.await;
+
+        match result {
+            Ok(Updated::Updated(update)) => {
+                tnx.commit().await?;
+                Ok(Updated::Updated(update))
+            }
+            Ok(updated) => {
+                tnx.rollback().await?;
+                Ok(updated)
+            }
+            Err(e) => {
+                println!("update_sensor: {:?}", e);
+                tnx.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn decommission_sensor(&self, id: &str, purge: bool) -> anyhow::Result<bool> {
+        let mut tnx = self.graph.start_txn().await?;
+
+        let result = async {
+            // 1. 運用を終了し、影響範囲から外れた河川ノードの水位を残ったセンサーから推定し直す
+            if !decommission(&mut tnx, id, &Utc::now().to_rfc3339()).await? && !purge {
+                return anyhow::Ok(false);
+            }
+            let previous_nodes = unlink_from_river(&mut tnx, id).await?;
+            recompute_nodes(&mut tnx, &previous_nodes, &self.env).await?;
+
+            // 2. 完全に削除する場合は、センサーデータとセンサーに付随するノードも削除する
+            if purge {
+                return purge_sensor(&mut tnx, id).await;
+            }
+
+            anyhow::Ok(true)
+        }.await;
+
+        match result {
+            Ok(true) => {
+                tnx.commit().await?;
+                Ok(true)
+            }
+            Ok(false) => {
+                tnx.rollback().await?;
+                Ok(false)
+            }
+            Err(e) => {
+                println!("decommission_sensor: {:?}", e);
+                tnx.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn sensor_locations(&self, id: &str) -> anyhow::Result<Option<Vec<SensorLocation>>> {
+        // 移設したことがないセンサーは、現在の場所を登録時からの場所として返す
+        let mut result = self.graph.execute(query(
+            r#"
+MATCH (sensor:Sensor {id: $id})
+OPTIONAL MATCH (sensor)-[:LOCATED_AT]->(location:SensorLocation)
+WITH sensor, location
+ORDER BY location.since IS NOT NULL, datetime(location.since)
+WITH sensor, collect(CASE WHEN location IS NULL THEN null ELSE {
+    parent_node: location.parent_node,
+    altitude: toFloat(location.altitude),
+    since: location.since,
+    until: location.until
+} END) AS locations
+RETURN CASE WHEN size(locations) = 0
+    THEN [{parent_node: sensor.parent_node, altitude: toFloat(sensor.altitude), since: null, until: null}]
+    ELSE locations
+END AS locations
+            "#,
+        )
+            .param("id", id)
+        ).await?;
+
+        let Some(row) = result.next().await? else {
+            return Ok(None);
+        };
+
+        let locations = row.get::<Vec<LocationRow>>("locations")?
+            .into_iter()
+            .map(SensorLocation::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Some(locations))
+    }
+
+    async fn record_pending(&self, id: &str, reading: &SensorReading, now: DateTime<Utc>) -> anyhow::Result<bool> {
+        provision::record_pending(&self.graph, id, reading, now, self.env.sensor_pending_limit).await
+    }
+
+    async fn list_pending(&self) -> anyhow::Result<Vec<PendingSensor>> {
+        provision::list_pending(&self.graph).await
+    }
+
+    async fn is_pending(&self, id: &str) -> anyhow::Result<bool> {
+        provision::is_pending(&self.graph, id).await
+    }
+
+    async fn remove_pending(&self, id: &str) -> anyhow::Result<bool> {
+        provision::remove_pending(&self.graph, id).await
+    }
+}
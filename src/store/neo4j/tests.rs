@@ -0,0 +1,303 @@
+//! 実際のNeo4jを使う格納先のテスト
+//!
+//! `NEO4J_TEST_URI`を指定した場合のみ実行し、指定しない場合は何もせずに成功する。
+//! 認証とデータベースは`NEO4J_TEST_AUTH`(省略時は`neo4j/password`)と`NEO4J_TEST_DB`(省略時は`neo4j`)で指定する。
+//!
+//! ```sh
+//! NEO4J_TEST_URI=127.0.0.1:7687 cargo test store::neo4j
+//! ```
+//!
+//! 河川ノードとセンサーはテストごとに固有のIDで作り、終了時に削除する。
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use neo4rs::{query, BoltType, Graph};
+use openapi::models::{ApiSensorsPostRequest, SensorPatch};
+use uuid::Uuid;
+
+use crate::db::{self, Capabilities};
+use crate::env::EnvVars;
+use crate::migration::migrate;
+use crate::store::neo4j::Neo4jStore;
+use crate::store::{Reading, ReadingStore, SensorStore, SignedReadings, Updated};
+
+/// テスト用の河川: 親河川ノード`M`から下流側で支川が分かれて`C`で合流し、`E`へは直接と`C`経由の経路がある
+const RIVER_LINKS: [(&str, &str, f64, Option<f64>); 8] = [
+    ("U", "M", 100.0, Some(0.01)),
+    ("M", "A", 100.0, Some(0.01)),
+    ("M", "B", 50.0, Some(0.02)),
+    ("M", "E", 400.0, None),
+    ("A", "C", 100.0, Some(0.01)),
+    ("B", "C", 100.0, Some(0.02)),
+    ("C", "D", 100.0, Some(0.01)),
+    ("C", "E", 50.0, Some(0.01)),
+];
+
+/// テスト用のNeo4jと、テストごとに作った河川
+struct TestRiver {
+    graph: Graph,
+    env: Arc<EnvVars>,
+    /// `env`を作った環境変数
+    vars: Vec<(String, String)>,
+    /// 河川ノードの`hilbert18`の接頭辞
+    prefix: String,
+}
+
+impl TestRiver {
+    /// `NEO4J_TEST_URI`のNeo4jに接続してスキーマを移行し、テスト用の河川を作る。指定されていない場合は`None`を返す
+    async fn create() -> Option<Self> {
+        let Ok(uri) = std::env::var("NEO4J_TEST_URI") else {
+            println!("NEO4J_TEST_URI is not set, skipped");
+            return None;
+        };
+
+        let dir = std::env::temp_dir().join(format!("nahlun-server-test-{}", Uuid::new_v4()));
+        let vars = vec![
+            ("NEO4J_URI", uri),
+            ("NEO4J_AUTH", std::env::var("NEO4J_TEST_AUTH").unwrap_or_else(|_| "neo4j/password".to_string())),
+            ("NEO4J_DB", std::env::var("NEO4J_TEST_DB").unwrap_or_else(|_| "neo4j".to_string())),
+            ("SOCKETIO_HOST", "http://127.0.0.1:3000".to_string()),
+            ("SERVER_HOST", "127.0.0.1:0".to_string()),
+            ("CLIENT_HOST", "http://localhost:3000".to_string()),
+            ("DISK_CACHE_BASE_PATH", dir.join("cache").to_string_lossy().into_owned()),
+            ("DISK_CACHE_MAX_SIZE", "1048576".to_string()),
+            ("MEMORY_CACHE_MAX_SIZE", "1048576".to_string()),
+            ("ADMIN_KEY", "test-admin-key".to_string()),
+        ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect::<Vec<_>>();
+        let env = Arc::new(envy::from_iter::<_, EnvVars>(vars.clone()).unwrap());
+
+        let graph = db::connect(&env).await;
+        migrate(&graph, false).await.unwrap();
+
+        let river = TestRiver { graph, env, vars, prefix: format!("test-{}-", Uuid::new_v4()) };
+
+        let nodes = ["U", "M", "A", "B", "C", "D", "E"]
+            .iter()
+            .enumerate()
+            .map(|(i, node)| HashMap::from([
+                ("hilbert18".to_string(), BoltType::from(river.node(node))),
+                ("longitude".to_string(), BoltType::from(135.0 + i as f64 * 0.001)),
+            ]))
+            .collect::<Vec<_>>();
+        let links = RIVER_LINKS
+            .iter()
+            .map(|(from, to, length, slope)| HashMap::from([
+                ("from".to_string(), BoltType::from(river.node(from))),
+                ("to".to_string(), BoltType::from(river.node(to))),
+                ("length".to_string(), BoltType::from(*length)),
+                ("slope".to_string(), BoltType::from(*slope)),
+            ]))
+            .collect::<Vec<_>>();
+
+        river.graph.run(query(
+            r#"
+UNWIND $nodes AS node
+CREATE (:RiverNode {hilbert18: node.hilbert18, location: point({longitude: node.longitude, latitude: 35.0})})
+            "#,
+        )
+            .param("nodes", nodes)
+        ).await.unwrap();
+        river.graph.run(query(
+            r#"
+UNWIND $links AS link
+MATCH (from:RiverNode {hilbert18: link.from})
+MATCH (to:RiverNode {hilbert18: link.to})
+CREATE (from)-[:RIVER_LINK {length: link.length, slope: link.slope}]->(to)
+            "#,
+        )
+            .param("links", links)
+        ).await.unwrap();
+
+        Some(river)
+    }
+
+    /// テスト用の河川ノードの`hilbert18`
+    fn node(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    fn store(&self, apoc: bool) -> Neo4jStore {
+        Neo4jStore::new(self.graph.clone(), self.env.clone(), Capabilities { apoc })
+    }
+
+    /// 一方向に到達する河川ノードの数の上限を変えた格納先
+    fn store_with_max_paths(&self, apoc: bool, max_paths: i64) -> Neo4jStore {
+        let vars = self.vars.iter().cloned().chain([("AFFECTS_MAX_PATHS".to_string(), max_paths.to_string())]);
+        let env = Arc::new(envy::from_iter::<_, EnvVars>(vars).unwrap());
+        Neo4jStore::new(self.graph.clone(), env, Capabilities { apoc })
+    }
+
+    fn settings(&self, parent_node: &str) -> ApiSensorsPostRequest {
+        ApiSensorsPostRequest {
+            altitude: 10.0,
+            interval: 600,
+            min_interval: None,
+            max_interval: None,
+            scope: 250.0,
+            scope_upstream: None,
+            scope_downstream: None,
+            parent_node: self.node(parent_node),
+        }
+    }
+
+    /// センサーの`AFFECTS`を、河川ノードの名前の順に返す
+    async fn affects(&self, id: &str) -> Vec<(String, f64, String)> {
+        let mut result = self.graph.execute(query(
+            r#"
+MATCH (:Sensor {id: $id})-[affects:AFFECTS]->(node:RiverNode)
+RETURN node.hilbert18 AS node, toFloat(affects.distance) AS distance, affects.position AS position
+ORDER BY node
+            "#,
+        )
+            .param("id", id)
+        ).await.unwrap();
+
+        let mut affects = Vec::new();
+        while let Some(row) = result.next().await.unwrap() {
+            let node = row.get::<String>("node").unwrap();
+            affects.push((node.trim_start_matches(&self.prefix).to_string(), row.get("distance").unwrap(), row.get("position").unwrap()));
+        }
+
+        affects
+    }
+
+    /// センサーとテスト用の河川を削除する
+    async fn remove(self, ids: &[String]) {
+        let store = self.store(false);
+        for id in ids {
+            store.decommission_sensor(id, true).await.unwrap();
+        }
+
+        self.graph.run(query(
+            r#"
+MATCH (node:RiverNode)
+WHERE node.hilbert18 STARTS WITH $prefix
+DETACH DELETE node
+            "#,
+        )
+            .param("prefix", self.prefix.clone())
+        ).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn sensor_lifecycle() {
+    let Some(river) = TestRiver::create().await else {
+        return;
+    };
+    let store = river.store(false);
+    let id = Uuid::new_v4().to_string();
+
+    let registration = store.register_sensor(&id, &river.settings("M"), false).await.unwrap().unwrap();
+    assert!(registration.secret.is_some());
+    // 上流側の U と、下流側の A、B、C、D、E(C 経由で 200 メートル)
+    assert_eq!(registration.affects.nodes, 6);
+    assert_eq!(registration.affects.max_distance, Some(250.0));
+
+    // 保存された送信間隔より大きい下限は受け付けず、何も変更しない
+    let patch = SensorPatch {
+        altitude: None,
+        interval: None,
+        min_interval: Some(900),
+        max_interval: None,
+        scope: None,
+        scope_upstream: None,
+        scope_downstream: None,
+        parent_node: Some(river.node("B")),
+    };
+    assert!(matches!(store.update_sensor(&id, &patch).await.unwrap(), Updated::Invalid(_)));
+    assert_eq!(store.sensor_locations(&id).await.unwrap().unwrap().len(), 1);
+
+    let patch = SensorPatch { min_interval: None, ..patch };
+    let Updated::Updated(update) = store.update_sensor(&id, &patch).await.unwrap() else {
+        panic!("sensor {id} was not updated");
+    };
+    assert!(update.relocated);
+    assert!(update.relinked);
+    let locations = store.sensor_locations(&id).await.unwrap().unwrap();
+    assert_eq!(locations.len(), 2);
+    assert_eq!(locations[1].parent_node, river.node("B"));
+
+    let secret = store.sensor_secret(&id).await.unwrap();
+    assert_eq!(secret, Some(registration.secret));
+
+    let signed_at = chrono::Utc::now().timestamp();
+    let readings = vec![Reading::new(1.5, 3300, 600, "good".to_string(), None)];
+    let ingested = store.ingest_readings(vec![SignedReadings { id: id.clone(), signed_at, readings }]).await.unwrap();
+    assert_eq!(ingested.unknown_ids, Vec::<String>::new());
+    assert!(ingested.replayed_ids.is_empty());
+
+    // 同じ署名時刻の計測値はリプレイとして登録しない
+    let readings = vec![Reading::new(1.5, 3300, 600, "good".to_string(), None)];
+    let ingested = store.ingest_readings(vec![SignedReadings { id: id.clone(), signed_at, readings }]).await.unwrap();
+    assert_eq!(ingested.replayed_ids, vec![id.clone()]);
+
+    assert!(store.decommission_sensor(&id, false).await.unwrap());
+    assert!(river.affects(&id).await.is_empty());
+    assert!(!store.decommission_sensor(&id, false).await.unwrap());
+
+    river.remove(&[id]).await;
+}
+
+#[tokio::test]
+async fn apoc_and_native_traversals_create_the_same_affects() {
+    let Some(river) = TestRiver::create().await else {
+        return;
+    };
+    let id = Uuid::new_v4().to_string();
+
+    let native = river.store(false);
+    native.register_sensor(&id, &river.settings("M"), false).await.unwrap().unwrap();
+    let expected = river.affects(&id).await;
+    assert_eq!(expected.iter().map(|(node, distance, _)| (node.as_str(), *distance)).collect::<Vec<_>>(), [
+        ("A", 100.0),
+        ("B", 50.0),
+        ("C", 150.0),
+        ("D", 250.0),
+        ("E", 200.0),
+        ("U", 100.0),
+    ]);
+
+    if db::detect_capabilities(&river.graph).await.apoc {
+        let relinked = river.store(true).relink_sensors(&[id.clone()]).await.unwrap();
+        assert_eq!(relinked.len(), 1);
+        assert_eq!(river.affects(&id).await, expected);
+    } else {
+        println!("APOC is not installed, skipped the APOC traversal");
+    }
+
+    river.remove(&[id]).await;
+}
+
+#[tokio::test]
+async fn apoc_traversal_falls_back_when_the_subgraph_is_too_large() {
+    let Some(river) = TestRiver::create().await else {
+        return;
+    };
+    let id = Uuid::new_v4().to_string();
+
+    // 下流側は親河川ノードを含めて6つの河川ノードがあり、APOCで取得する上限((2 + 1) * 2)に達する
+    let registration = river.store_with_max_paths(false, 2).register_sensor(&id, &river.settings("M"), false).await.unwrap().unwrap();
+    assert!(registration.affects.truncated);
+    let expected = river.affects(&id).await;
+    assert_eq!(expected.iter().map(|(node, distance, _)| (node.as_str(), *distance)).collect::<Vec<_>>(), [
+        ("A", 100.0),
+        ("B", 50.0),
+        ("U", 100.0),
+    ]);
+
+    if db::detect_capabilities(&river.graph).await.apoc {
+        let relinked = river.store_with_max_paths(true, 2).relink_sensors(&[id.clone()]).await.unwrap();
+        assert_eq!(relinked.len(), 1);
+        assert!(relinked[0].affects.as_ref().is_some_and(|affects| affects.truncated));
+        assert_eq!(river.affects(&id).await, expected);
+    } else {
+        println!("APOC is not installed, skipped the APOC traversal");
+    }
+
+    river.remove(&[id]).await;
+}
@@ -0,0 +1,83 @@
+//! 河道の縦断形を考慮した補間
+
+use super::linear::{interpolate, nearest};
+use super::{Contribution, Position, WaterLevelModel};
+
+/// 上流・下流の最寄りのセンサーの間は河道に沿った距離で線形補間し、
+/// 片側にしかセンサーがない河川網の端では河床勾配に沿って外挿する
+///
+/// 水面は河床と平行に下るものとして、外挿ではセンサーから対象までの河床の落差を水位から引く。
+/// 落差は`RIVER_LINK`の`slope`から求めたもの(`bed_drop`)を使い、分からない場合は`bed_slope`と距離から求める。
+/// 支流など上下流の関係にないセンサーしかない場合は、最寄りのセンサーから河床の落差が分かる場合のみ外挿する。
+#[derive(Debug, Clone)]
+pub struct HydraulicInterpolation {
+    /// 河床の落差が分からない場合に使う河床勾配
+    pub bed_slope: f64,
+}
+
+impl HydraulicInterpolation {
+    /// 1台のセンサーの水位から、河床勾配に沿って対象の河川ノードの水位を外挿する
+    fn extrapolate(&self, contribution: &Contribution) -> Option<f64> {
+        let bed_drop = match (contribution.bed_drop, contribution.position) {
+            (Some(bed_drop), _) => bed_drop,
+            (None, Position::Upstream) => self.bed_slope * contribution.distance,
+            (None, Position::Downstream) => -self.bed_slope * contribution.distance,
+            (None, Position::Other) => return None,
+        };
+
+        Some(contribution.water_level - bed_drop)
+    }
+}
+
+impl WaterLevelModel for HydraulicInterpolation {
+    fn estimate(&self, contributions: &[Contribution]) -> Option<f64> {
+        let upstream = nearest(contributions, Position::Upstream);
+        let downstream = nearest(contributions, Position::Downstream);
+
+        match (upstream, downstream) {
+            (Some(upstream), Some(downstream)) => Some(interpolate(upstream, downstream)),
+            (Some(only), None) | (None, Some(only)) => self.extrapolate(only),
+            (None, None) => nearest(contributions, Position::Other).and_then(|other| self.extrapolate(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: HydraulicInterpolation = HydraulicInterpolation { bed_slope: 0.001 };
+
+    fn contribution(water_level: f64, distance: f64, position: Position, bed_drop: Option<f64>) -> Contribution {
+        Contribution { water_level, distance, position, freshness: 1.0, bed_drop }
+    }
+
+    #[test]
+    fn interpolates_between_gauges() {
+        let level = MODEL.estimate(&[
+            contribution(12.0, 100.0, Position::Upstream, Some(0.5)),
+            contribution(8.0, 300.0, Position::Downstream, Some(-1.5)),
+        ]).unwrap();
+        assert!((level - 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn extrapolates_downstream_along_bed_drop() {
+        let level = MODEL.estimate(&[contribution(12.0, 1000.0, Position::Upstream, Some(2.0))]).unwrap();
+        assert!((level - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn extrapolates_upstream_with_default_slope() {
+        let level = MODEL.estimate(&[contribution(12.0, 1000.0, Position::Downstream, None)]).unwrap();
+        assert!((level - 13.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tributary_gauge_needs_known_bed_drop() {
+        assert_eq!(MODEL.estimate(&[contribution(12.0, 1000.0, Position::Other, None)]), None);
+
+        let level = MODEL.estimate(&[contribution(12.0, 1000.0, Position::Other, Some(-0.5))]).unwrap();
+        assert!((level - 12.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,77 @@
+//! 逆距離加重(IDW)
+
+use super::{Contribution, WaterLevelModel};
+
+/// 距離がこれ以下のセンサーは対象の河川ノードと同じ位置にあるものとする
+const COINCIDENT_DISTANCE: f64 = 1e-6;
+
+/// 距離の`power`乗の逆数に計測値の新しさを掛けた重みで、水位を加重平均する
+#[derive(Debug, Clone)]
+pub struct InverseDistanceWeighting {
+    pub power: f64,
+}
+
+impl WaterLevelModel for InverseDistanceWeighting {
+    fn estimate(&self, contributions: &[Contribution]) -> Option<f64> {
+        // 同じ位置にあるセンサーがあれば、距離の逆数が発散するのでそれらの値だけを使う
+        let coincident = contributions.iter()
+            .filter(|contribution| contribution.distance <= COINCIDENT_DISTANCE)
+            .collect::<Vec<_>>();
+
+        let (weighted_sum, total_weight) = if coincident.is_empty() {
+            contributions.iter()
+                .map(|contribution| (contribution.water_level, contribution.freshness / contribution.distance.powf(self.power)))
+                .fold((0.0, 0.0), |(sum, total), (value, weight)| (sum + value * weight, total + weight))
+        } else {
+            coincident.iter()
+                .map(|contribution| (contribution.water_level, contribution.freshness))
+                .fold((0.0, 0.0), |(sum, total), (value, weight)| (sum + value * weight, total + weight))
+        };
+
+        (total_weight > 0.0).then(|| weighted_sum / total_weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::water_level::Position;
+
+    fn contribution(water_level: f64, distance: f64) -> Contribution {
+        Contribution { water_level, distance, position: Position::Other, freshness: 1.0, bed_drop: None }
+    }
+
+    #[test]
+    fn weights_by_inverse_distance() {
+        let model = InverseDistanceWeighting { power: 1.0 };
+        let level = model.estimate(&[contribution(10.0, 100.0), contribution(20.0, 300.0)]).unwrap();
+        assert!((level - 12.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn higher_power_favours_nearer_sensors() {
+        let model = InverseDistanceWeighting { power: 2.0 };
+        let level = model.estimate(&[contribution(10.0, 100.0), contribution(20.0, 300.0)]).unwrap();
+        assert!((level - 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sensor_at_zero_distance_wins() {
+        let model = InverseDistanceWeighting { power: 1.0 };
+        let level = model.estimate(&[contribution(10.0, 0.0), contribution(20.0, 300.0)]).unwrap();
+        assert_eq!(level, 10.0);
+    }
+
+    #[test]
+    fn stale_sensors_weigh_less() {
+        let model = InverseDistanceWeighting { power: 1.0 };
+        let stale = Contribution { freshness: 0.25, ..contribution(20.0, 100.0) };
+        let level = model.estimate(&[contribution(10.0, 100.0), stale]).unwrap();
+        assert!((level - 12.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_contributions() {
+        assert_eq!(InverseDistanceWeighting { power: 1.0 }.estimate(&[]), None);
+    }
+}
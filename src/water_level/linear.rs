@@ -0,0 +1,93 @@
+//! 河道に沿った線形補間
+
+use super::{Contribution, NearestSensor, Position, WaterLevelModel};
+
+/// 上流・下流それぞれの最寄りのセンサーの水位を、河道に沿った距離で線形補間する
+///
+/// 片側にしかセンサーがない場合はそのセンサーの水位を使う。
+/// 上下流の関係にあるセンサーがない場合は、最寄りのセンサーの水位を使う。
+#[derive(Debug, Clone)]
+pub struct LinearInterpolation;
+
+/// 指定した位置にあるセンサーのうち最も近いもの
+pub(super) fn nearest(contributions: &[Contribution], position: Position) -> Option<&Contribution> {
+    contributions.iter()
+        .filter(|contribution| contribution.position == position)
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// 上流と下流のセンサーの水位を、河道に沿った距離で線形補間する
+pub(super) fn interpolate(upstream: &Contribution, downstream: &Contribution) -> f64 {
+    let total = upstream.distance + downstream.distance;
+    if total <= 0.0 {
+        return (upstream.water_level + downstream.water_level) / 2.0;
+    }
+    upstream.water_level + (downstream.water_level - upstream.water_level) * upstream.distance / total
+}
+
+impl WaterLevelModel for LinearInterpolation {
+    fn estimate(&self, contributions: &[Contribution]) -> Option<f64> {
+        let upstream = nearest(contributions, Position::Upstream);
+        let downstream = nearest(contributions, Position::Downstream);
+
+        match (upstream, downstream) {
+            (Some(upstream), Some(downstream)) => Some(interpolate(upstream, downstream)),
+            (Some(only), None) | (None, Some(only)) => Some(only.water_level),
+            (None, None) => NearestSensor.estimate(contributions),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contribution(water_level: f64, distance: f64, position: Position) -> Contribution {
+        Contribution { water_level, distance, position, freshness: 1.0, bed_drop: None }
+    }
+
+    #[test]
+    fn interpolates_between_upstream_and_downstream() {
+        let level = LinearInterpolation.estimate(&[
+            contribution(12.0, 100.0, Position::Upstream),
+            contribution(8.0, 300.0, Position::Downstream),
+        ]).unwrap();
+        assert!((level - 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn uses_nearest_gauge_on_each_side() {
+        let level = LinearInterpolation.estimate(&[
+            contribution(12.0, 100.0, Position::Upstream),
+            contribution(30.0, 500.0, Position::Upstream),
+            contribution(8.0, 100.0, Position::Downstream),
+            contribution(0.0, 900.0, Position::Downstream),
+        ]).unwrap();
+        assert!((level - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ignores_tributaries_when_both_sides_are_gauged() {
+        let level = LinearInterpolation.estimate(&[
+            contribution(12.0, 100.0, Position::Upstream),
+            contribution(8.0, 100.0, Position::Downstream),
+            contribution(50.0, 10.0, Position::Other),
+        ]).unwrap();
+        assert!((level - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn single_side_uses_that_gauge() {
+        let level = LinearInterpolation.estimate(&[contribution(12.0, 100.0, Position::Upstream)]);
+        assert_eq!(level, Some(12.0));
+    }
+
+    #[test]
+    fn falls_back_to_nearest_without_upstream_or_downstream() {
+        let level = LinearInterpolation.estimate(&[
+            contribution(12.0, 300.0, Position::Other),
+            contribution(8.0, 100.0, Position::Other),
+        ]);
+        assert_eq!(level, Some(8.0));
+    }
+}
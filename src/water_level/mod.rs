@@ -0,0 +1,488 @@
+//! 河川ノードの水位の推定
+//!
+//! センサーの水位から、`AFFECTS`で結ばれた河川ノードの水位を推定する。
+//! 推定方法は`WaterLevelModel`として実装し、水系(`(:RiverSystem)-[:IN_SYSTEM]->(:RiverNode)`)ごとに選択できる。
+//! 水系に設定がない場合は環境変数の設定を使う。
+//!
+//! `RIVER_LINK`は上流から下流に向かう関係とし、`AFFECTS`の`position`には対象の河川ノードから見たセンサーの位置
+//! (`upstream`、`downstream`、どちらでもない場合は`other`)を、`bed_drop`にはセンサーの親河川ノードから
+//! 対象の河川ノードまでの河床の落差(`RIVER_LINK`の`slope`と`length`から求めたもの)を保存する。
+//! `AFFECTS`の作り方は[`crate::affects`]を参照。上流側・下流側に分けてたどるため、新しく作る`AFFECTS`は`other`にならない。
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use neo4rs::{query, BoltType, Graph, Txn};
+use serde::Deserialize;
+
+use crate::env::EnvVars;
+
+mod hydraulic;
+mod idw;
+mod linear;
+mod nearest;
+
+pub use hydraulic::HydraulicInterpolation;
+pub use idw::InverseDistanceWeighting;
+pub use linear::LinearInterpolation;
+pub use nearest::NearestSensor;
+
+/// 対象の河川ノードから見たセンサーの位置
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Position {
+    /// 河道を下って対象に到達する
+    Upstream,
+    /// 河道を遡って対象に到達する
+    Downstream,
+    /// 支流を経由するなど、上下流の関係にない
+    #[default]
+    Other,
+}
+
+/// 河川ノードの水位の推定に使う、1台のセンサーの寄与
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contribution {
+    /// センサーの水位
+    pub water_level: f64,
+    /// 対象の河川ノードまでの河道に沿った距離
+    pub distance: f64,
+    pub position: Position,
+    /// 計測値の新しさ(0〜1)。経過時間に応じて減衰する
+    pub freshness: f64,
+    /// センサーの親河川ノードから対象の河川ノードまでの河床の落差(下流に向かって下がる場合に正)
+    pub bed_drop: Option<f64>,
+}
+
+/// 河川ノードの水位の推定方法
+pub trait WaterLevelModel: Send + Sync {
+    /// センサーの寄与から水位を推定する
+    ///
+    /// `contributions`には新しさが0より大きいものだけが渡される。推定できない場合は`None`を返す。
+    fn estimate(&self, contributions: &[Contribution]) -> Option<f64>;
+}
+
+/// 推定方法の種類
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, strum::Display, strum::EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum WaterLevelModelKind {
+    /// 逆距離加重
+    #[default]
+    Idw,
+    /// 上下流の最寄りのセンサーの間の線形補間
+    Linear,
+    /// 最寄りのセンサー
+    Nearest,
+    /// 上下流のセンサーの間の線形補間と、河川網の端での河床勾配に沿った外挿
+    Hydraulic,
+}
+
+impl From<openapi::models::WaterLevelModelKind> for WaterLevelModelKind {
+    fn from(value: openapi::models::WaterLevelModelKind) -> Self {
+        match value {
+            openapi::models::WaterLevelModelKind::Idw => WaterLevelModelKind::Idw,
+            openapi::models::WaterLevelModelKind::Linear => WaterLevelModelKind::Linear,
+            openapi::models::WaterLevelModelKind::Nearest => WaterLevelModelKind::Nearest,
+            openapi::models::WaterLevelModelKind::Hydraulic => WaterLevelModelKind::Hydraulic,
+        }
+    }
+}
+
+impl From<WaterLevelModelKind> for openapi::models::WaterLevelModelKind {
+    fn from(value: WaterLevelModelKind) -> Self {
+        match value {
+            WaterLevelModelKind::Idw => openapi::models::WaterLevelModelKind::Idw,
+            WaterLevelModelKind::Linear => openapi::models::WaterLevelModelKind::Linear,
+            WaterLevelModelKind::Nearest => openapi::models::WaterLevelModelKind::Nearest,
+            WaterLevelModelKind::Hydraulic => openapi::models::WaterLevelModelKind::Hydraulic,
+        }
+    }
+}
+
+/// 推定方法の設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelConfig {
+    pub kind: WaterLevelModelKind,
+    /// 逆距離加重のべき指数
+    pub idw_power: f64,
+    /// 河床の落差が分からない場合に使う河床勾配
+    pub bed_slope: f64,
+}
+
+impl From<&EnvVars> for ModelConfig {
+    fn from(env: &EnvVars) -> Self {
+        ModelConfig {
+            kind: env.water_level_model,
+            idw_power: env.idw_power,
+            bed_slope: env.bed_slope,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// 設定から推定方法を作る
+    pub fn build(&self) -> Box<dyn WaterLevelModel> {
+        match self.kind {
+            WaterLevelModelKind::Idw => Box::new(InverseDistanceWeighting { power: self.idw_power }),
+            WaterLevelModelKind::Linear => Box::new(LinearInterpolation),
+            WaterLevelModelKind::Nearest => Box::new(NearestSensor),
+            WaterLevelModelKind::Hydraulic => Box::new(HydraulicInterpolation { bed_slope: self.bed_slope }),
+        }
+    }
+}
+
+/// 計測値の新しさを求める
+///
+/// 経過時間`age`がセンサーの送信間隔`interval`(秒)以内なら1、
+/// 送信間隔の`stale_intervals`倍で0になるまで線形に減衰する。
+/// 経過時間や送信間隔が分からない場合は減衰させない。
+pub fn freshness(age: Option<f64>, interval: Option<f64>, stale_intervals: f64) -> f64 {
+    let (Some(age), Some(interval)) = (age, interval) else {
+        return 1.0;
+    };
+    if interval <= 0.0 || age <= interval {
+        return 1.0;
+    }
+
+    let horizon = interval * stale_intervals;
+    if age >= horizon {
+        0.0
+    } else {
+        (horizon - age) / (horizon - interval)
+    }
+}
+
+/// Neo4jから取得した、センサーの寄与
+#[derive(Debug, Deserialize)]
+struct ContributionRow {
+    sensor: String,
+    water_level: f64,
+    distance: f64,
+    position: Option<String>,
+    bed_drop: Option<f64>,
+    /// 計測値の経過時間(秒)
+    age: Option<f64>,
+    /// センサーの送信間隔(秒)
+    interval: Option<f64>,
+}
+
+/// 水位を推定する河川ノード
+#[derive(Debug, Clone)]
+pub struct AffectedNode {
+    /// 河川ノードの`elementId`
+    pub node: String,
+    pub config: ModelConfig,
+    /// センサーのIDとその寄与(新しさが0のものは含まない)
+    pub contributions: Vec<(String, Contribution)>,
+}
+
+impl AffectedNode {
+    /// センサーの水位を`water_level`で置き換えて推定する
+    ///
+    /// `water_level`が`None`を返すセンサーは現在の水位を使う。
+    pub fn estimate_with(&self, water_level: impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        let contributions = self.contributions.iter()
+            .map(|(sensor, contribution)| Contribution {
+                water_level: water_level(sensor).unwrap_or(contribution.water_level),
+                ..contribution.clone()
+            })
+            .collect::<Vec<_>>();
+
+        self.config.build().estimate(&contributions)
+    }
+
+    /// センサーの現在の水位から推定する
+    pub fn estimate(&self) -> Option<f64> {
+        self.estimate_with(|_| None)
+    }
+}
+
+/// センサーが`AFFECTS`で影響するすべての河川ノードの`elementId`を取得する
+///
+/// 複数のセンサーの影響範囲が重なる河川ノードも一度だけ返す。
+pub async fn affected_node_ids(tnx: &mut Txn, ids: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut result = tnx.execute(query(
+        r#"
+UNWIND $ids AS id
+MATCH (:Sensor {id: id})-[:AFFECTS]->(affectedRiverNode:RiverNode)
+RETURN DISTINCT elementId(affectedRiverNode) AS node
+        "#,
+    )
+        .param("ids", ids.to_vec())
+    ).await?;
+
+    let mut nodes = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        nodes.push(row.get("node")?);
+    }
+
+    Ok(nodes)
+}
+
+/// センサーが`AFFECTS`で影響するすべての河川ノードについて、推定方法とセンサーの寄与を取得する
+///
+/// 複数のセンサーの影響範囲が重なる河川ノードも一度だけ返す。
+pub async fn load_affected_nodes(tnx: &mut Txn, ids: &[String], env: &EnvVars) -> anyhow::Result<Vec<AffectedNode>> {
+    let nodes = affected_node_ids(tnx, ids).await?;
+    load_nodes(tnx, &nodes, env).await
+}
+
+/// 河川ノード(`elementId`)について、推定方法とそれに影響するセンサーの寄与を取得する
+///
+/// 影響するセンサーがない河川ノードは含まない。
+async fn load_nodes(tnx: &mut Txn, nodes: &[String], env: &EnvVars) -> anyhow::Result<Vec<AffectedNode>> {
+    let mut result = tnx.execute(query(
+        r#"
+// 1. 河川ノードと、その水系の推定方法を取得
+UNWIND $nodes AS nodeId
+MATCH (affectedRiverNode:RiverNode)
+WHERE elementId(affectedRiverNode) = nodeId
+WITH DISTINCT affectedRiverNode
+OPTIONAL MATCH (riverSystem:RiverSystem)-[:IN_SYSTEM]->(affectedRiverNode)
+WITH affectedRiverNode, head(collect(riverSystem)) AS riverSystem
+
+// 2. 河川ノードに影響するすべてのセンサーの水位を取得
+// 水位を保持していないセンサーは親河川ノードの WaterLevel で代用する
+MATCH (affectedRiverNode)<-[affectsLink:AFFECTS]-(contributor:Sensor)-[:BELONGS_TO]->(:RiverNode)-[:WATER_LEVEL]->(waterLevel:WaterLevel)
+OPTIONAL MATCH (contributor)-[:CURRENT_DATA]->(latest:SensorData)
+
+// 3. 推定に必要な値をまとめて返す
+WITH affectedRiverNode, riverSystem, {
+    sensor: contributor.id,
+    water_level: coalesce(contributor.water_level, waterLevel.value),
+    distance: toFloat(affectsLink.distance),
+    position: affectsLink.position,
+    bed_drop: toFloat(affectsLink.bed_drop),
+    age: toFloat(duration.inSeconds(datetime(coalesce(contributor.water_level_time, waterLevel.time, latest.time)), datetime()).seconds),
+    interval: toFloat(contributor.interval)
+} AS contribution
+RETURN elementId(affectedRiverNode) AS node,
+       riverSystem.model AS model,
+       riverSystem.idw_power AS idw_power,
+       riverSystem.bed_slope AS bed_slope,
+       collect(contribution) AS contributions
+        "#,
+    )
+        .param("nodes", nodes.to_vec())
+    ).await?;
+
+    let default_config = ModelConfig::from(env);
+
+    let mut nodes = Vec::new();
+    while let Some(row) = result.next(tnx.handle()).await? {
+        let config = ModelConfig {
+            kind: row.get::<Option<String>>("model")?
+                .map(|model| model.parse())
+                .transpose()?
+                .unwrap_or(default_config.kind),
+            idw_power: row.get::<Option<f64>>("idw_power")?.unwrap_or(default_config.idw_power),
+            bed_slope: row.get::<Option<f64>>("bed_slope")?.unwrap_or(default_config.bed_slope),
+        };
+
+        let mut contributions = row.get::<Vec<ContributionRow>>("contributions")?
+            .into_iter()
+            .map(|row| anyhow::Ok((row.sensor, Contribution {
+                water_level: row.water_level,
+                distance: row.distance,
+                position: row.position.map(|position| position.parse()).transpose()?.unwrap_or_default(),
+                freshness: freshness(row.age, row.interval, env.stale_reading_intervals),
+                bed_drop: row.bed_drop,
+            })))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        contributions.retain(|(_, contribution)| contribution.freshness > 0.0);
+
+        nodes.push(AffectedNode {
+            node: row.get("node")?,
+            config,
+            contributions,
+        });
+    }
+
+    Ok(nodes)
+}
+
+/// センサーの最新データから水位を計算し、影響範囲にある河川ノードの水位を更新する
+///
+/// QCで棄却されたデータと、移設前(`relocated_at`より前)のデータは使わず、受け付けた最新のデータから水位を計算する。
+/// 複数のセンサーの影響範囲が重なる河川ノードも一度だけ再計算される。
+/// 推定できなかった(すべてのセンサーの寄与が失効した)河川ノードは、水位が不明になったものとしてWaterLevelを削除する。
+pub async fn update_water_levels(tnx: &mut Txn, ids: &[String], env: &EnvVars) -> anyhow::Result<()> {
+    tnx.run(query(
+        r#"
+// 1. センサーとその受け付けた最新データ、所属する親河川ノードの取得
+// 連結リストは最新のデータから棄却されたデータの間だけたどる
+UNWIND $ids AS id
+MATCH (sensor:Sensor {id: id})-[:CURRENT_DATA]->(current:SensorData)
+OPTIONAL MATCH path = (current)-[:PREVIOUS_DATA*0..]->(rejected:SensorData)
+WHERE all(data IN nodes(path) WHERE data.qc_status = 'rejected')
+WITH sensor, current, rejected
+ORDER BY length(path) DESC
+WITH sensor, current, head(collect(rejected)) AS lastRejected
+WITH sensor,
+     CASE WHEN lastRejected IS NULL THEN current ELSE [(lastRejected)-[:PREVIOUS_DATA]->(next:SensorData) | next][0] END AS currentData
+WHERE currentData IS NOT NULL
+  AND (sensor.relocated_at IS NULL OR datetime(currentData.time) >= datetime(sensor.relocated_at))
+MATCH (sensor)-[:BELONGS_TO]->(parentRiverNode:RiverNode)
+
+// 2. センサーの水位データを計算し、センサーと WaterLevel ノードに保存
+// 水位は sensor の altitude から校正後の distance を引いた値
+// 経過時間を求めるため、計測時刻も保持する
+SET sensor.water_level = sensor.altitude - currentData.distance,
+    sensor.water_level_time = currentData.time
+MERGE (parentRiverNode)-[:WATER_LEVEL]->(parentWaterLevel:WaterLevel)
+  ON CREATE SET parentWaterLevel.value = sensor.water_level
+  ON MATCH SET parentWaterLevel.value = sensor.water_level
+SET parentWaterLevel.time = currentData.time
+        "#,
+    )
+        .param("ids", ids.to_vec())
+    ).await?;
+
+    let nodes = affected_node_ids(tnx, ids).await?;
+    recompute_nodes(tnx, &nodes, env).await
+}
+
+/// 河川ノード(`elementId`)の水位を、影響するセンサーから推定し直す
+///
+/// 推定できなかった河川ノードと、影響するセンサーがなくなった河川ノードは、水位が不明になったものとしてWaterLevelを削除する。
+/// ただし、水位を計測したセンサーが所属する(`BELONGS_TO`)河川ノードの水位はそのセンサーの水位なので、推定値で上書きも削除もしない。
+pub async fn recompute_nodes(tnx: &mut Txn, nodes: &[String], env: &EnvVars) -> anyhow::Result<()> {
+    let mut levels = Vec::new();
+    let mut unknown = Vec::new();
+    let mut missing = nodes.to_vec();
+    for affected in load_nodes(tnx, nodes, env).await? {
+        missing.retain(|node| *node != affected.node);
+        match affected.estimate() {
+            Some(value) => levels.push(HashMap::from([
+                ("node".to_string(), BoltType::from(affected.node)),
+                ("value".to_string(), BoltType::from(value)),
+            ])),
+            None => unknown.push(affected.node),
+        }
+    }
+
+    // 推定した水位を WaterLevel ノードに設定
+    // 水位を計測したセンサーが所属する河川ノードの水位は計測値なので、推定値で上書きしない
+    tnx.run(query(
+        r#"
+UNWIND $levels AS level
+MATCH (node:RiverNode)
+WHERE elementId(node) = level.node
+  AND NOT EXISTS { MATCH (sensor:Sensor)-[:BELONGS_TO]->(node) WHERE sensor.water_level IS NOT NULL }
+MERGE (node)-[:WATER_LEVEL]->(wl:WaterLevel)
+  ON CREATE SET wl.value = level.value
+  ON MATCH SET wl.value = level.value
+        "#,
+    )
+        .param("levels", levels)
+    ).await?;
+
+    // 推定できなかった河川ノードと、影響するセンサーがなくなった河川ノードの WaterLevel を削除
+    // こちらも計測値は残す
+    tnx.run(query(
+        r#"
+MATCH (node:RiverNode)-[:WATER_LEVEL]->(wl:WaterLevel)
+WHERE (elementId(node) IN $unknown OR elementId(node) IN $missing)
+  AND NOT EXISTS { MATCH (sensor:Sensor)-[:BELONGS_TO]->(node) WHERE sensor.water_level IS NOT NULL }
+DETACH DELETE wl
+        "#,
+    )
+        .param("unknown", unknown)
+        .param("missing", missing)
+    ).await?;
+
+    Ok(())
+}
+
+/// 計測値が古くなって寄与が減衰しているセンサーの影響範囲を推定し直す
+///
+/// 新しい計測値が届かない間も寄与の新しさは下がり続けるため、最新の計測から送信間隔を過ぎたセンサーを対象とする。
+/// 寄与が0になった後も一度は推定し直せるよう、`stale_reading_intervals`倍に実行間隔`period`(秒)を加えた経過時間まで含める。
+/// 推定し直したセンサーの数を返す。
+pub async fn recompute_stale(graph: &Graph, env: &EnvVars, period: f64) -> anyhow::Result<usize> {
+    let mut tnx = graph.start_txn().await?;
+
+    let result = async {
+        // 1. 寄与が減衰しているセンサーの取得
+        let mut result = tnx.execute(query(
+            r#"
+MATCH (sensor:Sensor)
+WHERE sensor.decommissioned_at IS NULL
+  AND sensor.water_level_time IS NOT NULL
+  AND sensor.interval > 0
+WITH sensor, toFloat(duration.inSeconds(datetime(sensor.water_level_time), datetime()).seconds) AS age
+WHERE age > sensor.interval
+  AND age <= sensor.interval * $stale_intervals + $period
+RETURN sensor.id AS id
+            "#,
+        )
+            .param("stale_intervals", env.stale_reading_intervals)
+            .param("period", period)
+        ).await?;
+
+        let mut ids = Vec::new();
+        while let Some(row) = result.next(tnx.handle()).await? {
+            ids.push(row.get::<String>("id")?);
+        }
+
+        // 2. 影響範囲の河川ノードを推定し直す
+        let nodes = affected_node_ids(&mut tnx, &ids).await?;
+        recompute_nodes(&mut tnx, &nodes, env).await?;
+
+        anyhow::Ok(ids.len())
+    }.await;
+
+    match result {
+        Ok(count) => {
+            tnx.commit().await?;
+            Ok(count)
+        }
+        Err(e) => {
+            tnx.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// 寄与が減衰しているセンサーの影響範囲を、`water_level_recompute_interval`秒ごとに推定し直す
+pub async fn run(graph: Graph, env: Arc<EnvVars>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(env.water_level_recompute_interval));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = recompute_stale(&graph, &env, env.water_level_recompute_interval as f64).await {
+            println!("recompute_stale: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freshness_decays_linearly_after_interval() {
+        assert_eq!(freshness(Some(30.0), Some(60.0), 3.0), 1.0);
+        assert_eq!(freshness(Some(60.0), Some(60.0), 3.0), 1.0);
+        assert_eq!(freshness(Some(120.0), Some(60.0), 3.0), 0.5);
+        assert_eq!(freshness(Some(180.0), Some(60.0), 3.0), 0.0);
+        assert_eq!(freshness(Some(600.0), Some(60.0), 3.0), 0.0);
+    }
+
+    #[test]
+    fn freshness_without_age_or_interval_is_not_decayed() {
+        assert_eq!(freshness(None, Some(60.0), 3.0), 1.0);
+        assert_eq!(freshness(Some(600.0), None, 3.0), 1.0);
+        assert_eq!(freshness(Some(600.0), Some(0.0), 3.0), 1.0);
+    }
+
+    #[test]
+    fn model_kind_round_trips_through_string() {
+        for kind in [WaterLevelModelKind::Idw, WaterLevelModelKind::Linear, WaterLevelModelKind::Nearest, WaterLevelModelKind::Hydraulic] {
+            assert_eq!(kind.to_string().parse::<WaterLevelModelKind>().unwrap(), kind);
+        }
+    }
+}
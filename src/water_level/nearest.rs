@@ -0,0 +1,47 @@
+//! 最寄りのセンサー
+
+use super::{Contribution, WaterLevelModel};
+
+/// 河道に沿った距離が最も近いセンサーの水位をそのまま使う
+///
+/// 距離が同じ場合は計測値が新しいほうを使う。
+#[derive(Debug, Clone)]
+pub struct NearestSensor;
+
+impl WaterLevelModel for NearestSensor {
+    fn estimate(&self, contributions: &[Contribution]) -> Option<f64> {
+        contributions.iter()
+            .min_by(|a, b| {
+                a.distance.total_cmp(&b.distance)
+                    .then(b.freshness.total_cmp(&a.freshness))
+            })
+            .map(|contribution| contribution.water_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::water_level::Position;
+
+    fn contribution(water_level: f64, distance: f64, freshness: f64) -> Contribution {
+        Contribution { water_level, distance, position: Position::Other, freshness, bed_drop: None }
+    }
+
+    #[test]
+    fn picks_nearest_sensor() {
+        let level = NearestSensor.estimate(&[contribution(10.0, 300.0, 1.0), contribution(20.0, 100.0, 1.0)]);
+        assert_eq!(level, Some(20.0));
+    }
+
+    #[test]
+    fn prefers_fresher_sensor_on_tie() {
+        let level = NearestSensor.estimate(&[contribution(10.0, 100.0, 0.5), contribution(20.0, 100.0, 1.0)]);
+        assert_eq!(level, Some(20.0));
+    }
+
+    #[test]
+    fn no_contributions() {
+        assert_eq!(NearestSensor.estimate(&[]), None);
+    }
+}
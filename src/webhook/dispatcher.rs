@@ -0,0 +1,408 @@
+//! アウトボックスのイベントの配信
+//!
+//! 1. 未処理のイベントごとに、イベントの種類と水系が合う購読への`(:WebhookDelivery)`を作る
+//! 2. 再送時刻を過ぎた配信を送信し、結果を記録する
+//!
+//! 送信に失敗した配信は`webhook_retry_base`秒から倍々に間隔を空けて再送し、`webhook_max_attempts`回失敗したら諦める。
+
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use neo4rs::{query, Graph};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::env::EnvVars;
+use crate::webhook::sign;
+
+/// 1回の配信処理で送信する配信の最大数
+const BATCH_SIZE: i64 = 100;
+/// 1回の送信のタイムアウト(秒)
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// 送信する配信
+#[derive(Debug, Clone, Deserialize)]
+pub struct DueDelivery {
+    pub id: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: String,
+    pub url: String,
+    pub secret: String,
+    /// これまでに送信した回数
+    pub attempts: i64,
+}
+
+/// 1回の送信の結果と、それによる配信の状態
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attempt {
+    pub time: DateTime<Utc>,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    /// `pending`、`delivered`、`failed`のいずれか
+    pub status: &'static str,
+    /// この送信を含めた送信回数
+    pub attempts: i64,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+/// 配信の再送の方法
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// 最初の再送までの間隔(秒)。以降は倍々にする
+    pub retry_base: i64,
+    /// 諦めるまでの送信回数
+    pub max_attempts: i64,
+}
+
+impl From<&EnvVars> for RetryPolicy {
+    fn from(env: &EnvVars) -> Self {
+        RetryPolicy {
+            retry_base: env.webhook_retry_base,
+            max_attempts: env.webhook_max_attempts,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// `attempts`回失敗した配信を次に送信するまでの間隔
+    fn retry_delay(&self, attempts: i64) -> Duration {
+        Duration::seconds(self.retry_base.saturating_mul(1 << (attempts - 1).clamp(0, 16)))
+    }
+
+    /// 送信の結果から、配信の状態と次に送信する時刻を決める
+    fn attempt(&self, delivery: &DueDelivery, status_code: Option<u16>, error: Option<String>, time: DateTime<Utc>) -> Attempt {
+        let attempts = delivery.attempts + 1;
+        let status = if status_code.is_some_and(|code| (200..300).contains(&code)) {
+            "delivered"
+        } else if attempts >= self.max_attempts {
+            "failed"
+        } else {
+            "pending"
+        };
+
+        Attempt {
+            time,
+            status_code,
+            error,
+            status,
+            attempts,
+            next_attempt_at: time + self.retry_delay(attempts),
+        }
+    }
+}
+
+/// 配信の格納先
+#[async_trait]
+pub trait DeliveryQueue: Send + Sync {
+    /// 未処理のイベントを、条件の合う購読への配信に展開する
+    async fn fan_out(&self, now: DateTime<Utc>) -> anyhow::Result<()>;
+
+    /// 再送時刻を過ぎた配信を取得する
+    async fn due_deliveries(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<DueDelivery>>;
+
+    /// 送信の結果を記録し、配信の状態を更新する
+    async fn record_attempt(&self, delivery: &DueDelivery, attempt: &Attempt) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl DeliveryQueue for Graph {
+    async fn fan_out(&self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        self.run(query(
+            r#"
+// 1. 未処理のイベントを古い順に取得
+MATCH (event:OutboxEvent {dispatched: false})
+WITH event
+ORDER BY datetime(event.created_at)
+LIMIT $limit
+
+// 2. イベントの種類と水系が合う購読への配信を作る
+CALL {
+    WITH event
+    MATCH (subscription:WebhookSubscription)
+    WHERE event.type IN subscription.events
+      AND (subscription.river_system IS NULL OR subscription.river_system = event.river_system)
+    CREATE (subscription)-[:DELIVERY]->(delivery:WebhookDelivery {
+        id: randomUUID(),
+        subscription_id: subscription.id,
+        status: 'pending',
+        attempts: 0,
+        created_at: $now,
+        next_attempt_at: $now
+    })<-[:DELIVERY]-(event)
+}
+
+// 3. 処理済みにする
+SET event.dispatched = true
+            "#,
+        )
+            .param("limit", BATCH_SIZE)
+            .param("now", now.to_rfc3339())
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn due_deliveries(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<DueDelivery>> {
+        let mut result = self.execute(query(
+            r#"
+MATCH (event:OutboxEvent)-[:DELIVERY]->(delivery:WebhookDelivery {status: 'pending'})<-[:DELIVERY]-(subscription:WebhookSubscription)
+WHERE datetime(delivery.next_attempt_at) <= datetime($now)
+RETURN delivery.id AS id,
+       event.id AS event_id,
+       event.type AS event_type,
+       event.payload AS payload,
+       subscription.url AS url,
+       subscription.secret AS secret,
+       delivery.attempts AS attempts
+ORDER BY datetime(delivery.next_attempt_at)
+LIMIT $limit
+            "#,
+        )
+            .param("now", now.to_rfc3339())
+            .param("limit", BATCH_SIZE)
+        ).await?;
+
+        let mut deliveries = Vec::new();
+        while let Some(row) = result.next().await? {
+            deliveries.push(row.to()?);
+        }
+
+        Ok(deliveries)
+    }
+
+    async fn record_attempt(&self, delivery: &DueDelivery, attempt: &Attempt) -> anyhow::Result<()> {
+        self.run(query(
+            r#"
+// 1. 送信の結果を記録
+MATCH (delivery:WebhookDelivery {id: $id})
+CREATE (delivery)-[:ATTEMPT]->(:WebhookAttempt {
+    time: $now,
+    status_code: $status_code,
+    error: $error
+})
+
+// 2. 配信の状態を更新
+SET delivery.status = $status,
+    delivery.attempts = $attempts,
+    delivery.last_status_code = $status_code,
+    delivery.last_error = $error,
+    delivery.next_attempt_at = $next_attempt_at,
+    delivery.delivered_at = CASE WHEN $status = 'delivered' THEN $now ELSE null END
+            "#,
+        )
+            .param("id", delivery.id.clone())
+            .param("now", attempt.time.to_rfc3339())
+            .param("status_code", attempt.status_code.map(i64::from))
+            .param("error", attempt.error.clone())
+            .param("status", attempt.status)
+            .param("attempts", attempt.attempts)
+            .param("next_attempt_at", attempt.next_attempt_at.to_rfc3339())
+        ).await?;
+
+        Ok(())
+    }
+}
+
+/// 配信を1回送信し、ステータスコードとエラーを返す
+async fn send(client: &reqwest::Client, delivery: &DueDelivery) -> (Option<u16>, Option<String>) {
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(&delivery.secret, timestamp, delivery.payload.as_bytes());
+
+    let response = client.post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", &delivery.event_id)
+        .header("X-Webhook-Event", &delivery.event_type)
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", signature)
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) => {
+            let status = response.status();
+            (Some(status.as_u16()), (!status.is_success()).then(|| status.to_string()))
+        }
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+/// 未処理のイベントを展開し、再送時刻を過ぎた配信を送信する
+pub async fn dispatch(queue: &dyn DeliveryQueue, client: &reqwest::Client, policy: &RetryPolicy) -> anyhow::Result<()> {
+    queue.fan_out(Utc::now()).await?;
+
+    for delivery in queue.due_deliveries(Utc::now()).await? {
+        let (status_code, error) = send(client, &delivery).await;
+        let attempt = policy.attempt(&delivery, status_code, error, Utc::now());
+        queue.record_attempt(&delivery, &attempt).await?;
+
+        if attempt.status == "failed" {
+            println!("webhook: gave up delivering event {} to {} after {} attempts: {:?}", delivery.event_id, delivery.url, attempt.attempts, attempt.error);
+        }
+    }
+
+    Ok(())
+}
+
+/// `webhook_dispatch_interval`秒ごとに配信する
+pub async fn run(graph: Graph, env: Arc<EnvVars>) {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(REQUEST_TIMEOUT))
+        .build()
+        .expect("Failed to build HTTP client");
+    let policy = RetryPolicy::from(&*env);
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(env.webhook_dispatch_interval));
+    loop {
+        interval.tick().await;
+        if let Err(e) = dispatch(&graph, &client, &policy).await {
+            println!("webhook: {:?}", e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::{serve, Router};
+    use tokio::net::TcpListener;
+
+    /// 受信したリクエストのヘッダーとボディ
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+    /// これから応答するステータスコード
+    type Statuses = Arc<Mutex<VecDeque<StatusCode>>>;
+
+    /// 配信を1件だけ持つ格納先
+    struct SingleDelivery {
+        delivery: Mutex<(DueDelivery, &'static str, DateTime<Utc>)>,
+        attempts: Mutex<Vec<Attempt>>,
+    }
+
+    impl SingleDelivery {
+        fn new(url: String) -> Self {
+            let delivery = DueDelivery {
+                id: "delivery".to_string(),
+                event_id: "event".to_string(),
+                event_type: "alert_changed".to_string(),
+                payload: r#"{"type":"alert_changed"}"#.to_string(),
+                url,
+                secret: "secret".to_string(),
+                attempts: 0,
+            };
+
+            SingleDelivery {
+                delivery: Mutex::new((delivery, "pending", Utc::now())),
+                attempts: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl DeliveryQueue for SingleDelivery {
+        async fn fan_out(&self, _now: DateTime<Utc>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn due_deliveries(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<DueDelivery>> {
+            let guard = self.delivery.lock().unwrap();
+            let (delivery, status, next_attempt_at) = &*guard;
+            Ok((*status == "pending" && *next_attempt_at <= now).then(|| delivery.clone()).into_iter().collect())
+        }
+
+        async fn record_attempt(&self, _delivery: &DueDelivery, attempt: &Attempt) -> anyhow::Result<()> {
+            let mut guard = self.delivery.lock().unwrap();
+            let (delivery, status, next_attempt_at) = &mut *guard;
+            delivery.attempts = attempt.attempts;
+            *status = attempt.status;
+            *next_attempt_at = attempt.next_attempt_at;
+            self.attempts.lock().unwrap().push(attempt.clone());
+            Ok(())
+        }
+    }
+
+    /// 受信したリクエストを記録し、`statuses`の順に応答する受信側を起動してURLを返す
+    async fn spawn_receiver(statuses: Vec<StatusCode>) -> (String, Received) {
+        let received = Received::default();
+        let statuses = Statuses::new(Mutex::new(VecDeque::from(statuses)));
+
+        let router = Router::new()
+            .fallback(|State((received, statuses)): State<(Received, Statuses)>, headers: HeaderMap, body: Bytes| async move {
+                received.lock().unwrap().push((headers, body));
+                statuses.lock().unwrap().pop_front().unwrap_or(StatusCode::OK)
+            })
+            .with_state((received.clone(), statuses));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { serve(listener, router).await });
+
+        (url, received)
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_and_signed() {
+        let (url, received) = spawn_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+        let queue = SingleDelivery::new(url);
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy { retry_base: 0, max_attempts: 3 };
+
+        dispatch(&queue, &client, &policy).await.unwrap();
+        dispatch(&queue, &client, &policy).await.unwrap();
+        // 配信済みのものは送信しない
+        dispatch(&queue, &client, &policy).await.unwrap();
+
+        let attempts = queue.attempts.lock().unwrap().clone();
+        assert_eq!(attempts.iter().map(|attempt| (attempt.status_code, attempt.status, attempt.attempts)).collect::<Vec<_>>(), vec![
+            (Some(500), "pending", 1),
+            (Some(200), "delivered", 2),
+        ]);
+        assert!(attempts[0].error.is_some());
+        assert!(attempts[1].error.is_none());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        for (headers, body) in received.iter() {
+            assert_eq!(body.as_ref(), br#"{"type":"alert_changed"}"#);
+            assert_eq!(header(headers, "X-Webhook-Id"), "event");
+            assert_eq!(header(headers, "X-Webhook-Event"), "alert_changed");
+            let timestamp = header(headers, "X-Webhook-Timestamp").parse::<i64>().unwrap();
+            assert_eq!(header(headers, "X-Webhook-Signature"), sign("secret", timestamp, body));
+        }
+    }
+
+    #[tokio::test]
+    async fn delivery_gives_up_after_max_attempts() {
+        let (url, received) = spawn_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR; 3]).await;
+        let queue = SingleDelivery::new(url);
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy { retry_base: 0, max_attempts: 2 };
+
+        for _ in 0..3 {
+            dispatch(&queue, &client, &policy).await.unwrap();
+        }
+
+        let statuses = queue.attempts.lock().unwrap().iter().map(|attempt| attempt.status).collect::<Vec<_>>();
+        assert_eq!(statuses, vec!["pending", "failed"]);
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        let policy = RetryPolicy { retry_base: 30, max_attempts: 5 };
+        assert_eq!(policy.retry_delay(1), Duration::seconds(30));
+        assert_eq!(policy.retry_delay(2), Duration::seconds(60));
+        assert_eq!(policy.retry_delay(4), Duration::seconds(240));
+    }
+}
@@ -0,0 +1,324 @@
+//! 外部へのWebhook通知
+//!
+//! 通知するイベントは、発生元の処理と同じトランザクションで`(:OutboxEvent)`として保存する(アウトボックス)。
+//! 配信は`dispatcher`が定期的に行い、イベントごとに条件の合う購読(`(:WebhookSubscription)`)への
+//! `(:WebhookDelivery)`を作って送信する。失敗した配信は間隔を空けて再送し、送信のたびに
+//! `(:WebhookDelivery)-[:ATTEMPT]->(:WebhookAttempt)`を記録する。
+//!
+//! リクエストボディはイベントのJSONで、購読のシークレットを鍵として`{X-Webhook-Timestamp}\n{ボディ}`の
+//! HMAC-SHA256を16進数で`X-Webhook-Signature`に付ける。
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use neo4rs::{query, BoltType, Graph, Txn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::alert::AlertChange;
+use crate::auth::generate_secret;
+
+pub mod dispatcher;
+pub mod sink;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 通知するイベントの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display, strum::EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum EventType {
+    /// 警報の段階が変わった
+    AlertChanged,
+    /// センサーからの送信が途絶えた
+    SensorOffline,
+    /// 計測値がQCで棄却された
+    ReadingRejected,
+}
+
+impl From<openapi::models::WebhookEventType> for EventType {
+    fn from(value: openapi::models::WebhookEventType) -> Self {
+        match value {
+            openapi::models::WebhookEventType::AlertChanged => EventType::AlertChanged,
+            openapi::models::WebhookEventType::SensorOffline => EventType::SensorOffline,
+            openapi::models::WebhookEventType::ReadingRejected => EventType::ReadingRejected,
+        }
+    }
+}
+
+impl From<EventType> for openapi::models::WebhookEventType {
+    fn from(value: EventType) -> Self {
+        match value {
+            EventType::AlertChanged => openapi::models::WebhookEventType::AlertChanged,
+            EventType::SensorOffline => openapi::models::WebhookEventType::SensorOffline,
+            EventType::ReadingRejected => openapi::models::WebhookEventType::ReadingRejected,
+        }
+    }
+}
+
+/// 通知するイベント
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub sensor_id: String,
+    pub time: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    pub fn new(event_type: EventType, sensor_id: String, data: serde_json::Value) -> Self {
+        Event {
+            id: Uuid::new_v4(),
+            event_type,
+            sensor_id,
+            time: Utc::now(),
+            data,
+        }
+    }
+
+    /// 警報の段階が変わったことを表すイベント
+    pub fn alert_changed(change: &AlertChange) -> Self {
+        Event::new(
+            EventType::AlertChanged,
+            change.sensor_id.clone(),
+            serde_json::json!({
+                "from": change.from.to_string(),
+                "to": change.to.to_string(),
+                "level": change.level,
+                "time": change.time,
+            }),
+        )
+    }
+}
+
+/// Webhookのリクエストの署名を計算する
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{timestamp}\n").as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// イベントをアウトボックスに保存する
+///
+/// イベントを発生させた処理と同じトランザクションで呼び出す。購読の絞り込みに使うため、
+/// センサーが所属する河川ノードの水系も保存する。
+pub async fn enqueue(tnx: &mut Txn, events: &[Event]) -> anyhow::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let events = events.iter()
+        .map(|event| anyhow::Ok(HashMap::from([
+            ("id".to_string(), BoltType::from(event.id.to_string())),
+            ("type".to_string(), BoltType::from(event.event_type.to_string())),
+            ("sensor_id".to_string(), BoltType::from(event.sensor_id.clone())),
+            ("time".to_string(), BoltType::from(event.time.to_rfc3339())),
+            ("payload".to_string(), BoltType::from(serde_json::to_string(event)?)),
+        ])))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    tnx.run(query(
+        r#"
+UNWIND $events AS event
+OPTIONAL MATCH (:Sensor {id: event.sensor_id})-[:BELONGS_TO]->(:RiverNode)<-[:IN_SYSTEM]-(riverSystem:RiverSystem)
+WITH event, head(collect(riverSystem.id)) AS riverSystem
+CREATE (:OutboxEvent {
+    id: event.id,
+    type: event.type,
+    sensor_id: event.sensor_id,
+    river_system: riverSystem,
+    payload: event.payload,
+    created_at: event.time,
+    dispatched: false
+})
+        "#,
+    )
+        .param("events", events)
+    ).await?;
+
+    Ok(())
+}
+
+/// Webhookの購読
+#[derive(Debug, Clone, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    /// この水系のセンサーのイベントのみ通知する
+    pub river_system: Option<String>,
+    pub created_at: String,
+}
+
+impl TryFrom<Subscription> for openapi::models::WebhookSubscription {
+    type Error = anyhow::Error;
+
+    fn try_from(subscription: Subscription) -> Result<Self, Self::Error> {
+        let Subscription { id, url, events, river_system, created_at } = subscription;
+
+        Ok(openapi::models::WebhookSubscription {
+            id: id.parse()?,
+            url,
+            events: events.iter()
+                .map(|event| anyhow::Ok(event.parse::<EventType>()?.into()))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            river_system,
+            created_at: DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc),
+        })
+    }
+}
+
+/// 購読を作成し、作成した購読と署名用シークレットを返す
+pub async fn subscribe(graph: &Graph, url: String, events: &[EventType], river_system: Option<String>) -> anyhow::Result<(Subscription, String)> {
+    let secret = generate_secret();
+    let subscription = Subscription {
+        id: Uuid::new_v4().to_string(),
+        url,
+        events: events.iter().map(|event| event.to_string()).collect(),
+        river_system,
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    graph.run(query(
+        r#"
+CREATE (:WebhookSubscription {
+    id: $id,
+    url: $url,
+    events: $events,
+    river_system: $river_system,
+    secret: $secret,
+    created_at: $created_at
+})
+        "#,
+    )
+        .param("id", subscription.id.clone())
+        .param("url", subscription.url.clone())
+        .param("events", subscription.events.clone())
+        .param("river_system", subscription.river_system.clone())
+        .param("secret", secret.clone())
+        .param("created_at", subscription.created_at.clone())
+    ).await?;
+
+    Ok((subscription, secret))
+}
+
+/// 購読の一覧を取得する
+pub async fn list_subscriptions(graph: &Graph) -> anyhow::Result<Vec<Subscription>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (subscription:WebhookSubscription)
+RETURN subscription.id AS id,
+       subscription.url AS url,
+       subscription.events AS events,
+       subscription.river_system AS river_system,
+       subscription.created_at AS created_at
+ORDER BY created_at
+        "#,
+    )).await?;
+
+    let mut subscriptions = Vec::new();
+    while let Some(row) = result.next().await? {
+        subscriptions.push(row.to()?);
+    }
+
+    Ok(subscriptions)
+}
+
+/// 購読を削除する
+///
+/// 未配信の配信は失敗として残す。購読がない場合は`false`を返す。
+pub async fn unsubscribe(graph: &Graph, id: &str) -> anyhow::Result<bool> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (subscription:WebhookSubscription {id: $id})
+OPTIONAL MATCH (subscription)-[:DELIVERY]->(delivery:WebhookDelivery {status: 'pending'})
+SET delivery.status = 'failed',
+    delivery.last_error = 'unsubscribed'
+WITH DISTINCT subscription
+DETACH DELETE subscription
+RETURN count(*) AS count
+        "#,
+    )
+        .param("id", id)
+    ).await?;
+
+    let mut count = 0;
+    while let Some(row) = result.next().await? {
+        count = row.get::<i64>("count")?;
+    }
+
+    Ok(count > 0)
+}
+
+/// 配信の記録
+#[derive(Debug, Clone, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    pub subscription_id: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub status: String,
+    pub attempts: i64,
+    pub last_status_code: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+}
+
+impl TryFrom<Delivery> for openapi::models::WebhookDelivery {
+    type Error = anyhow::Error;
+
+    fn try_from(delivery: Delivery) -> Result<Self, Self::Error> {
+        let Delivery { id, subscription_id, event_id, event_type, status, attempts, last_status_code, last_error, created_at, delivered_at } = delivery;
+        let parse_time = |time: &str| anyhow::Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc));
+
+        Ok(openapi::models::WebhookDelivery {
+            id: id.parse()?,
+            subscription_id: subscription_id.parse()?,
+            event_id: event_id.parse()?,
+            event_type: event_type.parse::<EventType>()?.into(),
+            status,
+            attempts: attempts as i32,
+            last_status_code: last_status_code.map(|code| code as i32),
+            last_error,
+            created_at: parse_time(&created_at)?,
+            delivered_at: delivered_at.as_deref().map(parse_time).transpose()?,
+        })
+    }
+}
+
+/// 配信の記録を新しい順に取得する
+pub async fn list_deliveries(graph: &Graph, subscription_id: Option<String>, limit: i64) -> anyhow::Result<Vec<Delivery>> {
+    let mut result = graph.execute(query(
+        r#"
+MATCH (event:OutboxEvent)-[:DELIVERY]->(delivery:WebhookDelivery)
+WHERE $subscription_id IS NULL OR delivery.subscription_id = $subscription_id
+RETURN delivery.id AS id,
+       delivery.subscription_id AS subscription_id,
+       event.id AS event_id,
+       event.type AS event_type,
+       delivery.status AS status,
+       delivery.attempts AS attempts,
+       delivery.last_status_code AS last_status_code,
+       delivery.last_error AS last_error,
+       delivery.created_at AS created_at,
+       delivery.delivered_at AS delivered_at
+ORDER BY datetime(created_at) DESC
+LIMIT $limit
+        "#,
+    )
+        .param("subscription_id", subscription_id)
+        .param("limit", limit)
+    ).await?;
+
+    let mut deliveries = Vec::new();
+    while let Some(row) = result.next().await? {
+        deliveries.push(row.to()?);
+    }
+
+    Ok(deliveries)
+}
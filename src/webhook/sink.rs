@@ -0,0 +1,70 @@
+//! Webhookの受信を確認するためのHTTPサーバー
+//!
+//! 受け取ったリクエストを標準出力に表示し、シークレットが指定されていれば署名を検証する。
+//! 応答のステータスコードを変えて、再送の動作も確認できる。
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::{serve, Router};
+use clap::Args;
+use tokio::net::TcpListener;
+
+use crate::webhook::sign;
+
+#[derive(Debug, Args)]
+pub struct SinkArgs {
+    /// 待ち受けるアドレス
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    pub listen: String,
+    /// 署名の検証に使うシークレット(省略時は検証しない)
+    #[arg(long)]
+    pub secret: Option<String>,
+    /// 応答するステータスコード
+    #[arg(long, default_value_t = 200)]
+    pub status: u16,
+}
+
+#[derive(Clone)]
+struct SinkState {
+    secret: Option<String>,
+    status: StatusCode,
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+    headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or("")
+}
+
+async fn receive(State(state): State<SinkState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let event = header(&headers, "X-Webhook-Event");
+    let id = header(&headers, "X-Webhook-Id");
+    let timestamp = header(&headers, "X-Webhook-Timestamp");
+    let signature = header(&headers, "X-Webhook-Signature");
+
+    let verified = match (&state.secret, timestamp.parse::<i64>()) {
+        (Some(secret), Ok(timestamp)) => Some(sign(secret, timestamp, &body) == signature),
+        (Some(_), Err(_)) => Some(false),
+        (None, _) => None,
+    };
+
+    println!("{event} {id} (timestamp: {timestamp}, signature verified: {verified:?})");
+    println!("{}", String::from_utf8_lossy(&body));
+
+    state.status
+}
+
+/// `webhook-sink`サブコマンドを実行する
+pub async fn run_sink(args: SinkArgs) -> anyhow::Result<()> {
+    let SinkArgs { listen, secret, status } = args;
+
+    let state = SinkState { secret, status: StatusCode::from_u16(status)? };
+    let router = Router::new()
+        .fallback(receive)
+        .with_state(state);
+
+    let listener = TcpListener::bind(&listen).await?;
+    println!("Listening on {listen}");
+    serve(listener, router).await?;
+
+    Ok(())
+}